@@ -0,0 +1,429 @@
+use crate::protocol::{Command, RebootTarget, Response};
+use std::collections::VecDeque;
+use tracing::{debug, trace};
+
+/// Protocol version reported through `getvar:version`
+const PROTOCOL_VERSION: &str = "0.4";
+
+/// Default size of the download buffer
+pub const DEFAULT_MAX_DOWNLOAD_SIZE: u32 = 64 * 1024 * 1024;
+
+/// Sends INFO messages back to the host while a command is being handled
+pub struct Reporter<'a> {
+    out: &'a mut VecDeque<Vec<u8>>,
+}
+
+impl Reporter<'_> {
+    pub fn info(&mut self, message: impl Into<String>) {
+        self.out
+            .push_back(Response::Info(message.into()).to_bytes());
+    }
+}
+
+/// Implements the device specific parts of the fastboot commands
+///
+/// Every method defaults to refusing the command. Errors are reported to the host as FAIL with
+/// the returned message.
+pub trait Handler {
+    /// Value of the given variable
+    fn getvar(&mut self, var: &str) -> Result<String, String> {
+        let _ = var;
+        Err("unknown variable".to_string())
+    }
+
+    /// All variables, as reported for `getvar:all`
+    fn vars(&mut self) -> Vec<(String, String)> {
+        vec![]
+    }
+
+    /// Write downloaded data to a partition
+    fn flash(
+        &mut self,
+        partition: &str,
+        data: &[u8],
+        reporter: &mut Reporter,
+    ) -> Result<(), String> {
+        let _ = (partition, data, reporter);
+        Err("flashing not supported".to_string())
+    }
+
+    fn erase(&mut self, partition: &str, reporter: &mut Reporter) -> Result<(), String> {
+        let _ = (partition, reporter);
+        Err("erasing not supported".to_string())
+    }
+
+    /// Read `size` bytes at `offset` of a partition, to be sent to the host
+    fn fetch(&mut self, partition: &str, offset: u64, size: u64) -> Result<Vec<u8>, String> {
+        let _ = (partition, offset, size);
+        Err("fetching not supported".to_string())
+    }
+
+    /// Check that the downloaded data can be booted; the boot itself happens once the device
+    /// exits with [Exit::Boot]
+    fn boot(&mut self, data: &[u8]) -> Result<(), String> {
+        let _ = data;
+        Err("booting not supported".to_string())
+    }
+
+    fn set_active(&mut self, slot: &str) -> Result<(), String> {
+        let _ = slot;
+        Err("slots not supported".to_string())
+    }
+
+    /// Vendor specific `oem` commands, returning the OKAY message
+    fn oem(&mut self, command: &str, reporter: &mut Reporter) -> Result<String, String> {
+        let _ = (command, reporter);
+        Err("unknown oem command".to_string())
+    }
+
+    /// `flashing lock`, `flashing unlock` and friends
+    fn flashing(&mut self, command: &str, reporter: &mut Reporter) -> Result<(), String> {
+        let _ = (command, reporter);
+        Err("unknown flashing command".to_string())
+    }
+}
+
+/// Why a device stopped serving commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    Reboot(RebootTarget),
+    Powerdown,
+    Continue,
+    /// Boot the downloaded data, available through [Device::download_buffer]
+    Boot,
+}
+
+#[derive(Debug)]
+enum State {
+    Command,
+    Download { remaining: usize },
+    Exit(Exit),
+}
+
+/// The device side of the fastboot protocol
+///
+/// Transport agnostic: packets received from the host are fed in with [Device::handle], and the
+/// responses to send back are taken out with [Device::next_packet].
+pub struct Device<H> {
+    handler: H,
+    state: State,
+    max_download_size: u32,
+    download: Vec<u8>,
+    /// Data staged with [Device::stage] for the following `upload`
+    staged: Option<Vec<u8>>,
+    out: VecDeque<Vec<u8>>,
+}
+
+impl<H: Handler> Device<H> {
+    pub fn new(handler: H) -> Self {
+        Self {
+            handler,
+            state: State::Command,
+            max_download_size: DEFAULT_MAX_DOWNLOAD_SIZE,
+            download: vec![],
+            staged: None,
+            out: VecDeque::new(),
+        }
+    }
+
+    pub fn with_max_download_size(mut self, size: u32) -> Self {
+        self.max_download_size = size;
+        self
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// Data received by the last download
+    pub fn download_buffer(&self) -> &[u8] {
+        &self.download
+    }
+
+    /// Stage data for a following `upload` command
+    pub fn stage(&mut self, data: Vec<u8>) {
+        self.staged = Some(data);
+    }
+
+    /// Bytes of download data still expected from the host, if a download is in progress
+    pub fn download_remaining(&self) -> Option<usize> {
+        match self.state {
+            State::Download { remaining } => Some(remaining),
+            _ => None,
+        }
+    }
+
+    /// Set once a command ended the session; the remaining packets should still be sent
+    pub fn exit(&self) -> Option<Exit> {
+        match self.state {
+            State::Exit(exit) => Some(exit),
+            _ => None,
+        }
+    }
+
+    /// Next packet to send to the host
+    pub fn next_packet(&mut self) -> Option<Vec<u8>> {
+        self.out.pop_front()
+    }
+
+    fn respond(&mut self, response: Response) {
+        trace!("Response: {}", response);
+        self.out.push_back(response.to_bytes());
+    }
+
+    fn reply<T>(&mut self, result: Result<T, String>, okay: impl FnOnce(T) -> String) {
+        match result {
+            Ok(value) => self.respond(Response::Okay(okay(value))),
+            Err(reason) => self.respond(Response::Fail(reason)),
+        }
+    }
+
+    /// Process a packet received from the host
+    pub fn handle(&mut self, packet: &[u8]) {
+        match self.state {
+            State::Command => self.handle_command(packet),
+            State::Download { remaining } => {
+                if packet.len() > remaining {
+                    self.state = State::Command;
+                    self.respond(Response::Fail("too much download data".to_string()));
+                    return;
+                }
+                self.download.extend_from_slice(packet);
+                let remaining = remaining - packet.len();
+                if remaining == 0 {
+                    debug!("Download of {} bytes complete", self.download.len());
+                    self.state = State::Command;
+                    self.respond(Response::Okay(String::new()));
+                } else {
+                    self.state = State::Download { remaining };
+                }
+            }
+            State::Exit(_) => self.respond(Response::Fail("device is exiting".to_string())),
+        }
+    }
+
+    fn handle_command(&mut self, packet: &[u8]) {
+        let cmd = match Command::from_bytes(packet) {
+            Ok(cmd) => cmd,
+            Err(err) => return self.respond(Response::Fail(err.to_string())),
+        };
+        debug!("Command: {:?}", cmd);
+
+        match cmd {
+            Command::GetVar(var) => self.getvar(&var),
+            Command::Download(size) => {
+                if size > self.max_download_size {
+                    return self.respond(Response::Fail("data too large".to_string()));
+                }
+                self.download = Vec::with_capacity(size as usize);
+                self.respond(Response::Data(size));
+                if size == 0 {
+                    self.respond(Response::Okay(String::new()));
+                } else {
+                    self.state = State::Download {
+                        remaining: size as usize,
+                    };
+                }
+            }
+            Command::Fetch(partition, offset, size) => {
+                match self.handler.fetch(&partition, offset, size) {
+                    Ok(data) => {
+                        self.respond(Response::Data(data.len() as u32));
+                        self.out.push_back(data);
+                        self.respond(Response::Okay(String::new()));
+                    }
+                    Err(reason) => self.respond(Response::Fail(reason)),
+                }
+            }
+            Command::Upload => match self.staged.take() {
+                Some(data) => {
+                    self.respond(Response::Data(data.len() as u32));
+                    self.out.push_back(data);
+                    self.respond(Response::Okay(String::new()));
+                }
+                None => self.respond(Response::Fail("nothing to upload".to_string())),
+            },
+            Command::Flash(partition) => {
+                let mut reporter = Reporter { out: &mut self.out };
+                let result = self
+                    .handler
+                    .flash(&partition, &self.download, &mut reporter);
+                self.reply(result, |()| String::new());
+            }
+            Command::Erase(partition) => {
+                let mut reporter = Reporter { out: &mut self.out };
+                let result = self.handler.erase(&partition, &mut reporter);
+                self.reply(result, |()| String::new());
+            }
+            Command::SetActive(slot) => {
+                let result = self.handler.set_active(&slot);
+                self.reply(result, |()| String::new());
+            }
+            Command::Oem(command) => {
+                let mut reporter = Reporter { out: &mut self.out };
+                let result = self.handler.oem(&command, &mut reporter);
+                self.reply(result, |value| value);
+            }
+            Command::Flashing(command) => {
+                let mut reporter = Reporter { out: &mut self.out };
+                let result = self.handler.flashing(&command, &mut reporter);
+                self.reply(result, |()| String::new());
+            }
+            Command::Boot => match self.handler.boot(&self.download) {
+                Ok(()) => self.exit_with(Exit::Boot),
+                Err(reason) => self.respond(Response::Fail(reason)),
+            },
+            Command::Continue => self.exit_with(Exit::Continue),
+            Command::Reboot(target) => self.exit_with(Exit::Reboot(target)),
+            Command::Powerdown => self.exit_with(Exit::Powerdown),
+            Command::Unknown(cmd) => self.respond(Response::Fail(format!("unknown command {cmd}"))),
+        }
+    }
+
+    fn exit_with(&mut self, exit: Exit) {
+        self.respond(Response::Okay(String::new()));
+        self.state = State::Exit(exit);
+    }
+
+    /// Variables answered by the device itself rather than the handler
+    fn builtin_vars(&self) -> [(&'static str, String); 2] {
+        [
+            ("version", PROTOCOL_VERSION.to_string()),
+            (
+                "max-download-size",
+                format!("0x{:08x}", self.max_download_size),
+            ),
+        ]
+    }
+
+    fn getvar(&mut self, var: &str) {
+        if var == "all" {
+            let builtin = self.builtin_vars().map(|(k, v)| (k.to_string(), v));
+            let vars: Vec<_> = builtin.into_iter().chain(self.handler.vars()).collect();
+            for (key, value) in vars {
+                self.respond(Response::Info(format!("{key}:{value}")));
+            }
+            return self.respond(Response::Okay(String::new()));
+        }
+
+        if let Some((_, value)) = self.builtin_vars().into_iter().find(|(k, _)| *k == var) {
+            return self.respond(Response::Okay(value));
+        }
+        let result = self.handler.getvar(var);
+        self.reply(result, |value| value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct TestHandler {
+        partitions: HashMap<String, Vec<u8>>,
+    }
+
+    impl Handler for TestHandler {
+        fn getvar(&mut self, var: &str) -> Result<String, String> {
+            match var.split_once(':') {
+                Some(("partition-size", partition)) => self
+                    .partitions
+                    .get(partition)
+                    .map(|data| format!("0x{:x}", data.len()))
+                    .ok_or_else(|| "no such partition".to_string()),
+                _ => Err("unknown variable".to_string()),
+            }
+        }
+
+        fn flash(
+            &mut self,
+            partition: &str,
+            data: &[u8],
+            reporter: &mut Reporter,
+        ) -> Result<(), String> {
+            reporter.info(format!("writing {partition}"));
+            self.partitions.insert(partition.to_string(), data.to_vec());
+            Ok(())
+        }
+    }
+
+    fn responses<H: Handler>(device: &mut Device<H>) -> Vec<String> {
+        std::iter::from_fn(|| device.next_packet())
+            .map(|p| String::from_utf8(p).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn getvar() {
+        let mut device = Device::new(TestHandler::default()).with_max_download_size(0x1000);
+        device.handle(b"getvar:max-download-size");
+        device.handle(b"getvar:version");
+        device.handle(b"getvar:partition-size:boot");
+        assert_eq!(
+            responses(&mut device),
+            ["OKAY0x00001000", "OKAY0.4", "FAILno such partition"]
+        );
+    }
+
+    #[test]
+    fn download_and_flash() {
+        let mut device = Device::new(TestHandler::default());
+        device.handle(b"download:00000006");
+        assert_eq!(device.download_remaining(), Some(6));
+        device.handle(b"abc");
+        device.handle(b"def");
+        assert_eq!(device.download_remaining(), None);
+        device.handle(b"flash:boot");
+        assert_eq!(
+            responses(&mut device),
+            ["DATA00000006", "OKAY", "INFOwriting boot", "OKAY"]
+        );
+        assert_eq!(device.handler().partitions["boot"], b"abcdef");
+    }
+
+    #[test]
+    fn download_limits() {
+        let mut device = Device::new(TestHandler::default()).with_max_download_size(4);
+        device.handle(b"download:00000005");
+        device.handle(b"download:00000002");
+        device.handle(b"abc");
+        assert_eq!(
+            responses(&mut device),
+            [
+                "FAILdata too large",
+                "DATA00000002",
+                "FAILtoo much download data"
+            ]
+        );
+    }
+
+    #[test]
+    fn exit() {
+        let mut device = Device::new(TestHandler::default());
+        device.handle(b"boot");
+        assert_eq!(device.exit(), None);
+        device.handle(b"reboot-bootloader");
+        assert_eq!(
+            responses(&mut device),
+            ["FAILbooting not supported", "OKAY"]
+        );
+        assert_eq!(device.exit(), Some(Exit::Reboot(RebootTarget::Bootloader)));
+    }
+
+    #[test]
+    fn upload_staged() {
+        let mut device = Device::new(TestHandler::default());
+        device.handle(b"upload");
+        device.stage(b"hello".to_vec());
+        device.handle(b"upload");
+        assert_eq!(
+            responses(&mut device),
+            ["FAILnothing to upload", "DATA00000005", "hello", "OKAY"]
+        );
+    }
+}
@@ -0,0 +1,13 @@
+//! The device side of the fastboot protocol.
+//!
+//! [Device] implements the protocol itself, independent of any transport: packets from the host
+//! go in, response packets come out, and the device specific work (flashing, erasing,
+//! variables...) is delegated to a [Handler]. The [tcp] module serves it over TCP; the
+//! `fastboot_gadget` crate exposes it over USB with FunctionFS.
+
+mod device;
+mod protocol;
+pub mod tcp;
+
+pub use device::{Device, Exit, Handler, Reporter, DEFAULT_MAX_DOWNLOAD_SIZE};
+pub use protocol::{Command, CommandParseError, RebootTarget, Response, MAX_RESPONSE_SIZE};
@@ -0,0 +1,206 @@
+use std::fmt::Display;
+use thiserror::Error;
+
+/// Largest response packet a fastboot host is guaranteed to accept
+pub const MAX_RESPONSE_SIZE: usize = 64;
+
+/// Parse a hexadecimal number, with or without a 0x prefix
+fn parse_hex<T: TryFrom<u64>>(hex: &str) -> Option<T> {
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    u64::from_str_radix(hex, 16).ok()?.try_into().ok()
+}
+
+/// Errors parsing a command sent by the host
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CommandParseError {
+    #[error("Command is not valid utf-8")]
+    Utf8,
+    #[error("Invalid argument for {0}")]
+    Argument(&'static str),
+}
+
+/// Where to reboot to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebootTarget {
+    System,
+    Bootloader,
+    Fastboot,
+    Recovery,
+}
+
+/// Commands sent by a fastboot host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    GetVar(String),
+    Download(u32),
+    Upload,
+    Fetch(String, u64, u64),
+    Flash(String),
+    Erase(String),
+    Boot,
+    Continue,
+    Reboot(RebootTarget),
+    Powerdown,
+    SetActive(String),
+    Oem(String),
+    Flashing(String),
+    /// Anything this implementation doesn't know about
+    Unknown(String),
+}
+
+impl Command {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CommandParseError> {
+        let cmd = std::str::from_utf8(bytes).map_err(|_| CommandParseError::Utf8)?;
+        let cmd = match cmd.split_once([':', ' ']) {
+            Some(("getvar", var)) => Command::GetVar(var.to_string()),
+            Some(("download", size)) => {
+                Command::Download(parse_hex(size).ok_or(CommandParseError::Argument("download"))?)
+            }
+            Some(("fetch", args)) => {
+                let mut parts = args.rsplitn(3, ':');
+                let (Some(size), Some(offset), Some(partition)) =
+                    (parts.next(), parts.next(), parts.next())
+                else {
+                    return Err(CommandParseError::Argument("fetch"));
+                };
+                Command::Fetch(
+                    partition.to_string(),
+                    parse_hex(offset).ok_or(CommandParseError::Argument("fetch"))?,
+                    parse_hex(size).ok_or(CommandParseError::Argument("fetch"))?,
+                )
+            }
+            Some(("flash", partition)) => Command::Flash(partition.to_string()),
+            Some(("erase", partition)) => Command::Erase(partition.to_string()),
+            Some(("set_active", slot)) => Command::SetActive(slot.to_string()),
+            Some(("oem", args)) => Command::Oem(args.to_string()),
+            Some(("flashing", args)) => Command::Flashing(args.to_string()),
+            _ => match cmd {
+                "upload" => Command::Upload,
+                "boot" => Command::Boot,
+                "continue" => Command::Continue,
+                "reboot" => Command::Reboot(RebootTarget::System),
+                "reboot-bootloader" | "reboot:bootloader" => {
+                    Command::Reboot(RebootTarget::Bootloader)
+                }
+                "reboot-fastboot" | "reboot:fastboot" => Command::Reboot(RebootTarget::Fastboot),
+                "reboot-recovery" | "reboot:recovery" => Command::Reboot(RebootTarget::Recovery),
+                "powerdown" => Command::Powerdown,
+                other => Command::Unknown(other.to_string()),
+            },
+        };
+        Ok(cmd)
+    }
+}
+
+/// Responses sent back to the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Okay(String),
+    Info(String),
+    Fail(String),
+    Data(u32),
+}
+
+impl Response {
+    /// Encode the response, truncating messages that don't fit in a single packet
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.to_string().into_bytes();
+        if out.len() > MAX_RESPONSE_SIZE {
+            let mut len = MAX_RESPONSE_SIZE;
+            while std::str::from_utf8(&out[..len]).is_err() {
+                len -= 1;
+            }
+            out.truncate(len);
+        }
+        out
+    }
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Response::Okay(value) => write!(f, "OKAY{value}"),
+            Response::Info(info) => write!(f, "INFO{info}"),
+            Response::Fail(reason) => write!(f, "FAIL{reason}"),
+            Response::Data(size) => write!(f, "DATA{size:08x}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(cmd: &str) -> Command {
+        Command::from_bytes(cmd.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            parse("getvar:partition-size:boot_a"),
+            Command::GetVar("partition-size:boot_a".to_string())
+        );
+        assert_eq!(parse("download:00001000"), Command::Download(0x1000));
+        assert_eq!(parse("download:0x00001000"), Command::Download(0x1000));
+        assert_eq!(parse("flash:boot"), Command::Flash("boot".to_string()));
+        assert_eq!(
+            parse("erase:userdata"),
+            Command::Erase("userdata".to_string())
+        );
+        assert_eq!(parse("set_active:b"), Command::SetActive("b".to_string()));
+        assert_eq!(
+            parse("oem device-info"),
+            Command::Oem("device-info".to_string())
+        );
+        assert_eq!(
+            parse("flashing unlock"),
+            Command::Flashing("unlock".to_string())
+        );
+        assert_eq!(parse("upload"), Command::Upload);
+        assert_eq!(parse("boot"), Command::Boot);
+        assert_eq!(parse("reboot"), Command::Reboot(RebootTarget::System));
+        assert_eq!(
+            parse("reboot-bootloader"),
+            Command::Reboot(RebootTarget::Bootloader)
+        );
+        assert_eq!(
+            parse("reboot:fastboot"),
+            Command::Reboot(RebootTarget::Fastboot)
+        );
+        assert_eq!(
+            parse("frobnicate"),
+            Command::Unknown("frobnicate".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_fetch() {
+        assert_eq!(
+            parse("fetch:vendor_boot:a:0x00001000:0x00000200"),
+            Command::Fetch("vendor_boot:a".to_string(), 0x1000, 0x200)
+        );
+        assert_eq!(
+            Command::from_bytes(b"fetch:boot:0x10"),
+            Err(CommandParseError::Argument("fetch"))
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(
+            Command::from_bytes(b"download:zz"),
+            Err(CommandParseError::Argument("download"))
+        );
+        assert_eq!(Command::from_bytes(b"\xff"), Err(CommandParseError::Utf8));
+    }
+
+    #[test]
+    fn encode_responses() {
+        assert_eq!(Response::Okay("".to_string()).to_bytes(), b"OKAY");
+        assert_eq!(Response::Data(0x1234).to_bytes(), b"DATA00001234");
+        let long = Response::Info("é".repeat(40)).to_bytes();
+        assert_eq!(long.len(), MAX_RESPONSE_SIZE);
+        assert!(std::str::from_utf8(&long).is_ok());
+    }
+}
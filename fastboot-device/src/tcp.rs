@@ -0,0 +1,185 @@
+//! Fastboot over TCP, as spoken by `fastboot -s tcp:<host>`
+//!
+//! After a `FB01` handshake in both directions, every packet is prefixed with its length as a
+//! 64-bit big-endian number.
+
+use crate::device::{Device, Exit, Handler};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use tracing::{debug, info};
+
+const HANDSHAKE: &[u8; 4] = b"FB01";
+/// Largest packet accepted outside of a download
+const MAX_COMMAND_SIZE: usize = 4096;
+
+fn handshake<S: Read + Write>(stream: &mut S) -> io::Result<()> {
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf)?;
+    // Only the "FB" part is fixed, the version is whatever the host speaks
+    if &buf[..2] != b"FB" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad fastboot handshake",
+        ));
+    }
+    stream.write_all(HANDSHAKE)
+}
+
+/// Read the next packet, or `None` if the host disconnected
+fn read_packet<S: Read>(stream: &mut S, limit: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 8];
+    match stream.read_exact(&mut len) {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    }
+    let len = u64::from_be_bytes(len);
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|&len| len <= limit)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "packet too large"))?;
+    let mut packet = vec![0; len];
+    stream.read_exact(&mut packet)?;
+    Ok(Some(packet))
+}
+
+fn write_packet<S: Write>(stream: &mut S, packet: &[u8]) -> io::Result<()> {
+    stream.write_all(&(packet.len() as u64).to_be_bytes())?;
+    stream.write_all(packet)
+}
+
+/// Serve a single host connection
+///
+/// Returns once the host disconnects, or with the [Exit] of a command that ended the session
+/// after its response has been sent.
+pub fn serve<S: Read + Write, H: Handler>(
+    stream: &mut S,
+    device: &mut Device<H>,
+) -> io::Result<Option<Exit>> {
+    handshake(stream)?;
+    loop {
+        while let Some(packet) = device.next_packet() {
+            write_packet(stream, &packet)?;
+        }
+        stream.flush()?;
+        if let Some(exit) = device.exit() {
+            return Ok(Some(exit));
+        }
+
+        let limit = device
+            .download_remaining()
+            .unwrap_or_default()
+            .max(MAX_COMMAND_SIZE);
+        match read_packet(stream, limit)? {
+            Some(packet) => device.handle(&packet),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Accept host connections one at a time until one of them ends the session
+pub fn listen<A: ToSocketAddrs, H: Handler>(addr: A, device: &mut Device<H>) -> io::Result<Exit> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Listening for fastboot on {}", listener.local_addr()?);
+    loop {
+        let (mut stream, peer) = listener.accept()?;
+        debug!("Connection from {peer}");
+        match serve(&mut stream, device) {
+            Ok(Some(exit)) => return Ok(exit),
+            Ok(None) => debug!("{peer} disconnected"),
+            Err(err) => debug!("Connection to {peer} failed: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::Handler;
+    use crate::protocol::RebootTarget;
+    use std::io::Cursor;
+
+    struct NoopHandler;
+
+    impl Handler for NoopHandler {}
+
+    /// A connection with everything the host sends queued up front
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn pipe(packets: &[&[u8]]) -> Pipe {
+        let mut input = HANDSHAKE.to_vec();
+        for packet in packets {
+            write_packet(&mut input, packet).unwrap();
+        }
+        Pipe {
+            input: Cursor::new(input),
+            output: vec![],
+        }
+    }
+
+    fn packets(mut output: &[u8]) -> Vec<Vec<u8>> {
+        assert_eq!(&output[..4], HANDSHAKE);
+        output = &output[4..];
+        let mut packets = vec![];
+        while let Some(packet) = read_packet(&mut output, usize::MAX).unwrap() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[test]
+    fn serve_until_disconnect() {
+        let mut device = Device::new(NoopHandler).with_max_download_size(0x100);
+        let mut pipe = pipe(&[b"getvar:max-download-size", b"download:00000002", b"hi"]);
+        assert_eq!(serve(&mut pipe, &mut device).unwrap(), None);
+        assert_eq!(
+            packets(&pipe.output),
+            [&b"OKAY0x00000100"[..], b"DATA00000002", b"OKAY"]
+        );
+        assert_eq!(device.download_buffer(), b"hi");
+    }
+
+    #[test]
+    fn serve_until_exit() {
+        let mut device = Device::new(NoopHandler);
+        let mut pipe = pipe(&[b"reboot", b"getvar:version"]);
+        assert_eq!(
+            serve(&mut pipe, &mut device).unwrap(),
+            Some(Exit::Reboot(RebootTarget::System))
+        );
+        assert_eq!(packets(&pipe.output), [b"OKAY"]);
+    }
+
+    #[test]
+    fn bad_handshake() {
+        let mut device = Device::new(NoopHandler);
+        let mut pipe = pipe(&[]);
+        pipe.input.get_mut()[..2].copy_from_slice(b"XX");
+        serve(&mut pipe, &mut device).unwrap_err();
+    }
+
+    #[test]
+    fn oversized_command() {
+        let mut device = Device::new(NoopHandler);
+        let mut pipe = pipe(&[&[b'a'; MAX_COMMAND_SIZE + 1]]);
+        serve(&mut pipe, &mut device).unwrap_err();
+    }
+}
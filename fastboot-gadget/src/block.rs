@@ -0,0 +1,121 @@
+use crate::sparse;
+use fastboot_device::{Handler, Reporter};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// Chunk size used when zeroing partitions
+const ERASE_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Serves the block devices in a directory (e.g. `/dev/disk/by-partlabel`) as partitions
+pub struct BlockDeviceHandler {
+    dir: PathBuf,
+}
+
+impl BlockDeviceHandler {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn open(&self, partition: &str, write: bool) -> Result<File, String> {
+        if partition.is_empty() || partition.contains('/') || partition.starts_with('.') {
+            return Err(format!("invalid partition {partition}"));
+        }
+        OpenOptions::new()
+            .read(true)
+            .write(write)
+            .open(self.dir.join(partition))
+            .map_err(|err| format!("{partition}: {err}"))
+    }
+
+    fn size(&self, partition: &str) -> Result<u64, String> {
+        self.open(partition, false)?
+            .seek(SeekFrom::End(0))
+            .map_err(|err| err.to_string())
+    }
+
+    fn partitions(&self) -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return vec![];
+        };
+        let mut partitions: Vec<_> = entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .collect();
+        partitions.sort();
+        partitions
+    }
+}
+
+impl Handler for BlockDeviceHandler {
+    fn getvar(&mut self, var: &str) -> Result<String, String> {
+        match var.split_once(':') {
+            Some(("partition-size", partition)) => Ok(format!("0x{:x}", self.size(partition)?)),
+            Some(("partition-type", partition)) => self.size(partition).map(|_| "raw".to_string()),
+            Some(("has-slot", _)) => Ok("no".to_string()),
+            _ => match var {
+                "product" => Ok("fastboot_gadget".to_string()),
+                "is-userspace" => Ok("yes".to_string()),
+                "slot-count" => Ok("0".to_string()),
+                _ => Err("unknown variable".to_string()),
+            },
+        }
+    }
+
+    fn vars(&mut self) -> Vec<(String, String)> {
+        let mut vars = vec![];
+        for partition in self.partitions() {
+            if let Ok(size) = self.size(&partition) {
+                vars.push((format!("partition-size:{partition}"), format!("0x{size:x}")));
+            }
+        }
+        vars
+    }
+
+    fn flash(
+        &mut self,
+        partition: &str,
+        data: &[u8],
+        reporter: &mut Reporter,
+    ) -> Result<(), String> {
+        let size = self.size(partition)?;
+        let mut file = self.open(partition, true)?;
+        if sparse::is_sparse(data) {
+            reporter.info(format!("Writing sparse image to {partition}"));
+            sparse::write(data, &mut file, size)?;
+        } else {
+            if data.len() as u64 > size {
+                return Err(format!("image is larger than {partition}"));
+            }
+            reporter.info(format!("Writing {} bytes to {partition}", data.len()));
+            file.write_all(data).map_err(|err| err.to_string())?;
+        }
+        file.sync_all().map_err(|err| err.to_string())
+    }
+
+    fn erase(&mut self, partition: &str, reporter: &mut Reporter) -> Result<(), String> {
+        let mut remaining = self.size(partition)?;
+        reporter.info(format!("Zeroing {partition}"));
+        let mut file = self.open(partition, true)?;
+        let zeroes = vec![0; ERASE_CHUNK_SIZE];
+        while remaining > 0 {
+            let len = remaining.min(ERASE_CHUNK_SIZE as u64) as usize;
+            file.write_all(&zeroes[..len])
+                .map_err(|err| err.to_string())?;
+            remaining -= len as u64;
+        }
+        file.sync_all().map_err(|err| err.to_string())
+    }
+
+    fn fetch(&mut self, partition: &str, offset: u64, size: u64) -> Result<Vec<u8>, String> {
+        let end = offset.checked_add(size).ok_or("invalid range")?;
+        if end > self.size(partition)? {
+            return Err(format!("range is outside of {partition}"));
+        }
+        let mut file = self.open(partition, false)?;
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|err| err.to_string())?;
+        let mut data = vec![0; size as usize];
+        file.read_exact(&mut data).map_err(|err| err.to_string())?;
+        Ok(data)
+    }
+}
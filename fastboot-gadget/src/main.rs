@@ -0,0 +1,92 @@
+use bytes::{Bytes, BytesMut};
+use fastboot_device::{Device, Exit, Handler, RebootTarget};
+use usb_gadget::function::custom::{Custom, Endpoint, EndpointDirection, Event, Interface};
+use usb_gadget::{Class, Config, Gadget, Id, Strings};
+
+mod block;
+mod sparse;
+
+/// Largest bulk transfer queued at once, in either direction
+const MAX_TRANSFER_SIZE: usize = 1024 * 1024;
+/// Largest command packet a host may send
+const MAX_COMMAND_SIZE: usize = 4096;
+
+/// Serves fastboot over a FunctionFS gadget until a command ends the session
+async fn serve_usb<H: Handler>(device: &mut Device<H>) -> anyhow::Result<Exit> {
+    let (mut rx, rx_dir) = EndpointDirection::host_to_device();
+    let (mut tx, tx_dir) = EndpointDirection::device_to_host();
+
+    // Fastboot is identified by its interface class, not the device's
+    let (mut custom, handle) = Custom::builder()
+        .with_interface(
+            Interface::new(Class::vendor_specific(0x42, 0x03), "fastboot")
+                .with_endpoint(Endpoint::bulk(rx_dir))
+                .with_endpoint(Endpoint::bulk(tx_dir)),
+        )
+        .build();
+
+    let udc = usb_gadget::default_udc()?;
+    let _reg = Gadget::new(
+        Class::new(0, 0, 0),
+        Id::new(0x18d1, 0x4ee0),
+        Strings::new("bootbud", "fastboot gadget", "fastboot"),
+    )
+    .with_config(Config::new("fastboot").with_function(handle))
+    .bind(&udc)?;
+
+    loop {
+        match custom.event()? {
+            Event::Enable => break,
+            ev => println!("Unhandled event {:?}", ev),
+        }
+    }
+
+    loop {
+        while let Some(packet) = device.next_packet() {
+            for chunk in packet.chunks(MAX_TRANSFER_SIZE) {
+                tx.send_async(Bytes::copy_from_slice(chunk)).await?;
+            }
+        }
+        if let Some(exit) = device.exit() {
+            return Ok(exit);
+        }
+
+        let size = device
+            .download_remaining()
+            .unwrap_or(MAX_COMMAND_SIZE)
+            .min(MAX_TRANSFER_SIZE);
+        if let Some(packet) = rx.recv_async(BytesMut::with_capacity(size)).await? {
+            device.handle(&packet);
+        }
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let mut dir = "/dev/disk/by-partlabel".to_string();
+    let mut tcp = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tcp" => tcp = Some(args.next().unwrap_or("0.0.0.0:5554".to_string())),
+            _ => dir = arg,
+        }
+    }
+
+    let mut device = Device::new(block::BlockDeviceHandler::new(dir));
+    let exit = match tcp {
+        Some(addr) => fastboot_device::tcp::listen(addr, &mut device)?,
+        None => serve_usb(&mut device).await?,
+    };
+    println!("Exiting: {:?}", exit);
+
+    let reboot = match exit {
+        Exit::Reboot(RebootTarget::System) => Some("reboot"),
+        Exit::Powerdown => Some("poweroff"),
+        _ => None,
+    };
+    if let Some(cmd) = reboot {
+        std::process::Command::new(cmd).status()?;
+    }
+    Ok(())
+}
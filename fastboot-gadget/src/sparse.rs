@@ -0,0 +1,190 @@
+//! Expansion of Android sparse images, which the `fastboot` CLI sends for images built sparse and
+//! for anything larger than the download buffer.
+
+use std::io::{Seek, SeekFrom, Write};
+
+const SPARSE_MAGIC: u32 = 0xed26_ff3a;
+const MAJOR_VERSION: u16 = 1;
+const FILE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+/// Largest single write of a fill pattern
+const FILL_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Whether the downloaded data is a sparse image
+pub fn is_sparse(data: &[u8]) -> bool {
+    data.get(..4) == Some(&SPARSE_MAGIC.to_le_bytes())
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or("sparse image is truncated")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+}
+
+/// Writes out the sparse image in `data`, seeking over the blocks it doesn't care about so a
+/// series of sparse images can each fill in their own part of the partition
+pub fn write<W: Write + Seek>(data: &[u8], out: &mut W, size: u64) -> Result<(), String> {
+    let mut cursor = Cursor { data, pos: 0 };
+    if cursor.u32()? != SPARSE_MAGIC {
+        return Err("not a sparse image".to_string());
+    }
+    let major_version = cursor.u16()?;
+    let _minor_version = cursor.u16()?;
+    let file_header_size = cursor.u16()? as usize;
+    let chunk_header_size = cursor.u16()? as usize;
+    let block_size = cursor.u32()?;
+    let total_blocks = cursor.u32()?;
+    let total_chunks = cursor.u32()?;
+    let _checksum = cursor.u32()?;
+    if major_version != MAJOR_VERSION {
+        return Err(format!("unsupported sparse image version {major_version}"));
+    }
+    if file_header_size < FILE_HEADER_SIZE
+        || chunk_header_size < CHUNK_HEADER_SIZE
+        || block_size == 0
+        || !block_size.is_multiple_of(4)
+    {
+        return Err("invalid sparse image header".to_string());
+    }
+    let block_size = block_size as u64;
+    if total_blocks as u64 * block_size > size {
+        return Err("sparse image is larger than the partition".to_string());
+    }
+    cursor.bytes(file_header_size - FILE_HEADER_SIZE)?;
+
+    let mut block = 0u64;
+    for _ in 0..total_chunks {
+        let chunk_type = cursor.u16()?;
+        cursor.u16()?;
+        let blocks = cursor.u32()? as u64;
+        let total_size = cursor.u32()? as usize;
+        cursor.bytes(chunk_header_size - CHUNK_HEADER_SIZE)?;
+        let body = cursor.bytes(
+            total_size
+                .checked_sub(chunk_header_size)
+                .ok_or("invalid sparse chunk size")?,
+        )?;
+        if chunk_type == CHUNK_TYPE_CRC32 {
+            continue;
+        }
+        if block + blocks > total_blocks as u64 {
+            return Err("sparse chunk is past the end of the image".to_string());
+        }
+        let len = blocks * block_size;
+
+        match chunk_type {
+            CHUNK_TYPE_RAW => {
+                if body.len() as u64 != len {
+                    return Err("invalid sparse raw chunk size".to_string());
+                }
+                out.seek(SeekFrom::Start(block * block_size))
+                    .map_err(|err| err.to_string())?;
+                out.write_all(body).map_err(|err| err.to_string())?;
+            }
+            CHUNK_TYPE_FILL => {
+                let pattern = <[u8; 4]>::try_from(body)
+                    .map_err(|_| "invalid sparse fill chunk size".to_string())?;
+                out.seek(SeekFrom::Start(block * block_size))
+                    .map_err(|err| err.to_string())?;
+                let fill = pattern.repeat(len.min(FILL_BUFFER_SIZE as u64) as usize / 4);
+                let mut remaining = len;
+                while remaining > 0 {
+                    let chunk = remaining.min(fill.len() as u64) as usize;
+                    out.write_all(&fill[..chunk])
+                        .map_err(|err| err.to_string())?;
+                    remaining -= chunk as u64;
+                }
+            }
+            CHUNK_TYPE_DONT_CARE => {
+                if !body.is_empty() {
+                    return Err("invalid sparse don't care chunk size".to_string());
+                }
+            }
+            _ => return Err(format!("unknown sparse chunk type 0x{chunk_type:04x}")),
+        }
+        block += blocks;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor as IoCursor;
+
+    fn header(block_size: u32, total_blocks: u32, total_chunks: u32) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend_from_slice(&SPARSE_MAGIC.to_le_bytes());
+        out.extend_from_slice(&1u16.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(&(FILE_HEADER_SIZE as u16).to_le_bytes());
+        out.extend_from_slice(&(CHUNK_HEADER_SIZE as u16).to_le_bytes());
+        out.extend_from_slice(&block_size.to_le_bytes());
+        out.extend_from_slice(&total_blocks.to_le_bytes());
+        out.extend_from_slice(&total_chunks.to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out
+    }
+
+    fn chunk(out: &mut Vec<u8>, chunk_type: u16, blocks: u32, body: &[u8]) {
+        out.extend_from_slice(&chunk_type.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(&blocks.to_le_bytes());
+        out.extend_from_slice(&((CHUNK_HEADER_SIZE + body.len()) as u32).to_le_bytes());
+        out.extend_from_slice(body);
+    }
+
+    #[test]
+    fn expand() {
+        let mut image = header(4, 5, 4);
+        chunk(&mut image, CHUNK_TYPE_RAW, 1, b"abcd");
+        chunk(&mut image, CHUNK_TYPE_DONT_CARE, 2, &[]);
+        chunk(&mut image, CHUNK_TYPE_FILL, 2, b"xy12");
+        chunk(&mut image, CHUNK_TYPE_CRC32, 0, &[0; 4]);
+        assert!(is_sparse(&image));
+
+        let mut out = IoCursor::new(vec![b'-'; 20]);
+        write(&image, &mut out, 20).unwrap();
+        assert_eq!(out.into_inner(), b"abcd--------xy12xy12");
+    }
+
+    #[test]
+    fn invalid() {
+        let mut out = IoCursor::new(vec![]);
+        assert!(write(&header(4, 5, 0), &mut out, 16).is_err());
+
+        let mut image = header(4, 2, 1);
+        chunk(&mut image, CHUNK_TYPE_RAW, 2, b"abcd");
+        assert!(write(&image, &mut out, 8).is_err());
+
+        let mut image = header(4, 1, 1);
+        chunk(&mut image, CHUNK_TYPE_DONT_CARE, 2, &[]);
+        assert!(write(&image, &mut out, 8).is_err());
+
+        let image = header(4, 1, 1);
+        assert!(write(&image, &mut out, 8).is_err());
+    }
+}
@@ -1,19 +1,480 @@
-use std::slice;
-use byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
-use bytes::{Buf, BufMut, Bytes, BytesMut};
-use libublk::io::{UblkDev, UblkIOCtx, UblkQueue};
-use libublk::{UblkFlags, UblkIORes};
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use bytes::{Bytes, BytesMut};
+use libublk::io::{UblkDev, UblkQueue};
+use libublk::UblkFlags;
 use usb_gadget::function::custom::{
-    Custom, Endpoint, EndpointDirection, Event, Interface, TransferType,
+    Custom, CtrlReceiver, Endpoint, EndpointDirection, EndpointReceiver, EndpointSender, Event,
+    Interface, TransferType,
 };
+use usb_gadget::function::net::{Net, NetClass};
+use usb_gadget::function::serial::{Serial, SerialClass};
 use usb_gadget::{Class, Config, Gadget, Id, Strings};
+use smoo_proto::{Capabilities, Export, Hello, Opcode, Request, Response};
+use options::Options;
+
+mod options;
+
+/// Granularity advertised for discards; the host punches holes in its backing file in units of
+/// filesystem blocks anyway
+const DISCARD_GRANULARITY: u32 = 4096;
+
+/// Largest I/O buffer per tag, which is ublk's default. Hosts may offer smaller ones.
+const MAX_IO_SIZE: u32 = 512 * 1024;
+
+/// Largest errno the kernel takes as one when completing a request
+const MAX_ERRNO: i32 = 4095;
+
+/// Where the ids of the gadget's ublk devices are recorded, in export order, so that a
+/// restarted gadget recovers the devices it left behind and never anyone else's
+const STATE_FILE: &str = "/run/smoo/devices";
+
+/// An I/O request from ublk, waiting to be served by the host
+struct Io {
+    request: Request,
+    /// Data to be written, kept until answered in case the request has to be sent again
+    payload: Option<Bytes>,
+    /// Space for the data being read, exactly as large as the request
+    buf: Option<BytesMut>,
+    /// Receives `buf` once it's filled (nothing for other ops), or the errno the host failed with
+    done: async_channel::Sender<Result<Option<BytesMut>, i32>>,
+}
+
+/// Requests sent to the host and not answered yet, by export and tag
+type Pending = Arc<Mutex<HashMap<(u8, u16), Io>>>;
+
+/// Serves the I/O of one ublk tag, forwarding every request to the host and waiting for its
+/// response. Every tag of every queue runs one of these, so the host sees as many requests at
+/// once as the kernel has in flight.
+///
+/// The tag's IO buffer, which ublk copies write payloads into and read data out of, is lent
+/// to the USB endpoints in between. Data is moved in one transfer per request, and never
+/// copied in userspace.
+async fn handle_tag(
+    q: &UblkQueue<'_>,
+    export: u8,
+    qid: u16,
+    tag: u16,
+    tx: &async_channel::Sender<Io>,
+) {
+    let max_io = q.dev.dev_info.max_io_buf_bytes as usize;
+    let mut buf = BytesMut::zeroed(max_io);
+    // Unique across queues, as the host answers them all on the same endpoint. Options keep
+    // queues × depth within 16 bits.
+    let wire_tag = u16::try_from(qid as u32 * q.dev.dev_info.queue_depth as u32 + tag as u32)
+        .expect("too many tags for the wire");
+
+    let mut cmd_op = libublk::sys::UBLK_U_IO_FETCH_REQ;
+    let mut res = 0;
+    loop {
+        let cmd_res = q.submit_io_cmd(tag, cmd_op, buf.as_mut_ptr(), res).await;
+        if cmd_res == libublk::sys::UBLK_IO_RES_ABORT {
+            break;
+        }
+
+        let iod = q.get_iod(tag);
+        let op = iod.op_flags & 0xFF;
+        let off = iod.start_sector << 9;
+        let sz = iod.nr_sectors << 9;
+
+        res = match Opcode::try_from(op as u8) {
+            Ok(op) => {
+                let request = Request { export, tag: wire_tag, op, offset: off, len: sz };
+                let (io_tx, io_rx) = async_channel::bounded(1);
+                let mut io = Io { request, payload: None, buf: None, done: io_tx };
+
+                let mut whole = None;
+                let mut rest = None;
+                if op.has_payload() {
+                    // ublk has already copied the data into the IO buffer
+                    let bytes = std::mem::take(&mut buf).freeze();
+                    io.payload = Some(bytes.slice(..sz as usize));
+                    whole = Some(bytes);
+                } else if op.has_response_data() {
+                    rest = Some(buf.split_off(sz as usize));
+                    buf.clear();
+                    io.buf = Some(std::mem::take(&mut buf));
+                }
+
+                // Once the connection to the host is gone, the request is dropped without an
+                // answer, and fails like any other
+                let result = match tx.send(io).await {
+                    Ok(()) => io_rx.recv().await.unwrap_or(Err(libc::EIO)),
+                    Err(_) => Err(libc::EIO),
+                };
+
+                if let Some(whole) = whole {
+                    // The host has received the whole payload by the time it answers, so the
+                    // endpoint is normally done with the buffer
+                    buf = whole.try_into_mut().unwrap_or_default();
+                }
+                let res = match result {
+                    Ok(data) => {
+                        if let (Some(data), Some(rest)) = (data, rest) {
+                            // Free when the data landed in place, otherwise it's copied back
+                            buf = data;
+                            buf.unsplit(rest);
+                        }
+                        sz as i32
+                    }
+                    Err(errno) => -errno,
+                };
+
+                // The buffer didn't come back in one piece
+                if buf.len() != max_io {
+                    buf = BytesMut::zeroed(max_io);
+                }
+                res
+            }
+            Err(_) => -libc::EOPNOTSUPP,
+        };
+        cmd_op = libublk::sys::UBLK_U_IO_COMMIT_AND_FETCH_REQ;
+    }
+}
+
+async fn send_request(
+    int_tx: &mut EndpointSender,
+    write_tx: &mut EndpointSender,
+    request: Request,
+    payload: Option<Bytes>,
+) -> anyhow::Result<()> {
+    let header = Bytes::copy_from_slice(&request.encode());
+    int_tx.send_async(header.clone()).await?;
+    if let Some(payload) = payload {
+        // Lets the host tell the payload apart from what's left of one it stopped reading
+        write_tx.send_async(header).await?;
+        write_tx.send_async(payload).await?;
+    }
+    Ok(())
+}
+
+/// Throws away whatever transfers are still queued on the endpoints, so a new session starts
+/// with none of the old one's requests, payloads or responses
+fn reset_endpoints(
+    int_tx: &mut EndpointSender,
+    write_tx: &mut EndpointSender,
+    data_rx: &mut EndpointReceiver,
+) -> anyhow::Result<()> {
+    int_tx.cancel()?;
+    write_tx.cancel()?;
+    data_rx.cancel()?;
+    Ok(())
+}
+
+/// Sends requests to the host as ublk hands them over, along with the payload of writes.
+/// Requests left unanswered by an earlier session go first, as they may never have reached the
+/// host.
+async fn send_requests(
+    rx: &async_channel::Receiver<Io>,
+    pending: &Pending,
+    int_tx: &mut EndpointSender,
+    write_tx: &mut EndpointSender,
+) -> anyhow::Result<()> {
+    let replay: Vec<_> = pending
+        .lock()
+        .unwrap()
+        .values()
+        .map(|io| (io.request, io.payload.clone()))
+        .collect();
+    if !replay.is_empty() {
+        println!("Replaying {} requests", replay.len());
+    }
+    for (request, payload) in replay {
+        send_request(int_tx, write_tx, request, payload).await?;
+    }
+
+    loop {
+        let io = rx.recv().await?;
+        let request = io.request;
+        let payload = io.payload.clone();
+        // Tracked before sending, so the response can't beat it
+        pending.lock().unwrap().insert((request.export, request.tag), io);
+        send_request(int_tx, write_tx, request, payload).await?;
+    }
+}
+
+/// Receives the host's responses, and completes the requests they answer. Requests stay
+/// pending until completely answered, so they're replayed if the connection drops halfway.
+async fn receive_responses(pending: &Pending, data_rx: &mut EndpointReceiver) -> anyhow::Result<()> {
+    loop {
+        // Short of a full packet, ending the transfer early
+        let buf = data_rx.recv_and_fetch_async(BytesMut::with_capacity(512)).await?;
+        let response = Response::decode(&buf)?;
+
+        let found = pending
+            .lock()
+            .unwrap()
+            .get_mut(&(response.export, response.tag))
+            .map(|io| (io.request, io.buf.take()));
+        let Some((request, buf)) = found else {
+            // Answered already, before being replayed
+            println!("Ignoring response for unknown tag {} of export {}", response.tag, response.export);
+            if response.len > 0 {
+                data_rx.recv_and_fetch_async(BytesMut::with_capacity(response.len as _)).await?;
+            }
+            continue;
+        };
+
+        let result = if response.status != 0 {
+            println!("Host failed {:?}: errno {}", request, response.status);
+            // Anything but a plain errno would reach ublk as some other result
+            Err(if (1..=MAX_ERRNO).contains(&response.status) { response.status } else { libc::EIO })
+        } else if request.op.has_response_data() {
+            // The whole read arrives in one transfer, straight into the IO buffer, unless that
+            // was lost to an interrupted attempt
+            let buf = buf.unwrap_or_else(|| BytesMut::with_capacity(request.len as _));
+            let data = data_rx.recv_and_fetch_async(buf).await?;
+            anyhow::ensure!(
+                data.len() == response.len as usize,
+                "Received {} of {} bytes for {:?}", data.len(), response.len, request,
+            );
+            Ok(Some(data))
+        } else {
+            Ok(None)
+        };
+
+        if let Some(io) = pending.lock().unwrap().remove(&(response.export, response.tag)) {
+            let _ = io.done.try_send(result);
+        }
+    }
+}
+
+/// Receives a hello, returning it if accepted. After the first, the host has to serve the same
+/// exports, and requests as large as the ones the devices were set up with, as the kernel's view
+/// of them can't change.
+fn recv_hello(req: CtrlReceiver, first: Option<&Hello>) -> anyhow::Result<Option<Hello>> {
+    if req.ctrl_req().length as usize > Hello::MAX_SIZE {
+        println!("Rejecting hello of {} bytes", req.ctrl_req().length);
+        req.halt()?;
+        return Ok(None);
+    }
+    let mut buf = [0; Hello::MAX_SIZE];
+    let len = req.recv(&mut buf)?;
+    match Hello::decode(&buf[..len]) {
+        Ok(hello) if first.is_some_and(|first| first.exports != hello.exports) => {
+            println!("Rejecting hello with different exports {:?}", hello.exports);
+            Ok(None)
+        }
+        Ok(hello) if first.is_some_and(|first| first.max_io_size > hello.max_io_size) => {
+            println!("Rejecting hello with smaller max I/O size {}", hello.max_io_size);
+            Ok(None)
+        }
+        Ok(hello) => Ok(Some(hello)),
+        Err(err) => {
+            println!("Rejecting hello: {}", err);
+            Ok(None)
+        }
+    }
+}
+
+/// Waits for the host to open a session, given what became of a hello received while the last
+/// one was being served, if any. Rejected hellos fail the host's
+/// [smoo_proto::REQUEST_HELLO_ACK], so it knows not to wait for any I/O.
+async fn next_hello(
+    custom: &mut Custom,
+    first: Option<&Hello>,
+    mut accepted: Option<Hello>,
+) -> anyhow::Result<Hello> {
+    loop {
+        custom.wait_event().await?;
+        let ev = custom.event()?;
+        match ev {
+            Event::SetupHostToDevice(req) if req.ctrl_req().request == smoo_proto::REQUEST_HELLO => {
+                accepted = recv_hello(req, first)?;
+            }
+            Event::SetupDeviceToHost(req) if req.ctrl_req().request == smoo_proto::REQUEST_HELLO_ACK => {
+                match accepted.take() {
+                    Some(hello) => {
+                        req.send(&[])?;
+                        return Ok(hello);
+                    }
+                    None => req.halt()?,
+                }
+            }
+            _ => {
+                println!("Unhandled event {:?}", ev);
+            }
+        }
+    }
+}
+
+/// What interrupted serving the host, other than losing it
+enum Interruption {
+    /// The host unconfigured the device, or reset the interface
+    Disabled,
+    /// The host opened a new session, with the hello if accepted
+    Hello(Option<Hello>),
+}
+
+/// Handles the events that come in while serving the host, returning once it stops the session.
+/// Only ever waits for an event, so it can be dropped at any point.
+async fn watch_events(custom: &mut Custom, first: &Hello) -> anyhow::Result<Interruption> {
+    loop {
+        custom.wait_event().await?;
+        let ev = custom.event()?;
+        match ev {
+            Event::Disable => return Ok(Interruption::Disabled),
+            Event::SetupHostToDevice(req) if req.ctrl_req().request == smoo_proto::REQUEST_HELLO => {
+                return Ok(Interruption::Hello(recv_hello(req, Some(first))?));
+            }
+            // No hello to acknowledge
+            Event::SetupDeviceToHost(req) if req.ctrl_req().request == smoo_proto::REQUEST_HELLO_ACK => {
+                req.halt()?;
+            }
+            _ => {
+                println!("Unhandled event {:?}", ev);
+            }
+        }
+    }
+}
+
+/// Ids of the devices an earlier gadget left behind, by export, as far as they still exist
+fn stale_device_ids() -> Vec<Option<i32>> {
+    let Ok(state) = std::fs::read_to_string(STATE_FILE) else {
+        return vec![];
+    };
+    state
+        .lines()
+        .map(|line| {
+            line.trim()
+                .parse()
+                .ok()
+                .filter(|id| Path::new(&format!("/dev/ublkc{}", id)).exists())
+        })
+        .collect()
+}
+
+fn record_device_ids(ids: &[i32]) -> anyhow::Result<()> {
+    let path = Path::new(STATE_FILE);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(path, ids.iter().map(|id| format!("{}\n", id)).collect::<String>())?;
+    Ok(())
+}
+
+/// A ublk device serving one of the host's exports
+struct Device {
+    /// Device left behind by an earlier gadget, or none to have the kernel pick a new one
+    stale_id: Option<i32>,
+    export_index: u8,
+    export: Export,
+    capabilities: Capabilities,
+    queues: u16,
+    depth: u16,
+    io_size: u32,
+}
+
+impl Device {
+    /// Creates the device, or recovers it from an earlier gadget, reports its id, and serves it
+    /// until it's removed
+    fn run(self, tx: async_channel::Sender<Io>, id_tx: mpsc::Sender<i32>) -> anyhow::Result<()> {
+        let Device { stale_id, export_index, export, capabilities, queues, depth, io_size } = self;
+
+        // Left behind by an earlier gadget that died, with the kernel holding on to its I/O
+        if let Some(id) = stale_id {
+            println!("Recovering ublk device {}", id);
+        }
+
+        // With user recovery, the device outlives the gadget: its I/O is held rather than
+        // failed until a new gadget takes over, which then gets the requests in flight reissued
+        let mut ctrl = libublk::ctrl::UblkCtrlBuilder::default()
+            .name("smoo")
+            .id(stale_id.unwrap_or(-1))
+            .nr_queues(queues)
+            .depth(depth)
+            .io_buf_bytes(io_size)
+            .ctrl_flags(
+                (libublk::sys::UBLK_F_USER_RECOVERY | libublk::sys::UBLK_F_USER_RECOVERY_REISSUE)
+                    as u64,
+            )
+            .dev_flags(if stale_id.is_some() {
+                UblkFlags::UBLK_DEV_F_RECOVER_DEV
+            } else {
+                UblkFlags::UBLK_DEV_F_ADD_DEV
+            })
+            .build()?;
+        let _ = id_tx.send(ctrl.dev_info().dev_id as i32);
+
+        ctrl.run_target(|dev: &mut UblkDev| {
+            dev.set_default_params(export.size);
+
+            let params = &mut dev.tgt.params;
+            let shift = export.block_size.trailing_zeros() as u8;
+            params.basic.logical_bs_shift = shift;
+            params.basic.physical_bs_shift = shift;
+            params.basic.io_min_shift = shift;
+            params.basic.io_opt_shift = shift;
+            if export.read_only {
+                params.basic.attrs |= libublk::sys::UBLK_ATTR_READ_ONLY;
+            }
+
+            // Without a volatile cache the kernel never sends flushes, and without discard
+            // parameters it never sends discards or write zeroes. None of them make sense for
+            // a read-only device.
+            let writable = |capability| !export.read_only && capabilities.contains(capability);
+            if writable(Capabilities::FLUSH) {
+                params.basic.attrs |= libublk::sys::UBLK_ATTR_VOLATILE_CACHE;
+            }
+            let discard = writable(Capabilities::DISCARD);
+            let write_zeroes = writable(Capabilities::WRITE_ZEROES);
+            if discard || write_zeroes {
+                params.types |= libublk::sys::UBLK_PARAM_TYPE_DISCARD;
+                params.discard.discard_granularity = DISCARD_GRANULARITY;
+                params.discard.max_discard_segments = 1;
+                if discard {
+                    params.discard.max_discard_sectors = u32::MAX >> 9;
+                }
+                if write_zeroes {
+                    params.discard.max_write_zeroes_sectors = u32::MAX >> 9;
+                }
+            }
+            Ok(())
+        }, move |qid: u16, dev: &UblkDev| {
+            let q = Rc::new(UblkQueue::new(qid, dev).unwrap());
+            let exe = smol::LocalExecutor::new();
+            let tasks: Vec<_> = (0..depth)
+                .map(|tag| {
+                    let (q, tx) = (q.clone(), tx.clone());
+                    exe.spawn(async move { handle_tag(&q, export_index, qid, tag, &tx).await })
+                })
+                .collect();
+
+            libublk::uring_async::ublk_wait_and_handle_ios(&exe, &q);
+            smol::block_on(async {
+                for task in tasks {
+                    task.await;
+                }
+            });
+        }, move |_| {})?;
+        Ok(())
+    }
+}
+
+/// Removes gadgets left behind by an earlier smoo gadget with the same identity, leaving any
+/// others alone
+fn remove_stale_gadgets(options: &Options) -> anyhow::Result<()> {
+    for reg in usb_gadget::registered()? {
+        let id = |attr| {
+            let value = std::fs::read_to_string(reg.path().join(attr)).ok()?;
+            u16::from_str_radix(value.trim().trim_start_matches("0x"), 16).ok()
+        };
+        if id("idVendor") == Some(options.vendor_id) && id("idProduct") == Some(options.product_id) {
+            println!("Removing stale gadget {}", reg.path().display());
+            reg.remove()?;
+        }
+    }
+    Ok(())
+}
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
-    usb_gadget::remove_all()?;
+    let options = Options::from_args(std::env::args().skip(1))?;
+    remove_stale_gadgets(&options)?;
 
     let (mut data_rx, data_dir) = EndpointDirection::host_to_device();
     let (mut int_tx, int_dir) = EndpointDirection::device_to_host();
+    let (mut write_tx, write_dir) = EndpointDirection::device_to_host();
 
     let mut int_ep = Endpoint::custom(int_dir, TransferType::Interrupt);
     int_ep.max_packet_size_hs = 16;
@@ -23,112 +484,153 @@ async fn main() -> anyhow::Result<()> {
     data_ep.max_packet_size_hs = 512;
     data_ep.max_packet_size_ss = 512;
 
+    // Carries the payload of writes to the host
+    let mut write_ep = Endpoint::bulk(write_dir);
+    write_ep.max_packet_size_hs = 512;
+    write_ep.max_packet_size_ss = 512;
+
     let (mut custom, handle) = Custom::builder()
         .with_interface(
-            Interface::new(Class::vendor_specific(123, 123), "smoo")
+            Interface::new(
+                Class::vendor_specific(options.interface_subclass, options.interface_protocol),
+                "smoo",
+            )
                 .with_endpoint(int_ep)
-                .with_endpoint(data_ep),
+                .with_endpoint(data_ep)
+                .with_endpoint(write_ep),
         )
         .build();
 
-    let klass = Class::new(255, 255, 3);
-    let id = Id::new(0xDEAD, 0xBEEF);
-    let strings = Strings::new("foo", "bar", "bacon");
+    let mut config = Config::new("config").with_function(handle);
 
-    let udc = usb_gadget::default_udc()?;
+    // Kept around for as long as the gadget is bound
+    let mut serial = None;
+    let mut net = None;
+    if options.console {
+        let (function, handle) = Serial::new(SerialClass::Acm);
+        config = config.with_function(handle);
+        serial = Some(function);
+    }
+    if options.network {
+        let (function, handle) = Net::new(NetClass::Ncm);
+        config = config.with_function(handle);
+        net = Some(function);
+    }
+
+    // ACM and NCM group their interfaces with interface association descriptors, which hosts
+    // only look for in devices of this class
+    let klass = if serial.is_some() || net.is_some() {
+        Class::new(0xef, 0x02, 0x01)
+    } else {
+        Class::new(255, 255, 3)
+    };
+    let id = Id::new(options.vendor_id, options.product_id);
+    let strings = Strings::new(&options.manufacturer, &options.product, &options.serial);
+
+    let udc = match &options.udc {
+        Some(name) => usb_gadget::udcs()?
+            .into_iter()
+            .find(|udc| udc.name() == name.as_str())
+            .ok_or(anyhow::anyhow!("No UDC named {}", name))?,
+        None => usb_gadget::default_udc()?,
+    };
 
     let reg = Gadget::new(klass, id, strings)
-        .with_config(Config::new("config").with_function(handle))
+        .with_config(config)
         .bind(&udc)?;
 
-    let mut size = None;
-
-    while size.is_none() {
-        let ev = custom.event()?;
-        match ev {
-            Event::SetupHostToDevice(req) => {
-                let mut buf: [u8; 8] = [0; 8];
-                assert_eq!(8, req.recv(&mut buf)?);
-                size = Some(NetworkEndian::read_u64(&buf));
-            }
-            _ => {
-                println!("Unhandled event {:?}", ev);
-            }
-        }
+    // The live OS is left to run a getty on the console and configure the interface
+    if let Some(serial) = &serial {
+        println!("Console on {}", serial.tty()?.display());
+    }
+    if let Some(net) = &net {
+        println!("Network interface {}", net.ifname()?.to_string_lossy());
     }
 
-    println!("Initializing device of size {}", size.unwrap());
+    let mut hello = next_hello(&mut custom, None, None).await?;
+    let (queues, depth) = options.negotiate(hello.queues, hello.queue_depth);
+    // Whole blocks of any size, so requests never get split mid-block
+    let io_size = hello.max_io_size.min(MAX_IO_SIZE);
+    let io_size = io_size - io_size % smoo_proto::MIN_IO_SIZE;
+    // What later hosts are held to
+    hello.max_io_size = io_size;
+    println!(
+        "Initializing {} devices with {:?}, {} queues of depth {}, requests of up to {} bytes",
+        hello.exports.len(), hello.capabilities, queues, depth, io_size,
+    );
 
     let (tx, rx) = async_channel::unbounded();
 
-    let jh = std::thread::spawn(move || {
-        let mut ctrl = libublk::ctrl::UblkCtrlBuilder::default()
-            .name("smoo")
-            .nr_queues(1)
-            .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
-            .build()
-            .unwrap();
-
-        ctrl.run_target(|dev: &mut UblkDev| {
-            dev.set_default_params(size.unwrap());
-            Ok(())
-        }, |qid: u16, dev: &UblkDev| {
-            let bufs = dev.alloc_queue_io_bufs();
-
-            UblkQueue::new(qid, dev)
-                .unwrap()
-                .regiser_io_bufs(Some(&bufs))
-                .submit_fetch_commands(Some(&bufs))
-                .wait_and_handle_io(move |q: &UblkQueue, tag: u16, io: &UblkIOCtx| {
-                    let buf_addr = bufs[tag as usize].as_mut_ptr();
-                    let iod = q.get_iod(tag);
-                    let op = iod.op_flags & 0xFF;
-
-                    let off = iod.start_sector << 9;
-                    let sz = iod.nr_sectors << 9;
-
-                    match op {
-                        libublk::sys::UBLK_IO_OP_READ => unsafe {
-                            // println!("io {} {}", off, sz);
-                            let (io_tx, io_rx) = async_channel::bounded(1);
-                            tx.send_blocking((off, sz, io_tx)).unwrap();
-                            let mut buf: Bytes = io_rx.recv_blocking().unwrap();
-                            // println!("got it {}", buf.len());
-                            unsafe { buf.copy_to_slice(slice::from_raw_parts_mut(buf_addr, sz as _)); }
-                        }
-                        _ => {
-
-                        }
-                    }
-
-                    q.complete_io_cmd(tag, buf_addr, Ok(UblkIORes::Result(sz as _)));
-                });
-        }, move |_| {}).unwrap();
-    });
+    let stale_ids = stale_device_ids();
+    let ids: Vec<_> = hello
+        .exports
+        .iter()
+        .enumerate()
+        .map(|(index, &export)| {
+            let device = Device {
+                stale_id: stale_ids.get(index).copied().flatten(),
+                export_index: index as u8,
+                export: Export { read_only: export.read_only || options.read_only, ..export },
+                capabilities: hello.capabilities,
+                queues,
+                depth,
+                io_size,
+            };
+            let tx = tx.clone();
+            let (id_tx, id_rx) = mpsc::channel();
+            std::thread::spawn(move || {
+                if let Err(err) = device.run(tx, id_tx) {
+                    println!("ublk device for export {} failed: {:#}", index, err);
+                }
+            });
+            id_rx
+        })
+        .collect();
+    // Held by the devices alone from here, so the channel closes once they're all gone
+    drop(tx);
 
+    let ids = ids
+        .into_iter()
+        .enumerate()
+        .map(|(index, id_rx)| {
+            id_rx
+                .recv()
+                .map_err(|_| anyhow::anyhow!("No ublk device for export {}", index))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    record_device_ids(&ids)?;
 
+    // Requests and payloads go out in order on one side, while responses come back in whatever
+    // order the host finishes them on the other. When the host goes away, I/O stalls until it
+    // says hello again, and whatever it didn't answer is sent again.
+    //
+    // A new host resets the interface before its hello, which fails the transfers on every
+    // endpoint at once, so serving usually stops with the endpoints idle rather than halfway
+    // through a request. Serving also stops as soon as the interface is disabled or a hello
+    // comes in, and whatever was still queued on the endpoints is thrown away either way.
+    let pending = Pending::default();
+    let mut accepted = None;
     loop {
-        let (off, sz, done_tx) = rx.recv().await?;
-
-        let mut int_buf = BytesMut::with_capacity(16);
-        int_buf.put_u64(off);
-        int_buf.put_u64(sz as _);
-        int_tx.send_async(int_buf.freeze()).await?;
-
-        let mut data = BytesMut::with_capacity(sz as _);
-
-        // Ideally we'd be able to just queue up a buffer for the total amount of data we're
-        // expecting. usb-gadget doesn't work like this, though (yet?). For now we're making the
-        // assumption that the block size == bulk transfer size (512b) and doing a lot
-        // of unnecessary copies.
-        while data.len() != sz as usize {
-            let buf = data_rx.recv_async(BytesMut::with_capacity(512)).await?;
-            if let Some(buf) = buf {
-                data.extend(buf);
-            }
+        let result = tokio::select! {
+            res = send_requests(&rx, &pending, &mut int_tx, &mut write_tx) => res,
+            res = receive_responses(&pending, &mut data_rx) => res,
+            res = watch_events(&mut custom, &hello) => match res? {
+                Interruption::Disabled => Err(anyhow::anyhow!("Interface disabled")),
+                Interruption::Hello(new) => {
+                    accepted = new;
+                    Err(anyhow::anyhow!("Host opened a new session"))
+                }
+            },
+        };
+        // Without ublk there's nothing left to serve
+        if rx.is_closed() {
+            return result;
+        }
+        if let Err(err) = result {
+            println!("Lost the host: {:#}", err);
         }
-        done_tx.send(data.freeze()).await?;
+        reset_endpoints(&mut int_tx, &mut write_tx, &mut data_rx)?;
+        next_hello(&mut custom, Some(&hello), accepted.take()).await?;
+        println!("Host reconnected");
     }
-
-    Ok(())
 }
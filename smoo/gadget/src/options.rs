@@ -0,0 +1,228 @@
+//! Identity of the gadget and limits on the block devices it exports, taken from the command
+//! line and optionally a config file. The devices themselves are described by the host.
+//!
+//! Every option is given as `--name value` on the command line, or as `name = value` in the
+//! file passed with `--config`. Options that switch something on are plain flags on the command
+//! line, e.g. `--read-only`. Options apply in order, so later ones win.
+
+use anyhow::{anyhow, bail, Context};
+use std::path::Path;
+
+/// Options given without a value on the command line
+const FLAGS: [&str; 3] = ["read-only", "console", "network"];
+
+/// ublk's limits on the queues of a device and their depth
+const MAX_QUEUES: u16 = 4096;
+const MAX_QUEUE_DEPTH: u16 = 4096;
+/// Tags of all the queues share 16 bits on the wire
+const MAX_IN_FLIGHT: u32 = u16::MAX as u32 + 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: String,
+    pub product: String,
+    pub serial: String,
+    /// Subclass and protocol of the vendor specific interface
+    pub interface_subclass: u8,
+    pub interface_protocol: u8,
+    /// UDC to bind to, or the default one
+    pub udc: Option<String>,
+    /// Makes every device read-only, whatever the host says
+    pub read_only: bool,
+    /// Limits on the queues and depth offered by the host
+    pub queues: u16,
+    pub queue_depth: u16,
+    /// Adds a CDC-ACM serial port for a login console
+    pub console: bool,
+    /// Adds a CDC-NCM network interface
+    pub network: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            vendor_id: smoo_proto::VENDOR_ID,
+            product_id: smoo_proto::PRODUCT_ID,
+            manufacturer: "foo".to_string(),
+            product: "bar".to_string(),
+            serial: "bacon".to_string(),
+            interface_subclass: smoo_proto::INTERFACE_SUBCLASS,
+            interface_protocol: smoo_proto::INTERFACE_PROTOCOL,
+            udc: None,
+            read_only: false,
+            queues: 8,
+            queue_depth: 128,
+            console: false,
+            network: false,
+        }
+    }
+}
+
+/// Parses decimal, or hex with a 0x prefix
+fn parse_int<T: TryFrom<u64>>(value: &str) -> anyhow::Result<T> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .with_context(|| format!("Invalid number {value}"))?;
+    T::try_from(parsed).map_err(|_| anyhow!("{value} is out of range"))
+}
+
+fn parse_bool(value: &str) -> anyhow::Result<bool> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Expected true or false, not {value}"))
+}
+
+impl Options {
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut options = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                bail!("Unexpected argument {arg}");
+            };
+            if FLAGS.contains(&name) {
+                options.set(name, "true")?;
+                continue;
+            }
+            let value = args.next().ok_or(anyhow!("Missing value for {arg}"))?;
+            match name {
+                "config" => options.load(&value)?,
+                _ => options.set(name, &value)?,
+            }
+        }
+        options.validate()?;
+        Ok(options)
+    }
+
+    /// Applies the options in a config file
+    pub fn load(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.set_line(line)
+                .with_context(|| format!("{}:{}", path.display(), number + 1))?;
+        }
+        Ok(())
+    }
+
+    /// Applies a `name = value` line, where the value may be quoted
+    fn set_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let (name, value) = line.split_once('=').ok_or(anyhow!("Expected name = value"))?;
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+        self.set(name.trim(), value)
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        match name {
+            "vendor-id" => self.vendor_id = parse_int(value)?,
+            "product-id" => self.product_id = parse_int(value)?,
+            "manufacturer" => self.manufacturer = value.to_string(),
+            "product" => self.product = value.to_string(),
+            "serial" => self.serial = value.to_string(),
+            "interface-subclass" => self.interface_subclass = parse_int(value)?,
+            "interface-protocol" => self.interface_protocol = parse_int(value)?,
+            "udc" => self.udc = Some(value.to_string()),
+            "read-only" => self.read_only = parse_bool(value)?,
+            "queues" => self.queues = parse_int(value)?,
+            "queue-depth" => self.queue_depth = parse_int(value)?,
+            "console" => self.console = parse_bool(value)?,
+            "network" => self.network = parse_bool(value)?,
+            _ => bail!("Unknown option {name}"),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.queues == 0 || self.queue_depth == 0 {
+            bail!("Queues and queue depth can't be 0");
+        }
+        if self.queues > MAX_QUEUES || self.queue_depth > MAX_QUEUE_DEPTH {
+            bail!("At most {MAX_QUEUES} queues of depth {MAX_QUEUE_DEPTH} are supported");
+        }
+        if self.queues as u32 * self.queue_depth as u32 > MAX_IN_FLIGHT {
+            bail!("At most {MAX_IN_FLIGHT} requests can be in flight over all queues");
+        }
+        Ok(())
+    }
+
+    /// Settles on the queues and depth offered by the host within these options, which keeps
+    /// them within ublk's and the protocol's limits
+    pub fn negotiate(&self, queues: u16, queue_depth: u16) -> (u16, u16) {
+        (
+            queues.clamp(1, self.queues),
+            queue_depth.clamp(1, self.queue_depth),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &str) -> anyhow::Result<Options> {
+        Options::from_args(args.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn command_line() {
+        let options =
+            args("--vendor-id 0x18d1 --product-id 20 --read-only --console --udc dummy_udc.0")
+                .unwrap();
+        assert_eq!(options.vendor_id, 0x18d1);
+        assert_eq!(options.product_id, 20);
+        assert!(options.read_only);
+        assert!(options.console);
+        assert!(!options.network);
+        assert_eq!(options.udc.as_deref(), Some("dummy_udc.0"));
+        assert_eq!(options.queues, 8);
+        assert_eq!(options.negotiate(64, 0), (8, 1));
+        assert_eq!(options.negotiate(2, 64), (2, 64));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(args("--vendor-id 0x10000").is_err());
+        assert!(args("--queues 0x10000").is_err());
+        assert!(args("--queues 0").is_err());
+        assert!(args("--queue-depth 8192").is_err());
+        assert!(args("--queues 4097 --queue-depth 1").is_err());
+        assert!(args("--queues 32 --queue-depth 4096").is_err());
+        assert!(args("--queues 16 --queue-depth 4096").is_ok());
+        assert!(args("--colour blue").is_err());
+        assert!(args("--serial").is_err());
+        assert!(args("bacon").is_err());
+    }
+
+    #[test]
+    fn config_file() {
+        let path = std::env::temp_dir().join(format!("smoo-options-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# Identity\nmanufacturer = \"Bootbud Inc\"\nqueues = 4\n\nread-only = true\nnetwork = true\n",
+        )
+        .unwrap();
+        let options = args(&format!("--config {} --queues 2", path.display())).unwrap();
+        assert_eq!(options.manufacturer, "Bootbud Inc");
+        assert_eq!(options.queues, 2);
+        assert!(options.read_only);
+        assert!(options.network);
+
+        std::fs::write(&path, "queues\n").unwrap();
+        let err = args(&format!("--config {}", path.display())).unwrap_err();
+        assert!(format!("{err:#}").contains(":1: Expected name = value"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,52 +1,380 @@
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::Duration;
-use byteorder::{ByteOrder, NetworkEndian, ReadBytesExt};
-use rusb::{Direction, Recipient, RequestType, TransferType};
+use anyhow::Context;
+use rusb::{Device, DeviceHandle, Direction, GlobalContext, Recipient, RequestType, TransferType};
+use smoo_proto::{Capabilities, Export, Hello, Opcode, Request, Response};
 
-fn main() -> anyhow::Result<()> {
-    let handle = rusb::open_device_with_vid_pid(0xDEAD, 0xBEEF).unwrap();
+/// Queues and depth offered to the gadget; it may use fewer
+const QUEUES: u16 = 4;
+const QUEUE_DEPTH: u16 = 64;
+/// Largest request offered to the gadget, which is all the data a request holds in memory;
+/// larger ones fail with EINVAL
+const MAX_IO_SIZE: u32 = 1024 * 1024;
+/// Requests served at once. The rest wait for a free worker, but are already off the wire.
+const WORKERS: usize = 16;
+/// How often to look for the gadget while it's gone
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait for a request before checking whether a worker failed, which may leave the
+/// gadget waiting on a response that's never coming
+const REQUEST_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn fallocate(f: &File, mode: libc::c_int, off: u64, len: u64) -> io::Result<()> {
+    let ret = unsafe { libc::fallocate(f.as_raw_fd(), mode, off as _, len as _) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether the backing file simply can't do the requested kind of fallocate
+fn unsupported(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EOPNOTSUPP | libc::ENOSYS))
+}
+
+/// Deallocates a range of the backing file. Discards are only hints, so this is a no-op where
+/// the filesystem can't punch holes.
+fn discard(f: &File, off: u64, len: u64) -> io::Result<()> {
+    match fallocate(f, libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE, off, len) {
+        Err(err) if unsupported(&err) => Ok(()),
+        res => res,
+    }
+}
+
+/// Zeroes a range of the backing file, without writing out the zeroes where the filesystem
+/// allows it
+fn write_zeroes(f: &File, off: u64, len: u64) -> io::Result<()> {
+    for mode in [libc::FALLOC_FL_ZERO_RANGE, libc::FALLOC_FL_PUNCH_HOLE] {
+        match fallocate(f, mode | libc::FALLOC_FL_KEEP_SIZE, off, len) {
+            Err(err) if unsupported(&err) => continue,
+            res => return res,
+        }
+    }
+
+    let zeroes = [0; 64 * 1024];
+    let mut done = 0;
+    while done < len {
+        let chunk = (len - done).min(zeroes.len() as u64) as usize;
+        f.write_all_at(&zeroes[..chunk], off + done)?;
+        done += chunk as u64;
+    }
+    Ok(())
+}
 
-    let desc = handle.device().active_config_descriptor()?;
+/// How to recognize the gadget and its smoo interface, matching its options
+#[derive(Clone, Copy)]
+struct Target {
+    vendor_id: u16,
+    product_id: u16,
+    interface_subclass: u8,
+    interface_protocol: u8,
+}
+
+/// The smoo interface, among whatever other functions the gadget has
+#[derive(Clone, Copy)]
+struct Interface {
+    number: u8,
+    /// Endpoint carrying [Request]s
+    requests: u8,
+    /// Endpoint carrying [Response]s and read data
+    responses: u8,
+    /// Endpoint carrying write data
+    payloads: u8,
+    /// Max packet size of the payload endpoint, so stale data can be skipped a packet at a time
+    payload_packet_size: usize,
+}
 
-    for intf in desc.interfaces() {
-        for ep in intf.descriptors().next().unwrap().endpoint_descriptors() {
-            println!("ep: {:?} {}", ep, ep.transfer_type() == TransferType::Interrupt);
+fn find_interface(device: &Device<GlobalContext>, target: &Target) -> anyhow::Result<Interface> {
+    let config = device.active_config_descriptor()?;
+    for intf in config.interfaces() {
+        for desc in intf.descriptors() {
+            let class = (desc.class_code(), desc.sub_class_code(), desc.protocol_code());
+            if class != (smoo_proto::INTERFACE_CLASS, target.interface_subclass, target.interface_protocol) {
+                continue;
+            }
+            let endpoint = |direction, transfer_type| {
+                desc.endpoint_descriptors()
+                    .find(|ep| ep.direction() == direction && ep.transfer_type() == transfer_type)
+                    .with_context(|| format!("No {:?} {:?} endpoint", transfer_type, direction))
+            };
+            let payloads = endpoint(Direction::In, TransferType::Bulk)?;
+            return Ok(Interface {
+                number: desc.interface_number(),
+                requests: endpoint(Direction::In, TransferType::Interrupt)?.address(),
+                responses: endpoint(Direction::Out, TransferType::Bulk)?.address(),
+                payloads: payloads.address(),
+                payload_packet_size: payloads.max_packet_size() as usize,
+            });
         }
     }
+    anyhow::bail!("Gadget has no smoo interface")
+}
+
+/// Reads the payload of `request`, which follows a copy of the request on the payload endpoint.
+/// Anything else before it is what's left of a transfer an earlier session stopped reading, and
+/// is skipped a packet at a time, so the copy, which is always a short packet, is never missed.
+fn read_payload(
+    handle: &DeviceHandle<GlobalContext>,
+    intf: &Interface,
+    request: &Request,
+    data: &mut [u8],
+) -> anyhow::Result<()> {
+    let header = request.encode();
+    let mut packet = vec![0; intf.payload_packet_size];
+    loop {
+        let read = handle.read_bulk(intf.payloads, &mut packet, Duration::from_secs(1))?;
+        if packet[..read] == header {
+            break;
+        }
+        eprintln!("Skipping {} stale payload bytes", read);
+    }
+
+    // Payloads are whole sectors, so one that ends short was cut off
+    let read = handle.read_bulk(intf.payloads, data, Duration::from_secs(1))?;
+    anyhow::ensure!(read == data.len(), "Received {} of {} bytes for {:?}", read, data.len(), request);
+    Ok(())
+}
+
+/// A backing file, and how it's exported
+struct Backing {
+    file: File,
+    export: Export,
+}
+
+/// Opens an export given as `PATH[,read-only][,block-size=N]`
+fn open_export(spec: &str) -> anyhow::Result<Backing> {
+    let mut parts = spec.split(',');
+    let path = parts.next().unwrap();
+    let mut export = Export { size: 0, block_size: 512, read_only: false };
+    for option in parts {
+        match option.split_once('=') {
+            None if option == "read-only" => export.read_only = true,
+            Some(("block-size", size)) => export.block_size = size.parse()?,
+            _ => anyhow::bail!("Unknown export option {}", option),
+        }
+    }
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(!export.read_only)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path))?;
+    export.size = file.seek(SeekFrom::End(0))?;
+    Ok(Backing { file, export })
+}
 
-    handle.claim_interface(0)?;
+/// A request waiting for a worker, along with its payload
+struct Job {
+    request: Request,
+    data: Vec<u8>,
+}
 
-    let mut buf: [u8; 512] = [0; 512];
+/// Whether a request moves more data than offered in the hello, so it's never allocated
+fn oversized(request: &Request) -> bool {
+    (request.op.has_payload() || request.op.has_response_data()) && request.len > MAX_IO_SIZE
+}
+
+/// Errno to report for a failed operation on the backing file. Reads past its end surface as
+/// EIO, like they would from a disk.
+fn errno(err: &io::Error) -> i32 {
+    err.raw_os_error().unwrap_or(libc::EIO)
+}
 
-    let mut f = File::open("/tmp/file")?;
-    f.seek(SeekFrom::End(0))?;
-    NetworkEndian::write_u64(&mut buf, f.stream_position()?);
+/// Serves a request against its backing file, and answers it. Requests are independent of each
+/// other, so any number of these can run at once.
+///
+/// Failing to serve the request is reported to the gadget, which fails the I/O in turn; only
+/// failing to answer it is an error here.
+fn serve(
+    backings: &[Backing],
+    handle: &DeviceHandle<GlobalContext>,
+    intf: &Interface,
+    out: &Mutex<()>,
+    job: Job,
+) -> anyhow::Result<()> {
+    let Job { request, mut data } = job;
+    let off = request.offset;
+    let sz = request.len as u64;
+
+    let result = match backings.get(request.export as usize) {
+        _ if oversized(&request) => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        None => Err(io::Error::from_raw_os_error(libc::ENXIO)),
+        Some(backing) if backing.export.read_only && request.op != Opcode::Read => {
+            Err(io::Error::from_raw_os_error(libc::EROFS))
+        }
+        Some(Backing { file: f, .. }) => match request.op {
+            Opcode::Read => f.read_exact_at(&mut data, off),
+            Opcode::Write => f.write_all_at(&data, off),
+            Opcode::Flush => f.sync_all(),
+            Opcode::Discard => discard(f, off, sz),
+            Opcode::WriteZeroes => write_zeroes(f, off, sz),
+        },
+    };
+
+    // Answered once the backing file is updated, with the data for reads. The response and its
+    // data must not be split up by another worker's response.
+    let response = match result {
+        Ok(()) => Response::ok(&request),
+        Err(err) => {
+            eprintln!("{:?} failed: {}", request, err);
+            Response::error(&request, errno(&err))
+        }
+    };
+    let _out = out.lock().unwrap();
+    handle.write_bulk(intf.responses, &response.encode(), Duration::from_secs(1))?;
+    if response.len > 0 {
+        handle.write_bulk(intf.responses, &data, Duration::from_secs(1))?;
+    }
+    Ok(())
+}
+
+/// Serves the gadget until the connection to it is lost
+fn session(target: &Target, backings: &Arc<Vec<Backing>>) -> anyhow::Result<()> {
+    let handle = rusb::open_device_with_vid_pid(target.vendor_id, target.product_id)
+        .context("Gadget not found")?;
+    let intf = find_interface(&handle.device(), target)?;
+    handle.claim_interface(intf.number)?;
+    // Fails whatever an earlier session left queued on the gadget's endpoints
+    handle.set_alternate_setting(intf.number, 0)?;
+
+    let capabilities = Capabilities::FLUSH | Capabilities::DISCARD | Capabilities::WRITE_ZEROES;
+    let exports = backings.iter().map(|backing| backing.export).collect();
+    let hello = Hello::new(capabilities, QUEUES, QUEUE_DEPTH, MAX_IO_SIZE, exports);
 
     handle.write_control(
         rusb::request_type(Direction::Out, RequestType::Vendor, Recipient::Interface),
-        0, 0, 0, &buf[0..8], Duration::from_secs(1),
+        smoo_proto::REQUEST_HELLO, 0, intf.number as u16, &hello.encode(), Duration::from_secs(1),
     )?;
+    handle
+        .read_control(
+            rusb::request_type(Direction::In, RequestType::Vendor, Recipient::Interface),
+            smoo_proto::REQUEST_HELLO_ACK, 0, intf.number as u16, &mut [], Duration::from_secs(1),
+        )
+        .context("Gadget rejected the hello")?;
 
-    let mut read_buf = Vec::new();
-    loop {
-        let read = handle.read_interrupt(129, &mut buf[0..16], Duration::from_secs(0))?;
-        assert_eq!(read, 16);
+    for (index, backing) in backings.iter().enumerate() {
+        println!("Serving export {}: {:?}", index, backing.export);
+    }
+    let handle = Arc::new(handle);
+    let out = Arc::new(Mutex::new(()));
+    let (jobs_tx, jobs_rx) = mpsc::channel::<Job>();
+    let jobs_rx = Arc::new(Mutex::new(jobs_rx));
+    let (err_tx, err_rx) = mpsc::channel();
+    // Set once the session is over, so the workers leave whatever jobs are still queued
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|_| {
+            let (backings, handle, out, jobs_rx, err_tx, stop) = (
+                backings.clone(), handle.clone(), out.clone(), jobs_rx.clone(), err_tx.clone(),
+                stop.clone(),
+            );
+            std::thread::spawn(move || loop {
+                let Ok(job) = jobs_rx.lock().unwrap().recv() else {
+                    return;
+                };
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                if let Err(err) = serve(&backings, &handle, &intf, &out, job) {
+                    let _ = err_tx.send(err);
+                    return;
+                }
+            })
+        })
+        .collect();
 
-        let mut buf_r = &buf[..];
-        let off = buf_r.read_u64::<NetworkEndian>()?;
-        let sz = buf_r.read_u64::<NetworkEndian>()?;
+    let result = read_requests(&handle, &intf, &jobs_tx, &err_rx);
 
-        // println!("read {} {}", off, sz);
+    // The workers are gone before the next session claims the interface, so none of them can
+    // answer an old request in the middle of the new session's responses
+    stop.store(true, Ordering::Relaxed);
+    drop(jobs_tx);
+    for worker in workers {
+        let _ = worker.join();
+    }
+    result
+}
 
-        read_buf.resize(sz as _, 0);
+/// Reads requests, and the payloads of writes, which arrive in order, and leaves serving them to
+/// the workers
+fn read_requests(
+    handle: &DeviceHandle<GlobalContext>,
+    intf: &Interface,
+    jobs_tx: &mpsc::Sender<Job>,
+    err_rx: &mpsc::Receiver<anyhow::Error>,
+) -> anyhow::Result<()> {
+    let mut buf = [0; Request::SIZE];
+    loop {
+        if let Ok(err) = err_rx.try_recv() {
+            return Err(err);
+        }
 
-        let resp = &mut read_buf[0..sz as usize];
-        f.seek(SeekFrom::Start(off))?;
-        f.read_exact(resp);
+        let read = match handle.read_interrupt(intf.requests, &mut buf, REQUEST_POLL_INTERVAL) {
+            Err(rusb::Error::Timeout) => continue,
+            res => res?,
+        };
+        let request = Request::decode(&buf[..read])?;
 
-        handle.write_bulk(2, &resp, Duration::from_secs(1))?;
+        // Only reads and writes move data; the other ops just describe a range. Requests larger
+        // than offered are failed without their data, and the payload of a write left to be
+        // skipped as stale by the next one.
+        let moves_data = request.op.has_payload() || request.op.has_response_data();
+        let fits = !oversized(&request);
+        let data_len = if moves_data && fits { request.len } else { 0 };
+        let mut data = vec![0; data_len as _];
+        if request.op.has_payload() && fits {
+            read_payload(handle, intf, &request, &mut data)?;
+        }
+
+        jobs_tx.send(Job { request, data })?;
     }
+}
 
-    Ok(())
+/// The gadget holds on to its I/O while we're gone, so whenever the connection is lost, e.g.
+/// to a bumped cable, we just wait for it to come back and pick up where we left off
+fn main() -> anyhow::Result<()> {
+    // Matching the gadget's options of the same names, if it was given any
+    let mut args = std::env::args().skip(1);
+    let mut target = Target {
+        vendor_id: smoo_proto::VENDOR_ID,
+        product_id: smoo_proto::PRODUCT_ID,
+        interface_subclass: smoo_proto::INTERFACE_SUBCLASS,
+        interface_protocol: smoo_proto::INTERFACE_PROTOCOL,
+    };
+    let mut specs = vec![];
+    let hex = |arg: Option<String>| -> anyhow::Result<u16> {
+        let arg = arg.context("Missing ID")?;
+        Ok(u16::from_str_radix(arg.trim_start_matches("0x"), 16)?)
+    };
+    let number = |arg: Option<String>| -> anyhow::Result<u8> {
+        Ok(arg.context("Missing number")?.parse()?)
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--vendor-id" => target.vendor_id = hex(args.next())?,
+            "--product-id" => target.product_id = hex(args.next())?,
+            "--interface-subclass" => target.interface_subclass = number(args.next())?,
+            "--interface-protocol" => target.interface_protocol = number(args.next())?,
+            _ => specs.push(arg),
+        }
+    }
+    if specs.is_empty() {
+        specs.push("/tmp/file".to_string());
+    }
+    anyhow::ensure!(specs.len() <= smoo_proto::MAX_EXPORTS, "Too many exports");
+
+    // Exported in order, as the gadget's devices are numbered
+    let backings = Arc::new(specs.iter().map(|spec| open_export(spec)).collect::<anyhow::Result<Vec<_>>>()?);
+
+    loop {
+        if let Err(err) = session(&target, &backings) {
+            eprintln!("Lost the gadget: {:#}", err);
+        }
+        std::thread::sleep(RECONNECT_INTERVAL);
+    }
 }
@@ -0,0 +1,542 @@
+//! The smoo wire protocol, spoken between the gadget (the device consuming the block device)
+//! and the host (the machine serving it).
+//!
+//! 1. The host resets the smoo interface with SET_INTERFACE, which fails whatever transfers an
+//!    earlier session left on its endpoints, then opens the session with a [Hello] in a vendor
+//!    control request, announcing the protocol version, the block devices it exports, which
+//!    optional ops it can serve, and how many requests it's willing to have in flight and how
+//!    large. The session is open once the host has read back the empty [REQUEST_HELLO_ACK],
+//!    which the gadget stalls instead if it rejected the hello.
+//! 2. The gadget sends a [Request] on the interrupt IN endpoint for every I/O. The payload of
+//!    writes follows on the bulk IN endpoint, in the same order as the requests, each in its own
+//!    transfer right after a copy of its request in another. Payloads are whole sectors, so the
+//!    short copies stand out, and the host can skip anything but the one it expects.
+//! 3. The host answers every request with a [Response] on the bulk OUT endpoint, followed by
+//!    the data for reads. Responses may come in any order, and are matched to requests by export
+//!    and tag.
+//!
+//! The gadget may be a composite device, so hosts find the smoo interface by its class, and its
+//! endpoints by their type and direction rather than by address.
+//!
+//! All integers are big endian.
+
+use std::fmt::Display;
+
+/// Bumped on any incompatible change to the messages below
+pub const PROTOCOL_VERSION: u16 = 5;
+
+pub const VENDOR_ID: u16 = 0xDEAD;
+pub const PRODUCT_ID: u16 = 0xBEEF;
+
+/// Class of the smoo interface, which is vendor specific
+pub const INTERFACE_CLASS: u8 = 0xff;
+/// Default subclass and protocol of the smoo interface
+pub const INTERFACE_SUBCLASS: u8 = 123;
+pub const INTERFACE_PROTOCOL: u8 = 123;
+
+/// Vendor control request carrying the [Hello]
+pub const REQUEST_HELLO: u8 = 0;
+/// Vendor control request the host reads right after its [Hello]. The data stage of the hello
+/// is acknowledged before the gadget gets to look at it, so it's rejected here instead.
+pub const REQUEST_HELLO_ACK: u8 = 1;
+
+/// Most block devices a host may export in one session
+pub const MAX_EXPORTS: usize = 16;
+
+/// Smallest [Hello::max_io_size] a host may offer, which is one block of the largest size
+pub const MIN_IO_SIZE: u32 = 4096;
+
+/// Errors decoding protocol messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtoError {
+    /// Fewer bytes than the message needs
+    Truncated { expected: usize, actual: usize },
+    UnsupportedVersion(u16),
+    UnknownOpcode(u8),
+    /// None, or more than [MAX_EXPORTS]
+    ExportCount(u16),
+    /// Not a power of two from 512 to 4096, as Linux requires
+    InvalidBlockSize(u32),
+    /// Below [MIN_IO_SIZE]
+    InvalidMaxIoSize(u32),
+}
+
+impl Display for ProtoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtoError::Truncated { expected, actual } => {
+                write!(f, "Message of {actual} bytes is too short, expected {expected}")
+            }
+            ProtoError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported protocol version {version}")
+            }
+            ProtoError::UnknownOpcode(op) => write!(f, "Unknown opcode {op}"),
+            ProtoError::ExportCount(count) => write!(f, "Unsupported number of exports {count}"),
+            ProtoError::InvalidBlockSize(size) => write!(f, "Invalid block size {size}"),
+            ProtoError::InvalidMaxIoSize(size) => write!(f, "Invalid max I/O size {size}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtoError {}
+
+fn check_len(buf: &[u8], expected: usize) -> Result<(), ProtoError> {
+    if buf.len() < expected {
+        return Err(ProtoError::Truncated {
+            expected,
+            actual: buf.len(),
+        });
+    }
+    Ok(())
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Block device operations. The numbers match ublk's, so the gadget can pass them through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Read = 0,
+    Write = 1,
+    Flush = 2,
+    Discard = 3,
+    WriteZeroes = 5,
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = ProtoError;
+
+    fn try_from(op: u8) -> Result<Self, Self::Error> {
+        match op {
+            0 => Ok(Opcode::Read),
+            1 => Ok(Opcode::Write),
+            2 => Ok(Opcode::Flush),
+            3 => Ok(Opcode::Discard),
+            5 => Ok(Opcode::WriteZeroes),
+            op => Err(ProtoError::UnknownOpcode(op)),
+        }
+    }
+}
+
+impl Opcode {
+    /// Whether the gadget sends data along with the request
+    pub fn has_payload(self) -> bool {
+        self == Opcode::Write
+    }
+
+    /// Whether the host sends data along with the response
+    pub fn has_response_data(self) -> bool {
+        self == Opcode::Read
+    }
+}
+
+/// Optional operations the host can serve, as a set of flags
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const FLUSH: Self = Self(1 << 0);
+    pub const DISCARD: Self = Self(1 << 1);
+    pub const WRITE_ZEROES: Self = Self(1 << 2);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Flags from a newer peer that this version doesn't know about are dropped
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits & (Self::FLUSH | Self::DISCARD | Self::WRITE_ZEROES).0)
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// A block device served by the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Export {
+    /// Size in bytes
+    pub size: u64,
+    /// Logical block size in bytes
+    pub block_size: u32,
+    pub read_only: bool,
+}
+
+impl Export {
+    pub const SIZE: usize = 16;
+    const READ_ONLY: u32 = 1 << 0;
+
+    fn encode(&self, buf: &mut [u8]) {
+        let flags = if self.read_only { Self::READ_ONLY } else { 0 };
+        buf[0..8].copy_from_slice(&self.size.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.block_size.to_be_bytes());
+        buf[12..16].copy_from_slice(&flags.to_be_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, ProtoError> {
+        let block_size = u32_at(buf, 8);
+        if !block_size.is_power_of_two() || !(512..=4096).contains(&block_size) {
+            return Err(ProtoError::InvalidBlockSize(block_size));
+        }
+        Ok(Self {
+            size: u64_at(buf, 0),
+            block_size,
+            read_only: u32_at(buf, 12) & Self::READ_ONLY != 0,
+        })
+    }
+}
+
+/// Opens a session: sent by the host in the [REQUEST_HELLO] control request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    pub capabilities: Capabilities,
+    /// Most queues the gadget should spread the requests of each export over
+    pub queues: u16,
+    /// Most requests in flight per queue. The gadget may settle for fewer queues or a shallower
+    /// depth, but never more.
+    pub queue_depth: u16,
+    /// Largest request the host serves, in bytes. The gadget may settle for smaller requests,
+    /// but never larger; the host fails larger ones with EINVAL.
+    pub max_io_size: u32,
+    /// Numbered in order, for [Request::export]
+    pub exports: Vec<Export>,
+}
+
+impl Hello {
+    /// Size of the fixed part, which is followed by one entry per export
+    pub const HEADER_SIZE: usize = 16;
+    pub const MAX_SIZE: usize = Self::HEADER_SIZE + MAX_EXPORTS * Export::SIZE;
+
+    pub fn new(
+        capabilities: Capabilities,
+        queues: u16,
+        queue_depth: u16,
+        max_io_size: u32,
+        exports: Vec<Export>,
+    ) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities,
+            queues,
+            queue_depth,
+            max_io_size,
+            exports,
+        }
+    }
+
+    /// Most requests that can be in flight at once for an export. Tags are unique among these,
+    /// so this never exceeds the number of distinct tags.
+    pub fn max_in_flight(&self) -> u32 {
+        (self.queues as u32 * self.queue_depth as u32).min(u16::MAX as u32 + 1)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; Self::HEADER_SIZE + self.exports.len() * Export::SIZE];
+        buf[0..2].copy_from_slice(&self.version.to_be_bytes());
+        buf[2..4].copy_from_slice(&self.queues.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.capabilities.bits().to_be_bytes());
+        buf[8..10].copy_from_slice(&self.queue_depth.to_be_bytes());
+        buf[10..12].copy_from_slice(&(self.exports.len() as u16).to_be_bytes());
+        buf[12..16].copy_from_slice(&self.max_io_size.to_be_bytes());
+        for (export, entry) in self
+            .exports
+            .iter()
+            .zip(buf[Self::HEADER_SIZE..].chunks_mut(Export::SIZE))
+        {
+            export.encode(entry);
+        }
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, ProtoError> {
+        check_len(buf, Self::HEADER_SIZE)?;
+        let version = u16_at(buf, 0);
+        if version != PROTOCOL_VERSION {
+            return Err(ProtoError::UnsupportedVersion(version));
+        }
+        let count = u16_at(buf, 10);
+        if count == 0 || count as usize > MAX_EXPORTS {
+            return Err(ProtoError::ExportCount(count));
+        }
+        let max_io_size = u32_at(buf, 12);
+        if max_io_size < MIN_IO_SIZE {
+            return Err(ProtoError::InvalidMaxIoSize(max_io_size));
+        }
+        check_len(buf, Self::HEADER_SIZE + count as usize * Export::SIZE)?;
+        Ok(Self {
+            version,
+            capabilities: Capabilities::from_bits(u32_at(buf, 4)),
+            queues: u16_at(buf, 2),
+            queue_depth: u16_at(buf, 8),
+            max_io_size,
+            exports: buf[Self::HEADER_SIZE..]
+                .chunks_exact(Export::SIZE)
+                .take(count as usize)
+                .map(Export::decode)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// An I/O request from the gadget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    /// Index of the export in the [Hello]
+    pub export: u8,
+    /// Chosen by the gadget, unique among the export's requests in flight, and echoed in the
+    /// [Response]
+    pub tag: u16,
+    pub op: Opcode,
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl Request {
+    pub const SIZE: usize = 16;
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0; Self::SIZE];
+        buf[0] = self.op as u8;
+        buf[1] = self.export;
+        buf[2..4].copy_from_slice(&self.tag.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.len.to_be_bytes());
+        buf[8..16].copy_from_slice(&self.offset.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, ProtoError> {
+        check_len(buf, Self::SIZE)?;
+        Ok(Self {
+            op: Opcode::try_from(buf[0])?,
+            export: buf[1],
+            tag: u16_at(buf, 2),
+            len: u32_at(buf, 4),
+            offset: u64_at(buf, 8),
+        })
+    }
+}
+
+/// The host's answer to a [Request]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Response {
+    pub export: u8,
+    pub tag: u16,
+    /// 0 on success, otherwise an errno
+    pub status: i32,
+    /// Length of the data following the response
+    pub len: u32,
+}
+
+impl Response {
+    pub const SIZE: usize = 12;
+
+    /// Successful response to `request`, followed by data if it was a read
+    pub fn ok(request: &Request) -> Self {
+        Self {
+            export: request.export,
+            tag: request.tag,
+            status: 0,
+            len: if request.op.has_response_data() {
+                request.len
+            } else {
+                0
+            },
+        }
+    }
+
+    /// Failed response to `request`, never followed by data
+    pub fn error(request: &Request, errno: i32) -> Self {
+        Self {
+            export: request.export,
+            tag: request.tag,
+            status: errno,
+            len: 0,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0; Self::SIZE];
+        buf[0..2].copy_from_slice(&self.tag.to_be_bytes());
+        buf[2] = self.export;
+        buf[4..8].copy_from_slice(&self.status.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.len.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, ProtoError> {
+        check_len(buf, Self::SIZE)?;
+        Ok(Self {
+            tag: u16_at(buf, 0),
+            export: buf[2],
+            status: u32_at(buf, 4) as i32,
+            len: u32_at(buf, 8),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ROOTFS: Export = Export {
+        size: 1 << 40,
+        block_size: 4096,
+        read_only: false,
+    };
+    const SCRATCH: Export = Export {
+        size: 1 << 20,
+        block_size: 512,
+        read_only: true,
+    };
+
+    #[test]
+    fn hello_roundtrip() {
+        let capabilities = Capabilities::FLUSH | Capabilities::DISCARD;
+        let hello = Hello::new(capabilities, 4, 64, 1 << 20, vec![ROOTFS, SCRATCH]);
+        let encoded = hello.encode();
+        assert_eq!(encoded.len(), 48);
+        assert_eq!(&encoded[0..4], &[0, 5, 0, 4]);
+        assert_eq!(&encoded[8..16], &[0, 64, 0, 2, 0, 0x10, 0, 0]);
+        assert_eq!(&encoded[40..48], &[0, 0, 2, 0, 0, 0, 0, 1]);
+        assert_eq!(Hello::decode(&encoded), Ok(hello.clone()));
+        assert!(hello.capabilities.contains(Capabilities::DISCARD));
+        assert!(!hello.capabilities.contains(Capabilities::WRITE_ZEROES));
+    }
+
+    #[test]
+    fn hello_invalid() {
+        let mut encoded =
+            Hello::new(Capabilities::default(), 1, 1, MIN_IO_SIZE, vec![ROOTFS]).encode();
+        assert_eq!(
+            Hello::decode(&encoded[..16]),
+            Err(ProtoError::Truncated {
+                expected: 32,
+                actual: 16
+            })
+        );
+        encoded[26] = 0x30;
+        assert_eq!(
+            Hello::decode(&encoded),
+            Err(ProtoError::InvalidBlockSize(0x3000))
+        );
+        encoded[1] = 2;
+        assert_eq!(
+            Hello::decode(&encoded),
+            Err(ProtoError::UnsupportedVersion(2))
+        );
+
+        let small = Hello::new(Capabilities::default(), 1, 1, 512, vec![ROOTFS]).encode();
+        assert_eq!(
+            Hello::decode(&small),
+            Err(ProtoError::InvalidMaxIoSize(512))
+        );
+
+        let none = Hello::new(Capabilities::default(), 1, 1, MIN_IO_SIZE, vec![]).encode();
+        assert_eq!(Hello::decode(&none), Err(ProtoError::ExportCount(0)));
+        let many =
+            Hello::new(Capabilities::default(), 1, 1, MIN_IO_SIZE, vec![ROOTFS; 17]).encode();
+        assert_eq!(Hello::decode(&many), Err(ProtoError::ExportCount(17)));
+    }
+
+    #[test]
+    fn unknown_capabilities_dropped() {
+        let mut encoded = Hello::new(Capabilities::FLUSH, 1, 1, MIN_IO_SIZE, vec![ROOTFS]).encode();
+        encoded[4] = 0x80;
+        let hello = Hello::decode(&encoded).unwrap();
+        assert_eq!(hello.capabilities, Capabilities::FLUSH);
+    }
+
+    #[test]
+    fn max_in_flight() {
+        let hello = Hello::new(Capabilities::default(), 4, 64, MIN_IO_SIZE, vec![ROOTFS]);
+        assert_eq!(hello.max_in_flight(), 256);
+        let hello = Hello::new(
+            Capabilities::default(),
+            u16::MAX,
+            u16::MAX,
+            MIN_IO_SIZE,
+            vec![ROOTFS],
+        );
+        assert_eq!(hello.max_in_flight(), 65536);
+    }
+
+    #[test]
+    fn request_roundtrip() {
+        let request = Request {
+            export: 3,
+            tag: 0x1234,
+            op: Opcode::WriteZeroes,
+            offset: 0x1_0000_0200,
+            len: 4096,
+        };
+        let encoded = request.encode();
+        assert_eq!(
+            encoded,
+            [5, 3, 0x12, 0x34, 0, 0, 0x10, 0, 0, 0, 0, 1, 0, 0, 2, 0]
+        );
+        assert_eq!(Request::decode(&encoded), Ok(request));
+    }
+
+    #[test]
+    fn request_unknown_opcode() {
+        let mut encoded = Request {
+            export: 0,
+            tag: 0,
+            op: Opcode::Read,
+            offset: 0,
+            len: 512,
+        }
+        .encode();
+        encoded[0] = 4;
+        assert_eq!(Request::decode(&encoded), Err(ProtoError::UnknownOpcode(4)));
+    }
+
+    #[test]
+    fn response_roundtrip() {
+        let read = Request {
+            export: 1,
+            tag: 7,
+            op: Opcode::Read,
+            offset: 0,
+            len: 512,
+        };
+        assert_eq!(Response::ok(&read).len, 512);
+        let flush = Request {
+            op: Opcode::Flush,
+            ..read
+        };
+        assert_eq!(Response::ok(&flush).len, 0);
+        let failed = Response::error(&read, 28);
+        assert_eq!(
+            (failed.export, failed.tag, failed.status, failed.len),
+            (1, 7, 28, 0)
+        );
+
+        let response = Response {
+            export: 1,
+            tag: 7,
+            status: 5,
+            len: 0,
+        };
+        assert_eq!(Response::decode(&response.encode()), Ok(response));
+    }
+}
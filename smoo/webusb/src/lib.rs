@@ -1,15 +1,226 @@
-use js_sys::{Array, Object, Reflect};
+use js_sys::{Array, Object, Reflect, Uint8Array};
+use smoo_proto::{Hello, Request, Response};
+use wasm_bindgen::{JsCast, JsValue};
 use wasm_bindgen_futures::JsFuture;
-use web_sys::UsbDeviceRequestOptions;
+use web_sys::{
+    UsbConfiguration, UsbControlTransferParameters, UsbDevice, UsbDeviceRequestOptions, UsbDirection, UsbEndpoint,
+    UsbEndpointType, UsbInTransferResult, UsbInterface, UsbOutTransferResult, UsbRecipient, UsbRequestType,
+    UsbTransferStatus,
+};
 
-pub async fn start() {
-    let usb = web_sys::window().unwrap().navigator().usb();
+fn proto_error(err: smoo_proto::ProtoError) -> JsValue {
+    JsValue::from_str(&err.to_string())
+}
+
+/// Number of the smoo interface, among whatever other functions the gadget has. The subclass
+/// and protocol match the gadget's options, or [smoo_proto::INTERFACE_SUBCLASS] and
+/// [smoo_proto::INTERFACE_PROTOCOL] by default.
+pub fn find_interface(device: &UsbDevice, subclass: u8, protocol: u8) -> Option<u8> {
+    let config = device.configuration()?;
+    config
+        .unchecked_into::<UsbConfiguration>()
+        .interfaces()
+        .into_iter()
+        .map(UsbInterface::unchecked_from_js)
+        .find(|intf| {
+            let alternate = intf.alternate();
+            (
+                alternate.interface_class(),
+                alternate.interface_subclass(),
+                alternate.interface_protocol(),
+            ) == (smoo_proto::INTERFACE_CLASS, subclass, protocol)
+        })
+        .map(|intf| intf.interface_number())
+}
 
+/// Asks the user for a smoo gadget, returning it along with its smoo interface
+pub async fn start(subclass: u8, protocol: u8) -> Result<(UsbDevice, u8), JsValue> {
+    let usb = web_sys::window()
+        .ok_or(JsValue::from_str("No window"))?
+        .navigator()
+        .usb();
+
+    // Matched against the interfaces, so gadgets with their own identity are found too
     let filter = Object::new();
     let filters = Array::of1(&filter);
-    Reflect::set(&filter, &"vendorId".into(), &0xDEAD.into()).unwrap();
-    Reflect::set(&filter, &"productId".into(), &0xBEEF.into()).unwrap();
+    Reflect::set(&filter, &"classCode".into(), &smoo_proto::INTERFACE_CLASS.into())?;
+    Reflect::set(&filter, &"subclassCode".into(), &subclass.into())?;
+    Reflect::set(&filter, &"protocolCode".into(), &protocol.into())?;
+
+    let usb_device: UsbDevice = JsFuture::from(usb.request_device(&UsbDeviceRequestOptions::new(&filters)))
+        .await?
+        .unchecked_into();
+    let interface = find_interface(&usb_device, subclass, protocol)
+        .ok_or(JsValue::from_str("Device has no smoo interface"))?;
+    Ok((usb_device, interface))
+}
+
+/// An open session with a smoo gadget, served from the browser the way the host tool serves it
+/// over libusb
+pub struct Session {
+    device: UsbDevice,
+    interface: u8,
+    /// Endpoint carrying [Request]s
+    requests: u8,
+    /// Endpoint carrying [Response]s and read data
+    responses: u8,
+    /// Endpoint carrying write data
+    payloads: u8,
+    /// Max packet size of the payload endpoint, so stale data can be skipped a packet at a time
+    payload_packet_size: u32,
+    /// Largest request offered in the hello
+    max_io_size: u32,
+}
+
+impl Session {
+    /// Claims the smoo interface and opens a session with `hello`, failing if the gadget
+    /// rejects it
+    pub async fn open(device: UsbDevice, interface: u8, hello: &Hello) -> Result<Self, JsValue> {
+        if !device.opened() {
+            JsFuture::from(device.open()).await?;
+        }
+        JsFuture::from(device.claim_interface(interface)).await?;
+        // Fails whatever an earlier session left queued on the gadget's endpoints
+        JsFuture::from(device.select_alternate_interface(interface, 0)).await?;
+
+        let usb_interface = device
+            .configuration()
+            .ok_or(JsValue::from_str("Device is not configured"))?
+            .interfaces()
+            .into_iter()
+            .map(UsbInterface::unchecked_from_js)
+            .find(|intf| intf.interface_number() == interface)
+            .ok_or(JsValue::from_str("Device has no smoo interface"))?;
+        let endpoint = |direction, type_| {
+            usb_interface
+                .alternate()
+                .endpoints()
+                .into_iter()
+                .map(UsbEndpoint::unchecked_from_js)
+                .find(|ep| ep.direction() == direction && ep.type_() == type_)
+                .ok_or(JsValue::from_str("Smoo interface lacking endpoints"))
+        };
+        let payloads = endpoint(UsbDirection::In, UsbEndpointType::Bulk)?;
+        let session = Self {
+            interface,
+            requests: endpoint(UsbDirection::In, UsbEndpointType::Interrupt)?.endpoint_number(),
+            responses: endpoint(UsbDirection::Out, UsbEndpointType::Bulk)?.endpoint_number(),
+            payloads: payloads.endpoint_number(),
+            payload_packet_size: payloads.packet_size(),
+            max_io_size: hello.max_io_size,
+            device,
+        };
+
+        let params = |request| {
+            UsbControlTransferParameters::new(
+                interface as u16,
+                UsbRecipient::Interface,
+                request,
+                UsbRequestType::Vendor,
+                0,
+            )
+        };
+        JsFuture::from(
+            session
+                .device
+                .control_transfer_out_with_u8_slice(&params(smoo_proto::REQUEST_HELLO), &mut hello.encode())?,
+        )
+        .await?;
+        let ack: UsbInTransferResult = JsFuture::from(
+            session.device.control_transfer_in(&params(smoo_proto::REQUEST_HELLO_ACK), 0),
+        )
+        .await?
+        .unchecked_into();
+        if ack.status() != UsbTransferStatus::Ok {
+            return Err(JsValue::from_str("Gadget rejected the hello"));
+        }
+        Ok(session)
+    }
+
+    pub fn device(&self) -> &UsbDevice {
+        &self.device
+    }
+
+    pub fn interface(&self) -> u8 {
+        self.interface
+    }
+
+    async fn transfer_in(&self, endpoint: u8, len: u32) -> Result<Vec<u8>, JsValue> {
+        let res: UsbInTransferResult = JsFuture::from(self.device.transfer_in(endpoint, len))
+            .await?
+            .unchecked_into();
+        if res.status() != UsbTransferStatus::Ok {
+            return Err(JsValue::from_str(&format!("Transfer failed: {:?}", res.status())));
+        }
+        let Some(data) = res.data() else {
+            return Ok(vec![]);
+        };
+        Ok(Uint8Array::new_with_byte_offset_and_length(
+            &data.buffer(),
+            data.byte_offset() as u32,
+            data.byte_length() as u32,
+        )
+        .to_vec())
+    }
+
+    async fn transfer_out(&self, endpoint: u8, data: &[u8]) -> Result<(), JsValue> {
+        // WebUSB wants a mutable view, even though it only reads from it
+        let mut data = data.to_vec();
+        let res: UsbOutTransferResult =
+            JsFuture::from(self.device.transfer_out_with_u8_slice(endpoint, &mut data)?)
+                .await?
+                .unchecked_into();
+        if res.bytes_written() as usize != data.len() {
+            return Err(JsValue::from_str(&format!(
+                "Sent {} of {} bytes",
+                res.bytes_written(),
+                data.len()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Waits for the gadget's next request
+    pub async fn read_request(&self) -> Result<Request, JsValue> {
+        let buf = self.transfer_in(self.requests, Request::SIZE as u32).await?;
+        Request::decode(&buf).map_err(proto_error)
+    }
+
+    /// Whether a request moves more data than offered in the hello. These should be failed with
+    /// EINVAL without reading their payload, which the next one skips.
+    pub fn oversized(&self, request: &Request) -> bool {
+        (request.op.has_payload() || request.op.has_response_data()) && request.len > self.max_io_size
+    }
+
+    /// Reads the payload of `request`, which follows a copy of the request on the payload
+    /// endpoint. Payloads must be read in the order of their requests; anything before the copy
+    /// is what's left of a transfer an earlier session stopped reading, and is skipped.
+    pub async fn read_payload(&self, request: &Request) -> Result<Vec<u8>, JsValue> {
+        if self.oversized(request) {
+            return Err(JsValue::from_str(&format!("Payload of {:?} is too large", request)));
+        }
+        let header = request.encode();
+        while self.transfer_in(self.payloads, self.payload_packet_size).await? != header {}
+
+        // Payloads are whole sectors, so one that ends short was cut off
+        let data = self.transfer_in(self.payloads, request.len).await?;
+        if data.len() != request.len as usize {
+            return Err(JsValue::from_str(&format!(
+                "Received {} of {} bytes for {:?}",
+                data.len(),
+                request.len,
+                request
+            )));
+        }
+        Ok(data)
+    }
 
-    let usb_device = JsFuture::from(usb.request_device(&UsbDeviceRequestOptions::new(&filters))).await.unwrap();
-    // console::log_2(&"USB device connected".into(), &usb_device);
+    /// Answers a request, along with the data of reads
+    pub async fn respond(&self, response: &Response, data: &[u8]) -> Result<(), JsValue> {
+        self.transfer_out(self.responses, &response.encode()).await?;
+        if !data.is_empty() {
+            self.transfer_out(self.responses, data).await?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,167 @@
+//! The RSA key used to authenticate with adbd, persisted in browser storage so devices only ask
+//! the user to allow USB debugging once.
+
+use super::AdbError;
+use base64::Engine;
+use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding};
+use rsa::rand_core::OsRng;
+use rsa::traits::PublicKeyParts;
+use rsa::{BigUint, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use sha1::Sha1;
+
+/// adbd only accepts 2048 bit keys
+const KEY_BITS: usize = 2048;
+const KEY_WORDS: usize = KEY_BITS / 32;
+/// localStorage item the private key is kept in
+const STORAGE_KEY: &str = "bootbud-adbkey";
+/// Shown next to the key in the device's list of authorized computers
+const KEY_NAME: &str = "bootbud";
+
+pub struct AdbKey {
+    private: RsaPrivateKey,
+}
+
+impl AdbKey {
+    pub fn generate() -> Result<Self, AdbError> {
+        let private = RsaPrivateKey::new(&mut OsRng, KEY_BITS)?;
+        Ok(Self { private })
+    }
+
+    pub fn from_pem(pem: &str) -> Result<Self, AdbError> {
+        let private =
+            RsaPrivateKey::from_pkcs8_pem(pem).map_err(|err| AdbError::Key(err.to_string()))?;
+        Ok(Self { private })
+    }
+
+    pub fn to_pem(&self) -> Result<String, AdbError> {
+        let pem = self
+            .private
+            .to_pkcs8_pem(LineEnding::LF)
+            .map_err(|err| AdbError::Key(err.to_string()))?;
+        Ok(pem.to_string())
+    }
+
+    /// Loads the key from browser storage, generating and storing a new one the first time
+    pub fn load_or_generate() -> anyhow::Result<Self> {
+        let storage = web_sys::window()
+            .and_then(|window| window.local_storage().ok().flatten())
+            .ok_or(anyhow::anyhow!("Browser storage unavailable"))?;
+        if let Some(pem) = storage.get_item(STORAGE_KEY).map_err(crate::js_error)? {
+            return Ok(Self::from_pem(&pem)?);
+        }
+        let key = Self::generate()?;
+        storage
+            .set_item(STORAGE_KEY, &key.to_pem()?)
+            .map_err(crate::js_error)?;
+        Ok(key)
+    }
+
+    /// Signs an AUTH token; adbd treats the token as an already computed SHA-1 digest
+    pub fn sign(&self, token: &[u8]) -> Result<Vec<u8>, AdbError> {
+        Ok(self.private.sign(Pkcs1v15Sign::new::<Sha1>(), token)?)
+    }
+
+    #[cfg(test)]
+    pub fn rsa_public_key(&self) -> RsaPublicKey {
+        self.private.to_public_key()
+    }
+
+    /// Public key as sent in AUTH messages, in Android's own format
+    pub fn public_key(&self) -> Result<Vec<u8>, AdbError> {
+        encode_public_key(&self.private.to_public_key(), KEY_NAME)
+    }
+}
+
+/// -1 / n mod 2^32, for Montgomery multiplication on the device
+fn n0inv(n0: u32) -> u32 {
+    // Newton's method doubles the correct low bits with every step
+    let mut inv: u32 = 1;
+    for _ in 0..5 {
+        inv = inv.wrapping_mul(2u32.wrapping_sub(n0.wrapping_mul(inv)));
+    }
+    inv.wrapping_neg()
+}
+
+fn words(value: &BigUint) -> impl Iterator<Item = u32> {
+    let mut bytes = value.to_bytes_le();
+    bytes.resize(KEY_WORDS * 4, 0);
+    (0..KEY_WORDS).map(move |i| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap()))
+}
+
+/// Encodes a public key as adbd expects: the words of Android's `RSAPublicKey` struct, base64
+/// encoded, followed by a name and a NUL terminator
+fn encode_public_key(key: &RsaPublicKey, name: &str) -> Result<Vec<u8>, AdbError> {
+    let n = key.n();
+    if n.bits() != KEY_BITS {
+        return Err(AdbError::Key(format!(
+            "{} bit keys aren't supported",
+            n.bits()
+        )));
+    }
+    let mut e = key.e().to_bytes_le();
+    if e.len() > 4 {
+        return Err(AdbError::Key("public exponent too large".to_string()));
+    }
+    e.resize(4, 0);
+    // R^2 mod n, with R = 2^KEY_BITS
+    let rr = (BigUint::from(1u32) << (2 * KEY_BITS)) % n;
+
+    let n0 = words(n).next().unwrap();
+    let mut raw = vec![];
+    raw.extend_from_slice(&(KEY_WORDS as u32).to_le_bytes());
+    raw.extend_from_slice(&n0inv(n0).to_le_bytes());
+    words(n).for_each(|word| raw.extend_from_slice(&word.to_le_bytes()));
+    words(&rr).for_each(|word| raw.extend_from_slice(&word.to_le_bytes()));
+    raw.extend_from_slice(&e);
+
+    let mut encoded = base64::engine::general_purpose::STANDARD
+        .encode(raw)
+        .into_bytes();
+    encoded.push(b' ');
+    encoded.extend_from_slice(name.as_bytes());
+    encoded.push(0);
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn montgomery_inverse() {
+        for n0 in [1u32, 3, 0xffff_ffff, 0x1234_5679] {
+            assert_eq!(n0.wrapping_mul(n0inv(n0)), u32::MAX);
+        }
+    }
+
+    #[test]
+    fn public_key_format() {
+        let n = (BigUint::from(1u32) << (KEY_BITS - 1)) + BigUint::from(0x1_0001u32 * 2 + 1);
+        let key = RsaPublicKey::new(n.clone(), BigUint::from(65537u32)).unwrap();
+        let encoded = encode_public_key(&key, "test").unwrap();
+        assert!(encoded.ends_with(b" test\0"));
+
+        let raw = base64::engine::general_purpose::STANDARD
+            .decode(&encoded[..encoded.len() - 6])
+            .unwrap();
+        assert_eq!(raw.len(), 4 + 4 + KEY_WORDS * 8 + 4);
+        let word = |i: usize| u32::from_le_bytes(raw[i * 4..i * 4 + 4].try_into().unwrap());
+        assert_eq!(word(0), KEY_WORDS as u32);
+        assert_eq!(word(2), 0x2_0003);
+        assert_eq!(word(2).wrapping_mul(word(1)), u32::MAX);
+        assert_eq!(word(1 + KEY_WORDS), 0x8000_0000);
+        assert_eq!(word(2 + 2 * KEY_WORDS), 65537);
+
+        let rr = BigUint::from_bytes_le(&raw[8 + KEY_WORDS * 4..8 + KEY_WORDS * 8]);
+        assert_eq!(rr, (BigUint::from(1u32) << (2 * KEY_BITS)) % &n);
+    }
+
+    #[test]
+    fn rejects_small_keys() {
+        let key = RsaPublicKey::new(BigUint::from(0xffff_fffbu32), BigUint::from(3u32)).unwrap();
+        assert!(matches!(
+            encode_public_key(&key, "test"),
+            Err(AdbError::Key(_))
+        ));
+    }
+}
@@ -0,0 +1,578 @@
+pub mod auth;
+mod protocol;
+mod sync;
+pub mod webusb;
+
+use auth::AdbKey;
+use protocol::{AdbMessageParseError, Command, Header, Message};
+use protocol::{AUTH_RSAPUBLICKEY, AUTH_SIGNATURE, AUTH_TOKEN, HEADER_SIZE, MAX_PAYLOAD, VERSION};
+use std::collections::VecDeque;
+use thiserror::Error;
+use tracing::trace;
+
+/// ADB communication errors
+#[derive(Debug, Error)]
+pub enum AdbError {
+    #[error("General error: {0}")]
+    Transfer(#[from] Box<dyn std::error::Error + Send + Sync>),
+    #[error("Invalid ADB message: {0}")]
+    Parse(#[from] AdbMessageParseError),
+    #[error("Unexpected {0} message")]
+    Unexpected(Command),
+    #[error("Device refused to open {0}")]
+    Refused(String),
+    #[error("Stream closed by the device")]
+    Closed,
+    #[error("File transfer failed: {0}")]
+    Sync(String),
+    #[error("ADB key error: {0}")]
+    Key(String),
+    #[error(transparent)]
+    Rsa(#[from] rsa::Error),
+}
+
+pub trait AdbOps {
+    async fn write_out(&mut self, buf: &[u8]) -> Result<usize, AdbError>;
+    async fn read_in(&mut self, buf: &mut [u8]) -> Result<usize, AdbError>;
+}
+
+/// A stream opened to a service on the device, identified by the id each end gave it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stream {
+    local: u32,
+    remote: u32,
+}
+
+/// ADB client
+pub struct Adb<Ops> {
+    ops: Ops,
+    max_payload: usize,
+    banner: String,
+    next_id: u32,
+    /// Messages that arrived for streams other than the one being waited on
+    queued: VecDeque<Message>,
+}
+
+impl<Ops: AdbOps> Adb<Ops> {
+    async fn send(&mut self, message: Message) -> Result<(), AdbError> {
+        trace!(
+            "Sending {} {} {} ({} bytes)",
+            message.command,
+            message.arg0,
+            message.arg1,
+            message.data.len()
+        );
+        self.ops.write_out(&message.header()).await?;
+        if !message.data.is_empty() {
+            self.ops.write_out(&message.data).await?;
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Message, AdbError> {
+        let mut header = [0; HEADER_SIZE];
+        let len = self.ops.read_in(&mut header).await?;
+        let header = Header::from_bytes(&header[..len])?;
+
+        let mut data = vec![0; header.data_length as usize];
+        let mut read = 0;
+        while read < data.len() {
+            let len = self.ops.read_in(&mut data[read..]).await?;
+            if len == 0 {
+                return Err(AdbError::Parse(AdbMessageParseError::Truncated));
+            }
+            read += len;
+        }
+        trace!(
+            "Received {} {} {} ({} bytes)",
+            header.command,
+            header.arg0,
+            header.arg1,
+            data.len()
+        );
+        Ok(Message::new(header.command, header.arg0, header.arg1, data))
+    }
+
+    /// Connect to adbd, authenticating with `key`
+    ///
+    /// `on_prompt` is called when the device doesn't know the key yet, and is asking the user
+    /// whether to allow USB debugging.
+    pub async fn connect(
+        ops: Ops,
+        key: &AdbKey,
+        mut on_prompt: impl FnMut(),
+    ) -> Result<Self, AdbError> {
+        let mut adb = Self {
+            ops,
+            max_payload: MAX_PAYLOAD as usize,
+            banner: String::new(),
+            next_id: 1,
+            queued: VecDeque::new(),
+        };
+        adb.send(Message::new(
+            Command::CNXN,
+            VERSION,
+            MAX_PAYLOAD,
+            b"host::bootbud\0".to_vec(),
+        ))
+        .await?;
+
+        let mut signed = false;
+        loop {
+            let message = adb.recv().await?;
+            match message.command {
+                Command::CNXN => {
+                    adb.max_payload = message.arg1.min(MAX_PAYLOAD) as usize;
+                    adb.banner = String::from_utf8_lossy(&message.data)
+                        .trim_end_matches('\0')
+                        .to_string();
+                    return Ok(adb);
+                }
+                Command::AUTH if message.arg0 == AUTH_TOKEN && !signed => {
+                    signed = true;
+                    let signature = key.sign(&message.data)?;
+                    adb.send(Message::new(Command::AUTH, AUTH_SIGNATURE, 0, signature))
+                        .await?;
+                }
+                Command::AUTH if message.arg0 == AUTH_TOKEN => {
+                    // The signature was rejected, so offer the key for the user to accept
+                    on_prompt();
+                    let public_key = key.public_key()?;
+                    adb.send(Message::new(
+                        Command::AUTH,
+                        AUTH_RSAPUBLICKEY,
+                        0,
+                        public_key,
+                    ))
+                    .await?;
+                }
+                command => return Err(AdbError::Unexpected(command)),
+            }
+        }
+    }
+
+    /// Value of a property from the identity the device announced when connecting, e.g.
+    /// `device::ro.product.name=...;ro.product.model=...;`
+    pub fn banner_property(&self, name: &str) -> Option<&str> {
+        let (_, props) = self.banner.split_once("::")?;
+        props
+            .split(';')
+            .filter_map(|prop| prop.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Wait for the next message addressed to the stream with the given local id
+    async fn recv_for(&mut self, local: u32) -> Result<Message, AdbError> {
+        if let Some(pos) = self.queued.iter().position(|m| m.arg1 == local) {
+            return Ok(self.queued.remove(pos).unwrap());
+        }
+        loop {
+            let message = self.recv().await?;
+            if message.arg1 == local {
+                return Ok(message);
+            }
+            self.queued.push_back(message);
+        }
+    }
+
+    /// Open a stream to a service on the device, e.g. `shell:ls`
+    pub async fn open(&mut self, destination: &str) -> Result<Stream, AdbError> {
+        let local = self.next_id;
+        self.next_id += 1;
+        let mut data = destination.as_bytes().to_vec();
+        data.push(0);
+        self.send(Message::new(Command::OPEN, local, 0, data))
+            .await?;
+
+        let message = self.recv_for(local).await?;
+        match message.command {
+            Command::OKAY => Ok(Stream {
+                local,
+                remote: message.arg0,
+            }),
+            Command::CLSE => Err(AdbError::Refused(destination.to_string())),
+            command => Err(AdbError::Unexpected(command)),
+        }
+    }
+
+    /// Write to a stream, waiting for the device to acknowledge each message
+    pub async fn write(&mut self, stream: Stream, data: &[u8]) -> Result<(), AdbError> {
+        for chunk in data.chunks(self.max_payload) {
+            self.send(Message::new(
+                Command::WRTE,
+                stream.local,
+                stream.remote,
+                chunk.to_vec(),
+            ))
+            .await?;
+
+            // Data the device sends meanwhile is kept for the next read, as is the device
+            // closing the stream, which means the acknowledgement is never coming
+            let mut received = vec![];
+            let result = loop {
+                let message = self.recv_for(stream.local).await?;
+                match message.command {
+                    Command::OKAY => break Ok(()),
+                    Command::WRTE => received.push(message),
+                    Command::CLSE => {
+                        received.push(message);
+                        break Err(AdbError::Closed);
+                    }
+                    command => return Err(AdbError::Unexpected(command)),
+                }
+            };
+            for message in received.into_iter().rev() {
+                self.queued.push_front(message);
+            }
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Read the next chunk of data from a stream, or `None` once the device has closed it
+    pub async fn read(&mut self, stream: Stream) -> Result<Option<Vec<u8>>, AdbError> {
+        loop {
+            let message = self.recv_for(stream.local).await?;
+            match message.command {
+                Command::WRTE => {
+                    self.send(Message::new(
+                        Command::OKAY,
+                        stream.local,
+                        stream.remote,
+                        vec![],
+                    ))
+                    .await?;
+                    return Ok(Some(message.data));
+                }
+                Command::CLSE => {
+                    self.close(stream).await?;
+                    return Ok(None);
+                }
+                // Late acknowledgement of a write
+                Command::OKAY => (),
+                command => return Err(AdbError::Unexpected(command)),
+            }
+        }
+    }
+
+    pub async fn close(&mut self, stream: Stream) -> Result<(), AdbError> {
+        self.queued.retain(|m| m.arg1 != stream.local);
+        self.send(Message::new(
+            Command::CLSE,
+            stream.local,
+            stream.remote,
+            vec![],
+        ))
+        .await
+    }
+
+    /// Run a command on the device, returning its output once it exits
+    pub async fn shell(&mut self, command: &str) -> Result<String, AdbError> {
+        let stream = self.open(&format!("shell:{command}")).await?;
+        let mut output = vec![];
+        while let Some(data) = self.read(stream).await? {
+            output.extend_from_slice(&data);
+        }
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    /// Send `request` to a TCP port on the device, returning everything it answers with until
+    /// the connection is closed
+    ///
+    /// Browsers can't listen for connections, so rather than forwarding a local port this
+    /// relays a single exchange.
+    pub async fn relay(&mut self, port: u16, request: &[u8]) -> Result<Vec<u8>, AdbError> {
+        let stream = self.open(&format!("tcp:{port}")).await?;
+        if !request.is_empty() {
+            self.write(stream, request).await?;
+        }
+        let mut response = vec![];
+        while let Some(data) = self.read(stream).await? {
+            response.extend_from_slice(&data);
+        }
+        Ok(response)
+    }
+
+    /// Reboot the device, into the given target (e.g. `bootloader`) if not empty
+    ///
+    /// The device usually drops off the bus before acknowledging, so this doesn't wait for it.
+    pub async fn reboot(&mut self, target: &str) -> Result<(), AdbError> {
+        let local = self.next_id;
+        self.next_id += 1;
+        self.send(Message::new(
+            Command::OPEN,
+            local,
+            0,
+            format!("reboot:{target}\0").into_bytes(),
+        ))
+        .await
+    }
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use super::*;
+    use futures::executor::block_on;
+    use rsa::{Pkcs1v15Sign, RsaPublicKey};
+    use sha1::Sha1;
+    use std::collections::HashMap;
+
+    const TOKEN: [u8; 20] = [7; 20];
+
+    /// Local id the fake gives `sync:` streams
+    const SYNC_ID: u32 = 102;
+
+    /// Scripted adbd that knows at most one key, and only offers `shell:echo hi`, `shell:cat`,
+    /// an echo server on `tcp:7`, a server on `tcp:9` that hangs up on anything written to it,
+    /// and `sync:` backed by an in-memory filesystem
+    pub(in crate::adb) struct FakeAdbd {
+        known_key: Option<RsaPublicKey>,
+        offered_key: Option<Vec<u8>>,
+        written: Vec<u8>,
+        responses: VecDeque<Vec<u8>>,
+        files: HashMap<String, Vec<u8>>,
+        sync_received: Vec<u8>,
+        /// Path and data of the file being pushed
+        pushing: Option<(String, Vec<u8>)>,
+    }
+
+    impl FakeAdbd {
+        fn new(known_key: Option<RsaPublicKey>) -> Self {
+            Self {
+                known_key,
+                offered_key: None,
+                written: vec![],
+                responses: VecDeque::new(),
+                files: HashMap::new(),
+                sync_received: vec![],
+                pushing: None,
+            }
+        }
+
+        /// A client that's already past the handshake, sparing tests the slow key generation
+        pub(in crate::adb) fn connected_adb() -> Adb<Self> {
+            Adb {
+                ops: Self::new(None),
+                max_payload: 4096,
+                banner: "device::".to_string(),
+                next_id: 1,
+                queued: VecDeque::new(),
+            }
+        }
+
+        /// Sends sync data back in small pieces, to exercise reassembly
+        fn respond_sync(&mut self, local: u32, data: &[u8]) {
+            for chunk in data.chunks(5) {
+                self.respond(Message::new(Command::WRTE, SYNC_ID, local, chunk.to_vec()));
+            }
+        }
+
+        fn sync(&mut self, local: u32) {
+            while self.sync_received.len() >= 8 {
+                let id: [u8; 4] = self.sync_received[..4].try_into().unwrap();
+                let arg = u32::from_le_bytes(self.sync_received[4..8].try_into().unwrap());
+                let len = if &id == b"DONE" { 0 } else { arg as usize };
+                if self.sync_received.len() < 8 + len {
+                    return;
+                }
+                let payload: Vec<u8> = self.sync_received.drain(..8 + len).skip(8).collect();
+                match &id {
+                    b"SEND" => {
+                        let spec = String::from_utf8(payload).unwrap();
+                        let (path, _mode) = spec.rsplit_once(',').unwrap();
+                        self.pushing = Some((path.to_string(), vec![]));
+                    }
+                    b"DATA" => self.pushing.as_mut().unwrap().1.extend(payload),
+                    b"DONE" => {
+                        let (path, data) = self.pushing.take().unwrap();
+                        self.files.insert(path, data);
+                        self.respond_sync(local, b"OKAY\0\0\0\0");
+                    }
+                    b"RECV" => {
+                        let path = String::from_utf8(payload).unwrap();
+                        let mut response = vec![];
+                        match self.files.get(&path) {
+                            Some(data) => {
+                                for chunk in data.chunks(1000) {
+                                    response.extend(sync::packet(b"DATA", chunk));
+                                }
+                                response.extend(sync::packet(b"DONE", &[]));
+                            }
+                            None => response.extend(sync::packet(b"FAIL", b"No such file")),
+                        }
+                        self.respond_sync(local, &response);
+                    }
+                    _ => panic!("unexpected sync request"),
+                }
+            }
+        }
+
+        fn respond(&mut self, message: Message) {
+            self.responses.push_back(message.header().to_vec());
+            if !message.data.is_empty() {
+                self.responses.push_back(message.data);
+            }
+        }
+
+        fn connected(&mut self) {
+            self.respond(Message::new(
+                Command::CNXN,
+                VERSION,
+                4,
+                b"device::ro.product.name=test;ro.product.model=Test\0".to_vec(),
+            ));
+        }
+
+        fn handle(&mut self, message: Message) {
+            match (message.command, message.arg0) {
+                (Command::CNXN, _) => {
+                    self.respond(Message::new(Command::AUTH, AUTH_TOKEN, 0, TOKEN.to_vec()))
+                }
+                (Command::AUTH, AUTH_SIGNATURE) => {
+                    let valid = self.known_key.as_ref().is_some_and(|key| {
+                        key.verify(Pkcs1v15Sign::new::<Sha1>(), &TOKEN, &message.data)
+                            .is_ok()
+                    });
+                    if valid {
+                        self.connected();
+                    } else {
+                        self.respond(Message::new(Command::AUTH, AUTH_TOKEN, 0, TOKEN.to_vec()));
+                    }
+                }
+                (Command::AUTH, AUTH_RSAPUBLICKEY) => {
+                    self.offered_key = Some(message.data);
+                    self.connected();
+                }
+                (Command::OPEN, local) if message.data == b"shell:echo hi\0" => {
+                    self.respond(Message::new(Command::OKAY, 100, local, vec![]));
+                    self.respond(Message::new(Command::WRTE, 100, local, b"hi\n".to_vec()));
+                    self.respond(Message::new(Command::CLSE, 100, local, vec![]));
+                }
+                (Command::OPEN, local) if message.data == b"shell:cat\0" => {
+                    self.respond(Message::new(Command::OKAY, 101, local, vec![]));
+                }
+                (Command::WRTE, local) if message.arg1 == 101 => {
+                    self.respond(Message::new(Command::OKAY, 101, local, vec![]));
+                    self.respond(Message::new(Command::WRTE, 101, local, message.data));
+                }
+                (Command::OPEN, local) if message.data == b"tcp:7\0" => {
+                    self.respond(Message::new(Command::OKAY, 103, local, vec![]));
+                }
+                (Command::WRTE, local) if message.arg1 == 103 => {
+                    // Echo the request back and hang up
+                    self.respond(Message::new(Command::OKAY, 103, local, vec![]));
+                    self.respond(Message::new(Command::WRTE, 103, local, message.data));
+                    self.respond(Message::new(Command::CLSE, 103, local, vec![]));
+                }
+                (Command::OPEN, local) if message.data == b"tcp:9\0" => {
+                    self.respond(Message::new(Command::OKAY, 104, local, vec![]));
+                }
+                (Command::WRTE, local) if message.arg1 == 104 => {
+                    self.respond(Message::new(Command::CLSE, 104, local, vec![]));
+                }
+                (Command::OPEN, local) if message.data == b"sync:\0" => {
+                    self.respond(Message::new(Command::OKAY, SYNC_ID, local, vec![]));
+                }
+                (Command::WRTE, local) if message.arg1 == SYNC_ID => {
+                    self.respond(Message::new(Command::OKAY, SYNC_ID, local, vec![]));
+                    self.sync_received.extend(message.data);
+                    self.sync(local);
+                }
+                (Command::OPEN, local) => {
+                    self.respond(Message::new(Command::CLSE, 0, local, vec![]))
+                }
+                _ => (),
+            }
+        }
+    }
+
+    impl AdbOps for FakeAdbd {
+        async fn write_out(&mut self, buf: &[u8]) -> Result<usize, AdbError> {
+            self.written.extend_from_slice(buf);
+            while let Ok(header) = Header::from_bytes(&self.written) {
+                let len = HEADER_SIZE + header.data_length as usize;
+                if self.written.len() < len {
+                    break;
+                }
+                let data = self.written[HEADER_SIZE..len].to_vec();
+                self.written.drain(..len);
+                self.handle(Message::new(header.command, header.arg0, header.arg1, data));
+            }
+            Ok(buf.len())
+        }
+
+        async fn read_in(&mut self, buf: &mut [u8]) -> Result<usize, AdbError> {
+            let response = self
+                .responses
+                .pop_front()
+                .ok_or(AdbError::Unexpected(Command::CLSE))?;
+            buf[..response.len()].copy_from_slice(&response);
+            Ok(response.len())
+        }
+    }
+
+    #[test]
+    fn connect() {
+        // Generating keys is slow, so both handshakes share one
+        let key = AdbKey::generate().unwrap();
+
+        let mut prompted = false;
+        let adb = block_on(Adb::connect(FakeAdbd::new(None), &key, || prompted = true)).unwrap();
+        assert!(prompted);
+        assert_eq!(adb.ops.offered_key, Some(key.public_key().unwrap()));
+
+        let mut prompted = false;
+        let mut adb = block_on(Adb::connect(
+            FakeAdbd::new(Some(key.rsa_public_key())),
+            &key,
+            || prompted = true,
+        ))
+        .unwrap();
+        assert!(!prompted);
+        assert_eq!(adb.max_payload, 4);
+        assert_eq!(adb.banner_property("ro.product.model"), Some("Test"));
+        assert_eq!(adb.banner_property("ro.serialno"), None);
+
+        block_on(async {
+            assert_eq!(adb.shell("echo hi").await.unwrap(), "hi\n");
+            assert!(matches!(
+                adb.open("tcp:80").await,
+                Err(AdbError::Refused(_))
+            ));
+
+            let cat = adb.open("shell:cat").await.unwrap();
+            adb.write(cat, b"hello").await.unwrap();
+            let mut echoed = vec![];
+            while echoed.len() < 5 {
+                echoed.extend(adb.read(cat).await.unwrap().unwrap());
+            }
+            assert_eq!(echoed, b"hello");
+            adb.close(cat).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn relay() {
+        let mut adb = FakeAdbd::connected_adb();
+        let response = block_on(adb.relay(7, b"GET / HTTP/1.0\r\n\r\n")).unwrap();
+        assert_eq!(response, b"GET / HTTP/1.0\r\n\r\n");
+    }
+
+    #[test]
+    fn closed_during_write() {
+        let mut adb = FakeAdbd::connected_adb();
+        block_on(async {
+            let stream = adb.open("tcp:9").await.unwrap();
+            assert!(matches!(
+                adb.write(stream, b"hello").await,
+                Err(AdbError::Closed)
+            ));
+            assert_eq!(adb.read(stream).await.unwrap(), None);
+        });
+        assert!(matches!(
+            block_on(adb.relay(9, b"hello")),
+            Err(AdbError::Closed)
+        ));
+    }
+}
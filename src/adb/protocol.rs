@@ -0,0 +1,181 @@
+use std::fmt::{Debug, Display};
+use thiserror::Error;
+
+/// Protocol version announced in CNXN; newer devices skip payload checksums with it
+pub const VERSION: u32 = 0x0100_0001;
+/// Largest payload we accept in a single message
+pub const MAX_PAYLOAD: u32 = 1024 * 1024;
+/// Size of every message header
+pub const HEADER_SIZE: usize = 24;
+
+/// AUTH types
+pub const AUTH_TOKEN: u32 = 1;
+pub const AUTH_SIGNATURE: u32 = 2;
+pub const AUTH_RSAPUBLICKEY: u32 = 3;
+
+/// ADB message commands
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Command(u32);
+
+impl Command {
+    pub const CNXN: Self = Self::from_name(b"CNXN");
+    pub const AUTH: Self = Self::from_name(b"AUTH");
+    pub const OPEN: Self = Self::from_name(b"OPEN");
+    pub const OKAY: Self = Self::from_name(b"OKAY");
+    pub const CLSE: Self = Self::from_name(b"CLSE");
+    pub const WRTE: Self = Self::from_name(b"WRTE");
+
+    const fn from_name(name: &[u8; 4]) -> Self {
+        Self(u32::from_le_bytes(*name))
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.0.to_le_bytes();
+        match std::str::from_utf8(&name) {
+            Ok(name) if name.chars().all(|c| c.is_ascii_uppercase()) => f.write_str(name),
+            _ => write!(f, "{:#010x}", self.0),
+        }
+    }
+}
+
+impl Debug for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+/// Errors parsing ADB message headers
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AdbMessageParseError {
+    #[error("Message header too short")]
+    Truncated,
+    #[error("Message magic doesn't match command {0}")]
+    BadMagic(Command),
+    #[error("{0} payload of {1} bytes is too large")]
+    TooLarge(Command, u32),
+}
+
+/// A single ADB message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub command: Command,
+    pub arg0: u32,
+    pub arg1: u32,
+    pub data: Vec<u8>,
+}
+
+/// Header of a message whose payload is still to be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub command: Command,
+    pub arg0: u32,
+    pub arg1: u32,
+    pub data_length: u32,
+}
+
+impl Header {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, AdbMessageParseError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(AdbMessageParseError::Truncated);
+        }
+        let word = |i: usize| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+        let command = Command(word(0));
+        if word(5) != !command.0 {
+            return Err(AdbMessageParseError::BadMagic(command));
+        }
+        let data_length = word(3);
+        if data_length > MAX_PAYLOAD {
+            return Err(AdbMessageParseError::TooLarge(command, data_length));
+        }
+        Ok(Self {
+            command,
+            arg0: word(1),
+            arg1: word(2),
+            data_length,
+        })
+    }
+}
+
+impl Message {
+    pub fn new(command: Command, arg0: u32, arg1: u32, data: impl Into<Vec<u8>>) -> Self {
+        Self {
+            command,
+            arg0,
+            arg1,
+            data: data.into(),
+        }
+    }
+
+    /// Header for this message, including the payload checksum older devices require
+    pub fn header(&self) -> [u8; HEADER_SIZE] {
+        let checksum = self
+            .data
+            .iter()
+            .fold(0u32, |sum, &b| sum.wrapping_add(b as u32));
+        let words = [
+            self.command.0,
+            self.arg0,
+            self.arg1,
+            self.data.len() as u32,
+            checksum,
+            !self.command.0,
+        ];
+        let mut header = [0; HEADER_SIZE];
+        for (chunk, word) in header.chunks_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        header
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn command_names() {
+        assert_eq!(Command::CNXN.0, 0x4e584e43);
+        assert_eq!(Command::WRTE.to_string(), "WRTE");
+        assert_eq!(Command(1).to_string(), "0x00000001");
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let message = Message::new(Command::WRTE, 1, 2, b"hi".to_vec());
+        let header = message.header();
+        assert_eq!(&header[16..20], &(b'h' as u32 + b'i' as u32).to_le_bytes());
+        assert_eq!(
+            Header::from_bytes(&header).unwrap(),
+            Header {
+                command: Command::WRTE,
+                arg0: 1,
+                arg1: 2,
+                data_length: 2
+            }
+        );
+    }
+
+    #[test]
+    fn header_invalid() {
+        let mut header = Message::new(Command::OKAY, 1, 2, vec![]).header();
+        assert_eq!(
+            Header::from_bytes(&header[..20]),
+            Err(AdbMessageParseError::Truncated)
+        );
+        header[12..16].copy_from_slice(&(MAX_PAYLOAD + 1).to_le_bytes());
+        assert_eq!(
+            Header::from_bytes(&header),
+            Err(AdbMessageParseError::TooLarge(
+                Command::OKAY,
+                MAX_PAYLOAD + 1
+            ))
+        );
+        header[20] ^= 1;
+        assert_eq!(
+            Header::from_bytes(&header),
+            Err(AdbMessageParseError::BadMagic(Command::OKAY))
+        );
+    }
+}
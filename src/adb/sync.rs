@@ -0,0 +1,175 @@
+//! The file sync service (`sync:`), used to push files to and pull them from the device.
+//!
+//! Every request and response is a four letter id followed by a little endian length, and for
+//! most of them that many bytes of payload. None of it is aligned to ADB messages.
+
+use super::{Adb, AdbError, AdbOps, Stream};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// adbd refuses DATA packets larger than this
+const DATA_MAX: usize = 64 * 1024;
+
+pub(super) fn packet(id: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut packet = id.to_vec();
+    packet.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn io_error(err: std::io::Error) -> AdbError {
+    AdbError::Transfer(err.into())
+}
+
+struct Sync<'a, Ops> {
+    adb: &'a mut Adb<Ops>,
+    stream: Stream,
+    received: Vec<u8>,
+}
+
+impl<'a, Ops: AdbOps> Sync<'a, Ops> {
+    async fn open(adb: &'a mut Adb<Ops>) -> Result<Self, AdbError> {
+        let stream = adb.open("sync:").await?;
+        Ok(Self {
+            adb,
+            stream,
+            received: vec![],
+        })
+    }
+
+    async fn read_exact(&mut self, len: usize) -> Result<Vec<u8>, AdbError> {
+        while self.received.len() < len {
+            let data = self.adb.read(self.stream).await?.ok_or(AdbError::Closed)?;
+            self.received.extend_from_slice(&data);
+        }
+        Ok(self.received.drain(..len).collect())
+    }
+
+    /// Reads the id and length of the next response
+    async fn read_header(&mut self) -> Result<([u8; 4], u32), AdbError> {
+        let header = self.read_exact(8).await?;
+        Ok((
+            header[..4].try_into().unwrap(),
+            u32::from_le_bytes(header[4..].try_into().unwrap()),
+        ))
+    }
+
+    /// Reads the message of a FAIL response, and gives up on the stream
+    async fn fail(mut self, len: u32) -> AdbError {
+        let err = match self.read_exact(len as usize).await {
+            Ok(message) => AdbError::Sync(String::from_utf8_lossy(&message).into_owned()),
+            Err(err) => return err,
+        };
+        match self.close().await {
+            Ok(()) => err,
+            Err(close_err) => close_err,
+        }
+    }
+
+    async fn close(self) -> Result<(), AdbError> {
+        self.adb.close(self.stream).await
+    }
+}
+
+impl<Ops: AdbOps> Adb<Ops> {
+    /// Push everything `reader` produces to `path` on the device, creating it with `mode` and
+    /// `mtime`. Returns the number of bytes pushed.
+    pub async fn push<R: AsyncRead + Unpin>(
+        &mut self,
+        mut reader: R,
+        path: &str,
+        mode: u32,
+        mtime: u32,
+    ) -> Result<u64, AdbError> {
+        let mut sync = Sync::open(self).await?;
+        let spec = format!("{path},{mode}");
+        sync.adb
+            .write(sync.stream, &packet(b"SEND", spec.as_bytes()))
+            .await?;
+
+        let mut buf = vec![0; DATA_MAX];
+        let mut pushed = 0;
+        loop {
+            let len = reader.read(&mut buf).await.map_err(io_error)?;
+            if len == 0 {
+                break;
+            }
+            sync.adb
+                .write(sync.stream, &packet(b"DATA", &buf[..len]))
+                .await?;
+            pushed += len as u64;
+        }
+
+        // DONE carries the modification time where the length would be
+        let mut done = b"DONE".to_vec();
+        done.extend_from_slice(&mtime.to_le_bytes());
+        sync.adb.write(sync.stream, &done).await?;
+
+        match sync.read_header().await? {
+            (id, _) if &id == b"OKAY" => (),
+            (id, len) if &id == b"FAIL" => return Err(sync.fail(len).await),
+            _ => return Err(AdbError::Sync("Unexpected response to push".to_string())),
+        }
+        sync.close().await?;
+        Ok(pushed)
+    }
+
+    /// Pull `path` from the device into `writer`, returning the number of bytes pulled
+    pub async fn pull<W: AsyncWrite + Unpin>(
+        &mut self,
+        path: &str,
+        mut writer: W,
+    ) -> Result<u64, AdbError> {
+        let mut sync = Sync::open(self).await?;
+        sync.adb
+            .write(sync.stream, &packet(b"RECV", path.as_bytes()))
+            .await?;
+
+        let mut pulled = 0;
+        loop {
+            match sync.read_header().await? {
+                (id, len) if &id == b"DATA" => {
+                    let data = sync.read_exact(len as usize).await?;
+                    writer.write_all(&data).await.map_err(io_error)?;
+                    pulled += len as u64;
+                }
+                (id, _) if &id == b"DONE" => break,
+                (id, len) if &id == b"FAIL" => return Err(sync.fail(len).await),
+                _ => return Err(AdbError::Sync("Unexpected response to pull".to_string())),
+            }
+        }
+        writer.flush().await.map_err(io_error)?;
+        sync.close().await?;
+        Ok(pulled)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::adb::test::FakeAdbd;
+    use futures::executor::block_on;
+    use futures::io::Cursor;
+
+    #[test]
+    fn push_pull() {
+        let mut adb = FakeAdbd::connected_adb();
+        let data: Vec<u8> = (0..DATA_MAX * 2 + 10).map(|i| (i % 251) as u8).collect();
+
+        block_on(async {
+            let pushed = adb
+                .push(Cursor::new(&data), "/tmp/test", 0o644, 0)
+                .await
+                .unwrap();
+            assert_eq!(pushed, data.len() as u64);
+
+            let mut pulled = vec![];
+            adb.pull("/tmp/test", &mut pulled).await.unwrap();
+            assert_eq!(pulled, data);
+
+            match adb.pull("/tmp/missing", &mut vec![]).await {
+                Err(AdbError::Sync(message)) => assert_eq!(message, "No such file"),
+                other => panic!("unexpected result {other:?}"),
+            }
+        });
+    }
+}
@@ -0,0 +1,104 @@
+use crate::adb::{AdbError, AdbOps};
+use crate::js_error;
+use anyhow::anyhow;
+use js_sys::Uint8Array;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    UsbConfiguration, UsbDevice, UsbDirection, UsbEndpoint, UsbEndpointType, UsbInTransferResult,
+    UsbInterface, UsbOutTransferResult,
+};
+
+pub struct AdbWebUsb {
+    dev: UsbDevice,
+    input_ep: u8,
+    output_ep: u8,
+}
+
+fn transfer_error(err: wasm_bindgen::JsValue) -> AdbError {
+    let err: gloo::utils::errors::JsError = err.try_into().unwrap();
+    AdbError::Transfer(err.into())
+}
+
+pub fn find_adb_interface(device: &UsbDevice) -> Option<(UsbConfiguration, UsbInterface)> {
+    for config in device.configurations() {
+        let config = config.unchecked_into::<UsbConfiguration>();
+        for iface in config.interfaces() {
+            let iface = iface.unchecked_into::<UsbInterface>();
+            let alternate = iface.alternate();
+            if alternate.interface_class() == 0xFF
+                && alternate.interface_subclass() == 0x42
+                && alternate.interface_protocol() == 0x1
+            {
+                return Some((config, iface));
+            }
+        }
+    }
+
+    None
+}
+
+impl AdbWebUsb {
+    pub async fn new(dev: UsbDevice) -> anyhow::Result<Self> {
+        let (config, iface) = find_adb_interface(&dev).ok_or(anyhow!("No ADB interface found"))?;
+
+        JsFuture::from(dev.open()).await.map_err(js_error)?;
+        JsFuture::from(dev.select_configuration(config.configuration_value()))
+            .await
+            .map_err(js_error)?;
+        JsFuture::from(dev.claim_interface(iface.interface_number()))
+            .await
+            .map_err(js_error)?;
+
+        let mut in_ep = None;
+        let mut out_ep = None;
+        for ep in iface.alternate().endpoints() {
+            let ep = UsbEndpoint::unchecked_from_js(ep);
+            if let UsbEndpointType::Bulk = ep.type_() {
+                match ep.direction() {
+                    UsbDirection::In => in_ep = Some(ep.endpoint_number()),
+                    UsbDirection::Out => out_ep = Some(ep.endpoint_number()),
+                    _ => {}
+                }
+            }
+        }
+
+        let (Some(input_ep), Some(output_ep)) = (in_ep, out_ep) else {
+            return Err(anyhow!("ADB interface lacking endpoints"));
+        };
+        Ok(Self {
+            dev,
+            input_ep,
+            output_ep,
+        })
+    }
+}
+
+impl AdbOps for AdbWebUsb {
+    async fn write_out(&mut self, buf: &[u8]) -> Result<usize, AdbError> {
+        // WebUSB wants a mutable view, even though it only reads from it
+        let mut buf = buf.to_vec();
+        let res = JsFuture::from(
+            self.dev
+                .transfer_out_with_u8_slice(self.output_ep, &mut buf)
+                .map_err(transfer_error)?,
+        )
+        .await
+        .map_err(transfer_error)?;
+        let res: UsbOutTransferResult = res.unchecked_into();
+        Ok(res.bytes_written() as usize)
+    }
+
+    async fn read_in(&mut self, buf: &mut [u8]) -> Result<usize, AdbError> {
+        let res = JsFuture::from(self.dev.transfer_in(self.input_ep, buf.len() as _))
+            .await
+            .map_err(transfer_error)?;
+        let res = UsbInTransferResult::unchecked_from_js(res);
+        let Some(data) = res.data() else {
+            return Ok(0);
+        };
+        let len = data.byte_length().min(buf.len());
+        Uint8Array::new(&data.buffer()).copy_to(&mut buf[..len]);
+        Ok(len)
+    }
+}
@@ -0,0 +1,754 @@
+//! Parsing and generation of Android Verified Boot (AVB) vbmeta images.
+//!
+//! All structures are big-endian, see AOSP's `libavb/avb_vbmeta_image.h` and
+//! `libavb/avb_*_descriptor.h`.
+
+use crate::blob;
+use crate::connection::FastbootConnection;
+use crate::fastboot::webusb::FastbootWebUsb;
+use crate::fastboot::{Fastboot, SlotSelection};
+use dioxus::logger::tracing;
+use dioxus::prelude::*;
+use thiserror::Error;
+
+const VBMETA_MAGIC: &[u8; 4] = b"AVB0";
+const VBMETA_HEADER_SIZE: usize = 256;
+const FOOTER_MAGIC: &[u8; 4] = b"AVBf";
+const FOOTER_SIZE: usize = 64;
+/// Offset of the flags field within the vbmeta header
+const FLAGS_OFFSET: usize = 120;
+const RELEASE_STRING_LEN: usize = 48;
+/// libavb version generated images claim to require
+const REQUIRED_VERSION: (u32, u32) = (1, 0);
+
+/// Tells libavb to not set up dm-verity for hashtree descriptors
+pub const FLAGS_HASHTREE_DISABLED: u32 = 1 << 0;
+/// Tells libavb to skip verification of descriptors entirely
+pub const FLAGS_VERIFICATION_DISABLED: u32 = 1 << 1;
+
+const TAG_PROPERTY: u64 = 0;
+const TAG_HASHTREE: u64 = 1;
+const TAG_HASH: u64 = 2;
+const TAG_KERNEL_CMDLINE: u64 = 3;
+const TAG_CHAIN_PARTITION: u64 = 4;
+
+/// Errors parsing a vbmeta image
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AvbError {
+    #[error("Image is truncated")]
+    Truncated,
+    #[error("Not a vbmeta image")]
+    BadMagic,
+    #[error("Invalid vbmeta image: {0}")]
+    Invalid(&'static str),
+}
+
+/// Big-endian reader over a vbmeta buffer
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], AvbError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.checked_add(len).ok_or(AvbError::Truncated)?)
+            .ok_or(AvbError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Read a length field and the bytes it covers
+    fn sized(&mut self, len: u64) -> Result<&'a [u8], AvbError> {
+        self.bytes(usize::try_from(len).map_err(|_| AvbError::Truncated)?)
+    }
+
+    fn u32(&mut self) -> Result<u32, AvbError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, AvbError> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self, len: u64) -> Result<String, AvbError> {
+        Ok(String::from_utf8_lossy(self.sized(len)?).into_owned())
+    }
+
+    /// A fixed size, NUL padded string field
+    fn fixed_string(&mut self, len: usize) -> Result<String, AvbError> {
+        let raw = self.bytes(len)?;
+        let end = raw.iter().position(|&b| b == 0).unwrap_or(len);
+        Ok(String::from_utf8_lossy(&raw[..end]).into_owned())
+    }
+}
+
+/// Signing algorithm of a vbmeta image
+pub fn algorithm_name(algorithm: u32) -> &'static str {
+    match algorithm {
+        0 => "NONE",
+        1 => "SHA256_RSA2048",
+        2 => "SHA256_RSA4096",
+        3 => "SHA256_RSA8192",
+        4 => "SHA512_RSA2048",
+        5 => "SHA512_RSA4096",
+        6 => "SHA512_RSA8192",
+        _ => "unknown",
+    }
+}
+
+/// The fixed size header at the start of every vbmeta image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub required_version: (u32, u32),
+    pub authentication_block_size: u64,
+    pub auxiliary_block_size: u64,
+    pub algorithm: u32,
+    pub rollback_index: u64,
+    pub flags: u32,
+    pub rollback_index_location: u32,
+    pub release_string: String,
+    descriptors_offset: u64,
+    descriptors_size: u64,
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> Result<Self, AvbError> {
+        let mut cursor = Cursor::new(data);
+        if cursor.bytes(4)? != VBMETA_MAGIC {
+            return Err(AvbError::BadMagic);
+        }
+        let required_version = (cursor.u32()?, cursor.u32()?);
+        let authentication_block_size = cursor.u64()?;
+        let auxiliary_block_size = cursor.u64()?;
+        let algorithm = cursor.u32()?;
+        // Hash, signature, public key and public key metadata locations; only needed to verify
+        // the signature, which is left to the bootloader
+        cursor.bytes(8 * 8)?;
+        let descriptors_offset = cursor.u64()?;
+        let descriptors_size = cursor.u64()?;
+        let rollback_index = cursor.u64()?;
+        let flags = cursor.u32()?;
+        let rollback_index_location = cursor.u32()?;
+        let release_string = cursor.fixed_string(RELEASE_STRING_LEN)?;
+
+        Ok(Self {
+            required_version,
+            authentication_block_size,
+            auxiliary_block_size,
+            algorithm,
+            rollback_index,
+            flags,
+            rollback_index_location,
+            release_string,
+            descriptors_offset,
+            descriptors_size,
+        })
+    }
+
+    pub fn hashtree_disabled(&self) -> bool {
+        self.flags & FLAGS_HASHTREE_DISABLED != 0
+    }
+
+    pub fn verification_disabled(&self) -> bool {
+        self.flags & FLAGS_VERIFICATION_DISABLED != 0
+    }
+}
+
+/// dm-verity hashtree protecting a partition that's too large to hash at boot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashtreeDescriptor {
+    pub partition_name: String,
+    pub dm_verity_version: u32,
+    pub image_size: u64,
+    pub tree_offset: u64,
+    pub tree_size: u64,
+    pub data_block_size: u32,
+    pub hash_block_size: u32,
+    pub fec_num_roots: u32,
+    pub fec_offset: u64,
+    pub fec_size: u64,
+    pub hash_algorithm: String,
+    pub salt: Vec<u8>,
+    pub root_digest: Vec<u8>,
+    pub flags: u32,
+}
+
+/// Digest of a whole partition, checked by the bootloader before booting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashDescriptor {
+    pub partition_name: String,
+    pub image_size: u64,
+    pub hash_algorithm: String,
+    pub salt: Vec<u8>,
+    pub digest: Vec<u8>,
+    pub flags: u32,
+}
+
+/// Delegates verification of a partition to the vbmeta found in it, signed with the given key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainPartitionDescriptor {
+    pub partition_name: String,
+    pub rollback_index_location: u32,
+    pub public_key: Vec<u8>,
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Descriptor {
+    Property {
+        key: String,
+        value: String,
+    },
+    Hashtree(HashtreeDescriptor),
+    Hash(HashDescriptor),
+    KernelCmdline {
+        flags: u32,
+        cmdline: String,
+    },
+    ChainPartition(ChainPartitionDescriptor),
+    /// A descriptor type this parser doesn't know about
+    Unknown {
+        tag: u64,
+        data: Vec<u8>,
+    },
+}
+
+impl Descriptor {
+    fn parse(tag: u64, data: &[u8]) -> Result<Self, AvbError> {
+        let mut cursor = Cursor::new(data);
+        let descriptor = match tag {
+            TAG_PROPERTY => {
+                let key_len = cursor.u64()?;
+                let value_len = cursor.u64()?;
+                let key = cursor.string(key_len)?;
+                cursor.bytes(1)?;
+                let value = cursor.string(value_len)?;
+                Descriptor::Property { key, value }
+            }
+            TAG_HASHTREE => {
+                let dm_verity_version = cursor.u32()?;
+                let image_size = cursor.u64()?;
+                let tree_offset = cursor.u64()?;
+                let tree_size = cursor.u64()?;
+                let data_block_size = cursor.u32()?;
+                let hash_block_size = cursor.u32()?;
+                let fec_num_roots = cursor.u32()?;
+                let fec_offset = cursor.u64()?;
+                let fec_size = cursor.u64()?;
+                let hash_algorithm = cursor.fixed_string(32)?;
+                let name_len = cursor.u32()?;
+                let salt_len = cursor.u32()?;
+                let digest_len = cursor.u32()?;
+                let flags = cursor.u32()?;
+                cursor.bytes(60)?;
+                Descriptor::Hashtree(HashtreeDescriptor {
+                    partition_name: cursor.string(name_len.into())?,
+                    dm_verity_version,
+                    image_size,
+                    tree_offset,
+                    tree_size,
+                    data_block_size,
+                    hash_block_size,
+                    fec_num_roots,
+                    fec_offset,
+                    fec_size,
+                    hash_algorithm,
+                    salt: cursor.sized(salt_len.into())?.to_vec(),
+                    root_digest: cursor.sized(digest_len.into())?.to_vec(),
+                    flags,
+                })
+            }
+            TAG_HASH => {
+                let image_size = cursor.u64()?;
+                let hash_algorithm = cursor.fixed_string(32)?;
+                let name_len = cursor.u32()?;
+                let salt_len = cursor.u32()?;
+                let digest_len = cursor.u32()?;
+                let flags = cursor.u32()?;
+                cursor.bytes(60)?;
+                Descriptor::Hash(HashDescriptor {
+                    partition_name: cursor.string(name_len.into())?,
+                    image_size,
+                    hash_algorithm,
+                    salt: cursor.sized(salt_len.into())?.to_vec(),
+                    digest: cursor.sized(digest_len.into())?.to_vec(),
+                    flags,
+                })
+            }
+            TAG_KERNEL_CMDLINE => {
+                let flags = cursor.u32()?;
+                let len = cursor.u32()?;
+                let cmdline = cursor.string(len.into())?;
+                Descriptor::KernelCmdline { flags, cmdline }
+            }
+            TAG_CHAIN_PARTITION => {
+                let rollback_index_location = cursor.u32()?;
+                let name_len = cursor.u32()?;
+                let key_len = cursor.u32()?;
+                // Older images leave this as part of the reserved space, which is zeroed
+                let flags = cursor.u32()?;
+                cursor.bytes(60)?;
+                Descriptor::ChainPartition(ChainPartitionDescriptor {
+                    partition_name: cursor.string(name_len.into())?,
+                    rollback_index_location,
+                    public_key: cursor.sized(key_len.into())?.to_vec(),
+                    flags,
+                })
+            }
+            tag => Descriptor::Unknown {
+                tag,
+                data: data.to_vec(),
+            },
+        };
+        Ok(descriptor)
+    }
+}
+
+/// A parsed vbmeta image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VbMeta {
+    pub header: Header,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl VbMeta {
+    /// Parse a vbmeta image, either standalone (as flashed to the vbmeta partition) or appended
+    /// to a partition image with an AVB footer
+    pub fn parse(data: &[u8]) -> Result<Self, AvbError> {
+        let data = find_vbmeta(data)?;
+        let header = Header::parse(data)?;
+
+        let descriptors_end = header
+            .descriptors_offset
+            .checked_add(header.descriptors_size)
+            .filter(|&end| end <= header.auxiliary_block_size)
+            .ok_or(AvbError::Invalid("descriptors outside the auxiliary block"))?;
+        let descriptors_start = (VBMETA_HEADER_SIZE as u64)
+            .checked_add(header.authentication_block_size)
+            .and_then(|start| start.checked_add(header.descriptors_offset))
+            .ok_or(AvbError::Truncated)?;
+        let mut cursor = Cursor::new(data);
+        cursor.sized(descriptors_start)?;
+        let mut cursor = Cursor::new(cursor.sized(descriptors_end - header.descriptors_offset)?);
+
+        let mut descriptors = vec![];
+        while cursor.pos < cursor.data.len() {
+            let tag = cursor.u64()?;
+            let len = cursor.u64()?;
+            if len % 8 != 0 {
+                return Err(AvbError::Invalid("descriptor size not 8 byte aligned"));
+            }
+            descriptors.push(Descriptor::parse(tag, cursor.sized(len)?)?);
+        }
+
+        Ok(Self {
+            header,
+            descriptors,
+        })
+    }
+
+    /// Descriptors for the partitions this image covers, as `(partition, kind)`
+    pub fn partitions(&self) -> impl Iterator<Item = (&str, &'static str)> {
+        self.descriptors.iter().filter_map(|d| match d {
+            Descriptor::Hashtree(d) => Some((d.partition_name.as_str(), "hashtree")),
+            Descriptor::Hash(d) => Some((d.partition_name.as_str(), "hash")),
+            Descriptor::ChainPartition(d) => Some((d.partition_name.as_str(), "chained")),
+            _ => None,
+        })
+    }
+}
+
+/// The vbmeta image within `data`, which may be followed by an AVB footer
+pub fn find_vbmeta(data: &[u8]) -> Result<&[u8], AvbError> {
+    if data.starts_with(VBMETA_MAGIC) {
+        return Ok(data);
+    }
+    let footer = data
+        .len()
+        .checked_sub(FOOTER_SIZE)
+        .map(|start| &data[start..])
+        .ok_or(AvbError::BadMagic)?;
+    let mut cursor = Cursor::new(footer);
+    if cursor.bytes(4)? != FOOTER_MAGIC {
+        return Err(AvbError::BadMagic);
+    }
+    // Footer version and the original image size
+    cursor.bytes(16)?;
+    let offset = usize::try_from(cursor.u64()?).map_err(|_| AvbError::Truncated)?;
+    let size = usize::try_from(cursor.u64()?).map_err(|_| AvbError::Truncated)?;
+    let end = offset.checked_add(size).ok_or(AvbError::Truncated)?;
+    data.get(offset..end).ok_or(AvbError::Truncated)
+}
+
+/// Copy of a standalone vbmeta image with the given flags set, like `fastboot
+/// --disable-verity --disable-verification flash vbmeta` does
+///
+/// The flags are outside of the signed data, so the image keeps the original descriptors and
+/// signature.
+pub fn set_flags(image: &[u8], flags: u32) -> Result<Vec<u8>, AvbError> {
+    let header = Header::parse(image)?;
+    let mut image = image.to_vec();
+    image[FLAGS_OFFSET..FLAGS_OFFSET + 4].copy_from_slice(&(header.flags | flags).to_be_bytes());
+    Ok(image)
+}
+
+/// An unsigned vbmeta image without any descriptors, the equivalent of
+/// `avbtool make_vbmeta_image --flags <flags>`
+pub fn empty_vbmeta(flags: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(VBMETA_HEADER_SIZE);
+    out.extend_from_slice(VBMETA_MAGIC);
+    out.extend_from_slice(&REQUIRED_VERSION.0.to_be_bytes());
+    out.extend_from_slice(&REQUIRED_VERSION.1.to_be_bytes());
+    // Block sizes, algorithm and the offsets/sizes of everything inside them are all zero
+    out.resize(FLAGS_OFFSET, 0);
+    out.extend_from_slice(&flags.to_be_bytes());
+    out.extend_from_slice(&0u32.to_be_bytes());
+    let mut release = [0; RELEASE_STRING_LEN];
+    let name = concat!("bootbud ", env!("CARGO_PKG_VERSION"));
+    release[..name.len()].copy_from_slice(name.as_bytes());
+    out.extend_from_slice(&release);
+    out.resize(VBMETA_HEADER_SIZE, 0);
+    out
+}
+
+/// Verified boot related state the bootloader reports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AvbState {
+    pub unlocked: Option<bool>,
+    pub secure: Option<bool>,
+    pub vbmeta_slotted: bool,
+}
+
+async fn load_state(fastboot: &mut Fastboot<FastbootWebUsb>) -> anyhow::Result<AvbState> {
+    Ok(AvbState {
+        unlocked: fastboot.is_unlocked().await.ok(),
+        secure: fastboot.is_secure().await.ok(),
+        vbmeta_slotted: fastboot.has_slot("vbmeta").await?,
+    })
+}
+
+/// The vbmeta image to flash: the selected one with verification disabled, or a generated empty
+/// one when no image is selected
+async fn disabled_vbmeta() -> anyhow::Result<Vec<u8>> {
+    let flags = FLAGS_HASHTREE_DISABLED | FLAGS_VERIFICATION_DISABLED;
+    match blob::selected_file("vbmeta-image") {
+        Some(file) => {
+            let data = blob::read_prefix(&file, file.size() as u64).await?;
+            Ok(set_flags(&data, flags)?)
+        }
+        None => Ok(empty_vbmeta(flags)),
+    }
+}
+
+async fn disable_verity(
+    fastboot: &mut Fastboot<FastbootWebUsb>,
+    selection: SlotSelection,
+    mut status: Signal<Vec<String>>,
+) -> anyhow::Result<()> {
+    let image = disabled_vbmeta().await?;
+    for target in fastboot.slot_targets("vbmeta", selection).await? {
+        status.write().push(format!("Flashing {target}"));
+        fastboot
+            .flash_stream(&target, image.len() as u64, image.as_slice())
+            .await?;
+    }
+    Ok(())
+}
+
+async fn inspect_vbmeta() -> anyhow::Result<Option<VbMeta>> {
+    let Some(file) = blob::selected_file("vbmeta-image") else {
+        return Ok(None);
+    };
+    let data = blob::read_prefix(&file, file.size() as u64).await?;
+    Ok(Some(VbMeta::parse(&data)?))
+}
+
+fn flag(value: Option<bool>) -> &'static str {
+    match value {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "unknown",
+    }
+}
+
+/// Shows the verified boot state of a device and guides through flashing a vbmeta with
+/// verification disabled
+#[component]
+pub fn Verity(conn: FastbootConnection) -> Element {
+    let mut selection = use_signal(SlotSelection::default);
+    let mut status = use_signal(Vec::<String>::new);
+    let mut running = use_signal(|| false);
+    let mut vbmeta = use_signal(|| None::<Result<VbMeta, String>>);
+    let state = use_resource({
+        to_owned![conn];
+        move || {
+            to_owned![conn];
+            async move {
+                conn.run(async |fastboot| load_state(fastboot).await)
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+        }
+    });
+
+    let inspect = move |_| async move {
+        vbmeta.set(
+            inspect_vbmeta()
+                .await
+                .map_err(|err| err.to_string())
+                .transpose(),
+        );
+    };
+
+    let start = move |_| {
+        to_owned![conn];
+        async move {
+            status.write().clear();
+            running.set(true);
+            let result = conn
+                .run(async |fastboot| disable_verity(fastboot, selection(), status).await)
+                .await;
+            match result {
+                Ok(()) => status.write().push("Verification disabled".to_string()),
+                Err(err) => {
+                    tracing::error!("Disabling verity failed: {}", err);
+                    status.write().push(format!("Failed: {err}"));
+                }
+            }
+            running.set(false);
+        }
+    };
+
+    rsx! {
+        h3 { "Verified boot" }
+        match &*state.read_unchecked() {
+            None => rsx! { p { "Reading AVB state..." } },
+            Some(Err(err)) => rsx! { p { "Failed to read AVB state: {err}" } },
+            Some(Ok(state)) => rsx! {
+                p { "Unlocked: " {flag(state.unlocked)} ", secure: " {flag(state.secure)} }
+                if state.unlocked == Some(false) {
+                    p { "The bootloader is locked and will refuse to flash vbmeta." }
+                }
+            },
+        }
+        p {
+            "Flashes vbmeta with the hashtree and verification disabled flags set. "
+            "Without a selected image an empty vbmeta is generated."
+        }
+        input {
+            id: "vbmeta-image",
+            r#type: "file",
+            onchange: inspect,
+        }
+        select {
+            onchange: move |evt| {
+                if let Ok(value) = evt.value().parse() {
+                    selection.set(value);
+                }
+            },
+            for value in ["current", "other", "all"] {
+                option {
+                    value: value,
+                    selected: selection().to_string() == value,
+                    "--slot={value}"
+                }
+            }
+        }
+        button {
+            disabled: running(),
+            onclick: start,
+            "Disable verity"
+        }
+        match &*vbmeta.read() {
+            None => rsx! {},
+            Some(Err(err)) => rsx! { p { "Failed to parse vbmeta: {err}" } },
+            Some(Ok(vbmeta)) => rsx! {
+                p {
+                    "Algorithm: " {algorithm_name(vbmeta.header.algorithm)}
+                    ", rollback index: {vbmeta.header.rollback_index}"
+                }
+                p {
+                    "Hashtree disabled: " {flag(Some(vbmeta.header.hashtree_disabled()))}
+                    ", verification disabled: "
+                    {flag(Some(vbmeta.header.verification_disabled()))}
+                }
+                table {
+                    for (partition, kind) in vbmeta.partitions() {
+                        tr {
+                            td { "{partition}" }
+                            td { "{kind}" }
+                        }
+                    }
+                }
+            },
+        }
+        ul {
+            for line in status.read().iter() {
+                li { "{line}" }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn descriptor(tag: u64, body: &[u8]) -> Vec<u8> {
+        let mut out = tag.to_be_bytes().to_vec();
+        let padded = body.len().div_ceil(8) * 8;
+        out.extend_from_slice(&(padded as u64).to_be_bytes());
+        out.extend_from_slice(body);
+        out.resize(16 + padded, 0);
+        out
+    }
+
+    fn hash_descriptor() -> Vec<u8> {
+        let mut body = 0x1000u64.to_be_bytes().to_vec();
+        let mut algorithm = [0; 32];
+        algorithm[..6].copy_from_slice(b"sha256");
+        body.extend_from_slice(&algorithm);
+        body.extend_from_slice(&4u32.to_be_bytes());
+        body.extend_from_slice(&2u32.to_be_bytes());
+        body.extend_from_slice(&3u32.to_be_bytes());
+        body.extend_from_slice(&0u32.to_be_bytes());
+        body.extend_from_slice(&[0; 60]);
+        body.extend_from_slice(b"boot");
+        body.extend_from_slice(&[0xaa, 0xbb]);
+        body.extend_from_slice(&[1, 2, 3]);
+        descriptor(TAG_HASH, &body)
+    }
+
+    fn chain_descriptor() -> Vec<u8> {
+        let mut body = 2u32.to_be_bytes().to_vec();
+        body.extend_from_slice(&6u32.to_be_bytes());
+        body.extend_from_slice(&2u32.to_be_bytes());
+        body.extend_from_slice(&[0; 64]);
+        body.extend_from_slice(b"system");
+        body.extend_from_slice(&[7, 8]);
+        descriptor(TAG_CHAIN_PARTITION, &body)
+    }
+
+    fn property_descriptor() -> Vec<u8> {
+        let mut body = 3u64.to_be_bytes().to_vec();
+        body.extend_from_slice(&5u64.to_be_bytes());
+        body.extend_from_slice(b"key\0value\0");
+        descriptor(TAG_PROPERTY, &body)
+    }
+
+    /// A vbmeta image with a fake authentication block followed by the given descriptors
+    fn vbmeta(descriptors: &[u8]) -> Vec<u8> {
+        let mut image = empty_vbmeta(0);
+        let auth_size = 64u64;
+        let aux_size = descriptors.len() as u64;
+        image[12..20].copy_from_slice(&auth_size.to_be_bytes());
+        image[20..28].copy_from_slice(&aux_size.to_be_bytes());
+        image[28..32].copy_from_slice(&2u32.to_be_bytes());
+        // descriptors offset is 0 within the auxiliary block
+        image[104..112].copy_from_slice(&aux_size.to_be_bytes());
+        image[112..120].copy_from_slice(&7u64.to_be_bytes());
+        image.extend_from_slice(&[0x55; 64]);
+        image.extend_from_slice(descriptors);
+        image
+    }
+
+    #[test]
+    fn empty_image() {
+        let image = empty_vbmeta(FLAGS_HASHTREE_DISABLED | FLAGS_VERIFICATION_DISABLED);
+        assert_eq!(image.len(), VBMETA_HEADER_SIZE);
+        let parsed = VbMeta::parse(&image).unwrap();
+        assert_eq!(parsed.header.flags, 3);
+        assert_eq!(parsed.header.required_version, (1, 0));
+        assert_eq!(parsed.header.algorithm, 0);
+        assert!(parsed.header.release_string.starts_with("bootbud "));
+        assert!(parsed.header.hashtree_disabled());
+        assert!(parsed.header.verification_disabled());
+        assert!(parsed.descriptors.is_empty());
+    }
+
+    #[test]
+    fn parse_descriptors() {
+        let mut descriptors = hash_descriptor();
+        descriptors.extend(chain_descriptor());
+        descriptors.extend(property_descriptor());
+        let parsed = VbMeta::parse(&vbmeta(&descriptors)).unwrap();
+
+        assert_eq!(algorithm_name(parsed.header.algorithm), "SHA256_RSA4096");
+        assert_eq!(parsed.header.rollback_index, 7);
+        assert_eq!(
+            parsed.descriptors,
+            vec![
+                Descriptor::Hash(HashDescriptor {
+                    partition_name: "boot".to_string(),
+                    image_size: 0x1000,
+                    hash_algorithm: "sha256".to_string(),
+                    salt: vec![0xaa, 0xbb],
+                    digest: vec![1, 2, 3],
+                    flags: 0,
+                }),
+                Descriptor::ChainPartition(ChainPartitionDescriptor {
+                    partition_name: "system".to_string(),
+                    rollback_index_location: 2,
+                    public_key: vec![7, 8],
+                    flags: 0,
+                }),
+                Descriptor::Property {
+                    key: "key".to_string(),
+                    value: "value".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            parsed.partitions().collect::<Vec<_>>(),
+            vec![("boot", "hash"), ("system", "chained")]
+        );
+    }
+
+    #[test]
+    fn parse_footer() {
+        let vbmeta = vbmeta(&hash_descriptor());
+        let mut image = vec![0; 4096];
+        image.extend_from_slice(&vbmeta);
+        image.resize(8192 - FOOTER_SIZE, 0);
+        image.extend_from_slice(FOOTER_MAGIC);
+        image.extend_from_slice(&[0; 16]);
+        image.extend_from_slice(&4096u64.to_be_bytes());
+        image.extend_from_slice(&(vbmeta.len() as u64).to_be_bytes());
+        image.resize(8192, 0);
+
+        assert_eq!(find_vbmeta(&image).unwrap(), vbmeta.as_slice());
+        assert_eq!(VbMeta::parse(&image).unwrap().descriptors.len(), 1);
+        assert_eq!(find_vbmeta(&[0; 128]), Err(AvbError::BadMagic));
+    }
+
+    #[test]
+    fn truncated() {
+        let image = vbmeta(&hash_descriptor());
+        assert_eq!(
+            VbMeta::parse(&image[..image.len() - 8]),
+            Err(AvbError::Truncated)
+        );
+        assert_eq!(VbMeta::parse(&image[..100]), Err(AvbError::Truncated));
+    }
+
+    #[test]
+    fn disable_flags() {
+        let image = vbmeta(&hash_descriptor());
+        let patched = set_flags(&image, FLAGS_VERIFICATION_DISABLED).unwrap();
+        assert_eq!(patched.len(), image.len());
+        let parsed = VbMeta::parse(&patched).unwrap();
+        assert_eq!(parsed.header.flags, FLAGS_VERIFICATION_DISABLED);
+        assert_eq!(
+            parsed.descriptors,
+            VbMeta::parse(&image).unwrap().descriptors
+        );
+        assert_eq!(set_flags(b"AVB0", 3), Err(AvbError::Truncated));
+        assert_eq!(set_flags(&[0; 256], 3), Err(AvbError::BadMagic));
+    }
+}
@@ -0,0 +1,285 @@
+use crate::blob::{self, BlobWriter};
+use crate::connection::FastbootConnection;
+use crate::fastboot::webusb::FastbootWebUsb;
+use crate::fastboot::{FastBootError, FastBootOps, Fastboot};
+use dioxus::logger::tracing;
+use dioxus::prelude::*;
+use futures::AsyncWrite;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Partitions holding device specific data (calibration, IMEI, keys...) that can't be restored
+/// from a factory image
+pub const DEFAULT_PARTITIONS: &[&str] = &[
+    "modem", "modemst1", "modemst2", "fsg", "fsc", "persist", "efs", "sec",
+];
+
+/// Record of a partition that was backed up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupEntry {
+    pub partition: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl BackupEntry {
+    /// Name of the file the partition contents are saved as
+    pub fn filename(&self) -> String {
+        format!("{}.img", self.partition)
+    }
+}
+
+/// Passes writes through to an inner writer while computing their SHA-256 digest
+struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W> HashWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn finalize(self) -> String {
+        hex::encode(self.hasher.finalize())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            this.hasher.update(&buf[..written]);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Streams the full contents of a partition into `writer`, in chunks no larger than the device
+/// is willing to send at once
+pub async fn backup_partition<Ops: FastBootOps, W: AsyncWrite + Unpin>(
+    fastboot: &mut Fastboot<Ops>,
+    partition: &str,
+    writer: W,
+) -> Result<BackupEntry, FastBootError> {
+    let size = fastboot.partition_size(partition).await?;
+    let chunk = fastboot.max_fetch_size().await?;
+    tracing::debug!("Backing up {size} bytes of {partition} in {chunk} byte chunks");
+
+    let mut writer = HashWriter::new(writer);
+    let mut offset = 0;
+    while offset < size {
+        let len = fastboot
+            .fetch(partition, offset, chunk.min(size - offset))
+            .await?;
+        if len == 0 {
+            return Err(FastBootError::FastbootUnexpectedReply);
+        }
+        fastboot.do_upload(&mut writer, len).await?;
+        offset += len;
+    }
+
+    Ok(BackupEntry {
+        partition: partition.to_string(),
+        size,
+        sha256: writer.finalize(),
+    })
+}
+
+/// Renders backed up entries in `sha256sum` format, so a backup can be verified with
+/// `sha256sum -c`
+pub fn manifest(entries: &[BackupEntry]) -> String {
+    entries
+        .iter()
+        .map(|entry| format!("{}  {}\n", entry.sha256, entry.filename()))
+        .collect()
+}
+
+async fn run_backup(
+    fastboot: &mut Fastboot<FastbootWebUsb>,
+    serial: &str,
+    partitions: Vec<String>,
+    mut status: Signal<Vec<String>>,
+) -> anyhow::Result<()> {
+    let mut entries = vec![];
+    for partition in partitions {
+        status.write().push(format!("Backing up {partition}..."));
+        let mut writer = BlobWriter::new();
+        match backup_partition(fastboot, &partition, &mut writer).await {
+            Ok(entry) => {
+                blob::save(
+                    &writer.into_blob()?,
+                    &format!("{serial}-{}", entry.filename()),
+                )?;
+                status.write().push(format!(
+                    "{partition}: {} bytes, sha256 {}",
+                    entry.size, entry.sha256
+                ));
+                entries.push(entry);
+            }
+            // Not every device has every partition; skip the ones the device refuses.
+            Err(FastBootError::FastbootFailed(reason)) => {
+                status
+                    .write()
+                    .push(format!("Skipping {partition}: {reason}"));
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    blob::save_bytes(
+        manifest(&entries).as_bytes(),
+        &format!("{serial}-SHA256SUMS"),
+    )?;
+    status
+        .write()
+        .push(format!("Backed up {} partitions", entries.len()));
+    Ok(())
+}
+
+/// Saves data the device has staged for the legacy `upload` command, e.g. after an OEM dump
+/// command
+async fn run_upload(
+    fastboot: &mut Fastboot<FastbootWebUsb>,
+    serial: &str,
+    mut status: Signal<Vec<String>>,
+) -> anyhow::Result<()> {
+    let size = fastboot.upload().await?;
+    status
+        .write()
+        .push(format!("Receiving {size} staged bytes..."));
+
+    let mut blob_writer = BlobWriter::new();
+    let mut writer = HashWriter::new(&mut blob_writer);
+    fastboot.do_upload(&mut writer, size).await?;
+    let sha256 = writer.finalize();
+    blob::save(&blob_writer.into_blob()?, &format!("{serial}-upload.bin"))?;
+    status
+        .write()
+        .push(format!("Upload: {size} bytes, sha256 {sha256}"));
+    Ok(())
+}
+
+#[component]
+pub fn Backup(conn: FastbootConnection) -> Element {
+    let mut partitions = use_signal(|| DEFAULT_PARTITIONS.join(","));
+    let mut status = use_signal(Vec::<String>::new);
+    let mut running = use_signal(|| false);
+
+    let backup_conn = conn.clone();
+    let start_backup = move |_| {
+        let conn = backup_conn.clone();
+        async move {
+            let partitions = partitions
+                .read()
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(str::to_string)
+                .collect();
+            status.write().clear();
+            running.set(true);
+            if let Err(err) = conn
+                .run(async |fastboot| run_backup(fastboot, conn.serial(), partitions, status).await)
+                .await
+            {
+                tracing::error!("Backup failed: {}", err);
+                status.write().push(format!("Backup failed: {err}"));
+            }
+            running.set(false);
+        }
+    };
+
+    let save_upload = move |_| {
+        let conn = conn.clone();
+        async move {
+            status.write().clear();
+            running.set(true);
+            if let Err(err) = conn
+                .run(async |fastboot| run_upload(fastboot, conn.serial(), status).await)
+                .await
+            {
+                tracing::error!("Upload failed: {}", err);
+                status.write().push(format!("Upload failed: {err}"));
+            }
+            running.set(false);
+        }
+    };
+
+    rsx! {
+        h3 { "Backup" }
+        input {
+            value: "{partitions}",
+            oninput: move |evt| partitions.set(evt.value()),
+        }
+        button {
+            disabled: running(),
+            onclick: start_backup,
+            "Back up"
+        }
+        button {
+            disabled: running(),
+            onclick: save_upload,
+            "Save upload"
+        }
+        ul {
+            for line in status.read().iter() {
+                li { "{line}" }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::AsyncWriteExt;
+
+    #[test]
+    fn hash_writer_digest() {
+        let mut out = vec![];
+        let mut writer = HashWriter::new(&mut out);
+        block_on(writer.write_all(b"abc")).unwrap();
+        assert_eq!(
+            writer.finalize(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(out, b"abc");
+    }
+
+    #[test]
+    fn manifest_format() {
+        let entries = [
+            BackupEntry {
+                partition: "modem".to_string(),
+                size: 3,
+                sha256: "aa".to_string(),
+            },
+            BackupEntry {
+                partition: "persist".to_string(),
+                size: 4,
+                sha256: "bb".to_string(),
+            },
+        ];
+        assert_eq!(manifest(&entries), "aa  modem.img\nbb  persist.img\n");
+    }
+}
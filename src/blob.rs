@@ -0,0 +1,192 @@
+use crate::js_error;
+use futures::{ready, AsyncRead, AsyncSeek, AsyncWrite};
+use gloo::timers::callback::Timeout;
+use js_sys::{Array, Uint8Array};
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Blob, File, HtmlAnchorElement, HtmlInputElement, Response, Url};
+
+/// How long a saved file's object URL outlives the click that downloads it. Some browsers only
+/// start the download after the click returns, and cancel it if the URL is gone by then.
+const REVOKE_DELAY_MS: u32 = 60_000;
+
+/// Collects written data as JS buffers, so large payloads (e.g. partition backups) can be handed
+/// to the browser as a [Blob] without being held in WASM memory.
+#[derive(Default)]
+pub struct BlobWriter {
+    parts: Array,
+}
+
+impl BlobWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_blob(self) -> anyhow::Result<Blob> {
+        Ok(Blob::new_with_u8_array_sequence(&self.parts).map_err(js_error)?)
+    }
+}
+
+impl AsyncWrite for BlobWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.parts.push(&Uint8Array::from(buf));
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Random access reader over a [Blob] (e.g. a user selected [web_sys::File]) that only pulls in
+/// the slices being read, rather than loading the whole thing into memory.
+///
+/// Every read is a round trip through JS, so this should be wrapped in a reasonably large
+/// [futures::io::BufReader].
+pub struct BlobReader {
+    blob: Blob,
+    pos: u64,
+    pending: Option<JsFuture>,
+}
+
+impl BlobReader {
+    pub fn new(blob: Blob) -> Self {
+        Self {
+            blob,
+            pos: 0,
+            pending: None,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.blob.size() as u64
+    }
+}
+
+impl AsyncRead for BlobReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.pending.is_none() {
+            let end = self.size().min(self.pos + buf.len() as u64);
+            if self.pos >= end {
+                return Poll::Ready(Ok(0));
+            }
+            let slice = self
+                .blob
+                .slice_with_f64_and_f64(self.pos as f64, end as f64)
+                .map_err(|err| io::Error::other(js_error(err)))?;
+            self.pending = Some(JsFuture::from(slice.array_buffer()));
+        }
+
+        let res = ready!(Pin::new(self.pending.as_mut().unwrap()).poll(cx));
+        self.pending = None;
+        let data = Uint8Array::new(&res.map_err(|err| io::Error::other(js_error(err)))?);
+
+        // The caller may have offered a smaller buffer than the one the read was started with.
+        let len = buf.len().min(data.length() as usize);
+        data.subarray(0, len as u32).copy_to(&mut buf[..len]);
+        self.pos += len as u64;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncSeek for BlobReader {
+    fn poll_seek(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.size().checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        let Some(pos) = pos else {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            )));
+        };
+
+        self.pending = None;
+        self.pos = pos;
+        Poll::Ready(Ok(pos))
+    }
+}
+
+/// Offers the given blob to the user as a file download
+pub fn save(blob: &Blob, filename: &str) -> anyhow::Result<()> {
+    let document = web_sys::window().unwrap().document().unwrap();
+    let url = Url::create_object_url_with_blob(blob).map_err(js_error)?;
+
+    let anchor: HtmlAnchorElement = document
+        .create_element("a")
+        .map_err(js_error)?
+        .unchecked_into();
+    anchor.set_href(&url);
+    anchor.set_download(filename);
+    anchor.click();
+
+    Timeout::new(REVOKE_DELAY_MS, move || {
+        let _ = Url::revoke_object_url(&url);
+    })
+    .forget();
+    Ok(())
+}
+
+/// Offers the given bytes to the user as a file download
+pub fn save_bytes(data: &[u8], filename: &str) -> anyhow::Result<()> {
+    let parts = Array::of1(&Uint8Array::from(data));
+    let blob = Blob::new_with_u8_array_sequence(&parts).map_err(js_error)?;
+    save(&blob, filename)
+}
+
+/// Fetches the given URL into memory
+pub async fn fetch(url: String) -> anyhow::Result<Vec<u8>> {
+    let window = web_sys::window().unwrap();
+    let resp: Response = JsFuture::from(window.fetch_with_str(&url))
+        .await
+        .map_err(js_error)?
+        .unchecked_into();
+    if !resp.ok() {
+        anyhow::bail!("fetching {url} failed: {}", resp.status());
+    }
+    let buffer = JsFuture::from(resp.array_buffer().map_err(js_error)?)
+        .await
+        .map_err(js_error)?;
+    Ok(Uint8Array::new(&buffer).to_vec())
+}
+
+/// Read up to `len` bytes from the start of a blob into memory
+pub async fn read_prefix(blob: &Blob, len: u64) -> anyhow::Result<Vec<u8>> {
+    let end = (blob.size() as u64).min(len);
+    let slice = blob
+        .slice_with_f64_and_f64(0.0, end as f64)
+        .map_err(js_error)?;
+    let buffer = JsFuture::from(slice.array_buffer())
+        .await
+        .map_err(js_error)?;
+    Ok(Uint8Array::new(&buffer).to_vec())
+}
+
+/// File currently selected in the file input with the given element id
+pub fn selected_file(input_id: &str) -> Option<File> {
+    let document = web_sys::window()?.document()?;
+    let input: HtmlInputElement = document.get_element_by_id(input_id)?.unchecked_into();
+    input.files()?.get(0)
+}
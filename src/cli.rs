@@ -0,0 +1,75 @@
+//! Command line mode of native builds, for what's better done without a browser:
+//!
+//! `bootbud backup [--serial SERIAL] [--out DIR] [PARTITION...]` backs up partitions (by default
+//! [DEFAULT_PARTITIONS]) to `DIR` (by default one named after the device), along with a
+//! `SHA256SUMS` manifest for `sha256sum -c`.
+
+use crate::backup::{backup_partition, manifest, DEFAULT_PARTITIONS};
+use crate::fastboot::usb::FastbootUsb;
+use crate::fastboot::{FastBootError, Fastboot};
+use anyhow::{anyhow, bail, Context};
+use futures::io::AllowStdIo;
+use std::fs::File;
+use std::path::PathBuf;
+
+async fn backup(args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let mut args = args;
+    let mut serial = None;
+    let mut out = None;
+    let mut partitions = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--serial" => serial = Some(args.next().ok_or(anyhow!("Missing serial"))?),
+            "--out" => out = Some(PathBuf::from(args.next().ok_or(anyhow!("Missing path"))?)),
+            _ => partitions.push(arg),
+        }
+    }
+    if partitions.is_empty() {
+        partitions = DEFAULT_PARTITIONS.iter().map(|p| p.to_string()).collect();
+    }
+
+    let ops = FastbootUsb::open(serial.as_deref())?;
+    let out = out.unwrap_or_else(|| match ops.serial() {
+        "" => PathBuf::from("backup"),
+        serial => PathBuf::from(serial),
+    });
+    std::fs::create_dir_all(&out).with_context(|| format!("Failed to create {}", out.display()))?;
+    let mut fastboot = Fastboot::new(ops);
+
+    let mut entries = vec![];
+    for partition in partitions {
+        println!("Backing up {partition}...");
+        let path = out.join(format!("{partition}.img"));
+        let file =
+            File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+        match backup_partition(&mut fastboot, &partition, AllowStdIo::new(file)).await {
+            Ok(entry) => {
+                println!("{partition}: {} bytes, sha256 {}", entry.size, entry.sha256);
+                entries.push(entry);
+            }
+            // Not every device has every partition; skip the ones the device refuses.
+            Err(FastBootError::FastbootFailed(reason)) => {
+                println!("Skipping {partition}: {reason}");
+                std::fs::remove_file(&path)?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    std::fs::write(out.join("SHA256SUMS"), manifest(&entries))?;
+    println!(
+        "Backed up {} partitions to {}",
+        entries.len(),
+        out.display()
+    );
+    Ok(())
+}
+
+/// Runs the command given on the command line
+pub async fn run(mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    match args.next().as_deref() {
+        Some("backup") => backup(args).await,
+        Some(command) => bail!("Unknown command {command}"),
+        None => bail!("Missing command"),
+    }
+}
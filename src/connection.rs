@@ -0,0 +1,79 @@
+//! The fastboot connection to a device, shared by all the tool panes so their exchanges with the
+//! device never interleave on the one pair of bulk endpoints.
+
+use crate::fastboot::webusb::FastbootWebUsb;
+use crate::fastboot::{FastBootError, Fastboot};
+use crate::open_fastboot;
+use futures::lock::{MappedMutexGuard, Mutex, MutexGuard};
+use std::rc::Rc;
+
+type ToolsFastboot = Fastboot<FastbootWebUsb>;
+
+/// Made on first use; whoever holds the lock has the device to themselves
+#[derive(Clone)]
+pub struct FastbootConnection {
+    serial: String,
+    fastboot: Rc<Mutex<Option<ToolsFastboot>>>,
+}
+
+impl PartialEq for FastbootConnection {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.fastboot, &other.fastboot)
+    }
+}
+
+impl FastbootConnection {
+    pub fn new(serial: String) -> Self {
+        Self {
+            serial,
+            fastboot: Rc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn serial(&self) -> &str {
+        &self.serial
+    }
+
+    /// Waits for any other pane to be done with the device, connecting if needed
+    async fn lock(
+        &self,
+    ) -> anyhow::Result<MappedMutexGuard<'_, Option<ToolsFastboot>, ToolsFastboot>> {
+        let mut fastboot = self.fastboot.lock().await;
+        if fastboot.is_none() {
+            *fastboot = Some(open_fastboot(&self.serial).await?);
+        }
+        Ok(MutexGuard::map(fastboot, |fastboot| {
+            fastboot.as_mut().unwrap()
+        }))
+    }
+
+    /// Runs `action` on the connection. Anything but the device refusing a command drops the
+    /// connection, so the next action starts afresh.
+    pub async fn run<T>(
+        &self,
+        action: impl AsyncFnOnce(&mut ToolsFastboot) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let result = match self.lock().await {
+            Ok(mut fastboot) => action(&mut fastboot).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = &result {
+            if !matches!(
+                err.downcast_ref::<FastBootError>(),
+                Some(FastBootError::FastbootFailed(_))
+            ) {
+                *self.fastboot.lock().await = None;
+            }
+        }
+        result
+    }
+
+    /// Waits for any other pane to be done with the device, then closes the connection so the
+    /// caller can take the device through reboots on its own. The connection is made again by
+    /// the next user once the returned guard is dropped.
+    pub async fn exclusive(&self) -> MutexGuard<'_, Option<ToolsFastboot>> {
+        let mut fastboot = self.fastboot.lock().await;
+        *fastboot = None;
+        fastboot
+    }
+}
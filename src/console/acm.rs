@@ -0,0 +1,183 @@
+//! Reading a CDC-ACM serial port (e.g. the console of a USB gadget) over WebUSB.
+//!
+//! On Linux hosts the kernel's cdc_acm driver claims these ports first, and has to be unbound
+//! before the browser can claim the interface.
+
+use crate::js_error;
+use anyhow::anyhow;
+use js_sys::Uint8Array;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    UsbConfiguration, UsbControlTransferParameters, UsbDevice, UsbDirection, UsbEndpoint,
+    UsbEndpointType, UsbInTransferResult, UsbInterface, UsbRecipient, UsbRequestType,
+};
+
+pub const CLASS_COMM: u8 = 0x02;
+pub const SUBCLASS_ACM: u8 = 0x02;
+const CLASS_DATA: u8 = 0x0a;
+
+const SET_LINE_CODING: u8 = 0x20;
+const SET_CONTROL_LINE_STATE: u8 = 0x22;
+/// DTR and RTS; some gadgets don't send anything until a terminal is "connected"
+const CONTROL_LINES: u16 = 0x03;
+
+/// Largest bulk IN transfer issued for console data
+const READ_SIZE: u32 = 16 * 1024;
+
+struct AcmInterfaces {
+    config: UsbConfiguration,
+    control: UsbInterface,
+    data: UsbInterface,
+}
+
+/// Pairs the first data interface with the ACM control interface of its function, given the
+/// class and subclass of each interface in order
+fn find_acm_pair<T>(ifaces: impl IntoIterator<Item = (u8, u8, T)>) -> Option<(T, T)> {
+    let mut control = None;
+    for (class, subclass, iface) in ifaces {
+        match (class, subclass) {
+            (CLASS_COMM, SUBCLASS_ACM) => control = Some(iface),
+            // Another communications function (e.g. ECM), so a data interface after it isn't ours
+            (CLASS_COMM, _) => control = None,
+            (CLASS_DATA, _) => {
+                if let Some(control) = control.take() {
+                    return Some((control, iface));
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+fn find_acm_interfaces(device: &UsbDevice) -> Option<AcmInterfaces> {
+    for config in device.configurations() {
+        let config = config.unchecked_into::<UsbConfiguration>();
+        let ifaces = config.interfaces().into_iter().map(|iface| {
+            let iface = iface.unchecked_into::<UsbInterface>();
+            let alternate = iface.alternate();
+            (
+                alternate.interface_class(),
+                alternate.interface_subclass(),
+                iface,
+            )
+        });
+        if let Some((control, data)) = find_acm_pair(ifaces) {
+            return Some(AcmInterfaces {
+                config,
+                control,
+                data,
+            });
+        }
+    }
+    None
+}
+
+pub struct AcmWebUsb {
+    dev: UsbDevice,
+    input_ep: u8,
+}
+
+impl AcmWebUsb {
+    pub async fn new(dev: UsbDevice, baud_rate: u32) -> anyhow::Result<Self> {
+        let ifaces = find_acm_interfaces(&dev).ok_or(anyhow!("No serial port found"))?;
+
+        JsFuture::from(dev.open()).await.map_err(js_error)?;
+        JsFuture::from(dev.select_configuration(ifaces.config.configuration_value()))
+            .await
+            .map_err(js_error)?;
+        JsFuture::from(dev.claim_interface(ifaces.data.interface_number()))
+            .await
+            .map_err(js_error)?;
+
+        let number = ifaces.control.interface_number();
+        JsFuture::from(dev.claim_interface(number))
+            .await
+            .map_err(js_error)?;
+
+        // 8N1 at the given rate
+        let mut coding = baud_rate.to_le_bytes().to_vec();
+        coding.extend_from_slice(&[0, 0, 8]);
+        let params = |request, value| {
+            UsbControlTransferParameters::new(
+                number as u16,
+                UsbRecipient::Interface,
+                request,
+                UsbRequestType::Class,
+                value,
+            )
+        };
+        JsFuture::from(
+            dev.control_transfer_out_with_u8_slice(&params(SET_LINE_CODING, 0), &mut coding)
+                .map_err(js_error)?,
+        )
+        .await
+        .map_err(js_error)?;
+        JsFuture::from(dev.control_transfer_out(&params(SET_CONTROL_LINE_STATE, CONTROL_LINES)))
+            .await
+            .map_err(js_error)?;
+
+        let input_ep = ifaces
+            .data
+            .alternate()
+            .endpoints()
+            .into_iter()
+            .map(UsbEndpoint::unchecked_from_js)
+            .find(|ep| {
+                matches!(ep.type_(), UsbEndpointType::Bulk)
+                    && matches!(ep.direction(), UsbDirection::In)
+            })
+            .ok_or(anyhow!("Serial port lacking an input endpoint"))?
+            .endpoint_number();
+        Ok(Self { dev, input_ep })
+    }
+
+    /// Waits for the next chunk of console output
+    pub async fn read(&mut self) -> anyhow::Result<Vec<u8>> {
+        let res = JsFuture::from(self.dev.transfer_in(self.input_ep, READ_SIZE))
+            .await
+            .map_err(js_error)?;
+        let res = UsbInTransferResult::unchecked_from_js(res);
+        let Some(data) = res.data() else {
+            return Ok(vec![]);
+        };
+        Ok(Uint8Array::new_with_byte_offset_and_length(
+            &data.buffer(),
+            data.byte_offset() as u32,
+            data.byte_length() as u32,
+        )
+        .to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLASS_VENDOR: u8 = 0xff;
+    const SUBCLASS_ECM: u8 = 0x06;
+
+    #[test]
+    fn pairs_data_with_acm_control() {
+        let ifaces = [
+            (CLASS_VENDOR, 0x42, 0),
+            (CLASS_COMM, SUBCLASS_ACM, 1),
+            (CLASS_DATA, 0, 2),
+        ];
+        assert_eq!(find_acm_pair(ifaces), Some((1, 2)));
+    }
+
+    #[test]
+    fn skips_data_without_acm_control() {
+        let ifaces = [
+            (CLASS_DATA, 0, 0),
+            (CLASS_COMM, SUBCLASS_ECM, 1),
+            (CLASS_DATA, 0, 2),
+            (CLASS_COMM, SUBCLASS_ACM, 3),
+            (CLASS_DATA, 0, 4),
+        ];
+        assert_eq!(find_acm_pair(ifaces), Some((3, 4)));
+        assert_eq!(find_acm_pair([(CLASS_DATA, 0, 0)]), None);
+    }
+}
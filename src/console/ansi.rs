@@ -0,0 +1,356 @@
+//! Incremental parsing of console output containing ANSI escape sequences into styled lines.
+//!
+//! Only SGR sequences (colours and text attributes) affect the output. Every other escape
+//! sequence, carriage returns and the remaining control characters are dropped, since a log
+//! view has no cursor to move around.
+
+/// A colour from the 256 colour palette, or a 24-bit one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// The 16 basic colours, as xterm renders them
+const BASIC: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00),
+    (0xcd, 0x00, 0x00),
+    (0x00, 0xcd, 0x00),
+    (0xcd, 0xcd, 0x00),
+    (0x00, 0x00, 0xee),
+    (0xcd, 0x00, 0xcd),
+    (0x00, 0xcd, 0xcd),
+    (0xe5, 0xe5, 0xe5),
+    (0x7f, 0x7f, 0x7f),
+    (0xff, 0x00, 0x00),
+    (0x00, 0xff, 0x00),
+    (0xff, 0xff, 0x00),
+    (0x5c, 0x5c, 0xff),
+    (0xff, 0x00, 0xff),
+    (0x00, 0xff, 0xff),
+    (0xff, 0xff, 0xff),
+];
+
+impl Color {
+    pub fn rgb(self) -> (u8, u8, u8) {
+        match self {
+            Color::Rgb(r, g, b) => (r, g, b),
+            Color::Indexed(i @ 0..16) => BASIC[i as usize],
+            // 6x6x6 colour cube
+            Color::Indexed(i @ 16..232) => {
+                let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+                let i = i - 16;
+                (level(i / 36), level(i / 6 % 6), level(i % 6))
+            }
+            // Grayscale ramp
+            Color::Indexed(i) => {
+                let v = 8 + (i - 232) * 10;
+                (v, v, v)
+            }
+        }
+    }
+
+    pub fn css(self) -> String {
+        let (r, g, b) = self.rgb();
+        format!("#{r:02x}{g:02x}{b:02x}")
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub inverse: bool,
+}
+
+impl Style {
+    /// Inline CSS for text in this style; `default_fg` and `default_bg` stand in for unset
+    /// colours when the style is inverted
+    pub fn css(&self, default_fg: Color, default_bg: Color) -> String {
+        let (fg, bg) = if self.inverse {
+            (
+                Some(self.bg.unwrap_or(default_bg)),
+                Some(self.fg.unwrap_or(default_fg)),
+            )
+        } else {
+            (self.fg, self.bg)
+        };
+
+        let mut css = String::new();
+        if let Some(fg) = fg {
+            css.push_str(&format!("color:{};", fg.css()));
+        }
+        if let Some(bg) = bg {
+            css.push_str(&format!("background-color:{};", bg.css()));
+        }
+        if self.bold {
+            css.push_str("font-weight:bold;");
+        }
+        if self.dim {
+            css.push_str("opacity:0.7;");
+        }
+        if self.italic {
+            css.push_str("font-style:italic;");
+        }
+        if self.underline {
+            css.push_str("text-decoration:underline;");
+        }
+        css
+    }
+
+    /// Applies the parameters of an SGR (`ESC [ ... m`) sequence
+    fn apply(&mut self, params: &[u16]) {
+        // An empty sequence is a reset
+        if params.is_empty() {
+            *self = Style::default();
+        }
+        let mut params = params.iter().copied();
+        while let Some(param) = params.next() {
+            match param {
+                0 => *self = Style::default(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                7 => self.inverse = true,
+                22 => {
+                    self.bold = false;
+                    self.dim = false;
+                }
+                23 => self.italic = false,
+                24 => self.underline = false,
+                27 => self.inverse = false,
+                30..=37 => self.fg = Some(Color::Indexed((param - 30) as u8)),
+                38 => self.fg = extended_color(&mut params),
+                39 => self.fg = None,
+                40..=47 => self.bg = Some(Color::Indexed((param - 40) as u8)),
+                48 => self.bg = extended_color(&mut params),
+                49 => self.bg = None,
+                90..=97 => self.fg = Some(Color::Indexed((param - 90 + 8) as u8)),
+                100..=107 => self.bg = Some(Color::Indexed((param - 100 + 8) as u8)),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Parses the rest of a `38;5;n` or `38;2;r;g;b` colour
+fn extended_color(params: &mut impl Iterator<Item = u16>) -> Option<Color> {
+    let mut component = || params.next().map(|v| v.min(255) as u8);
+    match component()? {
+        5 => component().map(Color::Indexed),
+        2 => Some(Color::Rgb(component()?, component()?, component()?)),
+        _ => None,
+    }
+}
+
+/// A run of text in a single style
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub style: Style,
+    pub text: String,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub spans: Vec<Span>,
+}
+
+impl Line {
+    /// The line without any styling
+    pub fn text(&self) -> String {
+        self.spans.iter().map(|span| span.text.as_str()).collect()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    #[default]
+    Text,
+    Escape,
+    /// Control sequence; collecting parameters until the final byte
+    Csi,
+    /// Operating system command (e.g. setting the title); skipped until BEL or ST
+    Osc,
+    OscEscape,
+}
+
+/// Turns console output into lines, however the output happens to be split into chunks
+#[derive(Debug, Default)]
+pub struct Parser {
+    state: State,
+    style: Style,
+    params: Vec<u8>,
+    /// Bytes of the current span, which may end in an incomplete UTF-8 sequence
+    text: Vec<u8>,
+    line: Line,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds in more output, returning the lines it completes
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Line> {
+        let mut lines = vec![];
+        for &byte in data {
+            match (self.state, byte) {
+                (State::Text, b'\n') => {
+                    self.flush();
+                    lines.push(std::mem::take(&mut self.line));
+                }
+                (State::Text, 0x1b) => self.state = State::Escape,
+                (State::Text, b'\t') => self.text.push(byte),
+                (State::Text, 0..0x20 | 0x7f) => (),
+                (State::Text, _) => self.text.push(byte),
+                (State::Escape, b'[') => {
+                    self.params.clear();
+                    self.state = State::Csi;
+                }
+                (State::Escape, b']') => self.state = State::Osc,
+                (State::Escape, _) => self.state = State::Text,
+                (State::Csi, 0x40..=0x7e) => {
+                    if byte == b'm' {
+                        self.flush();
+                        // Missing parameters count as 0, so `ESC [ m` resets too
+                        let params: Vec<u16> = self
+                            .params
+                            .split(|&b| b == b';' || b == b':')
+                            .map(|param| {
+                                std::str::from_utf8(param)
+                                    .ok()
+                                    .and_then(|p| p.parse().ok())
+                                    .unwrap_or(0)
+                            })
+                            .collect();
+                        self.style.apply(&params);
+                    }
+                    self.state = State::Text;
+                }
+                (State::Csi, _) => self.params.push(byte),
+                (State::Osc, 0x07) => self.state = State::Text,
+                (State::Osc, 0x1b) => self.state = State::OscEscape,
+                (State::Osc, _) => (),
+                (State::OscEscape, b'\\') => self.state = State::Text,
+                (State::OscEscape, _) => self.state = State::Osc,
+            }
+        }
+        lines
+    }
+
+    /// The line being received, for showing it before it's complete
+    pub fn partial(&self) -> Line {
+        let mut line = self.line.clone();
+        push_span(&mut line, self.style, &self.text);
+        line
+    }
+
+    /// Ends the current span, e.g. because the style is about to change
+    fn flush(&mut self) {
+        let text = std::mem::take(&mut self.text);
+        push_span(&mut self.line, self.style, &text);
+    }
+}
+
+fn push_span(line: &mut Line, style: Style, text: &[u8]) {
+    if text.is_empty() {
+        return;
+    }
+    let text = String::from_utf8_lossy(text);
+    match line.spans.last_mut() {
+        Some(last) if last.style == style => last.text.push_str(&text),
+        _ => line.spans.push(Span {
+            style,
+            text: text.into_owned(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn texts(lines: &[Line]) -> Vec<String> {
+        lines.iter().map(Line::text).collect()
+    }
+
+    #[test]
+    fn plain_lines() {
+        let mut parser = Parser::new();
+        assert_eq!(texts(&parser.feed(b"hello\r\nwor")), ["hello"]);
+        assert_eq!(parser.partial().text(), "wor");
+        assert_eq!(texts(&parser.feed(b"ld\n\n")), ["world", ""]);
+        assert_eq!(parser.partial(), Line::default());
+    }
+
+    #[test]
+    fn utf8_split() {
+        let mut parser = Parser::new();
+        let text = "µs\n".as_bytes();
+        assert!(parser.feed(&text[..1]).is_empty());
+        assert_eq!(texts(&parser.feed(&text[1..])), ["µs"]);
+    }
+
+    #[test]
+    fn colors() {
+        let mut parser = Parser::new();
+        let lines = parser.feed(b"[  \x1b[0;32mOK  \x1b[0m] Started\n");
+        assert_eq!(lines.len(), 1);
+        let spans = &lines[0].spans;
+        assert_eq!(spans.len(), 3);
+        assert_eq!(spans[0].text, "[  ");
+        assert_eq!(spans[0].style, Style::default());
+        assert_eq!(spans[1].text, "OK  ");
+        assert_eq!(spans[1].style.fg, Some(Color::Indexed(2)));
+        assert_eq!(spans[2].text, "] Started");
+        assert_eq!(spans[2].style, Style::default());
+    }
+
+    #[test]
+    fn attributes() {
+        let mut style = Style::default();
+        style.apply(&[1, 4, 91, 48, 5, 200]);
+        assert!(style.bold && style.underline);
+        assert_eq!(style.fg, Some(Color::Indexed(9)));
+        assert_eq!(style.bg, Some(Color::Indexed(200)));
+        style.apply(&[22, 38, 2, 1, 2, 3, 49]);
+        assert!(!style.bold);
+        assert_eq!(style.fg, Some(Color::Rgb(1, 2, 3)));
+        assert_eq!(style.bg, None);
+        style.apply(&[]);
+        assert_eq!(style, Style::default());
+    }
+
+    #[test]
+    fn escapes_stripped() {
+        let mut parser = Parser::new();
+        let mut lines = parser.feed(b"\x1b]0;title\x07a\x1b[2Kb\x1b[3");
+        lines.extend(parser.feed(b"1mc\x1b]2;x\x1b\\d\x08\n"));
+        assert_eq!(texts(&lines), ["abcd"]);
+        assert_eq!(lines[0].spans[1].style.fg, Some(Color::Indexed(1)));
+    }
+
+    #[test]
+    fn palette() {
+        assert_eq!(Color::Indexed(1).css(), "#cd0000");
+        assert_eq!(Color::Indexed(16).css(), "#000000");
+        assert_eq!(Color::Indexed(231).css(), "#ffffff");
+        assert_eq!(Color::Indexed(232).css(), "#080808");
+        assert_eq!(Color::Rgb(1, 2, 255).css(), "#0102ff");
+
+        let style = Style {
+            inverse: true,
+            fg: Some(Color::Indexed(1)),
+            ..Style::default()
+        };
+        assert_eq!(
+            style.css(Color::Indexed(7), Color::Indexed(0)),
+            "color:#000000;background-color:#cd0000;"
+        );
+    }
+}
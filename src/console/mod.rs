@@ -0,0 +1,246 @@
+//! Serial console of a device being booted, read from a CDC-ACM port over WebUSB (e.g. exposed
+//! by the live OS' gadget) or from a USB-serial adapter through Web Serial.
+
+pub mod acm;
+mod ansi;
+mod serial;
+
+use crate::{blob, device_by_id};
+use acm::AcmWebUsb;
+use ansi::{Color, Line, Parser};
+use dioxus::logger::tracing;
+use dioxus::prelude::*;
+use futures::stream::{self, LocalBoxStream};
+use futures::StreamExt;
+use std::collections::VecDeque;
+
+/// Lines kept around; a kernel boot log is usually a few thousand
+const MAX_LINES: usize = 10_000;
+const DEFAULT_BAUD_RATE: u32 = 115_200;
+/// Colours of the console pane, also standing in for unset colours in inverted text
+const FOREGROUND: Color = Color::Indexed(7);
+const BACKGROUND: Color = Color::Indexed(0);
+
+/// Console output received so far, dropping the oldest lines once there's too many
+pub struct ConsoleLog {
+    lines: VecDeque<Line>,
+    parser: Parser,
+    max_lines: usize,
+    dropped: usize,
+}
+
+impl ConsoleLog {
+    pub fn new(max_lines: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            parser: Parser::new(),
+            max_lines,
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        for line in self.parser.feed(data) {
+            if self.lines.len() == self.max_lines {
+                self.lines.pop_front();
+                self.dropped += 1;
+            }
+            self.lines.push_back(line);
+        }
+    }
+
+    /// Number of lines dropped to stay within the limit
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Every line, including the incomplete last one, that contains `search` ignoring case
+    pub fn matching(&self, search: &str) -> Vec<Line> {
+        let search = search.to_lowercase();
+        let partial = self.parser.partial();
+        self.lines
+            .iter()
+            .chain((!partial.spans.is_empty()).then_some(&partial))
+            .filter(|line| search.is_empty() || line.text().to_lowercase().contains(&search))
+            .cloned()
+            .collect()
+    }
+
+    /// The log as plain text, for saving
+    pub fn text(&self) -> String {
+        self.matching("")
+            .iter()
+            .map(|line| line.text() + "\n")
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Source {
+    /// The device's own CDC-ACM port
+    Usb,
+    /// A serial port picked by the user
+    Serial,
+}
+
+async fn open(
+    source: Source,
+    id: &str,
+    baud_rate: u32,
+) -> anyhow::Result<LocalBoxStream<'static, anyhow::Result<Vec<u8>>>> {
+    match source {
+        Source::Usb => {
+            let acm = AcmWebUsb::new(device_by_id(id).await?, baud_rate).await?;
+            Ok(stream::try_unfold(acm, |mut acm| async move {
+                let data = acm.read().await?;
+                Ok(Some((data, acm)))
+            })
+            .boxed_local())
+        }
+        Source::Serial => Ok(serial::read_stream(&serial::request_port(baud_rate).await?)),
+    }
+}
+
+/// Attaches to the console of a device, showing its output as it arrives
+#[component]
+pub fn Console(serial: String) -> Element {
+    let mut log = use_signal(|| ConsoleLog::new(MAX_LINES));
+    let mut status = use_signal(String::new);
+    let mut search = use_signal(String::new);
+    let mut baud_rate = use_signal(|| DEFAULT_BAUD_RATE.to_string());
+    let mut reader = use_signal(|| None::<Task>);
+
+    let attach = {
+        to_owned![serial];
+        move |source: Source| {
+            let Ok(rate) = baud_rate.read().parse::<u32>() else {
+                status.set("Invalid baud rate".to_string());
+                return;
+            };
+            if let Some(task) = reader.write().take() {
+                task.cancel();
+            }
+            to_owned![serial];
+            let task = spawn(async move {
+                status.set("Attaching".to_string());
+                let result = async {
+                    let mut output = open(source, &serial, rate).await?;
+                    status.set("Attached".to_string());
+                    while let Some(data) = output.next().await {
+                        log.write().push(&data?);
+                    }
+                    Ok::<_, anyhow::Error>(())
+                };
+                match result.await {
+                    Ok(()) => status.set("Console closed".to_string()),
+                    Err(err) => {
+                        tracing::error!("Console of {} failed: {:#}", serial, err);
+                        status.set(format!("Console failed: {err:#}"));
+                    }
+                }
+            });
+            reader.set(Some(task));
+        }
+    };
+
+    let save_log = move |_| {
+        let text = log.read().text();
+        if let Err(err) = blob::save_bytes(text.as_bytes(), &format!("{serial}-console.log")) {
+            status.set(err.to_string());
+        }
+    };
+
+    let lines = log.read().matching(&search.read());
+    let dropped = log.read().dropped();
+    let pane_style = format!(
+        "background-color:{};color:{};max-height:30em;overflow:auto;",
+        BACKGROUND.css(),
+        FOREGROUND.css()
+    );
+
+    rsx! {
+        div {
+            "Console: "
+            input {
+                placeholder: "baud rate",
+                value: "{baud_rate}",
+                oninput: move |evt| baud_rate.set(evt.value()),
+            }
+            button {
+                onclick: {
+                    let mut attach = attach.clone();
+                    move |_| attach(Source::Usb)
+                },
+                "Attach USB"
+            }
+            button {
+                onclick: {
+                    let mut attach = attach.clone();
+                    move |_| attach(Source::Serial)
+                },
+                "Attach serial adapter"
+            }
+            input {
+                placeholder: "search",
+                value: "{search}",
+                oninput: move |evt| search.set(evt.value()),
+            }
+            button {
+                onclick: save_log,
+                "Save console log"
+            }
+            " {status}"
+            if !search.read().is_empty() {
+                " ({lines.len()} matching lines)"
+            }
+            if dropped > 0 {
+                " ({dropped} earlier lines dropped)"
+            }
+        }
+        if !lines.is_empty() {
+            pre {
+                style: pane_style,
+                for line in lines {
+                    div {
+                        for span in line.spans.iter() {
+                            span {
+                                style: span.style.css(FOREGROUND, BACKGROUND),
+                                "{span.text}"
+                            }
+                        }
+                        if line.spans.is_empty() {
+                            br {}
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn log_lines() {
+        let mut log = ConsoleLog::new(2);
+        log.push(b"one\ntw");
+        assert_eq!(log.text(), "one\ntw\n");
+        log.push(b"o\nthree\nfour");
+        assert_eq!(log.dropped(), 1);
+        assert_eq!(log.text(), "two\nthree\nfour\n");
+    }
+
+    #[test]
+    fn search() {
+        let mut log = ConsoleLog::new(MAX_LINES);
+        log.push(b"[    0.0] Booting Linux\n\x1b[31mKernel panic\x1b[0m - not syncing\nlinux");
+        let texts =
+            |search| -> Vec<String> { log.matching(search).iter().map(Line::text).collect() };
+        assert_eq!(texts("PANIC"), ["Kernel panic - not syncing"]);
+        assert_eq!(texts("linux"), ["[    0.0] Booting Linux", "linux"]);
+        assert_eq!(texts("").len(), 3);
+        assert!(texts("initrd").is_empty());
+    }
+}
@@ -0,0 +1,36 @@
+//! Reading a serial port through Web Serial, e.g. a USB-serial adapter wired to the device's
+//! debug UART.
+
+use crate::js_error;
+use futures::stream::LocalBoxStream;
+use futures::StreamExt;
+use js_sys::Uint8Array;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{SerialOptions, SerialPort};
+
+/// Asks the user to pick a serial port, and opens it at the given rate
+///
+/// Has to be called while handling a user gesture, like a click.
+pub async fn request_port(baud_rate: u32) -> anyhow::Result<SerialPort> {
+    let navigator = web_sys::window().unwrap().navigator();
+    let port: SerialPort = JsFuture::from(navigator.serial().request_port())
+        .await
+        .map_err(js_error)?
+        .unchecked_into();
+    JsFuture::from(port.open(&SerialOptions::new(baud_rate)))
+        .await
+        .map_err(js_error)?;
+    Ok(port)
+}
+
+/// Everything the port receives, until it's closed or fails
+pub fn read_stream(port: &SerialPort) -> LocalBoxStream<'static, anyhow::Result<Vec<u8>>> {
+    wasm_streams::ReadableStream::from_raw(port.readable())
+        .into_stream()
+        .map(|chunk| {
+            let chunk = chunk.map_err(js_error)?;
+            Ok(chunk.unchecked_into::<Uint8Array>().to_vec())
+        })
+        .boxed_local()
+}
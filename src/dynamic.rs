@@ -0,0 +1,333 @@
+use crate::connection::FastbootConnection;
+use crate::fastboot::webusb::FastbootWebUsb;
+use crate::fastboot::Fastboot;
+use crate::lp::{self, Metadata};
+use crate::{blob, device_by_id, reconnect};
+use dioxus::logger::tracing;
+use dioxus::prelude::*;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Enough of the start of a super partition dump to cover the geometry and a few metadata slots
+const SUPER_PREFIX_SIZE: u64 = 4 * MIB;
+
+/// Logical partitions as seen by fastbootd, or `None` when the device is in the bootloader
+async fn load_partitions(
+    fastboot: &mut Fastboot<FastbootWebUsb>,
+) -> anyhow::Result<Option<Vec<(String, u64)>>> {
+    if !fastboot.is_userspace().await? {
+        return Ok(None);
+    }
+    Ok(Some(fastboot.logical_partitions().await?))
+}
+
+async fn reboot_fastbootd(conn: &FastbootConnection) -> anyhow::Result<()> {
+    let _exclusive = conn.exclusive().await;
+    let serial = conn.serial();
+    let device = device_by_id(serial).await?;
+    let mut fastboot = Fastboot::new(FastbootWebUsb::new(device.clone()).await?);
+    fastboot.reboot_fastboot().await?;
+    reconnect(serial, &device).await?;
+    Ok(())
+}
+
+/// Parse LP metadata out of either a metadata-only image (super_empty.img) or a super dump
+async fn load_metadata(file: web_sys::File) -> anyhow::Result<Metadata> {
+    let data = blob::read_prefix(&file, SUPER_PREFIX_SIZE).await?;
+    Ok(lp::parse_image(&data).or_else(|_| lp::parse_super(&data, 0))?)
+}
+
+async fn apply_metadata(
+    fastboot: &mut Fastboot<FastbootWebUsb>,
+    metadata: &Metadata,
+) -> anyhow::Result<()> {
+    let image = lp::write_image(metadata)?;
+    let super_name = fastboot
+        .get_var("super-partition-name")
+        .await
+        .unwrap_or_else(|_| "super".to_string());
+    fastboot.download(image.len() as u64).await?;
+    fastboot.do_download(image.as_slice()).await?;
+    fastboot.update_super(&super_name, false).await?;
+    Ok(())
+}
+
+fn mib(size: u64) -> String {
+    format!("{:.1} MiB", size as f64 / MIB as f64)
+}
+
+/// Manages the logical partitions inside super through fastbootd
+#[component]
+pub fn DynamicPartitions(conn: FastbootConnection) -> Element {
+    let mut error = use_signal(String::new);
+    let mut name = use_signal(String::new);
+    let mut size = use_signal(String::new);
+    let mut partitions = use_resource({
+        to_owned![conn];
+        move || {
+            to_owned![conn];
+            async move {
+                conn.run(async |fastboot| load_partitions(fastboot).await)
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+        }
+    });
+
+    // Runs an action against fastbootd, then reloads the partition list.
+    let run = {
+        to_owned![conn];
+        move |action: Action| {
+            to_owned![conn];
+            async move {
+                error.set(String::new());
+                let result = conn.run(async |fastboot| {
+                    match action {
+                        Action::Create(name, size) => {
+                            fastboot.create_logical_partition(&name, size).await?
+                        }
+                        Action::Resize(name, size) => {
+                            fastboot.resize_logical_partition(&name, size).await?
+                        }
+                        Action::Delete(name) => fastboot.delete_logical_partition(&name).await?,
+                    }
+                    Ok(())
+                });
+                if let Err(err) = result.await {
+                    tracing::error!("Logical partition operation failed: {}", err);
+                    error.set(err.to_string());
+                }
+                partitions.restart();
+            }
+        }
+    };
+    let reboot = {
+        to_owned![conn];
+        move |_| {
+            to_owned![conn];
+            async move {
+                error.set(String::new());
+                if let Err(err) = reboot_fastbootd(&conn).await {
+                    tracing::error!("Rebooting to fastbootd failed: {}", err);
+                    error.set(err.to_string());
+                }
+                partitions.restart();
+            }
+        }
+    };
+    let size_bytes = move || size.read().trim().parse::<u64>().ok().map(|s| s * MIB);
+
+    rsx! {
+        h3 { "Dynamic partitions" }
+        match &*partitions.read_unchecked() {
+            None => rsx! { p { "Reading partitions..." } },
+            Some(Err(err)) => rsx! { p { "Failed to read partitions: {err}" } },
+            Some(Ok(None)) => rsx! {
+                p { "Logical partitions can only be managed from fastbootd." }
+                button {
+                    onclick: reboot.clone(),
+                    "Reboot to fastbootd"
+                }
+            },
+            Some(Ok(Some(list))) => rsx! {
+                table {
+                    for (partition, partition_size) in list.iter().cloned() {
+                        tr {
+                            td { "{partition}" }
+                            td { {mib(partition_size)} }
+                            td {
+                                button {
+                                    onclick: {
+                                        let run = run.clone();
+                                        move |_| run(Action::Delete(partition.clone()))
+                                    },
+                                    "Delete"
+                                }
+                            }
+                        }
+                    }
+                }
+                input {
+                    placeholder: "name",
+                    value: "{name}",
+                    oninput: move |evt| name.set(evt.value()),
+                }
+                input {
+                    placeholder: "size (MiB)",
+                    value: "{size}",
+                    oninput: move |evt| size.set(evt.value()),
+                }
+                button {
+                    disabled: size_bytes().is_none(),
+                    onclick: {
+                        let run = run.clone();
+                        move |_| run(Action::Create(name(), size_bytes().unwrap_or_default()))
+                    },
+                    "Create"
+                }
+                button {
+                    disabled: size_bytes().is_none(),
+                    onclick: {
+                        let run = run.clone();
+                        move |_| run(Action::Resize(name(), size_bytes().unwrap_or_default()))
+                    },
+                    "Resize"
+                }
+            },
+        }
+        {error}
+        SuperMetadata { conn: conn.clone() }
+    }
+}
+
+enum Action {
+    Create(String, u64),
+    Resize(String, u64),
+    Delete(String),
+}
+
+/// Inspects and edits the LP metadata of a super_empty.img (or super dump), which can then be
+/// saved or applied to the device with `update-super`
+#[component]
+fn SuperMetadata(conn: FastbootConnection) -> Element {
+    let mut metadata = use_signal(|| None::<Metadata>);
+    let mut status = use_signal(String::new);
+    let mut name = use_signal(String::new);
+    let mut group = use_signal(String::new);
+    let mut size = use_signal(String::new);
+
+    let load = move |_| async move {
+        let Some(file) = blob::selected_file("super-metadata") else {
+            status.set("Select a super_empty.img first".to_string());
+            return;
+        };
+        match load_metadata(file).await {
+            Ok(loaded) => {
+                group.set(
+                    loaded
+                        .groups
+                        .last()
+                        .map(|g| g.name.clone())
+                        .unwrap_or_default(),
+                );
+                metadata.set(Some(loaded));
+                status.set(String::new());
+            }
+            Err(err) => status.set(format!("Failed to parse metadata: {err}")),
+        }
+    };
+
+    let add = move |_| {
+        let size = size.read().trim().parse::<u64>().unwrap_or_default() * MIB;
+        let mut metadata = metadata.write();
+        let Some(metadata) = metadata.as_mut() else {
+            return;
+        };
+        if let Err(err) = metadata.add_partition(&name.read(), &group.read(), size, 0) {
+            status.set(err.to_string());
+        }
+    };
+
+    let resize = move |_| {
+        let size = size.read().trim().parse::<u64>().unwrap_or_default() * MIB;
+        let mut metadata = metadata.write();
+        let Some(metadata) = metadata.as_mut() else {
+            return;
+        };
+        if let Err(err) = metadata.resize_partition(&name.read(), size) {
+            status.set(err.to_string());
+        }
+    };
+
+    let save = move |_| {
+        let Some(metadata) = metadata.read().clone() else {
+            return;
+        };
+        let result = lp::write_image(&metadata)
+            .map_err(anyhow::Error::from)
+            .and_then(|image| blob::save_bytes(&image, "super_empty.img"));
+        if let Err(err) = result {
+            status.set(err.to_string());
+        }
+    };
+
+    let apply = move |_| {
+        to_owned![conn];
+        async move {
+            let Some(metadata) = metadata.read().clone() else {
+                return;
+            };
+            status.set("Updating super...".to_string());
+            let result = conn
+                .run(async |fastboot| apply_metadata(fastboot, &metadata).await)
+                .await;
+            match result {
+                Ok(()) => status.set("Super updated".to_string()),
+                Err(err) => status.set(format!("Updating super failed: {err}")),
+            }
+        }
+    };
+
+    rsx! {
+        h4 { "Super metadata" }
+        input {
+            id: "super-metadata",
+            r#type: "file",
+        }
+        button { onclick: load, "Load" }
+        if let Some(loaded) = metadata.read().as_ref() {
+            p { "Free space: " {mib(loaded.free_space())} }
+            table {
+                for partition in loaded.partitions.iter().cloned() {
+                    tr {
+                        td { "{partition.name}" }
+                        td { "{loaded.groups[partition.group_index as usize].name}" }
+                        td { {mib(partition.size())} }
+                        td {
+                            if partition.attributes & lp::PARTITION_ATTR_READONLY != 0 {
+                                "readonly"
+                            }
+                        }
+                        td {
+                            button {
+                                onclick: move |_| {
+                                    if let Some(metadata) = metadata.write().as_mut() {
+                                        if let Err(err) = metadata.remove_partition(&partition.name) {
+                                            status.set(err.to_string());
+                                        }
+                                    }
+                                },
+                                "Remove"
+                            }
+                        }
+                    }
+                }
+            }
+            input {
+                placeholder: "name",
+                value: "{name}",
+                oninput: move |evt| name.set(evt.value()),
+            }
+            select {
+                onchange: move |evt| group.set(evt.value()),
+                for g in loaded.groups.iter() {
+                    option {
+                        value: "{g.name}",
+                        selected: *group.read() == g.name,
+                        "{g.name}"
+                    }
+                }
+            }
+            input {
+                placeholder: "size (MiB)",
+                value: "{size}",
+                oninput: move |evt| size.set(evt.value()),
+            }
+            button { onclick: add, "Add partition" }
+            button { onclick: resize, "Resize partition" }
+            button { onclick: save, "Save" }
+            button { onclick: apply, "Apply with update-super" }
+        }
+        {status}
+    }
+}
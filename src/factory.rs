@@ -0,0 +1,596 @@
+use crate::blob::{self, BlobReader};
+use crate::connection::FastbootConnection;
+use crate::fastboot::webusb::FastbootWebUsb;
+use crate::fastboot::{FastBootError, FastBootOps, Fastboot};
+use crate::{device_by_id, reconnect};
+use async_zip::base::read::{seek, stream};
+use async_zip::error::ZipError;
+use dioxus::logger::tracing;
+use dioxus::prelude::*;
+use futures::io::BufReader;
+use futures::{AsyncBufRead, AsyncReadExt};
+use std::collections::HashSet;
+use std::pin::Pin;
+use thiserror::Error;
+use web_sys::{Blob, UsbDevice};
+
+/// Buffer size for reads out of the zip, to keep the number of round trips through JS down
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Errors while flashing a factory image
+#[derive(Debug, Error)]
+pub enum FactoryError {
+    #[error("Zip error: {0}")]
+    Zip(#[from] ZipError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Fastboot(#[from] FastBootError),
+    #[error("Malformed android-info.txt line: {0}")]
+    AndroidInfo(String),
+    #[error("Device {var} is {actual}, image requires {expected}")]
+    Requirement {
+        var: String,
+        actual: String,
+        expected: String,
+    },
+    #[error("Factory image contains no {0}")]
+    Missing(&'static str),
+    #[error("{0} has no size recorded in the image zip")]
+    UnknownSize(String),
+}
+
+/// A single line of android-info.txt
+#[derive(Debug, PartialEq, Eq)]
+pub struct Requirement {
+    /// Only applies to devices reporting this product
+    pub product: Option<String>,
+    /// The device must match none of the values, rather than one of them
+    pub reject: bool,
+    pub var: String,
+    pub values: Vec<String>,
+}
+
+impl Requirement {
+    /// Variable to query from the device
+    fn getvar(&self) -> &str {
+        match self.var.as_str() {
+            "board" => "product",
+            var => var,
+        }
+    }
+
+    fn matches(&self, actual: &str) -> bool {
+        let found = self
+            .values
+            .iter()
+            .any(|value| match value.strip_suffix('*') {
+                Some(prefix) => actual.starts_with(prefix),
+                None => actual == value,
+            });
+        found != self.reject
+    }
+}
+
+/// Parse the requirements listed in an android-info.txt
+pub fn parse_android_info(text: &str) -> Result<Vec<Requirement>, FactoryError> {
+    let mut requirements = vec![];
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let malformed = || FactoryError::AndroidInfo(line.to_string());
+
+        let (kind, rest) = line.split_once(char::is_whitespace).ok_or_else(malformed)?;
+        let (product, reject) = match kind {
+            "require" => (None, false),
+            "reject" => (None, true),
+            kind => match kind.strip_prefix("require-for-product:") {
+                Some(product) => (Some(product.to_string()), false),
+                None => return Err(malformed()),
+            },
+        };
+        let (var, values) = rest.trim().split_once('=').ok_or_else(malformed)?;
+        requirements.push(Requirement {
+            product,
+            reject,
+            var: var.trim().to_string(),
+            values: values.split('|').map(|v| v.trim().to_string()).collect(),
+        });
+    }
+    Ok(requirements)
+}
+
+/// Verify the device satisfies all requirements, before anything is flashed to it
+pub async fn check_requirements<Ops: FastBootOps>(
+    fastboot: &mut Fastboot<Ops>,
+    requirements: &[Requirement],
+) -> Result<(), FactoryError> {
+    let product = fastboot.get_var("product").await?;
+    for requirement in requirements {
+        if requirement.product.as_ref().is_some_and(|p| *p != product) {
+            continue;
+        }
+
+        if requirement.var == "partition-exists" {
+            for partition in &requirement.values {
+                if fastboot.partition_size(partition).await.is_err() {
+                    return Err(FactoryError::Requirement {
+                        var: requirement.var.clone(),
+                        actual: "missing".to_string(),
+                        expected: partition.clone(),
+                    });
+                }
+            }
+            continue;
+        }
+
+        let actual = fastboot.get_var(requirement.getvar()).await?;
+        if !requirement.matches(&actual) {
+            let expected = requirement.values.join("|");
+            return Err(FactoryError::Requirement {
+                var: requirement.var.clone(),
+                actual,
+                expected: if requirement.reject {
+                    format!("anything but {expected}")
+                } else {
+                    expected
+                },
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Partition an image zip entry should be flashed to, if it's an image at all
+fn image_partition(name: &str) -> Option<&str> {
+    name.strip_suffix(".img").filter(|p| !p.contains('/'))
+}
+
+/// Base name of a zip entry, ignoring the directory factory images keep everything in
+fn entry_basename(name: &str) -> &str {
+    name.rsplit_once('/').map_or(name, |(_, base)| base)
+}
+
+type OuterZip = seek::ZipFileReader<BufReader<BlobReader>>;
+
+/// A factory image zip (bootloader, radio and an inner image zip), or a bare image zip as used
+/// with `fastboot update`.
+///
+/// Everything is read straight out of the [Blob] on demand, so multi-gigabyte images never have
+/// to fit in memory.
+pub struct FactoryImage {
+    blob: Blob,
+    zip: OuterZip,
+    bootloader: Option<usize>,
+    radio: Option<usize>,
+    /// Entry holding the image zip, or `None` if the blob is the image zip itself
+    images: Option<usize>,
+}
+
+impl FactoryImage {
+    pub async fn open(blob: Blob) -> Result<Self, FactoryError> {
+        let reader = BufReader::with_capacity(READ_BUFFER_SIZE, BlobReader::new(blob.clone()));
+        let zip = seek::ZipFileReader::new(reader).await?;
+
+        let mut bootloader = None;
+        let mut radio = None;
+        let mut images = None;
+        let mut android_info = false;
+        for (index, entry) in zip.file().entries().iter().enumerate() {
+            let name = entry_basename(entry.filename().as_str()?);
+            if name == "android-info.txt" {
+                android_info = true;
+            } else if name.starts_with("bootloader-") && name.ends_with(".img") {
+                bootloader = Some(index);
+            } else if name.starts_with("radio-") && name.ends_with(".img") {
+                radio = Some(index);
+            } else if name.starts_with("image-") && name.ends_with(".zip") {
+                images = Some(index);
+            }
+        }
+
+        if images.is_none() && !android_info {
+            return Err(FactoryError::Missing("image zip"));
+        }
+
+        Ok(Self {
+            blob,
+            zip,
+            bootloader,
+            radio,
+            images,
+        })
+    }
+
+    /// Start streaming through the image zip from the beginning
+    async fn image_zip(&mut self) -> Result<Pin<Box<dyn AsyncBufRead + '_>>, FactoryError> {
+        Ok(match self.images {
+            Some(index) => Box::pin(BufReader::with_capacity(
+                READ_BUFFER_SIZE,
+                self.zip.reader_without_entry(index).await?,
+            )),
+            None => Box::pin(BufReader::with_capacity(
+                READ_BUFFER_SIZE,
+                BlobReader::new(self.blob.clone()),
+            )),
+        })
+    }
+
+    /// Read the requirements out of the image zip's android-info.txt
+    async fn requirements(&mut self) -> Result<Vec<Requirement>, FactoryError> {
+        let mut zip = stream::ZipFileReader::new(self.image_zip().await?);
+        while let Some(mut entry) = zip.next_with_entry().await? {
+            if entry.reader().entry().filename().as_str()? == "android-info.txt" {
+                let mut text = String::new();
+                entry.reader_mut().read_to_string(&mut text).await?;
+                return parse_android_info(&text);
+            }
+            zip = entry.skip().await?;
+        }
+        Err(FactoryError::Missing("android-info.txt"))
+    }
+
+    /// Flash an entry of the outer zip (bootloader or radio) to the given partition
+    async fn flash_entry<Ops: FastBootOps>(
+        &mut self,
+        fastboot: &mut Fastboot<Ops>,
+        index: usize,
+        partition: &str,
+    ) -> Result<(), FactoryError> {
+        let size = self.zip.file().entries()[index].uncompressed_size();
+        let reader = self.zip.reader_without_entry(index).await?;
+        fastboot.flash_stream(partition, size, reader).await?;
+        Ok(())
+    }
+
+    /// Stream through the image zip, flashing images that `select` picks.
+    ///
+    /// Returns the partitions that were flashed.
+    async fn flash_images<Ops: FastBootOps>(
+        &mut self,
+        fastboot: &mut Fastboot<Ops>,
+        mut select: impl FnMut(&str) -> bool,
+        mut status: Signal<Vec<String>>,
+    ) -> Result<Vec<String>, FactoryError> {
+        let mut flashed = vec![];
+        let mut zip = stream::ZipFileReader::new(self.image_zip().await?);
+        while let Some(mut entry) = zip.next_with_entry().await? {
+            let name = entry.reader().entry().filename().as_str()?.to_string();
+            let size = entry.reader().entry().uncompressed_size();
+            let Some(partition) = image_partition(&name).filter(|p| select(p)) else {
+                zip = entry.skip().await?;
+                continue;
+            };
+
+            // Entries written with a data descriptor don't announce their size up front, which
+            // the download command needs.
+            if size == 0 {
+                return Err(FactoryError::UnknownSize(name));
+            }
+
+            status
+                .write()
+                .push(format!("Flashing {partition} ({size} bytes)"));
+            fastboot
+                .flash_stream(partition, size, entry.reader_mut())
+                .await?;
+            flashed.push(partition.to_string());
+            zip = entry.done().await?;
+        }
+        Ok(flashed)
+    }
+
+    /// Download super_empty.img and use it to lay out the super partition afresh
+    async fn update_super<Ops: FastBootOps>(
+        &mut self,
+        fastboot: &mut Fastboot<Ops>,
+        wipe: bool,
+        mut status: Signal<Vec<String>>,
+    ) -> Result<(), FactoryError> {
+        let super_name = fastboot
+            .get_var("super-partition-name")
+            .await
+            .unwrap_or_else(|_| "super".to_string());
+
+        let mut zip = stream::ZipFileReader::new(self.image_zip().await?);
+        while let Some(mut entry) = zip.next_with_entry().await? {
+            if entry.reader().entry().filename().as_str()? != "super_empty.img" {
+                zip = entry.skip().await?;
+                continue;
+            }
+
+            let size = entry.reader().entry().uncompressed_size();
+            status.write().push(format!("Updating {super_name}"));
+            fastboot.download(size).await?;
+            fastboot.do_download(entry.reader_mut()).await?;
+            fastboot.update_super(&super_name, wipe).await?;
+            return Ok(());
+        }
+        Err(FactoryError::Missing("super_empty.img"))
+    }
+}
+
+/// An open fastboot connection that is re-established whenever the device reboots
+struct Session {
+    serial: String,
+    device: UsbDevice,
+    fastboot: Fastboot<FastbootWebUsb>,
+}
+
+impl Session {
+    async fn open(serial: String) -> anyhow::Result<Self> {
+        let device = device_by_id(&serial).await?;
+        let fastboot = Fastboot::new(FastbootWebUsb::new(device.clone()).await?);
+        Ok(Self {
+            serial,
+            device,
+            fastboot,
+        })
+    }
+
+    async fn reconnect(&mut self) -> anyhow::Result<()> {
+        let (device, fastboot) = reconnect(&self.serial, &self.device).await?;
+        self.device = device;
+        self.fastboot = fastboot;
+        Ok(())
+    }
+}
+
+/// Equivalent of `fastboot update`: flash every image in the image zip.
+///
+/// Partitions the bootloader doesn't know about are assumed to be logical partitions living in
+/// super, and are flashed from fastbootd after super has been laid out with super_empty.img.
+async fn update(
+    session: &mut Session,
+    image: &mut FactoryImage,
+    wipe: bool,
+    mut status: Signal<Vec<String>>,
+) -> anyhow::Result<()> {
+    let requirements = image.requirements().await?;
+    check_requirements(&mut session.fastboot, &requirements).await?;
+
+    let userspace = session.fastboot.is_userspace().await?;
+    let mut known = HashSet::new();
+    let mut deferred = HashSet::new();
+    {
+        // Figure out what the running fastboot implementation can flash before touching anything.
+        let mut zip = stream::ZipFileReader::new(image.image_zip().await?);
+        while let Some(entry) = zip.next_with_entry().await? {
+            let name = entry.reader().entry().filename().as_str()?.to_string();
+            zip = entry.skip().await?;
+            let Some(partition) = image_partition(&name) else {
+                continue;
+            };
+            if partition == "super_empty" {
+                deferred.insert(partition.to_string());
+                continue;
+            }
+
+            let fastboot = &mut session.fastboot;
+            let flashable = if userspace {
+                // Logical partitions have to wait until super has been updated
+                !fastboot.is_logical(partition).await.unwrap_or(false)
+            } else {
+                fastboot
+                    .get_var(&format!("partition-type:{partition}"))
+                    .await
+                    .is_ok()
+            };
+            if flashable {
+                known.insert(partition.to_string());
+            } else {
+                deferred.insert(partition.to_string());
+            }
+        }
+    }
+
+    image
+        .flash_images(&mut session.fastboot, |p| known.contains(p), status)
+        .await?;
+
+    if !deferred.is_empty() {
+        if !userspace {
+            status.write().push("Rebooting into fastbootd".to_string());
+            session.fastboot.reboot_fastboot().await?;
+            session.reconnect().await?;
+        }
+        if deferred.remove("super_empty") {
+            image
+                .update_super(&mut session.fastboot, wipe, status)
+                .await?;
+        }
+        image
+            .flash_images(&mut session.fastboot, |p| deferred.contains(p), status)
+            .await?;
+    }
+
+    if wipe {
+        status.write().push("Wiping user data".to_string());
+        session.fastboot.erase("userdata").await?;
+        for partition in ["metadata", "cache"] {
+            if let Err(err) = session.fastboot.erase(partition).await {
+                tracing::debug!("Not erasing {partition}: {err}");
+            }
+        }
+    }
+
+    status.write().push("Rebooting".to_string());
+    session.fastboot.reboot().await?;
+    Ok(())
+}
+
+/// Equivalent of a factory image's flash-all script: bootloader and radio first, each followed
+/// by a reboot into the new bootloader, then everything in the image zip.
+async fn flash_all(
+    session: &mut Session,
+    image: &mut FactoryImage,
+    wipe: bool,
+    mut status: Signal<Vec<String>>,
+) -> anyhow::Result<()> {
+    // Don't flash a bootloader meant for some other device.
+    let requirements = image.requirements().await?;
+    check_requirements(&mut session.fastboot, &requirements).await?;
+
+    for (index, partition) in [(image.bootloader, "bootloader"), (image.radio, "radio")] {
+        let Some(index) = index else {
+            continue;
+        };
+        status.write().push(format!("Flashing {partition}"));
+        image
+            .flash_entry(&mut session.fastboot, index, partition)
+            .await?;
+        session.fastboot.reboot_bootloader().await?;
+        session.reconnect().await?;
+    }
+
+    update(session, image, wipe, status).await
+}
+
+#[component]
+pub fn FactoryFlash(conn: FastbootConnection) -> Element {
+    let mut wipe = use_signal(|| false);
+    let mut status = use_signal(Vec::<String>::new);
+    let mut running = use_signal(|| false);
+
+    let run = move |all: bool| {
+        let conn = conn.clone();
+        async move {
+            let Some(file) = blob::selected_file("factory-image") else {
+                status.set(vec!["Select a factory image first".to_string()]);
+                return;
+            };
+            status.write().clear();
+            running.set(true);
+            let result = async {
+                let mut image = FactoryImage::open(file.into()).await?;
+                // The device reboots along the way, so it's kept away from the other panes
+                let _exclusive = conn.exclusive().await;
+                let mut session = Session::open(conn.serial().to_string()).await?;
+                if all {
+                    flash_all(&mut session, &mut image, wipe(), status).await
+                } else {
+                    update(&mut session, &mut image, wipe(), status).await
+                }
+            };
+            match result.await {
+                Ok(()) => status.write().push("Done".to_string()),
+                Err(err) => {
+                    tracing::error!("Flashing factory image failed: {}", err);
+                    status.write().push(format!("Failed: {err}"));
+                }
+            }
+            running.set(false);
+        }
+    };
+    let update_run = run.clone();
+
+    rsx! {
+        h3 { "Factory image" }
+        input {
+            id: "factory-image",
+            r#type: "file",
+            accept: ".zip",
+        }
+        label {
+            input {
+                r#type: "checkbox",
+                checked: wipe(),
+                onchange: move |evt| wipe.set(evt.checked()),
+            }
+            "Wipe user data"
+        }
+        button {
+            disabled: running(),
+            onclick: move |_| run(true),
+            "Flash all"
+        }
+        button {
+            disabled: running(),
+            onclick: move |_| update_run(false),
+            "Update"
+        }
+        ul {
+            for line in status.read().iter() {
+                li { "{line}" }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_requirements() {
+        let info = "require board=sargo|bonito\n\
+                    require version-bootloader=b4s4-0.3-*\n\
+                    # comment\n\
+                    \n\
+                    require-for-product:bonito version-baseband=g670-00011\n\
+                    reject version-bootloader=b4s4-0.1-1234\n";
+        let requirements = parse_android_info(info).unwrap();
+        assert_eq!(
+            requirements,
+            vec![
+                Requirement {
+                    product: None,
+                    reject: false,
+                    var: "board".to_string(),
+                    values: vec!["sargo".to_string(), "bonito".to_string()],
+                },
+                Requirement {
+                    product: None,
+                    reject: false,
+                    var: "version-bootloader".to_string(),
+                    values: vec!["b4s4-0.3-*".to_string()],
+                },
+                Requirement {
+                    product: Some("bonito".to_string()),
+                    reject: false,
+                    var: "version-baseband".to_string(),
+                    values: vec!["g670-00011".to_string()],
+                },
+                Requirement {
+                    product: None,
+                    reject: true,
+                    var: "version-bootloader".to_string(),
+                    values: vec!["b4s4-0.1-1234".to_string()],
+                },
+            ]
+        );
+        assert_eq!(requirements[0].getvar(), "product");
+    }
+
+    #[test]
+    fn parse_requirements_malformed() {
+        parse_android_info("require").unwrap_err();
+        parse_android_info("require board").unwrap_err();
+        parse_android_info("insist board=sargo").unwrap_err();
+    }
+
+    #[test]
+    fn requirement_matches() {
+        let requirements = parse_android_info(
+            "require version-bootloader=b4s4-0.3-*|c2f2-0.1\nreject board=sargo",
+        )
+        .unwrap();
+        assert!(requirements[0].matches("b4s4-0.3-6789"));
+        assert!(requirements[0].matches("c2f2-0.1"));
+        assert!(!requirements[0].matches("c2f2-0.2"));
+        assert!(requirements[1].matches("bonito"));
+        assert!(!requirements[1].matches("sargo"));
+    }
+
+    #[test]
+    fn image_partitions() {
+        assert_eq!(image_partition("boot.img"), Some("boot"));
+        assert_eq!(image_partition("android-info.txt"), None);
+        assert_eq!(image_partition("META/boot.img"), None);
+        assert_eq!(
+            entry_basename("sargo-pq3b/image-sargo.zip"),
+            "image-sargo.zip"
+        );
+    }
+}
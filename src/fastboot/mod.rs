@@ -1,14 +1,21 @@
+pub mod pcap;
 mod protocol;
+pub mod record;
+mod slot;
+mod sparse;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod usb;
 pub mod webusb;
 
-use futures::AsyncRead;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use std::{collections::HashMap, fmt::Display, io::Write};
 use thiserror::Error;
 use tracing::{info, warn};
 use tracing::{instrument, trace};
 
-use protocol::FastBootResponse;
+use protocol::{parse_u64_hex, parse_unlock_ability, FastBootResponse};
 use protocol::{FastBootCommand, FastBootResponseParseError};
+pub use slot::{Slot, SlotInfo, SlotSelection};
 
 /// Fastboot communication errors
 #[derive(Debug, Error)]
@@ -21,6 +28,8 @@ pub enum FastBootError {
     FastbootUnexpectedReply,
     #[error("Unknown fastboot response: {0}")]
     FastbootParseError(#[from] FastBootResponseParseError),
+    #[error("Image of {size} bytes can't be downloaded with a max-download-size of {max}")]
+    TooLarge { size: u64, max: u64 },
 }
 
 /// Errors when opening the fastboot device
@@ -38,6 +47,9 @@ pub enum FastBootOpenError {
     FastbootParseError(#[from] FastBootResponseParseError),
 }
 
+/// Largest single bulk IN transfer issued while receiving uploaded data
+const UPLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+
 /// Fastboot client
 pub struct Fastboot<Ops> {
     ops: Ops,
@@ -49,8 +61,45 @@ pub trait FastBootOps {
     async fn write_out_stream<R: AsyncRead + Unpin>(
         &mut self,
         read: R,
-    ) -> Result<usize, FastBootError>;
+    ) -> Result<u64, FastBootError>;
     async fn read_in(&mut self, buf: &mut [u8]) -> Result<usize, FastBootError>;
+
+    /// USB status of the last completed transfer
+    fn last_status(&self) -> TransferStatus {
+        TransferStatus::Ok
+    }
+}
+
+/// Status of a USB transfer, as reported by the host controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferStatus {
+    Ok,
+    /// The endpoint stalled
+    Stall,
+    /// The device sent more data than requested
+    Babble,
+    /// The transfer failed outright
+    Error,
+}
+
+impl TransferStatus {
+    fn code(self) -> u8 {
+        match self {
+            TransferStatus::Ok => 0,
+            TransferStatus::Stall => 1,
+            TransferStatus::Babble => 2,
+            TransferStatus::Error => 3,
+        }
+    }
+
+    fn from_code(code: u8) -> Self {
+        match code & 0x3 {
+            0 => TransferStatus::Ok,
+            1 => TransferStatus::Stall,
+            2 => TransferStatus::Babble,
+            _ => TransferStatus::Error,
+        }
+    }
 }
 
 impl<Ops: FastBootOps> Fastboot<Ops> {
@@ -61,6 +110,11 @@ impl<Ops: FastBootOps> Fastboot<Ops> {
         }
     }
 
+    /// The transport the session runs over
+    pub fn ops(&self) -> &Ops {
+        &self.ops
+    }
+
     async fn send_command<S: Display>(
         &mut self,
         cmd: FastBootCommand<S>,
@@ -85,11 +139,19 @@ impl<Ops: FastBootOps> Fastboot<Ops> {
 
     #[tracing::instrument(skip_all, err)]
     async fn handle_responses(&mut self) -> Result<String, FastBootError> {
+        self.handle_responses_with_info(|_| ()).await
+    }
+
+    /// Wait for the final response to a command, passing INFO messages to `on_info` as they arrive
+    async fn handle_responses_with_info<F: FnMut(&str)>(
+        &mut self,
+        mut on_info: F,
+    ) -> Result<String, FastBootError> {
         loop {
             let resp = self.read_response().await?;
             trace!("Response: {:?}", resp);
             match resp {
-                FastBootResponse::Info(_) => (),
+                FastBootResponse::Info(info) => on_info(&info),
                 FastBootResponse::Data(_) => return Err(FastBootError::FastbootUnexpectedReply),
                 FastBootResponse::Okay(value) => return Ok(value),
                 FastBootResponse::Fail(fail) => return Err(FastBootError::FastbootFailed(fail)),
@@ -114,8 +176,220 @@ impl<Ops: FastBootOps> Fastboot<Ops> {
         self.execute(cmd).await
     }
 
+    /// Get the named variable, parsed as a 0x prefixed hexadecimal number
+    async fn get_var_hex(&mut self, var: &str) -> Result<u64, FastBootError> {
+        let value = self.get_var(var).await?;
+        parse_u64_hex(&value).map_err(|_| FastBootError::FastbootUnexpectedReply)
+    }
+
+    /// Get the named variable, parsed as a yes/no flag
+    async fn get_var_bool(&mut self, var: &str) -> Result<bool, FastBootError> {
+        match self.get_var(var).await?.as_str() {
+            "yes" => Ok(true),
+            "no" => Ok(false),
+            _ => Err(FastBootError::FastbootUnexpectedReply),
+        }
+    }
+
+    /// Get the named variable, parsed as a decimal number
+    async fn get_var_u32(&mut self, var: &str) -> Result<u32, FastBootError> {
+        let value = self.get_var(var).await?;
+        value
+            .trim()
+            .parse()
+            .map_err(|_| FastBootError::FastbootUnexpectedReply)
+    }
+
+    /// Whether the bootloader is unlocked and will flash unsigned images
+    pub async fn is_unlocked(&mut self) -> Result<bool, FastBootError> {
+        self.get_var_bool("unlocked").await
+    }
+
+    /// Whether the bootloader enforces signature checks on what it boots
+    pub async fn is_secure(&mut self) -> Result<bool, FastBootError> {
+        self.get_var_bool("secure").await
+    }
+
+    /// Whether the bootloader may be unlocked, i.e. OEM unlocking has been allowed from Android
+    ///
+    /// Bootloaders that don't report the `unlock_ability` variable are asked with `flashing
+    /// get_unlock_ability` instead.
+    pub async fn unlock_ability(&mut self) -> Result<bool, FastBootError> {
+        match self.get_var("unlock_ability").await {
+            Ok(value) => {
+                return match value.trim() {
+                    "1" | "yes" => Ok(true),
+                    "0" | "no" => Ok(false),
+                    _ => Err(FastBootError::FastbootUnexpectedReply),
+                }
+            }
+            Err(FastBootError::FastbootFailed(_)) => (),
+            Err(err) => return Err(err),
+        }
+
+        let mut ability = None;
+        let cmd = FastBootCommand::<&str>::FlashingGetUnlockAbility;
+        self.send_command(cmd).await?;
+        self.handle_responses_with_info(|info| {
+            ability = ability.or(parse_unlock_ability(info));
+        })
+        .await?;
+        ability.ok_or(FastBootError::FastbootUnexpectedReply)
+    }
+
+    /// Unlock the bootloader, or with `critical` the bootloader-critical partitions
+    ///
+    /// The device asks for confirmation on screen first, so this only completes once that has
+    /// been answered; INFO messages sent in the meantime are passed to `on_info`. Unlocking wipes
+    /// all user data, and some devices reboot once done.
+    pub async fn unlock<F: FnMut(&str)>(
+        &mut self,
+        critical: bool,
+        on_info: F,
+    ) -> Result<(), FastBootError> {
+        let cmd = if critical {
+            FastBootCommand::<&str>::FlashingUnlockCritical
+        } else {
+            FastBootCommand::<&str>::FlashingUnlock
+        };
+        self.send_command(cmd).await?;
+        self.handle_responses_with_info(on_info).await.map(|v| {
+            trace!("Unlock ok: {v}");
+        })
+    }
+
+    /// Number of slots the device has, or 0 for devices without A/B partitions
+    pub async fn slot_count(&mut self) -> Result<u32, FastBootError> {
+        match self.get_var_u32("slot-count").await {
+            Err(FastBootError::FastbootFailed(_)) => Ok(0),
+            res => res,
+        }
+    }
+
+    /// The currently active slot
+    pub async fn current_slot(&mut self) -> Result<Slot, FastBootError> {
+        let value = self.get_var("current-slot").await?;
+        value
+            .parse()
+            .map_err(|_| FastBootError::FastbootUnexpectedReply)
+    }
+
+    /// Whether the given slot has been marked as having booted successfully
+    pub async fn slot_successful(&mut self, slot: Slot) -> Result<bool, FastBootError> {
+        self.get_var_bool(&format!("slot-successful:{slot}")).await
+    }
+
+    /// Whether the given slot has been marked as unbootable
+    pub async fn slot_unbootable(&mut self, slot: Slot) -> Result<bool, FastBootError> {
+        self.get_var_bool(&format!("slot-unbootable:{slot}")).await
+    }
+
+    /// Number of boot attempts left for the given slot
+    pub async fn slot_retry_count(&mut self, slot: Slot) -> Result<u32, FastBootError> {
+        self.get_var_u32(&format!("slot-retry-count:{slot}")).await
+    }
+
+    /// Gather the boot state of every slot
+    ///
+    /// Bootloaders don't necessarily implement every slot variable; the ones they refuse are
+    /// left as `None`.
+    pub async fn slots(&mut self) -> Result<Vec<SlotInfo>, FastBootError> {
+        let mut slots = vec![];
+        for slot in (0..self.slot_count().await?).filter_map(Slot::from_index) {
+            slots.push(SlotInfo {
+                slot,
+                successful: self.slot_successful(slot).await.ok(),
+                unbootable: self.slot_unbootable(slot).await.ok(),
+                retry_count: self.slot_retry_count(slot).await.ok(),
+            });
+        }
+        Ok(slots)
+    }
+
+    /// Mark the given slot as active, so it's booted from next
+    pub async fn set_active(&mut self, slot: Slot) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::SetActive(slot);
+        self.execute(cmd).await.map(|v| {
+            trace!("Set active ok: {v}");
+        })
+    }
+
+    /// Whether the given partition is slotted, i.e. exists as `<partition>_a`, `<partition>_b`...
+    pub async fn has_slot(&mut self, partition: &str) -> Result<bool, FastBootError> {
+        match self.get_var_bool(&format!("has-slot:{partition}")).await {
+            Err(FastBootError::FastbootFailed(_)) => Ok(false),
+            res => res,
+        }
+    }
+
+    /// Partition names a flash of `partition` should target for the given slot selection
+    ///
+    /// Partitions that aren't slotted (including names that already carry a slot suffix) are
+    /// returned as-is.
+    pub async fn slot_targets(
+        &mut self,
+        partition: &str,
+        selection: SlotSelection,
+    ) -> Result<Vec<String>, FastBootError> {
+        if !self.has_slot(partition).await? {
+            return Ok(vec![partition.to_string()]);
+        }
+
+        let slots = match selection {
+            SlotSelection::Slot(slot) => vec![slot],
+            SlotSelection::Current => vec![self.current_slot().await?],
+            SlotSelection::Other => {
+                let count = self.slot_count().await?;
+                let current = self.current_slot().await?;
+                if count < 2 {
+                    return Err(FastBootError::FastbootFailed(
+                        "Device has no other slot".to_string(),
+                    ));
+                }
+                vec![Slot::from_index((current.index() + 1) % count)
+                    .ok_or(FastBootError::FastbootUnexpectedReply)?]
+            }
+            SlotSelection::All => (0..self.slot_count().await?)
+                .filter_map(Slot::from_index)
+                .collect(),
+        };
+        Ok(slots
+            .into_iter()
+            .map(|slot| format!("{partition}{}", slot.suffix()))
+            .collect())
+    }
+
+    /// Size of the given partition in bytes
+    pub async fn partition_size(&mut self, partition: &str) -> Result<u64, FastBootError> {
+        self.get_var_hex(&format!("partition-size:{partition}"))
+            .await
+    }
+
+    /// Largest amount of data the device can send in response to a single fetch
+    ///
+    /// Devices that don't advertise `max-fetch-size` are assumed to be limited by their download
+    /// buffer
+    pub async fn max_fetch_size(&mut self) -> Result<u64, FastBootError> {
+        match self.get_var_hex("max-fetch-size").await {
+            Ok(size) => Ok(size),
+            Err(_) => self.get_var_hex("max-download-size").await,
+        }
+    }
+
     /// Prepare a download of a given size
-    pub async fn download(&mut self, size: u32) -> Result<Option<String>, FastBootError> {
+    ///
+    /// Sizes past 4 GiB take more than the 8 hex digits most fastboot implementations accept, so
+    /// they're only sent to devices advertising a download buffer that large.
+    pub async fn download(&mut self, size: u64) -> Result<Option<String>, FastBootError> {
+        if size > u32::MAX as u64 {
+            let max = self
+                .get_var_hex("max-download-size")
+                .await
+                .unwrap_or(u32::MAX as u64);
+            if size > max {
+                return Err(FastBootError::TooLarge { size, max });
+            }
+        }
         let cmd = FastBootCommand::<&str>::Download(size);
         let mut info: Option<String> = None;
         self.send_command(cmd).await?;
@@ -147,6 +421,133 @@ impl<Ops: FastBootOps> Fastboot<Ops> {
         self.handle_responses().await
     }
 
+    /// Wait for the device to announce how much data it is about to send
+    async fn read_data_size(&mut self) -> Result<u64, FastBootError> {
+        loop {
+            let resp = self.read_response().await?;
+            trace!("Response: {:?}", resp);
+            match resp {
+                FastBootResponse::Info(_) => (),
+                FastBootResponse::Data(size) => return Ok(size),
+                FastBootResponse::Okay(_) => return Err(FastBootError::FastbootUnexpectedReply),
+                FastBootResponse::Fail(fail) => return Err(FastBootError::FastbootFailed(fail)),
+            }
+        }
+    }
+
+    /// Prepare a fetch of `size` bytes at `offset` into the given partition
+    ///
+    /// Returns the amount of data the device will send, which should be received with
+    /// [Self::do_upload]
+    pub async fn fetch(
+        &mut self,
+        partition: &str,
+        offset: u64,
+        size: u64,
+    ) -> Result<u64, FastBootError> {
+        let cmd = FastBootCommand::Fetch(partition, offset, size);
+        self.send_command(cmd).await?;
+        self.read_data_size().await
+    }
+
+    /// Prepare an upload of data previously staged on the device (legacy `upload` command)
+    ///
+    /// Returns the amount of data the device will send, which should be received with
+    /// [Self::do_upload]
+    pub async fn upload(&mut self) -> Result<u64, FastBootError> {
+        let cmd = FastBootCommand::<&str>::Upload;
+        self.send_command(cmd).await?;
+        self.read_data_size().await
+    }
+
+    /// Receive `size` bytes of data announced by [Self::fetch] or [Self::upload] into `writer`
+    pub async fn do_upload<W: AsyncWrite + Unpin>(
+        &mut self,
+        mut writer: W,
+        size: u64,
+    ) -> Result<String, FastBootError> {
+        let mut buf = vec![0; size.min(UPLOAD_CHUNK_SIZE as u64) as usize];
+        let mut remaining = size;
+        while remaining > 0 {
+            let len = remaining.min(buf.len() as u64) as usize;
+            let read = self.ops.read_in(&mut buf[..len]).await?;
+            if read == 0 {
+                return Err(FastBootError::FastbootUnexpectedReply);
+            }
+            writer
+                .write_all(&buf[..read])
+                .await
+                .map_err(|err| FastBootError::Transfer(err.into()))?;
+            remaining -= read as u64;
+        }
+        writer
+            .flush()
+            .await
+            .map_err(|err| FastBootError::Transfer(err.into()))?;
+        tracing::debug!("Read {} bytes", size);
+        self.handle_responses().await
+    }
+
+    /// Download `size` bytes from `reader` and flash them to the given target partition
+    ///
+    /// Raw images larger than the device's download buffer are split into sparse images that are
+    /// flashed one after another.
+    pub async fn flash_stream<R: AsyncRead + Unpin>(
+        &mut self,
+        target: &str,
+        size: u64,
+        reader: R,
+    ) -> Result<(), FastBootError> {
+        match self.get_var_hex("max-download-size").await {
+            Ok(max) if size > max => self.flash_split(target, size, max, reader).await,
+            // Without a known buffer size, downloads stay within what 8 hex digits can describe
+            Err(_) if size > u32::MAX as u64 => {
+                self.flash_split(target, size, u32::MAX as u64, reader)
+                    .await
+            }
+            _ => {
+                self.download(size).await?;
+                self.do_download(reader).await?;
+                self.flash(target).await
+            }
+        }
+    }
+
+    async fn flash_split<R: AsyncRead + Unpin>(
+        &mut self,
+        target: &str,
+        size: u64,
+        max: u64,
+        mut reader: R,
+    ) -> Result<(), FastBootError> {
+        let mut magic = [0; 4];
+        reader
+            .read_exact(&mut magic)
+            .await
+            .map_err(|err| FastBootError::Transfer(err.into()))?;
+        // Already sparse images would need to be resparsed, rather than wrapped
+        if sparse::is_sparse(&magic) {
+            return Err(FastBootError::TooLarge { size, max });
+        }
+        let pieces = sparse::split(size, max).ok_or(FastBootError::TooLarge { size, max })?;
+
+        let mut reader = (&magic[..]).chain(reader);
+        for piece in pieces {
+            tracing::debug!(
+                "Flashing {} bytes at {:#x} of {target}",
+                piece.len,
+                piece.offset
+            );
+            self.download(piece.download_size()).await?;
+            let data = (&piece.header[..])
+                .chain((&mut reader).take(piece.len))
+                .chain(&piece.trailer[..]);
+            self.do_download(data).await?;
+            self.flash(target).await?;
+        }
+        Ok(())
+    }
+
     /// Flash downloaded data to a given target partition
     pub async fn flash(&mut self, target: &str) -> Result<(), FastBootError> {
         let cmd = FastBootCommand::Flash(target);
@@ -186,6 +587,91 @@ impl<Ops: FastBootOps> Fastboot<Ops> {
         })
     }
 
+    /// Reboot the device into userspace fastboot (fastbootd)
+    pub async fn reboot_fastboot(&mut self) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::<&str>::RebootFastboot;
+        self.execute(cmd).await.map(|v| {
+            trace!("Reboot ok: {v}");
+        })
+    }
+
+    /// Whether the device is running userspace fastboot (fastbootd) rather than the bootloader
+    pub async fn is_userspace(&mut self) -> Result<bool, FastBootError> {
+        match self.get_var("is-userspace").await {
+            Ok(value) => Ok(value == "yes"),
+            // Bootloaders generally don't know about the variable at all
+            Err(FastBootError::FastbootFailed(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Whether the given partition is a logical partition inside super
+    pub async fn is_logical(&mut self, partition: &str) -> Result<bool, FastBootError> {
+        match self.get_var_bool(&format!("is-logical:{partition}")).await {
+            Err(FastBootError::FastbootFailed(_)) => Ok(false),
+            res => res,
+        }
+    }
+
+    /// List logical partitions and their sizes, as reported by fastbootd
+    pub async fn logical_partitions(&mut self) -> Result<Vec<(String, u64)>, FastBootError> {
+        let vars = self.get_all_vars().await?;
+        let mut partitions: Vec<_> = vars
+            .iter()
+            .filter(|(_, value)| *value == "yes")
+            .filter_map(|(key, _)| key.strip_prefix("is-logical:"))
+            .map(|name| {
+                let size = vars
+                    .get(&format!("partition-size:{name}"))
+                    .and_then(|size| parse_u64_hex(size).ok())
+                    .unwrap_or_default();
+                (name.to_string(), size)
+            })
+            .collect();
+        partitions.sort();
+        Ok(partitions)
+    }
+
+    /// Create a logical partition in super (fastbootd only)
+    pub async fn create_logical_partition(
+        &mut self,
+        partition: &str,
+        size: u64,
+    ) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::CreateLogicalPartition(partition, size);
+        self.execute(cmd).await.map(|v| {
+            trace!("Create logical partition ok: {v}");
+        })
+    }
+
+    /// Delete a logical partition from super (fastbootd only)
+    pub async fn delete_logical_partition(&mut self, partition: &str) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::DeleteLogicalPartition(partition);
+        self.execute(cmd).await.map(|v| {
+            trace!("Delete logical partition ok: {v}");
+        })
+    }
+
+    /// Resize a logical partition in super (fastbootd only)
+    pub async fn resize_logical_partition(
+        &mut self,
+        partition: &str,
+        size: u64,
+    ) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::ResizeLogicalPartition(partition, size);
+        self.execute(cmd).await.map(|v| {
+            trace!("Resize logical partition ok: {v}");
+        })
+    }
+
+    /// Update the super partition metadata with a downloaded super_empty image
+    pub async fn update_super(&mut self, target: &str, wipe: bool) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::UpdateSuper(target, wipe);
+        self.execute(cmd).await.map(|v| {
+            trace!("Update super ok: {v}");
+        })
+    }
+
     /// Retrieve all variables
     pub async fn get_all_vars(&mut self) -> Result<HashMap<String, String>, FastBootError> {
         let cmd = FastBootCommand::GetVar("all");
@@ -222,3 +708,294 @@ pub enum DownloadError {
     #[error(transparent)]
     Nusb(#[from] FastBootError),
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fastboot_device::{Device, Handler, Reporter};
+    use futures::executor::block_on;
+
+    /// Slotted test device with a couple of partitions
+    struct TestHandler {
+        partitions: HashMap<String, Vec<u8>>,
+    }
+
+    impl TestHandler {
+        fn new() -> Self {
+            let partitions = [
+                ("boot_a", 16),
+                ("boot_b", 16),
+                ("persist", 8),
+                ("userdata", 0x3000),
+            ]
+            .into_iter()
+            .map(|(name, size)| (name.to_string(), vec![0; size]))
+            .collect();
+            Self { partitions }
+        }
+    }
+
+    impl Handler for TestHandler {
+        fn getvar(&mut self, var: &str) -> Result<String, String> {
+            match var.split_once(':') {
+                Some(("partition-size", partition)) => self
+                    .partitions
+                    .get(partition)
+                    .map(|data| format!("0x{:x}", data.len()))
+                    .ok_or_else(|| "no such partition".to_string()),
+                Some(("has-slot", partition)) => {
+                    Ok(if partition == "boot" { "yes" } else { "no" }.to_string())
+                }
+                _ => match var {
+                    "slot-count" => Ok("2".to_string()),
+                    "current-slot" => Ok("b".to_string()),
+                    _ => Err("unknown variable".to_string()),
+                },
+            }
+        }
+
+        fn flash(
+            &mut self,
+            partition: &str,
+            data: &[u8],
+            reporter: &mut Reporter,
+        ) -> Result<(), String> {
+            let target = self
+                .partitions
+                .get_mut(partition)
+                .ok_or_else(|| "no such partition".to_string())?;
+            reporter.info(format!("writing {partition}"));
+            if sparse::is_sparse(data) {
+                sparse::apply(data, target);
+            } else {
+                target[..data.len()].copy_from_slice(data);
+            }
+            Ok(())
+        }
+
+        fn fetch(&mut self, partition: &str, offset: u64, size: u64) -> Result<Vec<u8>, String> {
+            let data = self
+                .partitions
+                .get(partition)
+                .ok_or_else(|| "no such partition".to_string())?;
+            Ok(data[offset as usize..(offset + size) as usize].to_vec())
+        }
+
+        fn flashing(&mut self, command: &str, reporter: &mut Reporter) -> Result<(), String> {
+            match command {
+                "get_unlock_ability" => {
+                    reporter.info("get_unlock_ability: 1");
+                    Ok(())
+                }
+                _ => Err("unknown flashing command".to_string()),
+            }
+        }
+    }
+
+    /// Connects the client directly to an in-process device
+    struct Loopback<H> {
+        device: Device<H>,
+        pending: Vec<u8>,
+    }
+
+    impl<H: Handler> FastBootOps for Loopback<H> {
+        async fn write_out(&mut self, buf: &mut [u8]) -> Result<usize, FastBootError> {
+            self.device.handle(buf);
+            Ok(buf.len())
+        }
+
+        async fn write_out_stream<R: AsyncRead + Unpin>(
+            &mut self,
+            mut read: R,
+        ) -> Result<u64, FastBootError> {
+            let mut buf = [0; 5];
+            let mut total = 0;
+            loop {
+                let read = read
+                    .read(&mut buf)
+                    .await
+                    .map_err(|err| FastBootError::Transfer(err.into()))?;
+                if read == 0 {
+                    return Ok(total);
+                }
+                self.device.handle(&buf[..read]);
+                total += read as u64;
+            }
+        }
+
+        async fn read_in(&mut self, buf: &mut [u8]) -> Result<usize, FastBootError> {
+            if self.pending.is_empty() {
+                self.pending = self
+                    .device
+                    .next_packet()
+                    .ok_or(FastBootError::FastbootUnexpectedReply)?;
+            }
+            let len = buf.len().min(self.pending.len());
+            buf[..len].copy_from_slice(&self.pending[..len]);
+            self.pending.drain(..len);
+            Ok(len)
+        }
+    }
+
+    fn fastboot() -> Fastboot<Loopback<TestHandler>> {
+        Fastboot::new(Loopback {
+            device: Device::new(TestHandler::new()).with_max_download_size(0x20),
+            pending: vec![],
+        })
+    }
+
+    #[test]
+    fn get_vars() {
+        let mut fastboot = fastboot();
+        block_on(async {
+            assert_eq!(fastboot.get_var("version").await.unwrap(), "0.4");
+            assert_eq!(fastboot.partition_size("persist").await.unwrap(), 8);
+            assert_eq!(fastboot.max_fetch_size().await.unwrap(), 0x20);
+            assert!(matches!(
+                fastboot.get_var("nope").await,
+                Err(FastBootError::FastbootFailed(_))
+            ));
+            let vars = fastboot.get_all_vars().await.unwrap();
+            assert_eq!(vars["max-download-size"], "0x00000020");
+        });
+    }
+
+    #[test]
+    fn flash_and_fetch() {
+        let mut fastboot = fastboot();
+        block_on(async {
+            fastboot
+                .flash_stream("persist", 6, &b"abcdef"[..])
+                .await
+                .unwrap();
+            let size = fastboot.fetch("persist", 2, 4).await.unwrap();
+            let mut data = vec![];
+            fastboot.do_upload(&mut data, size).await.unwrap();
+            assert_eq!(data, b"cdef");
+
+            assert!(matches!(
+                fastboot.flash_stream("modem", 1, &b"x"[..]).await,
+                Err(FastBootError::FastbootFailed(_))
+            ));
+            assert!(matches!(
+                fastboot.download(0x21).await,
+                Err(FastBootError::FastbootFailed(_))
+            ));
+            assert!(matches!(
+                fastboot.download(1 << 32).await,
+                Err(FastBootError::TooLarge { .. })
+            ));
+        });
+    }
+
+    #[test]
+    fn flash_split() {
+        // Room for a single block per download
+        let mut fastboot = Fastboot::new(Loopback {
+            device: Device::new(TestHandler::new()).with_max_download_size(0x1040),
+            pending: vec![],
+        });
+        let image: Vec<u8> = (0..0x2100u32).map(|i| (i % 251) as u8).collect();
+        block_on(async {
+            fastboot
+                .flash_stream("userdata", image.len() as u64, image.as_slice())
+                .await
+                .unwrap();
+
+            let mut sparse = sparse::split(0x2000, 0x1040).unwrap()[0].header.clone();
+            sparse.resize(0x2000, 0);
+            assert!(matches!(
+                fastboot
+                    .flash_stream("userdata", 0x2000, sparse.as_slice())
+                    .await,
+                Err(FastBootError::TooLarge { .. })
+            ));
+        });
+        let userdata = &fastboot.ops.device.handler().partitions["userdata"];
+        assert_eq!(&userdata[..image.len()], &image);
+        assert!(userdata[image.len()..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn slots() {
+        let mut fastboot = fastboot();
+        block_on(async {
+            assert_eq!(fastboot.current_slot().await.unwrap(), "b".parse().unwrap());
+            assert_eq!(
+                fastboot
+                    .slot_targets("boot", SlotSelection::Other)
+                    .await
+                    .unwrap(),
+                ["boot_a"]
+            );
+            assert_eq!(
+                fastboot
+                    .slot_targets("boot", SlotSelection::All)
+                    .await
+                    .unwrap(),
+                ["boot_a", "boot_b"]
+            );
+            assert_eq!(
+                fastboot
+                    .slot_targets("persist", SlotSelection::All)
+                    .await
+                    .unwrap(),
+                ["persist"]
+            );
+        });
+    }
+
+    #[test]
+    fn record_and_replay() {
+        use record::{Recorder, Replay, ReplayError, Session};
+        use std::cell::{Cell, RefCell};
+        use std::rc::Rc;
+
+        let session = Rc::new(RefCell::new(Session::new()));
+        let time = Cell::new(0);
+        let clock = || {
+            time.set(time.get() + 10);
+            time.get()
+        };
+        let mut fastboot = Fastboot::new(Recorder::new(fastboot().ops, session.clone(), clock));
+        block_on(async {
+            fastboot.get_var("version").await.unwrap();
+            fastboot
+                .flash_stream("persist", 6, &b"abcdef"[..])
+                .await
+                .unwrap();
+        });
+        let recorded = Session::parse(&session.borrow().to_bytes()).unwrap();
+        assert_eq!(recorded.transfers[0].data, b"getvar:version");
+        assert_eq!(recorded.transfers[1].timestamp, 10);
+
+        let mut replay = Fastboot::new(Replay::new(recorded.clone()));
+        block_on(async {
+            assert_eq!(replay.get_var("version").await.unwrap(), "0.4");
+            replay
+                .flash_stream("persist", 6, &b"abcdef"[..])
+                .await
+                .unwrap();
+        });
+        assert!(replay.ops.finished());
+
+        let mut replay = Fastboot::new(Replay::new(recorded));
+        block_on(async {
+            let Err(FastBootError::Transfer(err)) = replay.get_var("product").await else {
+                panic!("replay didn't diverge");
+            };
+            assert_eq!(
+                err.downcast_ref::<ReplayError>(),
+                Some(&ReplayError::Mismatch(0))
+            );
+        });
+    }
+
+    #[test]
+    fn unlock_ability_from_info() {
+        let mut fastboot = fastboot();
+        block_on(async {
+            assert!(fastboot.unlock_ability().await.unwrap());
+        });
+    }
+}
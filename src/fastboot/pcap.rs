@@ -0,0 +1,238 @@
+//! Capture of USB traffic as pcapng, using the Linux usbmon link type, so it can be inspected with
+//! Wireshark.
+//!
+//! Only the bulk transfers are captured; Wireshark won't see the descriptors identifying the
+//! interface as fastboot, so use "Decode As" to apply the fastboot dissector.
+
+use super::TransferStatus;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 1;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+/// LINKTYPE_USB_LINUX_MMAPPED: packets start with a 64 byte usbmon header
+const LINKTYPE_USB_LINUX_MMAPPED: u16 = 220;
+const USBMON_HEADER_SIZE: usize = 64;
+const URB_SUBMIT: u8 = b'S';
+const URB_COMPLETE: u8 = b'C';
+const TRANSFER_TYPE_BULK: u8 = 3;
+/// Status of URBs that were just submitted
+const EINPROGRESS: i32 = -115;
+
+/// WebUSB doesn't expose bus addresses, so every capture claims the same device
+const BUS_NUMBER: u16 = 1;
+const DEVICE_NUMBER: u8 = 1;
+
+fn errno(status: TransferStatus) -> i32 {
+    match status {
+        TransferStatus::Ok => 0,
+        TransferStatus::Stall => -32,  // EPIPE
+        TransferStatus::Babble => -75, // EOVERFLOW
+        TransferStatus::Error => -71,  // EPROTO
+    }
+}
+
+/// A URB event as written to the capture
+struct Urb<'a> {
+    id: u64,
+    kind: u8,
+    endpoint: u8,
+    timestamp: u64,
+    status: i32,
+    length: u32,
+    data: &'a [u8],
+}
+
+/// Writes USB traffic into an in-memory pcapng capture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PcapWriter {
+    out: Vec<u8>,
+    next_id: u64,
+}
+
+impl Default for PcapWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PcapWriter {
+    pub fn new() -> Self {
+        let mut writer = Self {
+            out: vec![],
+            next_id: 1,
+        };
+
+        let mut section = vec![];
+        section.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        section.extend_from_slice(&1u16.to_le_bytes());
+        section.extend_from_slice(&0u16.to_le_bytes());
+        // Section length isn't known up front
+        section.extend_from_slice(&(-1i64).to_le_bytes());
+        writer.block(BLOCK_SECTION_HEADER, &section);
+
+        let mut interface = vec![];
+        interface.extend_from_slice(&LINKTYPE_USB_LINUX_MMAPPED.to_le_bytes());
+        interface.extend_from_slice(&0u16.to_le_bytes());
+        // No snapshot length limit
+        interface.extend_from_slice(&0u32.to_le_bytes());
+        writer.block(BLOCK_INTERFACE_DESCRIPTION, &interface);
+
+        writer
+    }
+
+    fn block(&mut self, block_type: u32, body: &[u8]) {
+        let padded = body.len().next_multiple_of(4);
+        let total = (12 + padded) as u32;
+        self.out.extend_from_slice(&block_type.to_le_bytes());
+        self.out.extend_from_slice(&total.to_le_bytes());
+        self.out.extend_from_slice(body);
+        self.out.resize(self.out.len() + padded - body.len(), 0);
+        self.out.extend_from_slice(&total.to_le_bytes());
+    }
+
+    fn packet(&mut self, urb: Urb) {
+        let mut packet = Vec::with_capacity(USBMON_HEADER_SIZE + urb.data.len());
+        packet.extend_from_slice(&urb.id.to_le_bytes());
+        packet.push(urb.kind);
+        packet.push(TRANSFER_TYPE_BULK);
+        packet.push(urb.endpoint);
+        packet.push(DEVICE_NUMBER);
+        packet.extend_from_slice(&BUS_NUMBER.to_le_bytes());
+        // No setup packet
+        packet.push(b'-');
+        // Data present, or why not: '<' for IN submissions, '>' for OUT completions
+        packet.push(match (urb.data.is_empty(), urb.endpoint & 0x80 != 0) {
+            (false, _) => 0,
+            (true, true) => b'<',
+            (true, false) => b'>',
+        });
+        packet.extend_from_slice(&((urb.timestamp / 1_000_000) as i64).to_le_bytes());
+        packet.extend_from_slice(&((urb.timestamp % 1_000_000) as i32).to_le_bytes());
+        packet.extend_from_slice(&urb.status.to_le_bytes());
+        packet.extend_from_slice(&urb.length.to_le_bytes());
+        packet.extend_from_slice(&(urb.data.len() as u32).to_le_bytes());
+        // Setup packet, interval, start frame, transfer flags and isochronous descriptor count
+        packet.resize(USBMON_HEADER_SIZE, 0);
+        packet.extend_from_slice(urb.data);
+
+        let mut body = Vec::with_capacity(20 + packet.len());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((urb.timestamp >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(urb.timestamp as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&packet);
+        self.block(BLOCK_ENHANCED_PACKET, &body);
+    }
+
+    /// Record the submission of a transfer, returning the id to complete it with
+    ///
+    /// `endpoint` includes the direction bit; `data` is only captured for OUT transfers, and
+    /// timestamps are in microseconds since the epoch.
+    pub fn submit(&mut self, timestamp: u64, endpoint: u8, length: u32, data: &[u8]) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.packet(Urb {
+            id,
+            kind: URB_SUBMIT,
+            endpoint,
+            timestamp,
+            status: EINPROGRESS,
+            length,
+            data,
+        });
+        id
+    }
+
+    /// Record the completion of a transfer; `data` is only captured for IN transfers
+    pub fn complete(
+        &mut self,
+        id: u64,
+        timestamp: u64,
+        endpoint: u8,
+        status: TransferStatus,
+        length: u32,
+        data: &[u8],
+    ) {
+        self.packet(Urb {
+            id,
+            kind: URB_COMPLETE,
+            endpoint,
+            timestamp,
+            status: errno(status),
+            length,
+            data,
+        });
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Split a capture into (block type, body) pairs, checking the framing
+    fn blocks(mut data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = vec![];
+        while !data.is_empty() {
+            let len = u32_at(data, 4) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(u32_at(data, len - 4) as usize, len);
+            blocks.push((u32_at(data, 0), &data[8..len - 4]));
+            data = &data[len..];
+        }
+        blocks
+    }
+
+    #[test]
+    fn headers() {
+        let writer = PcapWriter::new();
+        let blocks = blocks(writer.as_bytes());
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].0, BLOCK_SECTION_HEADER);
+        assert_eq!(u32_at(blocks[0].1, 0), BYTE_ORDER_MAGIC);
+        assert_eq!(blocks[1].0, BLOCK_INTERFACE_DESCRIPTION);
+        assert_eq!(&blocks[1].1[..2], &LINKTYPE_USB_LINUX_MMAPPED.to_le_bytes());
+    }
+
+    #[test]
+    fn transfers() {
+        let mut writer = PcapWriter::new();
+        let id = writer.submit(1_500_000, 0x01, 5, b"boot!");
+        writer.complete(id, 1_500_100, 0x01, TransferStatus::Ok, 5, &[]);
+        let id = writer.submit(1_500_200, 0x81, 64, &[]);
+        writer.complete(id, 1_500_300, 0x81, TransferStatus::Stall, 0, &[]);
+
+        let blocks = blocks(writer.as_bytes());
+        assert_eq!(blocks.len(), 6);
+        let (kind, body) = blocks[2];
+        assert_eq!(kind, BLOCK_ENHANCED_PACKET);
+        assert_eq!(u32_at(body, 4), 0);
+        assert_eq!(u32_at(body, 8), 1_500_000);
+        assert_eq!(u32_at(body, 12) as usize, USBMON_HEADER_SIZE + 5);
+        let urb = &body[20..];
+        assert_eq!(u64::from_le_bytes(urb[..8].try_into().unwrap()), 1);
+        assert_eq!(urb[8], URB_SUBMIT);
+        assert_eq!(urb[9], TRANSFER_TYPE_BULK);
+        assert_eq!(urb[10], 0x01);
+        assert_eq!(&urb[16..24], &1i64.to_le_bytes());
+        assert_eq!(u32_at(urb, 24), 500_000);
+        assert_eq!(u32_at(urb, 28) as i32, EINPROGRESS);
+        assert_eq!(&urb[USBMON_HEADER_SIZE..USBMON_HEADER_SIZE + 5], b"boot!");
+
+        let urb = &blocks[5].1[20..];
+        assert_eq!(u64::from_le_bytes(urb[..8].try_into().unwrap()), 2);
+        assert_eq!(urb[8], URB_COMPLETE);
+        assert_eq!(urb[10], 0x81);
+        assert_eq!(u32_at(urb, 28) as i32, -32);
+    }
+}
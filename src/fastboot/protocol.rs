@@ -25,13 +25,24 @@ pub fn parse_u64_hex(hex: &str) -> Result<u64, ParseIntError> {
     u64::from_str_radix(hex, 16)
 }
 
+/// Parse the INFO message sent in reply to `flashing get_unlock_ability`, e.g.
+/// `get_unlock_ability: 1`
+pub fn parse_unlock_ability(info: &str) -> Option<bool> {
+    let (_, value) = info.rsplit_once(':')?;
+    match value.trim() {
+        "1" => Some(true),
+        "0" => Some(false),
+        _ => None,
+    }
+}
+
 /// Fastboot commands
 #[derive(Debug)]
 pub enum FastBootCommand<S> {
     /// Get a variable value
     GetVar(S),
     /// Download a given length of data to the devices
-    Download(u32),
+    Download(u64),
     /// Verify
     Verify(u32),
     /// Flash downloaded to a partition
@@ -46,8 +57,30 @@ pub enum FastBootCommand<S> {
     Reboot,
     /// Reboot into the bootloader
     RebootBootloader,
+    /// Reboot into userspace fastboot (fastbootd)
+    RebootFastboot,
     /// Power off the device
     Powerdown,
+    /// Fetch a range of a partition from the device
+    Fetch(S, u64, u64),
+    /// Upload data staged on the device
+    Upload,
+    /// Mark the given slot as active
+    SetActive(S),
+    /// Create a logical partition of the given size (fastbootd only)
+    CreateLogicalPartition(S, u64),
+    /// Delete a logical partition (fastbootd only)
+    DeleteLogicalPartition(S),
+    /// Resize a logical partition (fastbootd only)
+    ResizeLogicalPartition(S, u64),
+    /// Update super partition metadata from a downloaded super_empty image, optionally wiping it
+    UpdateSuper(S, bool),
+    /// Unlock the bootloader, after confirmation on the device
+    FlashingUnlock,
+    /// Unlock flashing of bootloader-critical partitions, after confirmation on the device
+    FlashingUnlockCritical,
+    /// Ask whether the bootloader may be unlocked
+    FlashingGetUnlockAbility,
 }
 
 impl<S: Display> Display for FastBootCommand<S> {
@@ -62,7 +95,27 @@ impl<S: Display> Display for FastBootCommand<S> {
             FastBootCommand::Continue => write!(f, "continue"),
             FastBootCommand::Reboot => write!(f, "reboot"),
             FastBootCommand::RebootBootloader => write!(f, "reboot-bootloader"),
+            FastBootCommand::RebootFastboot => write!(f, "reboot-fastboot"),
             FastBootCommand::Powerdown => write!(f, "powerdown"),
+            FastBootCommand::Fetch(part, offset, size) => {
+                write!(f, "fetch:{part}:0x{offset:08x}:0x{size:08x}")
+            }
+            FastBootCommand::Upload => write!(f, "upload"),
+            FastBootCommand::SetActive(slot) => write!(f, "set_active:{slot}"),
+            FastBootCommand::CreateLogicalPartition(part, size) => {
+                write!(f, "create-logical-partition:{part}:{size}")
+            }
+            FastBootCommand::DeleteLogicalPartition(part) => {
+                write!(f, "delete-logical-partition:{part}")
+            }
+            FastBootCommand::ResizeLogicalPartition(part, size) => {
+                write!(f, "resize-logical-partition:{part}:{size}")
+            }
+            FastBootCommand::UpdateSuper(part, false) => write!(f, "update-super:{part}"),
+            FastBootCommand::UpdateSuper(part, true) => write!(f, "update-super:{part}:wipe"),
+            FastBootCommand::FlashingUnlock => write!(f, "flashing unlock"),
+            FastBootCommand::FlashingUnlockCritical => write!(f, "flashing unlock_critical"),
+            FastBootCommand::FlashingGetUnlockAbility => write!(f, "flashing get_unlock_ability"),
         }
     }
 }
@@ -88,7 +141,7 @@ pub enum FastBootResponse {
     /// Command failed with provided reason
     Fail(String),
     /// Device expected the amount of data to be sent
-    Data(u32),
+    Data(u64),
 }
 
 impl<'a> FastBootResponse {
@@ -99,7 +152,7 @@ impl<'a> FastBootResponse {
             "INFO" => Ok(Self::Info(data.into())),
             "FAIL" => Ok(Self::Fail(data.into())),
             "DATA" => {
-                let offset = u32::from_str_radix(data, 16)
+                let offset = u64::from_str_radix(data, 16)
                     .or(Err(FastBootResponseParseError::DataLength))?;
                 Ok(Self::Data(offset))
             }
@@ -150,6 +203,55 @@ mod test {
         parse_u32_hex("123456").unwrap_err();
     }
 
+    #[test]
+    fn command_download() {
+        let cmd = FastBootCommand::<&str>::Download(0x1000);
+        assert_eq!(cmd.to_string(), "download:00001000");
+        let cmd = FastBootCommand::<&str>::Download(0x1_2345_6789);
+        assert_eq!(cmd.to_string(), "download:123456789");
+    }
+
+    #[test]
+    fn command_fetch() {
+        let cmd = FastBootCommand::Fetch("modem", 0x1000, 0x2000);
+        assert_eq!(cmd.to_string(), "fetch:modem:0x00001000:0x00002000");
+    }
+
+    #[test]
+    fn command_logical_partitions() {
+        let cmd = FastBootCommand::CreateLogicalPartition("rootfs", 4096);
+        assert_eq!(cmd.to_string(), "create-logical-partition:rootfs:4096");
+        let cmd = FastBootCommand::ResizeLogicalPartition("rootfs", 8192);
+        assert_eq!(cmd.to_string(), "resize-logical-partition:rootfs:8192");
+        let cmd = FastBootCommand::DeleteLogicalPartition("rootfs");
+        assert_eq!(cmd.to_string(), "delete-logical-partition:rootfs");
+    }
+
+    #[test]
+    fn command_update_super() {
+        let cmd = FastBootCommand::UpdateSuper("super", false);
+        assert_eq!(cmd.to_string(), "update-super:super");
+        let cmd = FastBootCommand::UpdateSuper("super", true);
+        assert_eq!(cmd.to_string(), "update-super:super:wipe");
+    }
+
+    #[test]
+    fn command_flashing() {
+        let cmd = FastBootCommand::<&str>::FlashingUnlock;
+        assert_eq!(cmd.to_string(), "flashing unlock");
+        let cmd = FastBootCommand::<&str>::FlashingUnlockCritical;
+        assert_eq!(cmd.to_string(), "flashing unlock_critical");
+        let cmd = FastBootCommand::<&str>::FlashingGetUnlockAbility;
+        assert_eq!(cmd.to_string(), "flashing get_unlock_ability");
+    }
+
+    #[test]
+    fn unlock_ability() {
+        assert_eq!(parse_unlock_ability("get_unlock_ability: 1"), Some(true));
+        assert_eq!(parse_unlock_ability("get_unlock_ability:0"), Some(false));
+        assert_eq!(parse_unlock_ability("unlock_ability"), None);
+    }
+
     #[test]
     fn response_parse_ok() {
         let r = FastBootResponse::from_bytes(b"OKAYtest").unwrap();
@@ -198,6 +300,12 @@ mod test {
         assert_eq!(r, FastBootResponse::Data(0x123456));
     }
 
+    #[test]
+    fn response_parse_data_64bit() {
+        let r = FastBootResponse::from_bytes(b"DATA0000000134b72400").unwrap();
+        assert_eq!(r, FastBootResponse::Data(0x134b72400));
+    }
+
     #[test]
     fn response_parse_invalid() {
         let e = FastBootResponse::from_bytes(b"UNKN").unwrap_err();
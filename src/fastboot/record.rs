@@ -0,0 +1,474 @@
+//! Recording of fastboot sessions, and deterministic replay of them.
+//!
+//! A session file starts with [MAGIC], followed by one entry per transfer:
+//!
+//! - a flags byte: bit 0 is the direction (set for IN), bits 1-2 the [TransferStatus]
+//! - microseconds since the previous transfer, LEB128 encoded
+//! - the length of the data, LEB128 encoded
+//! - the data; for failed transfers the error message
+//!
+//! Download payloads are recorded in full, so traces of flashing sessions get large.
+
+use super::{FastBootError, FastBootOps, TransferStatus};
+use futures::{AsyncRead, AsyncReadExt};
+use std::cell::RefCell;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use thiserror::Error;
+
+const MAGIC: &[u8; 6] = b"FBREC\x01";
+
+/// Buffer size used to read streamed downloads while replaying
+const REPLAY_CHUNK_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Out,
+    In,
+}
+
+/// A single recorded transfer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    pub direction: Direction,
+    pub status: TransferStatus,
+    /// Microseconds since the session started
+    pub timestamp: u64,
+    pub data: Vec<u8>,
+}
+
+/// Errors parsing a session file
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SessionParseError {
+    #[error("Not a fastboot session recording")]
+    BadMagic,
+    #[error("Session recording is truncated")]
+    Truncated,
+}
+
+/// Where a replayed session diverged from the recording
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ReplayError {
+    #[error("Transfer {0} was not expected, the recording has ended")]
+    Ended(usize),
+    #[error("Transfer {0} went the wrong direction")]
+    Direction(usize),
+    #[error("Transfer {0} sent different data than recorded")]
+    Mismatch(usize),
+    #[error("Transfer {0} was recorded into a larger buffer")]
+    BufferSize(usize),
+    #[error("Recorded transfer {index} failed: {message}")]
+    Failed { index: usize, message: String },
+}
+
+/// Source of timestamps for recordings, in microseconds
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+impl<F: Fn() -> u64> Clock for F {
+    fn now(&self) -> u64 {
+        self()
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn take_varint(data: &mut &[u8]) -> Result<u64, SessionParseError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first().ok_or(SessionParseError::Truncated)?;
+        *data = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(SessionParseError::Truncated)
+}
+
+/// A recorded sequence of transfers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Session {
+    /// Clock reading the session started at
+    start: Option<u64>,
+    pub transfers: Vec<Transfer>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, now: u64, direction: Direction, status: TransferStatus, data: &[u8]) {
+        let start = *self.start.get_or_insert(now);
+        self.transfers.push(Transfer {
+            direction,
+            status,
+            timestamp: now.saturating_sub(start),
+            data: data.to_vec(),
+        });
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        let mut last = 0;
+        for transfer in &self.transfers {
+            let direction = match transfer.direction {
+                Direction::Out => 0,
+                Direction::In => 1,
+            };
+            out.push(direction | (transfer.status.code() << 1));
+            put_varint(&mut out, transfer.timestamp.saturating_sub(last));
+            put_varint(&mut out, transfer.data.len() as u64);
+            out.extend_from_slice(&transfer.data);
+            last = transfer.timestamp;
+        }
+        out
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, SessionParseError> {
+        let mut data = data
+            .strip_prefix(MAGIC)
+            .ok_or(SessionParseError::BadMagic)?;
+        let mut transfers = vec![];
+        let mut timestamp = 0u64;
+        while let Some((&flags, rest)) = data.split_first() {
+            data = rest;
+            timestamp = timestamp.saturating_add(take_varint(&mut data)?);
+            let len = take_varint(&mut data)?;
+            let len = usize::try_from(len)
+                .ok()
+                .filter(|&len| len <= data.len())
+                .ok_or(SessionParseError::Truncated)?;
+            let (payload, rest) = data.split_at(len);
+            data = rest;
+            transfers.push(Transfer {
+                direction: if flags & 1 == 0 {
+                    Direction::Out
+                } else {
+                    Direction::In
+                },
+                status: TransferStatus::from_code(flags >> 1),
+                timestamp,
+                data: payload.to_vec(),
+            });
+        }
+        Ok(Self {
+            start: None,
+            transfers,
+        })
+    }
+}
+
+/// Passes transfers through to another [FastBootOps], recording them into a [Session]
+///
+/// The session is shared so one recording can span the several connections a boot goes through.
+pub struct Recorder<Ops, C> {
+    inner: Ops,
+    session: Rc<RefCell<Session>>,
+    clock: C,
+}
+
+impl<Ops: FastBootOps, C: Clock> Recorder<Ops, C> {
+    pub fn new(inner: Ops, session: Rc<RefCell<Session>>, clock: C) -> Self {
+        Self {
+            inner,
+            session,
+            clock,
+        }
+    }
+
+    fn record(&self, direction: Direction, result: Result<&[u8], &FastBootError>) {
+        let now = self.clock.now();
+        let mut session = self.session.borrow_mut();
+        match result {
+            Ok(data) => session.push(now, direction, self.inner.last_status(), data),
+            Err(err) => session.push(
+                now,
+                direction,
+                TransferStatus::Error,
+                err.to_string().as_bytes(),
+            ),
+        }
+    }
+}
+
+/// Records every chunk read out of a streamed download
+struct RecordingReader<'a, R, C> {
+    inner: R,
+    session: &'a RefCell<Session>,
+    clock: &'a C,
+}
+
+impl<R: AsyncRead + Unpin, C: Clock> AsyncRead for RecordingReader<'_, R, C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = poll {
+            if read > 0 {
+                self.session.borrow_mut().push(
+                    self.clock.now(),
+                    Direction::Out,
+                    TransferStatus::Ok,
+                    &buf[..read],
+                );
+            }
+        }
+        poll
+    }
+}
+
+impl<Ops: FastBootOps, C: Clock> FastBootOps for Recorder<Ops, C> {
+    async fn write_out(&mut self, buf: &mut [u8]) -> Result<usize, FastBootError> {
+        let result = self.inner.write_out(buf).await;
+        self.record(
+            Direction::Out,
+            result.as_ref().map(|&written| &buf[..written]),
+        );
+        result
+    }
+
+    async fn write_out_stream<R: AsyncRead + Unpin>(
+        &mut self,
+        read: R,
+    ) -> Result<u64, FastBootError> {
+        let reader = RecordingReader {
+            inner: read,
+            session: &self.session,
+            clock: &self.clock,
+        };
+        let result = self.inner.write_out_stream(reader).await;
+        if let Err(err) = &result {
+            self.session.borrow_mut().push(
+                self.clock.now(),
+                Direction::Out,
+                TransferStatus::Error,
+                err.to_string().as_bytes(),
+            );
+        }
+        result
+    }
+
+    async fn read_in(&mut self, buf: &mut [u8]) -> Result<usize, FastBootError> {
+        let result = self.inner.read_in(buf).await;
+        self.record(Direction::In, result.as_ref().map(|&read| &buf[..read]));
+        result
+    }
+
+    fn last_status(&self) -> TransferStatus {
+        self.inner.last_status()
+    }
+}
+
+/// Plays a recorded [Session] back, checking that the client sends exactly what was recorded
+pub struct Replay {
+    transfers: Vec<Transfer>,
+    next: usize,
+    /// Part of the current OUT transfer not yet matched by a streamed download
+    partial: usize,
+    status: TransferStatus,
+}
+
+fn replay_error(err: ReplayError) -> FastBootError {
+    FastBootError::Transfer(Box::new(err))
+}
+
+impl Replay {
+    pub fn new(session: Session) -> Self {
+        Self {
+            transfers: session.transfers,
+            next: 0,
+            partial: 0,
+            status: TransferStatus::Ok,
+        }
+    }
+
+    /// Whether every recorded transfer has been replayed
+    pub fn finished(&self) -> bool {
+        self.next == self.transfers.len()
+    }
+
+    fn take(&mut self, direction: Direction) -> Result<&Transfer, FastBootError> {
+        let index = self.next;
+        let transfer = self
+            .transfers
+            .get(index)
+            .ok_or_else(|| replay_error(ReplayError::Ended(index)))?;
+        if transfer.direction != direction {
+            return Err(replay_error(ReplayError::Direction(index)));
+        }
+        if transfer.status == TransferStatus::Error {
+            self.next += 1;
+            return Err(replay_error(ReplayError::Failed {
+                index,
+                message: String::from_utf8_lossy(&transfer.data).into_owned(),
+            }));
+        }
+        self.next += 1;
+        self.status = transfer.status;
+        Ok(&self.transfers[index])
+    }
+
+    /// Match streamed download data against the recorded OUT transfers, which may have been
+    /// split up differently
+    fn match_stream(&mut self, mut data: &[u8]) -> Result<(), FastBootError> {
+        while !data.is_empty() {
+            if self.partial == 0 {
+                self.take(Direction::Out)?;
+            }
+            let index = self.next - 1;
+            let expected = &self.transfers[index].data[self.partial..];
+            let len = expected.len().min(data.len());
+            if expected[..len] != data[..len] {
+                return Err(replay_error(ReplayError::Mismatch(index)));
+            }
+            data = &data[len..];
+            self.partial = if len == expected.len() {
+                0
+            } else {
+                self.partial + len
+            };
+        }
+        Ok(())
+    }
+}
+
+impl FastBootOps for Replay {
+    async fn write_out(&mut self, buf: &mut [u8]) -> Result<usize, FastBootError> {
+        let index = self.next;
+        let transfer = self.take(Direction::Out)?;
+        if transfer.data != buf {
+            return Err(replay_error(ReplayError::Mismatch(index)));
+        }
+        Ok(buf.len())
+    }
+
+    async fn write_out_stream<R: AsyncRead + Unpin>(
+        &mut self,
+        mut read: R,
+    ) -> Result<u64, FastBootError> {
+        let mut buf = vec![0; REPLAY_CHUNK_SIZE];
+        let mut total = 0;
+        loop {
+            let read = read
+                .read(&mut buf)
+                .await
+                .map_err(|err| FastBootError::Transfer(err.into()))?;
+            if read == 0 {
+                break;
+            }
+            self.match_stream(&buf[..read])?;
+            total += read as u64;
+        }
+        if self.partial != 0 {
+            return Err(replay_error(ReplayError::Mismatch(self.next - 1)));
+        }
+        Ok(total)
+    }
+
+    async fn read_in(&mut self, buf: &mut [u8]) -> Result<usize, FastBootError> {
+        let index = self.next;
+        let transfer = self.take(Direction::In)?;
+        let data = &transfer.data;
+        if data.len() > buf.len() {
+            return Err(replay_error(ReplayError::BufferSize(index)));
+        }
+        buf[..data.len()].copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn last_status(&self) -> TransferStatus {
+        self.status
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn transfer(direction: Direction, timestamp: u64, data: &[u8]) -> Transfer {
+        Transfer {
+            direction,
+            status: TransferStatus::Ok,
+            timestamp,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn varint_roundtrip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut out = vec![];
+            put_varint(&mut out, value);
+            let mut data = out.as_slice();
+            assert_eq!(take_varint(&mut data), Ok(value));
+            assert!(data.is_empty());
+        }
+        assert_eq!(
+            take_varint(&mut &[0x80][..]),
+            Err(SessionParseError::Truncated)
+        );
+    }
+
+    #[test]
+    fn session_roundtrip() {
+        let mut session = Session::new();
+        session.push(1000, Direction::Out, TransferStatus::Ok, b"getvar:product");
+        session.push(1250, Direction::In, TransferStatus::Ok, b"OKAYfoo");
+        session.push(90000, Direction::In, TransferStatus::Stall, b"");
+        session.push(
+            90001,
+            Direction::Out,
+            TransferStatus::Error,
+            b"disconnected",
+        );
+
+        assert_eq!(
+            session.transfers[..2],
+            [
+                transfer(Direction::Out, 0, b"getvar:product"),
+                transfer(Direction::In, 250, b"OKAYfoo")
+            ]
+        );
+        let bytes = session.to_bytes();
+        let parsed = Session::parse(&bytes).unwrap();
+        assert_eq!(parsed.transfers, session.transfers);
+
+        assert_eq!(
+            Session::parse(&bytes[..bytes.len() - 1]),
+            Err(SessionParseError::Truncated)
+        );
+        assert_eq!(Session::parse(b"nope"), Err(SessionParseError::BadMagic));
+    }
+
+    #[test]
+    fn replay_stream_split_differently() {
+        let mut replay = Replay::new(Session {
+            start: None,
+            transfers: vec![
+                transfer(Direction::Out, 0, b"abc"),
+                transfer(Direction::Out, 0, b"defg"),
+            ],
+        });
+        replay.match_stream(b"ab").unwrap();
+        replay.match_stream(b"cdefg").unwrap();
+        assert!(replay.finished());
+        assert_eq!(replay.partial, 0);
+    }
+}
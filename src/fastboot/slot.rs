@@ -0,0 +1,129 @@
+use std::fmt::Display;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// An A/B partition slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Slot(char);
+
+/// Error parsing a slot name
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("Invalid slot: {0}")]
+pub struct SlotParseError(String);
+
+impl Slot {
+    /// The slot at the given index, as counted by `slot-count`
+    pub fn from_index(index: u32) -> Option<Self> {
+        char::from_u32('a' as u32 + index)
+            .filter(char::is_ascii_lowercase)
+            .map(Slot)
+    }
+
+    pub fn index(&self) -> u32 {
+        self.0 as u32 - 'a' as u32
+    }
+
+    /// Partition name suffix for this slot, e.g. `_a`
+    pub fn suffix(&self) -> String {
+        format!("_{}", self.0)
+    }
+}
+
+impl Display for Slot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for Slot {
+    type Err = SlotParseError;
+
+    /// Parse a slot name, with or without the leading underscore some bootloaders report
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim();
+        let name = name.strip_prefix('_').unwrap_or(name);
+        let mut chars = name.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_lowercase() => Ok(Slot(c)),
+            _ => Err(SlotParseError(s.to_string())),
+        }
+    }
+}
+
+/// Which slot(s) to flash, following the semantics of fastboot's `--slot` option
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlotSelection {
+    /// The currently active slot
+    #[default]
+    Current,
+    /// The slot after the currently active one
+    Other,
+    /// Every slot
+    All,
+    /// A specific slot
+    Slot(Slot),
+}
+
+impl Display for SlotSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlotSelection::Current => write!(f, "current"),
+            SlotSelection::Other => write!(f, "other"),
+            SlotSelection::All => write!(f, "all"),
+            SlotSelection::Slot(slot) => write!(f, "{slot}"),
+        }
+    }
+}
+
+impl FromStr for SlotSelection {
+    type Err = SlotParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "current" => Ok(SlotSelection::Current),
+            "other" => Ok(SlotSelection::Other),
+            "all" => Ok(SlotSelection::All),
+            slot => slot.parse().map(SlotSelection::Slot),
+        }
+    }
+}
+
+/// Boot state of a single slot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotInfo {
+    pub slot: Slot,
+    pub successful: Option<bool>,
+    pub unbootable: Option<bool>,
+    pub retry_count: Option<u32>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_slot() {
+        assert_eq!("a".parse(), Ok(Slot('a')));
+        assert_eq!("_b".parse(), Ok(Slot('b')));
+        assert_eq!(" b\n".parse(), Ok(Slot('b')));
+        "ab".parse::<Slot>().unwrap_err();
+        "".parse::<Slot>().unwrap_err();
+        "A".parse::<Slot>().unwrap_err();
+    }
+
+    #[test]
+    fn slot_index() {
+        assert_eq!(Slot::from_index(1), Some(Slot('b')));
+        assert_eq!(Slot::from_index(26), None);
+        assert_eq!(Slot('b').index(), 1);
+        assert_eq!(Slot('a').suffix(), "_a");
+    }
+
+    #[test]
+    fn parse_slot_selection() {
+        assert_eq!("other".parse(), Ok(SlotSelection::Other));
+        assert_eq!("all".parse(), Ok(SlotSelection::All));
+        assert_eq!("b".parse(), Ok(SlotSelection::Slot(Slot('b'))));
+        "both".parse::<SlotSelection>().unwrap_err();
+    }
+}
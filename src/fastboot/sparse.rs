@@ -0,0 +1,196 @@
+//! Splitting of raw images that don't fit in the device's download buffer into Android sparse
+//! images, each carrying a slice of the image and skipping over the rest of the partition.
+
+const SPARSE_MAGIC: u32 = 0xed26_ff3a;
+const BLOCK_SIZE: u64 = 4096;
+const FILE_HEADER_SIZE: u16 = 28;
+const CHUNK_HEADER_SIZE: u16 = 12;
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+/// A piece carries at most three chunks: skip, data, skip
+const MAX_OVERHEAD: u64 = FILE_HEADER_SIZE as u64 + 3 * CHUNK_HEADER_SIZE as u64;
+/// Most blocks a raw chunk can carry, its size including the header being 32-bit
+const MAX_CHUNK_BLOCKS: u64 = (u32::MAX as u64 - CHUNK_HEADER_SIZE as u64) / BLOCK_SIZE;
+
+/// Whether the image starting with `prefix` is already a sparse image
+pub fn is_sparse(prefix: &[u8]) -> bool {
+    prefix.get(..4) == Some(&SPARSE_MAGIC.to_le_bytes())
+}
+
+fn chunk(out: &mut Vec<u8>, chunk_type: u16, blocks: u64, data_len: u64) {
+    out.extend_from_slice(&chunk_type.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out.extend_from_slice(&(blocks as u32).to_le_bytes());
+    out.extend_from_slice(&((CHUNK_HEADER_SIZE as u64 + data_len) as u32).to_le_bytes());
+}
+
+/// A sparse image carrying `len` bytes of the raw image starting at `offset`
+///
+/// The image is the header, followed by the raw data, followed by the trailer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Piece {
+    pub offset: u64,
+    pub len: u64,
+    pub header: Vec<u8>,
+    pub trailer: Vec<u8>,
+}
+
+impl Piece {
+    fn new(offset: u64, len: u64, total_blocks: u64) -> Self {
+        let start = offset / BLOCK_SIZE;
+        let blocks = len.div_ceil(BLOCK_SIZE);
+        let end = start + blocks;
+        let chunks = 1 + (start > 0) as u32 + (end < total_blocks) as u32;
+
+        let mut header = vec![];
+        header.extend_from_slice(&SPARSE_MAGIC.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&FILE_HEADER_SIZE.to_le_bytes());
+        header.extend_from_slice(&CHUNK_HEADER_SIZE.to_le_bytes());
+        header.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        header.extend_from_slice(&(total_blocks as u32).to_le_bytes());
+        header.extend_from_slice(&chunks.to_le_bytes());
+        // No checksum
+        header.extend_from_slice(&0u32.to_le_bytes());
+        if start > 0 {
+            chunk(&mut header, CHUNK_TYPE_DONT_CARE, start, 0);
+        }
+        chunk(&mut header, CHUNK_TYPE_RAW, blocks, blocks * BLOCK_SIZE);
+
+        // The last piece of an image that isn't block aligned is padded out with zeroes
+        let mut trailer = vec![0; (blocks * BLOCK_SIZE - len) as usize];
+        if end < total_blocks {
+            chunk(&mut trailer, CHUNK_TYPE_DONT_CARE, total_blocks - end, 0);
+        }
+
+        Self {
+            offset,
+            len,
+            header,
+            trailer,
+        }
+    }
+
+    /// Size of the sparse image, for the download command
+    pub fn download_size(&self) -> u64 {
+        self.header.len() as u64 + self.len + self.trailer.len() as u64
+    }
+}
+
+/// Splits a raw image of `size` bytes into sparse images no larger than `max_download_size`
+///
+/// Returns `None` if the download buffer can't even hold a single block, or the image is too
+/// large to be described by a sparse image.
+pub fn split(size: u64, max_download_size: u64) -> Option<Vec<Piece>> {
+    let blocks_per_piece =
+        (max_download_size.checked_sub(MAX_OVERHEAD)? / BLOCK_SIZE).min(MAX_CHUNK_BLOCKS);
+    let total_blocks = size.div_ceil(BLOCK_SIZE);
+    if blocks_per_piece == 0 || total_blocks > u32::MAX as u64 {
+        return None;
+    }
+
+    let piece_size = blocks_per_piece * BLOCK_SIZE;
+    let mut pieces = vec![];
+    let mut offset = 0;
+    while offset < size {
+        let len = piece_size.min(size - offset);
+        pieces.push(Piece::new(offset, len, total_blocks));
+        offset += len;
+    }
+    Some(pieces)
+}
+
+/// Writes a sparse image produced by [split] into `target`, as a device would
+#[cfg(test)]
+pub fn apply(image: &[u8], target: &mut [u8]) {
+    let u16_at = |offset: usize| u16::from_le_bytes(image[offset..offset + 2].try_into().unwrap());
+    let u32_at = |offset: usize| u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap());
+    assert!(is_sparse(image));
+
+    let mut pos = u16_at(8) as usize;
+    let mut block = 0;
+    for _ in 0..u32_at(20) {
+        let blocks = u32_at(pos + 4) as usize;
+        let total = u32_at(pos + 8) as usize;
+        let start = block * BLOCK_SIZE as usize;
+        match u16_at(pos) {
+            CHUNK_TYPE_RAW => {
+                let data = &image[pos + CHUNK_HEADER_SIZE as usize..pos + total];
+                let end = (start + data.len()).min(target.len());
+                target[start..end].copy_from_slice(&data[..end - start]);
+            }
+            CHUNK_TYPE_DONT_CARE => (),
+            other => panic!("unexpected chunk type {other:x}"),
+        }
+        block += blocks;
+        pos += total;
+    }
+    assert_eq!(pos, image.len());
+    assert_eq!(block as u32, u32_at(16));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn magic() {
+        assert!(is_sparse(&[0x3a, 0xff, 0x26, 0xed, 0x01]));
+        assert!(!is_sparse(b"ANDROID!"));
+        assert!(!is_sparse(&[0x3a]));
+    }
+
+    #[test]
+    fn too_small() {
+        assert_eq!(split(1 << 20, MAX_OVERHEAD), None);
+        assert_eq!(split(1 << 20, MAX_OVERHEAD + BLOCK_SIZE - 1), None);
+    }
+
+    #[test]
+    fn pieces() {
+        let max = 2 * BLOCK_SIZE + MAX_OVERHEAD;
+        let size = 5 * BLOCK_SIZE + 100;
+        let pieces = split(size, max).unwrap();
+        assert_eq!(pieces.len(), 3);
+        assert!(pieces.iter().all(|piece| piece.download_size() <= max));
+        assert_eq!(pieces[2].offset, 4 * BLOCK_SIZE);
+        assert_eq!(pieces[2].len, BLOCK_SIZE + 100);
+
+        let image: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let mut target = vec![0xff; 6 * BLOCK_SIZE as usize];
+        for piece in &pieces {
+            let mut sparse = piece.header.clone();
+            sparse.extend_from_slice(&image[piece.offset as usize..][..piece.len as usize]);
+            sparse.extend_from_slice(&piece.trailer);
+            assert_eq!(sparse.len() as u64, piece.download_size());
+            apply(&sparse, &mut target);
+        }
+        assert_eq!(&target[..size as usize], &image);
+        assert!(target[size as usize..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn large_image() {
+        // 6 GiB into a 512 MiB download buffer
+        let pieces = split(6 << 30, 512 << 20).unwrap();
+        assert_eq!(pieces.len(), 13);
+        let covered: u64 = pieces.iter().map(|piece| piece.len).sum();
+        assert_eq!(covered, 6 << 30);
+    }
+
+    #[test]
+    fn huge_download_buffer() {
+        // Raw chunks stay describable with a 32-bit size, however large the buffer
+        let pieces = split(6 << 30, 8 << 30).unwrap();
+        assert_eq!(pieces.len(), 2);
+        for piece in &pieces {
+            let raw = &piece.header[piece.header.len() - CHUNK_HEADER_SIZE as usize..];
+            let total_size = u32::from_le_bytes(raw[8..12].try_into().unwrap());
+            assert_eq!(
+                total_size as u64,
+                CHUNK_HEADER_SIZE as u64 + piece.len.div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+            );
+        }
+    }
+}
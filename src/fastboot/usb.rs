@@ -0,0 +1,135 @@
+//! Fastboot over libusb, for running from the command line rather than the browser
+
+use crate::fastboot::{FastBootError, FastBootOps, TransferStatus};
+use anyhow::anyhow;
+use futures::{AsyncRead, AsyncReadExt};
+use rusb::{Device, DeviceHandle, Direction, GlobalContext, TransferType};
+use std::time::Duration;
+
+/// Long enough for the device to answer commands that erase or write a large partition
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest single bulk OUT transfer while streaming a download
+const STREAM_CHUNK_SIZE: usize = 1024 * 1024;
+
+pub struct FastbootUsb {
+    handle: DeviceHandle<GlobalContext>,
+    serial: String,
+    input_ep: u8,
+    output_ep: u8,
+    status: TransferStatus,
+}
+
+fn transfer_error(err: rusb::Error) -> FastBootError {
+    FastBootError::Transfer(Box::new(err))
+}
+
+/// Number and bulk IN and OUT endpoints of the device's fastboot interface
+fn find_fastboot_interface(device: &Device<GlobalContext>) -> Option<(u8, u8, u8)> {
+    let config = device.active_config_descriptor().ok()?;
+    for iface in config.interfaces() {
+        for alternate in iface.descriptors() {
+            if (
+                alternate.class_code(),
+                alternate.sub_class_code(),
+                alternate.protocol_code(),
+            ) != (0xFF, 0x42, 0x3)
+            {
+                continue;
+            }
+            let endpoint = |direction| {
+                alternate
+                    .endpoint_descriptors()
+                    .find(|ep| {
+                        ep.transfer_type() == TransferType::Bulk && ep.direction() == direction
+                    })
+                    .map(|ep| ep.address())
+            };
+            return Some((
+                alternate.interface_number(),
+                endpoint(Direction::In)?,
+                endpoint(Direction::Out)?,
+            ));
+        }
+    }
+    None
+}
+
+impl FastbootUsb {
+    /// Opens the fastboot device with the given USB serial number, or the first one found
+    pub fn open(serial: Option<&str>) -> anyhow::Result<Self> {
+        for device in rusb::devices()?.iter() {
+            let Some((iface, input_ep, output_ep)) = find_fastboot_interface(&device) else {
+                continue;
+            };
+            let handle = device.open()?;
+            let descriptor = device.device_descriptor()?;
+            let device_serial = handle
+                .read_serial_number_string_ascii(&descriptor)
+                .unwrap_or_default();
+            if serial.is_some_and(|serial| serial != device_serial) {
+                continue;
+            }
+
+            let _ = handle.set_auto_detach_kernel_driver(true);
+            handle.claim_interface(iface)?;
+            return Ok(Self {
+                handle,
+                serial: device_serial,
+                input_ep,
+                output_ep,
+                status: TransferStatus::Ok,
+            });
+        }
+        Err(anyhow!("No fastboot device found"))
+    }
+
+    /// USB serial number of the device, which may be empty
+    pub fn serial(&self) -> &str {
+        &self.serial
+    }
+
+    fn complete<T>(&mut self, result: rusb::Result<T>) -> Result<T, FastBootError> {
+        self.status = match &result {
+            Ok(_) => TransferStatus::Ok,
+            Err(rusb::Error::Pipe) => TransferStatus::Stall,
+            Err(rusb::Error::Overflow) => TransferStatus::Babble,
+            Err(_) => TransferStatus::Error,
+        };
+        result.map_err(transfer_error)
+    }
+}
+
+impl FastBootOps for FastbootUsb {
+    async fn write_out(&mut self, buf: &mut [u8]) -> Result<usize, FastBootError> {
+        let result = self.handle.write_bulk(self.output_ep, buf, TIMEOUT);
+        self.complete(result)
+    }
+
+    async fn write_out_stream<R: AsyncRead + Unpin>(
+        &mut self,
+        mut read: R,
+    ) -> Result<u64, FastBootError> {
+        let mut buf = vec![0; STREAM_CHUNK_SIZE];
+        let mut total = 0;
+        loop {
+            let sz = read
+                .read(&mut buf)
+                .await
+                .map_err(|err| FastBootError::Transfer(err.into()))?;
+            if sz == 0 {
+                return Ok(total);
+            }
+            total += self.write_out(&mut buf[..sz]).await? as u64;
+        }
+    }
+
+    async fn read_in(&mut self, buf: &mut [u8]) -> Result<usize, FastBootError> {
+        let result = self.handle.read_bulk(self.input_ep, buf, TIMEOUT);
+        self.complete(result)
+    }
+
+    fn last_status(&self) -> TransferStatus {
+        self.status
+    }
+}
@@ -1,14 +1,17 @@
-use crate::fastboot::{FastBootError, FastBootOps};
-use crate::js_error;
+use crate::fastboot::pcap::PcapWriter;
+use crate::fastboot::{FastBootError, FastBootOps, TransferStatus};
+use crate::{js_error, now_micros};
 use anyhow::anyhow;
 use futures::{AsyncRead, AsyncReadExt};
 use js_sys::Uint8Array;
+use std::cell::RefCell;
 use std::collections::VecDeque;
+use std::rc::Rc;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
     UsbConfiguration, UsbDevice, UsbDirection, UsbEndpoint, UsbEndpointType, UsbInTransferResult,
-    UsbInterface, UsbOutTransferResult,
+    UsbInterface, UsbOutTransferResult, UsbTransferStatus,
 };
 
 pub struct FastbootWebUsb {
@@ -16,6 +19,17 @@ pub struct FastbootWebUsb {
     input_ep: u8,
     output_ep: u8,
     output_size: usize,
+    status: TransferStatus,
+    capture: Option<Rc<RefCell<PcapWriter>>>,
+}
+
+fn transfer_status(status: UsbTransferStatus) -> TransferStatus {
+    match status {
+        UsbTransferStatus::Ok => TransferStatus::Ok,
+        UsbTransferStatus::Stall => TransferStatus::Stall,
+        UsbTransferStatus::Babble => TransferStatus::Babble,
+        _ => TransferStatus::Error,
+    }
 }
 
 pub fn find_fastboot_interface(device: &UsbDevice) -> Option<(UsbConfiguration, UsbInterface)> {
@@ -76,14 +90,68 @@ impl FastbootWebUsb {
             input_ep: in_ep.endpoint_number(),
             output_ep: out_ep.endpoint_number(),
             output_size: out_ep.packet_size() as _,
+            status: TransferStatus::Ok,
+            capture: None,
             dev,
         })
     }
+
+    /// Captures all bulk transfers into `capture`
+    pub fn with_capture(mut self, capture: Rc<RefCell<PcapWriter>>) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    fn capture_submit(&self, endpoint: u8, length: usize, data: &[u8]) -> Option<u64> {
+        self.capture.as_ref().map(|capture| {
+            capture
+                .borrow_mut()
+                .submit(now_micros(), endpoint, length as u32, data)
+        })
+    }
+
+    /// Waits for a queued OUT transfer to finish
+    async fn complete_out(
+        &mut self,
+        transfer: JsFuture,
+        id: Option<u64>,
+    ) -> Result<usize, FastBootError> {
+        let written = transfer
+            .await
+            .map_err(|err| {
+                let err: gloo::utils::errors::JsError = err.try_into().unwrap();
+                FastBootError::Transfer(err.into())
+            })
+            .map(|res| {
+                let res: UsbOutTransferResult = res.unchecked_into();
+                self.status = transfer_status(res.status());
+                res.bytes_written() as usize
+            });
+        if written.is_err() {
+            self.status = TransferStatus::Error;
+        }
+        self.capture_complete(id, self.output_ep, *written.as_ref().unwrap_or(&0), &[]);
+        written
+    }
+
+    fn capture_complete(&self, id: Option<u64>, endpoint: u8, length: usize, data: &[u8]) {
+        if let (Some(capture), Some(id)) = (&self.capture, id) {
+            capture.borrow_mut().complete(
+                id,
+                now_micros(),
+                endpoint,
+                self.status,
+                length as u32,
+                data,
+            );
+        }
+    }
 }
 
 impl FastBootOps for FastbootWebUsb {
     async fn write_out(&mut self, buf: &mut [u8]) -> Result<usize, FastBootError> {
-        JsFuture::from(
+        let id = self.capture_submit(self.output_ep, buf.len(), buf);
+        let written = JsFuture::from(
             self.dev
                 .transfer_out_with_u8_slice(self.output_ep, buf)
                 .map_err(|err| {
@@ -98,17 +166,23 @@ impl FastBootOps for FastbootWebUsb {
         })
         .map(|res| {
             let res: UsbOutTransferResult = res.unchecked_into();
+            self.status = transfer_status(res.status());
             res.bytes_written() as usize
-        })
+        });
+        if written.is_err() {
+            self.status = TransferStatus::Error;
+        }
+        self.capture_complete(id, self.output_ep, *written.as_ref().unwrap_or(&0), &[]);
+        written
     }
 
     async fn write_out_stream<R: AsyncRead + Unpin>(
         &mut self,
         mut read: R,
-    ) -> Result<usize, FastBootError> {
+    ) -> Result<u64, FastBootError> {
         let mut buf = vec![];
         let mut total = 0;
-        let mut queued: VecDeque<JsFuture> = VecDeque::new();
+        let mut queued: VecDeque<(JsFuture, Option<u64>)> = VecDeque::new();
         buf.resize(self.output_size, 0);
 
         loop {
@@ -121,50 +195,35 @@ impl FastBootOps for FastbootWebUsb {
             }
 
             if queued.len() > 3 {
-                total += queued
-                    .pop_back()
-                    .unwrap()
-                    .await
-                    .map_err(|err| {
-                        let err: gloo::utils::errors::JsError = err.try_into().unwrap();
-                        FastBootError::Transfer(err.into())
-                    })
-                    .map(|res| {
-                        let res: UsbOutTransferResult = res.unchecked_into();
-                        res.bytes_written() as usize
-                    })?;
+                let (transfer, id) = queued.pop_back().unwrap();
+                total += self.complete_out(transfer, id).await? as u64;
             }
 
-            queued.push_front(JsFuture::from(
-                self.dev
-                    .transfer_out_with_u8_slice(self.output_ep, &mut buf[..sz])
-                    .map_err(|err| {
-                        let err: gloo::utils::errors::JsError = err.try_into().unwrap();
-                        FastBootError::Transfer(err.into())
-                    })?,
+            let id = self.capture_submit(self.output_ep, sz, &buf[..sz]);
+            queued.push_front((
+                JsFuture::from(
+                    self.dev
+                        .transfer_out_with_u8_slice(self.output_ep, &mut buf[..sz])
+                        .map_err(|err| {
+                            let err: gloo::utils::errors::JsError = err.try_into().unwrap();
+                            FastBootError::Transfer(err.into())
+                        })?,
+                ),
+                id,
             ));
         }
 
-        while !queued.is_empty() {
-            total += queued
-                .pop_back()
-                .unwrap()
-                .await
-                .map_err(|err| {
-                    let err: gloo::utils::errors::JsError = err.try_into().unwrap();
-                    FastBootError::Transfer(err.into())
-                })
-                .map(|res| {
-                    let res: UsbOutTransferResult = res.unchecked_into();
-                    res.bytes_written() as usize
-                })?;
+        while let Some((transfer, id)) = queued.pop_back() {
+            total += self.complete_out(transfer, id).await? as u64;
         }
 
         Ok(total)
     }
 
     async fn read_in(&mut self, buf: &mut [u8]) -> Result<usize, FastBootError> {
-        JsFuture::from(self.dev.transfer_in(self.input_ep, buf.len() as _))
+        let endpoint = self.input_ep | 0x80;
+        let id = self.capture_submit(endpoint, buf.len(), &[]);
+        let read = JsFuture::from(self.dev.transfer_in(self.input_ep, buf.len() as _))
             .await
             .map_err(|err| {
                 let err: gloo::utils::errors::JsError = err.try_into().unwrap();
@@ -172,7 +231,7 @@ impl FastBootOps for FastbootWebUsb {
             })
             .map(|res| {
                 let res = UsbInTransferResult::unchecked_from_js(res);
-                // TODO: res.status
+                self.status = transfer_status(res.status());
                 let data = res.data();
                 if data.is_none() {
                     return 0;
@@ -181,6 +240,16 @@ impl FastBootOps for FastbootWebUsb {
                 let u8arr = Uint8Array::new(&data.buffer());
                 u8arr.copy_to(&mut buf[0..data.byte_length()]);
                 data.byte_length()
-            })
+            });
+        if read.is_err() {
+            self.status = TransferStatus::Error;
+        }
+        let len = *read.as_ref().unwrap_or(&0);
+        self.capture_complete(id, endpoint, len, &buf[..len]);
+        read
+    }
+
+    fn last_status(&self) -> TransferStatus {
+        self.status
     }
 }
@@ -0,0 +1,104 @@
+use crate::blob::{self, BlobReader};
+use crate::connection::FastbootConnection;
+use crate::fastboot::webusb::FastbootWebUsb;
+use crate::fastboot::{Fastboot, SlotSelection};
+use dioxus::logger::tracing;
+use dioxus::prelude::*;
+use futures::io::BufReader;
+use web_sys::File;
+
+/// Buffer size for reads out of the image file, to keep the number of round trips through JS down
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+
+async fn flash_file(
+    fastboot: &mut Fastboot<FastbootWebUsb>,
+    file: File,
+    partition: &str,
+    selection: SlotSelection,
+    mut status: Signal<Vec<String>>,
+) -> anyhow::Result<()> {
+    let size = file.size() as u64;
+
+    // Every slot needs its own download; the file is simply read again for each.
+    for target in fastboot.slot_targets(partition, selection).await? {
+        status
+            .write()
+            .push(format!("Flashing {} to {target}", file.name()));
+        let reader =
+            BufReader::with_capacity(READ_BUFFER_SIZE, BlobReader::new(file.clone().into()));
+        fastboot.flash_stream(&target, size, reader).await?;
+    }
+    Ok(())
+}
+
+/// Flashes a single image file to a partition
+#[component]
+pub fn FlashImage(conn: FastbootConnection) -> Element {
+    let mut partition = use_signal(String::new);
+    let mut selection = use_signal(SlotSelection::default);
+    let mut status = use_signal(Vec::<String>::new);
+    let mut running = use_signal(|| false);
+
+    let start_flash = move |_| {
+        let conn = conn.clone();
+        async move {
+            let Some(file) = blob::selected_file("flash-image") else {
+                status.set(vec!["Select an image first".to_string()]);
+                return;
+            };
+            status.write().clear();
+            running.set(true);
+            let partition = partition();
+            let result = conn
+                .run(async |fastboot| {
+                    flash_file(fastboot, file, &partition, selection(), status).await
+                })
+                .await;
+            match result {
+                Ok(()) => status.write().push("Done".to_string()),
+                Err(err) => {
+                    tracing::error!("Flashing failed: {}", err);
+                    status.write().push(format!("Failed: {err}"));
+                }
+            }
+            running.set(false);
+        }
+    };
+
+    rsx! {
+        h3 { "Flash image" }
+        input {
+            id: "flash-image",
+            r#type: "file",
+        }
+        input {
+            placeholder: "partition",
+            value: "{partition}",
+            oninput: move |evt| partition.set(evt.value()),
+        }
+        select {
+            onchange: move |evt| {
+                if let Ok(value) = evt.value().parse() {
+                    selection.set(value);
+                }
+            },
+            for value in ["current", "other", "all", "a", "b"] {
+                option {
+                    value: value,
+                    selected: selection().to_string() == value,
+                    "--slot={value}"
+                }
+            }
+        }
+        button {
+            disabled: running() || partition.read().is_empty(),
+            onclick: start_flash,
+            "Flash"
+        }
+        ul {
+            for line in status.read().iter() {
+                li { "{line}" }
+            }
+        }
+    }
+}
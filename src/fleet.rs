@@ -0,0 +1,249 @@
+//! Booting several devices at once: every device runs its own boot pipeline, sharing downloaded
+//! payloads between them.
+
+use crate::{BootTrace, Device};
+use anyhow::anyhow;
+use dioxus::prelude::*;
+use futures::future::{LocalBoxFuture, Shared};
+use futures::FutureExt;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::rc::Rc;
+
+type Payload = Result<Rc<[u8]>, String>;
+
+/// Payloads (e.g. the U-Boot image) fetched for booting, keyed by URL so each is only fetched
+/// once no matter how many devices need it. Fetches that fail are forgotten, so the next device
+/// to ask retries them.
+#[derive(Clone, Default)]
+pub struct PayloadCache {
+    entries: Rc<RefCell<HashMap<String, Shared<LocalBoxFuture<'static, Payload>>>>>,
+}
+
+impl PartialEq for PayloadCache {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.entries, &other.entries)
+    }
+}
+
+impl PayloadCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the payload for `key`, calling `fetch` for it unless it's already cached or being
+    /// fetched for another device.
+    pub async fn get<F, Fut>(&self, key: &str, fetch: F) -> anyhow::Result<Rc<[u8]>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<Vec<u8>>> + 'static,
+    {
+        let payload = self
+            .entries
+            .borrow_mut()
+            .entry(key.to_string())
+            .or_insert_with(|| {
+                let fetch = fetch();
+                async move { fetch.await.map(Rc::from).map_err(|err| format!("{err:#}")) }
+                    .boxed_local()
+                    .shared()
+            })
+            .clone();
+
+        let result = payload.clone().await;
+        if result.is_err() {
+            // Another device may already have evicted this fetch and started a new one
+            let mut entries = self.entries.borrow_mut();
+            if entries
+                .get(key)
+                .is_some_and(|entry| Shared::ptr_eq(entry, &payload))
+            {
+                entries.remove(key);
+            }
+        }
+        result.map_err(|err| anyhow!(err))
+    }
+}
+
+/// Where a device is in its boot pipeline
+#[derive(Debug, Clone, PartialEq)]
+pub enum BootState {
+    Running(String),
+    Booted,
+    Failed(String),
+}
+
+/// Counts of devices in each state, for the fleet overview
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub running: usize,
+    pub booted: usize,
+    pub failed: usize,
+}
+
+impl Summary {
+    pub fn new<'a>(states: impl IntoIterator<Item = &'a BootState>) -> Self {
+        let mut summary = Self::default();
+        for state in states {
+            match state {
+                BootState::Running(_) => summary.running += 1,
+                BootState::Booted => summary.booted += 1,
+                BootState::Failed(_) => summary.failed += 1,
+            }
+        }
+        summary
+    }
+
+    pub fn finished(&self) -> bool {
+        self.running == 0
+    }
+}
+
+/// Status of every device being booted, one row per device
+#[component]
+pub fn Fleet(
+    serials: Vec<String>,
+    states: HashMap<String, BootState>,
+    traces: HashMap<String, BootTrace>,
+    on_close: EventHandler<()>,
+) -> Element {
+    let summary = Summary::new(states.values());
+
+    rsx! {
+        h2 { "Booting {serials.len()} device(s)" }
+        p {
+            "{summary.booted} booted, {summary.failed} failed, {summary.running} in progress"
+        }
+        if summary.finished() {
+            button {
+                onclick: move |_| on_close.call(()),
+                "Done"
+            }
+        }
+        ul {
+            for serial in serials {
+                li {
+                    key: "{serial}",
+                    if let (Some(state), Some(trace)) = (states.get(&serial), traces.get(&serial)) {
+                        Device { serial: serial.clone(), state: state.clone(), trace: trace.clone() }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::channel::oneshot;
+    use futures::executor::block_on;
+    use futures::task::noop_waker_ref;
+    use std::cell::Cell;
+    use std::task::{Context, Poll};
+
+    #[test]
+    fn payload_fetched_once() {
+        let cache = PayloadCache::new();
+        let fetches = Rc::new(Cell::new(0));
+        let fetch = || {
+            let fetches = fetches.clone();
+            async move {
+                fetches.set(fetches.get() + 1);
+                Ok(b"u-boot".to_vec())
+            }
+        };
+
+        let (a, b) = block_on(futures::future::join(
+            cache.get("u-boot.img", fetch),
+            cache.get("u-boot.img", fetch),
+        ));
+        assert_eq!(&*a.unwrap(), b"u-boot");
+        assert_eq!(&*b.unwrap(), b"u-boot");
+        block_on(cache.get("u-boot.img", fetch)).unwrap();
+        assert_eq!(fetches.get(), 1);
+
+        block_on(cache.get("other.img", fetch)).unwrap();
+        assert_eq!(fetches.get(), 2);
+    }
+
+    #[test]
+    fn failed_payload_retried() {
+        let cache = PayloadCache::new();
+        let err = block_on(cache.get("u-boot.img", || async { Err(anyhow!("offline")) }));
+        assert_eq!(err.unwrap_err().to_string(), "offline");
+        let payload = block_on(cache.get("u-boot.img", || async { Ok(vec![1, 2, 3]) }));
+        assert_eq!(&*payload.unwrap(), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn stale_failure_keeps_retry() {
+        let cache = PayloadCache::new();
+        let fetches = Rc::new(Cell::new(0));
+        let (fail_tx, fail_rx) = oneshot::channel::<()>();
+        let (_retry_tx, retry_rx) = oneshot::channel::<()>();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        // Two devices wait on the same fetch, which fails
+        let mut fail_rx = Some(fail_rx);
+        let fetch = || {
+            let fail_rx = fail_rx.take().unwrap();
+            async move {
+                let _ = fail_rx.await;
+                Err(anyhow!("offline"))
+            }
+        };
+        let mut a = cache.get("u-boot.img", fetch).boxed_local();
+        let mut c = cache
+            .get("u-boot.img", || async { unreachable!() })
+            .boxed_local();
+        assert!(a.poll_unpin(&mut cx).is_pending());
+        assert!(c.poll_unpin(&mut cx).is_pending());
+        drop(fail_tx);
+
+        // The first to see the failure evicts it, and another device starts a new fetch
+        assert!(matches!(a.poll_unpin(&mut cx), Poll::Ready(Err(_))));
+        let mut retry_rx = Some(retry_rx);
+        let retry = || {
+            let fetches = fetches.clone();
+            let retry_rx = retry_rx.take().unwrap();
+            async move {
+                fetches.set(fetches.get() + 1);
+                let _ = retry_rx.await;
+                Ok(vec![1, 2, 3])
+            }
+        };
+        let mut b = cache.get("u-boot.img", retry).boxed_local();
+        assert!(b.poll_unpin(&mut cx).is_pending());
+
+        // The second to see the failure must leave the new fetch in place
+        assert!(matches!(c.poll_unpin(&mut cx), Poll::Ready(Err(_))));
+        let mut d = cache
+            .get("u-boot.img", || async { unreachable!() })
+            .boxed_local();
+        assert!(d.poll_unpin(&mut cx).is_pending());
+        assert_eq!(fetches.get(), 1);
+    }
+
+    #[test]
+    fn summary() {
+        let states = [
+            BootState::Running("Detecting".to_string()),
+            BootState::Booted,
+            BootState::Failed("gone".to_string()),
+            BootState::Booted,
+        ];
+        let summary = Summary::new(&states);
+        assert_eq!(
+            summary,
+            Summary {
+                running: 1,
+                booted: 2,
+                failed: 1
+            }
+        );
+        assert!(!summary.finished());
+        assert!(Summary::new(&states[1..]).finished());
+    }
+}
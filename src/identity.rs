@@ -0,0 +1,196 @@
+//! Stable identities for devices, so they can be found again after re-enumerating (e.g. when
+//! rebooting from vendor fastboot into U-Boot).
+//!
+//! The USB serial number is used when the device has one. Some bootloaders (notably a few U-Boot
+//! builds) don't report one, so for those the `serialno` fastboot variable is used instead, which
+//! usually matches the serial number the device reports in other modes. WebUSB doesn't expose
+//! the bus topology, so a device with neither is identified by its descriptors, numbered when
+//! several identical devices are attached at once.
+
+use crate::fastboot::webusb::{find_fastboot_interface, FastbootWebUsb};
+use crate::fastboot::Fastboot;
+use dioxus::logger::tracing;
+use futures::future::{select, Either, LocalBoxFuture, Shared};
+use futures::FutureExt;
+use gloo::timers::future::TimeoutFuture;
+use std::cell::RefCell;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::UsbDevice;
+
+/// How long a device gets to report its `serialno` before it's identified without it
+const QUERY_TIMEOUT_MS: u32 = 3_000;
+
+struct Identity {
+    device: UsbDevice,
+    descriptor: String,
+    /// Tells apart attached devices with the same descriptors, counting from 1
+    instance: usize,
+    id: Shared<LocalBoxFuture<'static, String>>,
+}
+
+thread_local! {
+    /// Identities of attached devices, so each device is only queried once even when several
+    /// boot pipelines are waiting on it
+    static IDENTITIES: RefCell<Vec<Identity>> = const { RefCell::new(vec![]) };
+}
+
+/// Placeholder serial numbers reported by bootloaders that don't know theirs
+fn is_placeholder(serial: &str) -> bool {
+    let serial = serial.trim();
+    serial.is_empty() || serial == "unknown" || serial.chars().all(|c| c == '0')
+}
+
+/// Identity derived from the USB descriptors alone
+fn descriptor_id(
+    vendor_id: u16,
+    product_id: u16,
+    manufacturer: Option<&str>,
+    product: Option<&str>,
+) -> String {
+    format!(
+        "usb:{vendor_id:04x}:{product_id:04x}:{}:{}",
+        manufacturer.unwrap_or_default(),
+        product.unwrap_or_default(),
+    )
+}
+
+/// Identity of the `instance`th attached device with the same descriptors; the first keeps the
+/// plain descriptor identity, so a lone device is found again after it re-enumerates
+fn fallback_id(descriptor: &str, instance: usize) -> String {
+    match instance {
+        1 => descriptor.to_string(),
+        _ => format!("{descriptor}#{instance}"),
+    }
+}
+
+/// Picks a device's identity from what is known about it, most specific first
+fn choose_id(usb_serial: Option<String>, serialno: Option<String>, fallback: String) -> String {
+    usb_serial
+        .into_iter()
+        .chain(serialno)
+        .find(|serial| !is_placeholder(serial))
+        .unwrap_or(fallback)
+}
+
+async fn query_serialno(device: UsbDevice) -> Option<String> {
+    let ops = match FastbootWebUsb::new(device.clone()).await {
+        Ok(ops) => ops,
+        Err(err) => {
+            tracing::warn!("Couldn't open fastboot to identify device: {:#}", err);
+            return None;
+        }
+    };
+    let mut fastboot = Fastboot::new(ops);
+    let answer = {
+        let query = std::pin::pin!(fastboot.get_var("serialno"));
+        match select(query, TimeoutFuture::new(QUERY_TIMEOUT_MS)).await {
+            Either::Left((serialno, _)) => Some(serialno.ok()),
+            Either::Right(_) => None,
+        }
+    };
+    if answer.is_none() {
+        tracing::warn!("Device didn't report its serialno in time");
+        // Closing aborts the transfer left waiting for the answer, which would otherwise take
+        // the first response meant for whoever opens the device next
+        if let Err(err) = JsFuture::from(device.close()).await {
+            tracing::warn!("Couldn't close device after identifying it: {:?}", err);
+        }
+    }
+    answer.flatten()
+}
+
+async fn query_id(device: UsbDevice, fallback: String) -> String {
+    let usb_serial = device.serial_number();
+    if usb_serial
+        .as_deref()
+        .is_some_and(|serial| !is_placeholder(serial))
+    {
+        return choose_id(usb_serial, None, fallback);
+    }
+
+    let mut serialno = None;
+    if find_fastboot_interface(&device).is_some() {
+        serialno = query_serialno(device).await;
+    }
+    choose_id(usb_serial, serialno, fallback)
+}
+
+/// Stable identity for the given device
+pub async fn device_id(device: &UsbDevice) -> String {
+    let id = IDENTITIES.with_borrow_mut(|identities| {
+        if let Some(identity) = identities
+            .iter()
+            .find(|identity| identity.device == *device)
+        {
+            return identity.id.clone();
+        }
+        let descriptor = descriptor_id(
+            device.vendor_id(),
+            device.product_id(),
+            device.manufacturer_name().as_deref(),
+            device.product_name().as_deref(),
+        );
+        let instance = (1..)
+            .find(|&instance| {
+                !identities.iter().any(|identity| {
+                    identity.descriptor == descriptor && identity.instance == instance
+                })
+            })
+            .unwrap();
+        let id = query_id(device.clone(), fallback_id(&descriptor, instance))
+            .boxed_local()
+            .shared();
+        identities.push(Identity {
+            device: device.clone(),
+            descriptor,
+            instance,
+            id: id.clone(),
+        });
+        id
+    });
+    id.await
+}
+
+/// Drops the cached identity of a device that has gone away
+pub fn forget(device: &UsbDevice) {
+    IDENTITIES
+        .with_borrow_mut(|identities| identities.retain(|identity| identity.device != *device));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn placeholders() {
+        assert!(is_placeholder(""));
+        assert!(is_placeholder("unknown"));
+        assert!(is_placeholder("00000000"));
+        assert!(!is_placeholder("a1b2c3d4"));
+    }
+
+    #[test]
+    fn choose() {
+        let descriptor = descriptor_id(0x18d1, 0x4ee0, Some("U-Boot"), None);
+        assert_eq!(descriptor, "usb:18d1:4ee0:U-Boot:");
+
+        assert_eq!(
+            choose_id(Some("abc".into()), Some("def".into()), descriptor.clone()),
+            "abc"
+        );
+        assert_eq!(
+            choose_id(None, Some("def".into()), descriptor.clone()),
+            "def"
+        );
+        assert_eq!(
+            choose_id(Some("".into()), Some("unknown".into()), descriptor.clone()),
+            descriptor
+        );
+    }
+
+    #[test]
+    fn fallback_instances() {
+        assert_eq!(fallback_id("usb:18d1:4ee0::", 1), "usb:18d1:4ee0::");
+        assert_eq!(fallback_id("usb:18d1:4ee0::", 2), "usb:18d1:4ee0::#2");
+    }
+}
@@ -0,0 +1,323 @@
+//! Tools for a device that's running a live OS with adbd: a shell, file transfers, and reaching
+//! TCP ports on the device, all without the device needing a network connection.
+//!
+//! The live OS is told apart from Android by the product name its adbd announces.
+
+use crate::adb::auth::AdbKey;
+use crate::adb::webusb::AdbWebUsb;
+use crate::adb::{Adb, AdbOps};
+use crate::blob::{self, BlobReader, BlobWriter};
+use crate::device_by_id;
+use dioxus::logger::tracing;
+use dioxus::prelude::*;
+use futures::io::BufReader;
+use futures::lock::{MappedMutexGuard, Mutex, MutexGuard};
+use js_sys::Date;
+use std::rc::Rc;
+
+/// Buffer size for reads out of pushed files, to keep the number of round trips through JS down
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+/// Mode pushed files are created with
+const PUSH_MODE: u32 = 0o644;
+
+/// `ro.product.name` the live OS's adbd announces when connecting
+pub const LIVE_PRODUCT_NAME: &str = "bootbud-live";
+
+type LiveAdb = Adb<AdbWebUsb>;
+
+/// Whether the other end of an ADB connection is the live OS, rather than e.g. Android
+pub fn is_live<Ops: AdbOps>(adb: &Adb<Ops>) -> bool {
+    adb.banner_property("ro.product.name") == Some(LIVE_PRODUCT_NAME)
+}
+
+/// The ADB connection to a device, made on first use and shared by all the panes
+#[derive(Clone)]
+struct Connection {
+    serial: String,
+    adb: Rc<Mutex<Option<LiveAdb>>>,
+}
+
+impl PartialEq for Connection {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.adb, &other.adb)
+    }
+}
+
+impl Connection {
+    fn new(serial: String) -> Self {
+        Self {
+            serial,
+            adb: Rc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Waits for any other pane to be done with the connection, connecting if needed
+    async fn lock(
+        &self,
+        mut status: Signal<Vec<String>>,
+    ) -> anyhow::Result<MappedMutexGuard<'_, Option<LiveAdb>, LiveAdb>> {
+        let mut adb = self.adb.lock().await;
+        if adb.is_none() {
+            let device = device_by_id(&self.serial).await?;
+            let key = AdbKey::load_or_generate()?;
+            let ops = AdbWebUsb::new(device).await?;
+            let connected = Adb::connect(ops, &key, || {
+                status
+                    .write()
+                    .push("Waiting for USB debugging to be allowed on the device".to_string())
+            })
+            .await?;
+            if !is_live(&connected) {
+                anyhow::bail!("{} isn't running the live OS", self.serial);
+            }
+            let model = connected.banner_property("ro.product.model");
+            status
+                .write()
+                .push(format!("Connected to {}", model.unwrap_or("device")));
+            *adb = Some(connected);
+        }
+        Ok(MutexGuard::map(adb, |adb| adb.as_mut().unwrap()))
+    }
+
+    /// Drops the connection after a failure, so the next action starts afresh
+    async fn reset(&self) {
+        *self.adb.lock().await = None;
+    }
+}
+
+/// Runs `action` on the connection, reporting failures into `status`
+async fn run<T>(
+    conn: &Connection,
+    mut status: Signal<Vec<String>>,
+    action: impl AsyncFnOnce(&mut LiveAdb) -> anyhow::Result<T>,
+) -> Option<T> {
+    let result = match conn.lock(status).await {
+        Ok(mut adb) => action(&mut adb).await,
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(value) => Some(value),
+        Err(err) => {
+            tracing::error!("ADB action failed: {:#}", err);
+            status.write().push(format!("Failed: {err:#}"));
+            conn.reset().await;
+            None
+        }
+    }
+}
+
+/// Shell, file transfer and port access for a live booted device
+#[component]
+pub fn LiveSession(serial: String, on_close: EventHandler<()>) -> Element {
+    let conn = use_hook({
+        to_owned![serial];
+        move || Connection::new(serial)
+    });
+
+    rsx! {
+        h2 { "{serial}" }
+        button {
+            onclick: move |_| on_close.call(()),
+            "Back"
+        }
+        Shell { conn: conn.clone() }
+        Files { conn: conn.clone() }
+        Relay { conn }
+    }
+}
+
+/// Runs one command at a time, keeping a transcript of everything run so far
+#[component]
+fn Shell(conn: Connection) -> Element {
+    let mut command = use_signal(String::new);
+    let mut transcript = use_signal(String::new);
+    let status = use_signal(Vec::<String>::new);
+    let mut running = use_signal(|| false);
+
+    let run_command = move |_| {
+        to_owned![conn];
+        async move {
+            let line = command();
+            running.set(true);
+            transcript.write().push_str(&format!("$ {line}\n"));
+            if let Some(output) = run(&conn, status, async |adb| Ok(adb.shell(&line).await?)).await
+            {
+                transcript.write().push_str(&output);
+            }
+            command.set(String::new());
+            running.set(false);
+        }
+    };
+
+    rsx! {
+        h3 { "Shell" }
+        pre { "{transcript}" }
+        input {
+            placeholder: "command",
+            value: "{command}",
+            oninput: move |evt| command.set(evt.value()),
+        }
+        button {
+            disabled: running() || command.read().is_empty(),
+            onclick: run_command,
+            "Run"
+        }
+        ul {
+            for line in status.read().iter() {
+                li { "{line}" }
+            }
+        }
+    }
+}
+
+/// Pushes a selected file into a directory on the device, or pulls a file off it as a download
+#[component]
+fn Files(conn: Connection) -> Element {
+    let mut directory = use_signal(|| "/tmp".to_string());
+    let mut pull_path = use_signal(String::new);
+    let mut status = use_signal(Vec::<String>::new);
+    let mut running = use_signal(|| false);
+
+    let push = {
+        to_owned![conn];
+        move |_| {
+            to_owned![conn];
+            async move {
+                let Some(file) = blob::selected_file("live-push-file") else {
+                    status.set(vec!["Select a file first".to_string()]);
+                    return;
+                };
+                let path = format!("{}/{}", directory.read().trim_end_matches('/'), file.name());
+                status.set(vec![format!("Pushing {path}")]);
+                running.set(true);
+                let mtime = (Date::now() / 1000.0) as u32;
+                let reader =
+                    BufReader::with_capacity(READ_BUFFER_SIZE, BlobReader::new(file.into()));
+                let pushed = run(&conn, status, async |adb| {
+                    Ok(adb.push(reader, &path, PUSH_MODE, mtime).await?)
+                })
+                .await;
+                if let Some(pushed) = pushed {
+                    status.write().push(format!("Pushed {pushed} bytes"));
+                }
+                running.set(false);
+            }
+        }
+    };
+
+    let pull = move |_| {
+        to_owned![conn];
+        async move {
+            let path = pull_path();
+            status.set(vec![format!("Pulling {path}")]);
+            running.set(true);
+            let pulled = run(&conn, status, async |adb| {
+                let mut writer = BlobWriter::new();
+                let pulled = adb.pull(&path, &mut writer).await?;
+                let filename = path.rsplit('/').next().unwrap_or_default();
+                blob::save(&writer.into_blob()?, filename)?;
+                Ok(pulled)
+            })
+            .await;
+            if let Some(pulled) = pulled {
+                status.write().push(format!("Pulled {pulled} bytes"));
+            }
+            running.set(false);
+        }
+    };
+
+    rsx! {
+        h3 { "Files" }
+        input {
+            id: "live-push-file",
+            r#type: "file",
+        }
+        input {
+            placeholder: "directory",
+            value: "{directory}",
+            oninput: move |evt| directory.set(evt.value()),
+        }
+        button {
+            disabled: running() || directory.read().is_empty(),
+            onclick: push,
+            "Push"
+        }
+        br {}
+        input {
+            placeholder: "path on device",
+            value: "{pull_path}",
+            oninput: move |evt| pull_path.set(evt.value()),
+        }
+        button {
+            disabled: running() || pull_path.read().is_empty(),
+            onclick: pull,
+            "Pull"
+        }
+        ul {
+            for line in status.read().iter() {
+                li { "{line}" }
+            }
+        }
+    }
+}
+
+/// Sends a request to a TCP port on the device and shows what comes back, e.g. to poke at a web
+/// server running on the live OS
+#[component]
+fn Relay(conn: Connection) -> Element {
+    let mut port = use_signal(|| "80".to_string());
+    let mut request = use_signal(|| "GET / HTTP/1.0\r\n\r\n".to_string());
+    let mut response = use_signal(String::new);
+    let mut status = use_signal(Vec::<String>::new);
+    let mut running = use_signal(|| false);
+
+    let send = move |_| {
+        to_owned![conn];
+        async move {
+            let Ok(port) = port.read().parse::<u16>() else {
+                status.set(vec!["Invalid port".to_string()]);
+                return;
+            };
+            status.write().clear();
+            response.set(String::new());
+            running.set(true);
+            let request = request();
+            let data = run(&conn, status, async |adb| {
+                Ok(adb.relay(port, request.as_bytes()).await?)
+            })
+            .await;
+            if let Some(data) = data {
+                response.set(String::from_utf8_lossy(&data).into_owned());
+            }
+            running.set(false);
+        }
+    };
+
+    rsx! {
+        h3 { "TCP relay" }
+        p {
+            "Sends the request to a port on the device and shows the response once the "
+            "connection closes. This is a single exchange, not port forwarding."
+        }
+        input {
+            placeholder: "port",
+            value: "{port}",
+            oninput: move |evt| port.set(evt.value()),
+        }
+        textarea {
+            value: "{request}",
+            oninput: move |evt| request.set(evt.value()),
+        }
+        button {
+            disabled: running(),
+            onclick: send,
+            "Send"
+        }
+        pre { "{response}" }
+        ul {
+            for line in status.read().iter() {
+                li { "{line}" }
+            }
+        }
+    }
+}
@@ -0,0 +1,917 @@
+//! Reading and writing of liblp ("logical partition") metadata, which describes the dynamic
+//! partitions (system, vendor, product...) living inside the `super` partition.
+//!
+//! All structures are little-endian and packed, see AOSP's `liblp/metadata_format.h`.
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Space reserved at the start of super for boot sectors and the like
+const PARTITION_RESERVED_BYTES: usize = 4096;
+/// Space taken by each of the two geometry copies
+const GEOMETRY_SIZE: usize = 4096;
+const GEOMETRY_MAGIC: u32 = 0x616c4467;
+const GEOMETRY_STRUCT_SIZE: usize = 52;
+
+const HEADER_MAGIC: u32 = 0x414c5030;
+const MAJOR_VERSION: u16 = 10;
+const MAX_MINOR_VERSION: u16 = 2;
+const HEADER_V1_0_SIZE: usize = 128;
+const HEADER_V1_2_SIZE: usize = 256;
+/// Offset and length of the header checksum within the header
+const HEADER_CHECKSUM: std::ops::Range<usize> = 12..44;
+
+const NAME_LEN: usize = 36;
+const PARTITION_ENTRY_SIZE: usize = 52;
+const EXTENT_ENTRY_SIZE: usize = 24;
+const GROUP_ENTRY_SIZE: usize = 48;
+const BLOCK_DEVICE_ENTRY_SIZE: usize = 64;
+
+const TARGET_TYPE_LINEAR: u32 = 0;
+const TARGET_TYPE_ZERO: u32 = 1;
+
+pub const PARTITION_ATTR_READONLY: u32 = 1 << 0;
+
+/// Errors parsing or editing LP metadata
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum LpError {
+    #[error("Metadata is truncated")]
+    Truncated,
+    #[error("Bad {0} magic")]
+    BadMagic(&'static str),
+    #[error("Bad {0} checksum")]
+    BadChecksum(&'static str),
+    #[error("Unsupported metadata version {0}.{1}")]
+    UnsupportedVersion(u16, u16),
+    #[error("Invalid metadata: {0}")]
+    Invalid(&'static str),
+    #[error("Metadata doesn't fit in {0} bytes")]
+    TooLarge(u32),
+    #[error("Name is too long: {0}")]
+    NameTooLong(String),
+    #[error("Partition {0} already exists")]
+    PartitionExists(String),
+    #[error("No such partition: {0}")]
+    UnknownPartition(String),
+    #[error("No such group: {0}")]
+    UnknownGroup(String),
+    #[error("Group {0} has no room for another {1} bytes")]
+    GroupFull(String, u64),
+    #[error("Not enough free space for {0} bytes")]
+    NoSpace(u64),
+}
+
+/// Little-endian reader over a metadata buffer
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], LpError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(LpError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, LpError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, LpError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, LpError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn name(&mut self) -> Result<String, LpError> {
+        let raw = self.bytes(NAME_LEN)?;
+        let len = raw.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+        Ok(String::from_utf8_lossy(&raw[..len]).into_owned())
+    }
+}
+
+fn put_name(out: &mut Vec<u8>, name: &str) {
+    let mut raw = [0; NAME_LEN];
+    raw[..name.len()].copy_from_slice(name.as_bytes());
+    out.extend_from_slice(&raw);
+}
+
+fn check_name(name: &str) -> Result<(), LpError> {
+    if name.len() > NAME_LEN {
+        return Err(LpError::NameTooLong(name.to_string()));
+    }
+    Ok(())
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Layout of the metadata area, stored twice near the start of super
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    /// Space reserved for each copy of the metadata
+    pub metadata_max_size: u32,
+    /// Number of metadata copies (one per slot)
+    pub metadata_slot_count: u32,
+    pub logical_block_size: u32,
+}
+
+impl Geometry {
+    pub fn parse(data: &[u8]) -> Result<Self, LpError> {
+        let mut cursor = Cursor::new(data);
+        if cursor.u32()? != GEOMETRY_MAGIC {
+            return Err(LpError::BadMagic("geometry"));
+        }
+        if cursor.u32()? as usize != GEOMETRY_STRUCT_SIZE {
+            return Err(LpError::Invalid("geometry struct size"));
+        }
+        let checksum = cursor.bytes(32)?;
+        let geometry = Geometry {
+            metadata_max_size: cursor.u32()?,
+            metadata_slot_count: cursor.u32()?,
+            logical_block_size: cursor.u32()?,
+        };
+
+        let mut raw = data[..GEOMETRY_STRUCT_SIZE].to_vec();
+        raw[8..40].fill(0);
+        if sha256(&raw) != checksum {
+            return Err(LpError::BadChecksum("geometry"));
+        }
+        if geometry.metadata_slot_count == 0
+            || geometry.logical_block_size == 0
+            || !(geometry.logical_block_size as u64).is_multiple_of(SECTOR_SIZE)
+            || !(geometry.metadata_max_size as u64).is_multiple_of(SECTOR_SIZE)
+        {
+            return Err(LpError::Invalid("geometry"));
+        }
+        Ok(geometry)
+    }
+
+    /// Serialize, padded to the space a geometry copy occupies
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(GEOMETRY_SIZE);
+        out.extend_from_slice(&GEOMETRY_MAGIC.to_le_bytes());
+        out.extend_from_slice(&(GEOMETRY_STRUCT_SIZE as u32).to_le_bytes());
+        out.extend_from_slice(&[0; 32]);
+        out.extend_from_slice(&self.metadata_max_size.to_le_bytes());
+        out.extend_from_slice(&self.metadata_slot_count.to_le_bytes());
+        out.extend_from_slice(&self.logical_block_size.to_le_bytes());
+        let checksum = sha256(&out);
+        out[8..40].copy_from_slice(&checksum);
+        out.resize(GEOMETRY_SIZE, 0);
+        out
+    }
+
+    /// Offset of the primary metadata copy for a slot, relative to the start of super
+    pub fn metadata_offset(&self, slot: u32) -> u64 {
+        (PARTITION_RESERVED_BYTES + 2 * GEOMETRY_SIZE) as u64
+            + slot as u64 * self.metadata_max_size as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtentTarget {
+    /// Maps onto a range of sectors of a block device
+    Linear { block_device: u32, sector: u64 },
+    /// Reads back as zeroes
+    Zero,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub num_sectors: u64,
+    pub target: ExtentTarget,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub name: String,
+    pub attributes: u32,
+    pub group_index: u32,
+    pub extents: Vec<Extent>,
+}
+
+impl Partition {
+    pub fn size(&self) -> u64 {
+        self.extents.iter().map(|e| e.num_sectors).sum::<u64>() * SECTOR_SIZE
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub name: String,
+    pub flags: u32,
+    /// Combined size limit of the group's partitions, 0 for unlimited
+    pub maximum_size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockDevice {
+    /// First sector usable for partition extents
+    pub first_logical_sector: u64,
+    pub alignment: u32,
+    pub alignment_offset: u32,
+    pub size: u64,
+    pub partition_name: String,
+    pub flags: u32,
+}
+
+/// A table descriptor in the metadata header
+struct TableDescriptor {
+    offset: u32,
+    num_entries: u32,
+    entry_size: u32,
+}
+
+impl TableDescriptor {
+    fn parse(cursor: &mut Cursor) -> Result<Self, LpError> {
+        Ok(Self {
+            offset: cursor.u32()?,
+            num_entries: cursor.u32()?,
+            entry_size: cursor.u32()?,
+        })
+    }
+
+    /// The table's entries out of the tables area
+    fn entries<'a>(&self, tables: &'a [u8], entry_size: usize) -> Result<Vec<&'a [u8]>, LpError> {
+        if self.entry_size as usize != entry_size {
+            return Err(LpError::Invalid("table entry size"));
+        }
+        let start = self.offset as usize;
+        let end = (self.num_entries as usize)
+            .checked_mul(entry_size)
+            .and_then(|len| len.checked_add(start))
+            .ok_or(LpError::Invalid("table size"))?;
+        let table = tables.get(start..end).ok_or(LpError::Truncated)?;
+        Ok(table.chunks(entry_size).collect())
+    }
+
+    fn write(out: &mut Vec<u8>, offset: usize, num_entries: usize, entry_size: usize) {
+        out.extend_from_slice(&(offset as u32).to_le_bytes());
+        out.extend_from_slice(&(num_entries as u32).to_le_bytes());
+        out.extend_from_slice(&(entry_size as u32).to_le_bytes());
+    }
+}
+
+/// One copy of the partition metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub geometry: Geometry,
+    pub minor_version: u16,
+    /// Header flags, only present from version 10.2
+    pub flags: u32,
+    pub partitions: Vec<Partition>,
+    pub groups: Vec<Group>,
+    pub block_devices: Vec<BlockDevice>,
+}
+
+impl Metadata {
+    /// Parse a metadata copy
+    pub fn parse(geometry: Geometry, data: &[u8]) -> Result<Self, LpError> {
+        let mut cursor = Cursor::new(data);
+        if cursor.u32()? != HEADER_MAGIC {
+            return Err(LpError::BadMagic("metadata header"));
+        }
+        let major_version = cursor.u16()?;
+        let minor_version = cursor.u16()?;
+        if major_version != MAJOR_VERSION || minor_version > MAX_MINOR_VERSION {
+            return Err(LpError::UnsupportedVersion(major_version, minor_version));
+        }
+        let header_size = cursor.u32()? as usize;
+        let expected_header_size = if minor_version >= 2 {
+            HEADER_V1_2_SIZE
+        } else {
+            HEADER_V1_0_SIZE
+        };
+        if header_size != expected_header_size {
+            return Err(LpError::Invalid("header size"));
+        }
+        let header_checksum = cursor.bytes(32)?;
+        let tables_size = cursor.u32()? as usize;
+        let tables_checksum = cursor.bytes(32)?;
+        let partitions = TableDescriptor::parse(&mut cursor)?;
+        let extents = TableDescriptor::parse(&mut cursor)?;
+        let groups = TableDescriptor::parse(&mut cursor)?;
+        let block_devices = TableDescriptor::parse(&mut cursor)?;
+        let flags = if minor_version >= 2 { cursor.u32()? } else { 0 };
+
+        let mut header = data.get(..header_size).ok_or(LpError::Truncated)?.to_vec();
+        header[HEADER_CHECKSUM].fill(0);
+        if sha256(&header) != header_checksum {
+            return Err(LpError::BadChecksum("metadata header"));
+        }
+        let metadata_size = header_size
+            .checked_add(tables_size)
+            .ok_or(LpError::Invalid("tables size"))?;
+        if metadata_size > geometry.metadata_max_size as usize {
+            return Err(LpError::TooLarge(geometry.metadata_max_size));
+        }
+        let tables = data
+            .get(header_size..metadata_size)
+            .ok_or(LpError::Truncated)?;
+        if sha256(tables) != tables_checksum {
+            return Err(LpError::BadChecksum("metadata tables"));
+        }
+
+        let mut all_extents = vec![];
+        for raw in extents.entries(tables, EXTENT_ENTRY_SIZE)? {
+            let mut cursor = Cursor::new(raw);
+            let num_sectors = cursor.u64()?;
+            let target_type = cursor.u32()?;
+            let target_data = cursor.u64()?;
+            let target_source = cursor.u32()?;
+            let target = match target_type {
+                TARGET_TYPE_LINEAR => ExtentTarget::Linear {
+                    block_device: target_source,
+                    sector: target_data,
+                },
+                TARGET_TYPE_ZERO => ExtentTarget::Zero,
+                _ => return Err(LpError::Invalid("extent target type")),
+            };
+            all_extents.push(Extent {
+                num_sectors,
+                target,
+            });
+        }
+
+        let mut all_groups = vec![];
+        for raw in groups.entries(tables, GROUP_ENTRY_SIZE)? {
+            let mut cursor = Cursor::new(raw);
+            all_groups.push(Group {
+                name: cursor.name()?,
+                flags: cursor.u32()?,
+                maximum_size: cursor.u64()?,
+            });
+        }
+
+        let mut all_block_devices = vec![];
+        for raw in block_devices.entries(tables, BLOCK_DEVICE_ENTRY_SIZE)? {
+            let mut cursor = Cursor::new(raw);
+            all_block_devices.push(BlockDevice {
+                first_logical_sector: cursor.u64()?,
+                alignment: cursor.u32()?,
+                alignment_offset: cursor.u32()?,
+                size: cursor.u64()?,
+                partition_name: cursor.name()?,
+                flags: cursor.u32()?,
+            });
+        }
+        if all_block_devices.is_empty() {
+            return Err(LpError::Invalid("no block devices"));
+        }
+
+        let mut all_partitions = vec![];
+        for raw in partitions.entries(tables, PARTITION_ENTRY_SIZE)? {
+            let mut cursor = Cursor::new(raw);
+            let name = cursor.name()?;
+            let attributes = cursor.u32()?;
+            let first_extent = cursor.u32()? as usize;
+            let num_extents = cursor.u32()? as usize;
+            let group_index = cursor.u32()?;
+            let extents = all_extents
+                .get(first_extent..first_extent.saturating_add(num_extents))
+                .ok_or(LpError::Invalid("partition extents"))?
+                .to_vec();
+            if group_index as usize >= all_groups.len() {
+                return Err(LpError::Invalid("partition group"));
+            }
+            all_partitions.push(Partition {
+                name,
+                attributes,
+                group_index,
+                extents,
+            });
+        }
+
+        Ok(Metadata {
+            geometry,
+            minor_version,
+            flags,
+            partitions: all_partitions,
+            groups: all_groups,
+            block_devices: all_block_devices,
+        })
+    }
+
+    /// Serialize a metadata copy, with fresh checksums
+    pub fn to_bytes(&self) -> Result<Vec<u8>, LpError> {
+        let mut partitions = vec![];
+        let mut extents = vec![];
+        let mut num_extents = 0;
+        for partition in &self.partitions {
+            check_name(&partition.name)?;
+            put_name(&mut partitions, &partition.name);
+            partitions.extend_from_slice(&partition.attributes.to_le_bytes());
+            partitions.extend_from_slice(&(num_extents as u32).to_le_bytes());
+            partitions.extend_from_slice(&(partition.extents.len() as u32).to_le_bytes());
+            partitions.extend_from_slice(&partition.group_index.to_le_bytes());
+
+            for extent in &partition.extents {
+                let (target_type, target_data, target_source) = match extent.target {
+                    ExtentTarget::Linear {
+                        block_device,
+                        sector,
+                    } => (TARGET_TYPE_LINEAR, sector, block_device),
+                    ExtentTarget::Zero => (TARGET_TYPE_ZERO, 0, 0),
+                };
+                extents.extend_from_slice(&extent.num_sectors.to_le_bytes());
+                extents.extend_from_slice(&target_type.to_le_bytes());
+                extents.extend_from_slice(&target_data.to_le_bytes());
+                extents.extend_from_slice(&target_source.to_le_bytes());
+            }
+            num_extents += partition.extents.len();
+        }
+
+        let mut groups = vec![];
+        for group in &self.groups {
+            check_name(&group.name)?;
+            put_name(&mut groups, &group.name);
+            groups.extend_from_slice(&group.flags.to_le_bytes());
+            groups.extend_from_slice(&group.maximum_size.to_le_bytes());
+        }
+
+        let mut block_devices = vec![];
+        for device in &self.block_devices {
+            check_name(&device.partition_name)?;
+            block_devices.extend_from_slice(&device.first_logical_sector.to_le_bytes());
+            block_devices.extend_from_slice(&device.alignment.to_le_bytes());
+            block_devices.extend_from_slice(&device.alignment_offset.to_le_bytes());
+            block_devices.extend_from_slice(&device.size.to_le_bytes());
+            put_name(&mut block_devices, &device.partition_name);
+            block_devices.extend_from_slice(&device.flags.to_le_bytes());
+        }
+
+        let tables = [partitions.as_slice(), &extents, &groups, &block_devices].concat();
+
+        let header_size = if self.minor_version >= 2 {
+            HEADER_V1_2_SIZE
+        } else {
+            HEADER_V1_0_SIZE
+        };
+        let metadata_size = header_size
+            .checked_add(tables.len())
+            .ok_or(LpError::TooLarge(self.geometry.metadata_max_size))?;
+        if metadata_size > self.geometry.metadata_max_size as usize {
+            return Err(LpError::TooLarge(self.geometry.metadata_max_size));
+        }
+
+        let mut out = Vec::with_capacity(metadata_size);
+        out.extend_from_slice(&HEADER_MAGIC.to_le_bytes());
+        out.extend_from_slice(&MAJOR_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.minor_version.to_le_bytes());
+        out.extend_from_slice(&(header_size as u32).to_le_bytes());
+        out.extend_from_slice(&[0; 32]);
+        out.extend_from_slice(&(tables.len() as u32).to_le_bytes());
+        out.extend_from_slice(&sha256(&tables));
+        let mut offset = 0;
+        for (len, entry_size) in [
+            (partitions.len(), PARTITION_ENTRY_SIZE),
+            (extents.len(), EXTENT_ENTRY_SIZE),
+            (groups.len(), GROUP_ENTRY_SIZE),
+            (block_devices.len(), BLOCK_DEVICE_ENTRY_SIZE),
+        ] {
+            TableDescriptor::write(&mut out, offset, len / entry_size, entry_size);
+            offset += len;
+        }
+        if self.minor_version >= 2 {
+            out.extend_from_slice(&self.flags.to_le_bytes());
+        }
+        out.resize(header_size, 0);
+        let checksum = sha256(&out);
+        out[HEADER_CHECKSUM].copy_from_slice(&checksum);
+
+        out.extend_from_slice(&tables);
+        Ok(out)
+    }
+
+    pub fn partition(&self, name: &str) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.name == name)
+    }
+
+    fn group_index(&self, name: &str) -> Result<u32, LpError> {
+        self.groups
+            .iter()
+            .position(|g| g.name == name)
+            .map(|i| i as u32)
+            .ok_or_else(|| LpError::UnknownGroup(name.to_string()))
+    }
+
+    /// Round a size up to the logical block size
+    fn align_size(&self, size: u64) -> u64 {
+        size.div_ceil(self.geometry.logical_block_size as u64)
+            * self.geometry.logical_block_size as u64
+    }
+
+    /// Unused sector ranges (start, length) of the first block device, where new extents are
+    /// allocated from
+    pub fn free_regions(&self) -> Vec<(u64, u64)> {
+        let device = &self.block_devices[0];
+        let mut used: Vec<(u64, u64)> = self
+            .partitions
+            .iter()
+            .flat_map(|p| &p.extents)
+            .filter_map(|e| match e.target {
+                ExtentTarget::Linear {
+                    block_device: 0,
+                    sector,
+                } => Some((sector, e.num_sectors)),
+                _ => None,
+            })
+            .collect();
+        used.sort();
+
+        let mut free = vec![];
+        let mut pos = device.first_logical_sector;
+        let end = device.size / SECTOR_SIZE;
+        for (start, len) in used {
+            if start > pos {
+                free.push((pos, start - pos));
+            }
+            pos = pos.max(start + len);
+        }
+        if end > pos {
+            free.push((pos, end - pos));
+        }
+        free
+    }
+
+    /// Total unallocated space in bytes
+    pub fn free_space(&self) -> u64 {
+        self.free_regions().iter().map(|(_, len)| len).sum::<u64>() * SECTOR_SIZE
+    }
+
+    /// Allocate extents covering `sectors` out of free space, honouring the block device's
+    /// alignment
+    fn allocate(&self, sectors: u64) -> Result<Vec<Extent>, LpError> {
+        let device = &self.block_devices[0];
+        let alignment =
+            (device.alignment as u64).max(self.geometry.logical_block_size as u64) / SECTOR_SIZE;
+        let alignment_offset = device.alignment_offset as u64 / SECTOR_SIZE;
+
+        let mut extents = vec![];
+        let mut remaining = sectors;
+        for (start, len) in self.free_regions() {
+            let aligned = if alignment > 0 {
+                (start.saturating_sub(alignment_offset)).div_ceil(alignment) * alignment
+                    + alignment_offset
+            } else {
+                start
+            };
+            let available = (start + len).saturating_sub(aligned);
+            if available == 0 {
+                continue;
+            }
+            let take = available.min(remaining);
+            extents.push(Extent {
+                num_sectors: take,
+                target: ExtentTarget::Linear {
+                    block_device: 0,
+                    sector: aligned,
+                },
+            });
+            remaining -= take;
+            if remaining == 0 {
+                return Ok(extents);
+            }
+        }
+        Err(LpError::NoSpace(sectors * SECTOR_SIZE))
+    }
+
+    /// Verify `group` can hold another `size` bytes
+    fn check_group_room(&self, group_index: u32, size: u64) -> Result<(), LpError> {
+        let group = &self.groups[group_index as usize];
+        if group.maximum_size == 0 {
+            return Ok(());
+        }
+        let used: u64 = self
+            .partitions
+            .iter()
+            .filter(|p| p.group_index == group_index)
+            .map(Partition::size)
+            .sum();
+        if used + size > group.maximum_size {
+            return Err(LpError::GroupFull(group.name.clone(), size));
+        }
+        Ok(())
+    }
+
+    /// Add a partition of the given size to a group, allocating it from free space
+    pub fn add_partition(
+        &mut self,
+        name: &str,
+        group: &str,
+        size: u64,
+        attributes: u32,
+    ) -> Result<(), LpError> {
+        check_name(name)?;
+        if self.partition(name).is_some() {
+            return Err(LpError::PartitionExists(name.to_string()));
+        }
+        let group_index = self.group_index(group)?;
+        let size = self.align_size(size);
+        self.check_group_room(group_index, size)?;
+        let extents = self.allocate(size / SECTOR_SIZE)?;
+        self.partitions.push(Partition {
+            name: name.to_string(),
+            attributes,
+            group_index,
+            extents,
+        });
+        Ok(())
+    }
+
+    pub fn remove_partition(&mut self, name: &str) -> Result<(), LpError> {
+        let index = self
+            .partitions
+            .iter()
+            .position(|p| p.name == name)
+            .ok_or_else(|| LpError::UnknownPartition(name.to_string()))?;
+        self.partitions.remove(index);
+        Ok(())
+    }
+
+    /// Grow or shrink a partition, keeping its existing extents in place
+    pub fn resize_partition(&mut self, name: &str, size: u64) -> Result<(), LpError> {
+        let size = self.align_size(size);
+        let index = self
+            .partitions
+            .iter()
+            .position(|p| p.name == name)
+            .ok_or_else(|| LpError::UnknownPartition(name.to_string()))?;
+        let current = self.partitions[index].size();
+
+        if size > current {
+            self.check_group_room(self.partitions[index].group_index, size - current)?;
+            let extents = self.allocate((size - current) / SECTOR_SIZE)?;
+            self.partitions[index].extents.extend(extents);
+        } else {
+            let mut remaining = size / SECTOR_SIZE;
+            let extents = &mut self.partitions[index].extents;
+            extents.retain_mut(|extent| {
+                if remaining == 0 {
+                    return false;
+                }
+                extent.num_sectors = extent.num_sectors.min(remaining);
+                remaining -= extent.num_sectors;
+                true
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Parse a metadata-only image, like the super_empty.img shipped in image zips: the geometry
+/// followed immediately by the metadata
+pub fn parse_image(data: &[u8]) -> Result<Metadata, LpError> {
+    let geometry = Geometry::parse(data)?;
+    Metadata::parse(
+        geometry,
+        data.get(GEOMETRY_SIZE..).ok_or(LpError::Truncated)?,
+    )
+}
+
+/// Write a metadata-only image, in the same layout [parse_image] reads
+pub fn write_image(metadata: &Metadata) -> Result<Vec<u8>, LpError> {
+    let mut out = metadata.geometry.to_bytes();
+    out.extend(metadata.to_bytes()?);
+    let block_size = metadata.geometry.logical_block_size as usize;
+    out.resize(out.len().div_ceil(block_size) * block_size, 0);
+    Ok(out)
+}
+
+/// Parse the metadata for a slot out of the start of a super partition
+pub fn parse_super(data: &[u8], slot: u32) -> Result<Metadata, LpError> {
+    let geometry = Geometry::parse(
+        data.get(PARTITION_RESERVED_BYTES..)
+            .ok_or(LpError::Truncated)?,
+    )?;
+    let offset = usize::try_from(geometry.metadata_offset(slot)).map_err(|_| LpError::Truncated)?;
+    Metadata::parse(geometry, data.get(offset..).ok_or(LpError::Truncated)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn metadata() -> Metadata {
+        Metadata {
+            geometry: Geometry {
+                metadata_max_size: 65536,
+                metadata_slot_count: 2,
+                logical_block_size: 4096,
+            },
+            minor_version: 0,
+            flags: 0,
+            partitions: vec![],
+            groups: vec![
+                Group {
+                    name: "default".to_string(),
+                    flags: 0,
+                    maximum_size: 0,
+                },
+                Group {
+                    name: "main".to_string(),
+                    flags: 0,
+                    maximum_size: 64 * MIB,
+                },
+            ],
+            block_devices: vec![BlockDevice {
+                first_logical_sector: 2048,
+                alignment: 1024 * 1024,
+                alignment_offset: 0,
+                size: 128 * MIB,
+                partition_name: "super".to_string(),
+                flags: 0,
+            }],
+        }
+    }
+
+    #[test]
+    fn geometry_roundtrip() {
+        let geometry = metadata().geometry;
+        let bytes = geometry.to_bytes();
+        assert_eq!(bytes.len(), GEOMETRY_SIZE);
+        assert_eq!(Geometry::parse(&bytes), Ok(geometry));
+    }
+
+    #[test]
+    fn geometry_bad_checksum() {
+        let mut bytes = metadata().geometry.to_bytes();
+        bytes[40] ^= 1;
+        assert_eq!(
+            Geometry::parse(&bytes),
+            Err(LpError::BadChecksum("geometry"))
+        );
+    }
+
+    #[test]
+    fn image_roundtrip() {
+        let mut metadata = metadata();
+        metadata
+            .add_partition("system", "main", 10 * MIB, PARTITION_ATTR_READONLY)
+            .unwrap();
+        metadata
+            .add_partition("vendor", "main", 5 * MIB, PARTITION_ATTR_READONLY)
+            .unwrap();
+        let image = write_image(&metadata).unwrap();
+        assert_eq!(image.len() % 4096, 0);
+        assert_eq!(parse_image(&image), Ok(metadata));
+    }
+
+    #[test]
+    fn v1_2_roundtrip() {
+        let mut metadata = metadata();
+        metadata.minor_version = 2;
+        metadata.flags = 1;
+        let bytes = metadata.to_bytes().unwrap();
+        assert_eq!(
+            u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize,
+            HEADER_V1_2_SIZE
+        );
+        assert_eq!(Metadata::parse(metadata.geometry, &bytes), Ok(metadata));
+    }
+
+    #[test]
+    fn tables_bad_checksum() {
+        let mut metadata = metadata();
+        metadata.add_partition("system", "main", MIB, 0).unwrap();
+        let mut bytes = metadata.to_bytes().unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert_eq!(
+            Metadata::parse(metadata.geometry, &bytes),
+            Err(LpError::BadChecksum("metadata tables"))
+        );
+    }
+
+    #[test]
+    fn table_size_overflow() {
+        let metadata = metadata();
+        let mut bytes = metadata.to_bytes().unwrap();
+        // Partition table entry count, with the header checksum fixed up to match
+        bytes[84..88].copy_from_slice(&u32::MAX.to_le_bytes());
+        bytes[HEADER_CHECKSUM].fill(0);
+        let checksum = sha256(&bytes[..HEADER_V1_0_SIZE]);
+        bytes[HEADER_CHECKSUM].copy_from_slice(&checksum);
+        // Past the end of the tables on 64-bit targets, overflowing on 32-bit ones
+        assert!(matches!(
+            Metadata::parse(metadata.geometry, &bytes),
+            Err(LpError::Truncated | LpError::Invalid("table size"))
+        ));
+    }
+
+    #[test]
+    fn super_layout() {
+        let metadata = metadata();
+        let mut data = vec![0; PARTITION_RESERVED_BYTES];
+        data.extend(metadata.geometry.to_bytes());
+        data.extend(metadata.geometry.to_bytes());
+        data.extend(metadata.to_bytes().unwrap());
+        assert_eq!(parse_super(&data, 0), Ok(metadata));
+    }
+
+    #[test]
+    fn allocation_is_aligned() {
+        let mut metadata = metadata();
+        metadata
+            .add_partition("system", "default", 4096, 0)
+            .unwrap();
+        metadata
+            .add_partition("vendor", "default", 4096, 0)
+            .unwrap();
+        // first_logical_sector is 1MiB in, already aligned
+        assert_eq!(
+            metadata.partition("system").unwrap().extents,
+            vec![Extent {
+                num_sectors: 8,
+                target: ExtentTarget::Linear {
+                    block_device: 0,
+                    sector: 2048,
+                },
+            }]
+        );
+        assert_eq!(
+            metadata.partition("vendor").unwrap().extents[0].target,
+            ExtentTarget::Linear {
+                block_device: 0,
+                sector: 4096,
+            }
+        );
+    }
+
+    #[test]
+    fn add_partition_rounds_to_block_size() {
+        let mut metadata = metadata();
+        metadata.add_partition("system", "default", 1, 0).unwrap();
+        assert_eq!(metadata.partition("system").unwrap().size(), 4096);
+    }
+
+    #[test]
+    fn add_partition_errors() {
+        let mut metadata = metadata();
+        metadata.add_partition("system", "main", MIB, 0).unwrap();
+        assert_eq!(
+            metadata.add_partition("system", "main", MIB, 0),
+            Err(LpError::PartitionExists("system".to_string()))
+        );
+        assert_eq!(
+            metadata.add_partition("vendor", "nope", MIB, 0),
+            Err(LpError::UnknownGroup("nope".to_string()))
+        );
+        assert_eq!(
+            metadata.add_partition("vendor", "main", 64 * MIB, 0),
+            Err(LpError::GroupFull("main".to_string(), 64 * MIB))
+        );
+        assert_eq!(
+            metadata.add_partition("rootfs", "default", 200 * MIB, 0),
+            Err(LpError::NoSpace(200 * MIB))
+        );
+    }
+
+    #[test]
+    fn resize_and_remove() {
+        let mut metadata = metadata();
+        metadata
+            .add_partition("system", "default", 4 * MIB, 0)
+            .unwrap();
+        metadata
+            .add_partition("vendor", "default", 4 * MIB, 0)
+            .unwrap();
+        let free = metadata.free_space();
+
+        // Growing system has to continue after vendor
+        metadata.resize_partition("system", 8 * MIB).unwrap();
+        let system = metadata.partition("system").unwrap();
+        assert_eq!(system.size(), 8 * MIB);
+        assert_eq!(system.extents.len(), 2);
+        assert_eq!(metadata.free_space(), free - 4 * MIB);
+
+        metadata.resize_partition("system", 2 * MIB).unwrap();
+        let system = metadata.partition("system").unwrap();
+        assert_eq!(system.size(), 2 * MIB);
+        assert_eq!(system.extents.len(), 1);
+
+        // Only the first MiB and what's left of system remain in use
+        metadata.remove_partition("vendor").unwrap();
+        assert_eq!(metadata.free_space(), 128 * MIB - MIB - 2 * MIB);
+        assert_eq!(
+            metadata.remove_partition("vendor"),
+            Err(LpError::UnknownPartition("vendor".to_string()))
+        );
+    }
+}
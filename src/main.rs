@@ -1,53 +1,155 @@
+use crate::adb::auth::AdbKey;
+use crate::adb::webusb::{find_adb_interface, AdbWebUsb};
+use crate::adb::Adb;
+use crate::connection::FastbootConnection;
+use crate::fastboot::pcap::PcapWriter;
+use crate::fastboot::record::{Recorder, Session};
 use crate::fastboot::webusb::{find_fastboot_interface, FastbootWebUsb};
 use crate::fastboot::{FastBootError, FastBootOps, Fastboot};
 use anyhow::anyhow;
 use dioxus::logger::tracing;
 use dioxus::prelude::*;
+use fleet::{BootState, Fleet, PayloadCache};
 use futures::{AsyncRead, AsyncReadExt, StreamExt};
 use gloo::events::EventListener;
 use gloo::timers::future::TimeoutFuture;
 use js_sys::{Date, Uint8Array};
+use std::cell::RefCell;
 use std::collections::{HashMap, VecDeque};
-use std::str::FromStr;
+use std::rc::Rc;
 use std::time::Instant;
 use thiserror::Error;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
 use web_sys::js_sys::Array;
 use web_sys::{
-    console, DomException, Response, UsbConfiguration, UsbDevice, UsbDeviceFilter,
-    UsbDeviceRequestOptions, UsbDirection, UsbEndpoint, UsbEndpointType, UsbInTransferResult,
-    UsbInterface, UsbOutTransferResult,
+    DomException, UsbConfiguration, UsbDevice, UsbDeviceFilter, UsbDeviceRequestOptions,
+    UsbDirection, UsbEndpoint, UsbEndpointType, UsbInTransferResult, UsbInterface,
+    UsbOutTransferResult,
 };
 
+mod adb;
+mod avb;
+mod backup;
+mod blob;
+#[cfg(not(target_arch = "wasm32"))]
+mod cli;
+mod connection;
+mod console;
+mod dynamic;
+mod factory;
 mod fastboot;
+mod flash;
+mod fleet;
+mod identity;
+mod live;
+mod lp;
+mod replay;
+mod slots;
+mod unlock;
 
 static U_BOOT: Asset = asset!("/assets/u-boot.img");
 
 fn main() {
+    // Native builds given a command run it instead of the UI
+    #[cfg(not(target_arch = "wasm32"))]
+    if std::env::args().len() > 1 {
+        if let Err(err) = futures::executor::block_on(cli::run(std::env::args().skip(1))) {
+            eprintln!("{err:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
     launch(App);
 }
 
 enum DeviceMode {
+    /// Android with USB debugging, connected so it can be told to reboot
+    AndroidAdb(Adb<AdbWebUsb>),
     VendorFastboot,
     UBoot,
     LiveBooted,
 }
 
-async fn detect_device_mode(device: &UsbDevice) -> anyhow::Result<DeviceMode> {
-    let mut fastboot = Fastboot::new(FastbootWebUsb::new(device.clone()).await?);
-    if fastboot
-        .get_var("version-bootloader")
-        .await
-        .is_ok_and(|v| v.contains("U-Boot"))
+/// Microseconds since the epoch, for timestamping session recordings and USB captures
+fn now_micros() -> u64 {
+    (Date::now() * 1000.0) as u64
+}
+
+type RecordedFastboot = Fastboot<Recorder<FastbootWebUsb, fn() -> u64>>;
+
+/// Everything recorded while booting a device: the fastboot session and a USB capture
+#[derive(Clone, PartialEq)]
+struct BootTrace {
+    session: Rc<RefCell<Session>>,
+    capture: Rc<RefCell<PcapWriter>>,
+}
+
+impl BootTrace {
+    fn new() -> Self {
+        Self {
+            session: Rc::new(RefCell::new(Session::new())),
+            capture: Rc::new(RefCell::new(PcapWriter::new())),
+        }
+    }
+}
+
+/// Opens fastboot on the device, recording all transfers into `trace`
+async fn open_recorded(device: UsbDevice, trace: &BootTrace) -> anyhow::Result<RecordedFastboot> {
+    let ops = FastbootWebUsb::new(device)
+        .await?
+        .with_capture(trace.capture.clone());
+    Ok(Fastboot::new(Recorder::new(
+        ops,
+        trace.session.clone(),
+        now_micros as fn() -> u64,
+    )))
+}
+
+async fn detect_device_mode(
+    device: &UsbDevice,
+    trace: &BootTrace,
+    progress: impl FnMut(&str),
+) -> anyhow::Result<DeviceMode> {
+    if find_fastboot_interface(device).is_none() && find_adb_interface(device).is_some() {
+        let adb = adb_connect(device.clone(), progress).await?;
+        if live::is_live(&adb) {
+            return Ok(DeviceMode::LiveBooted);
+        }
+        return Ok(DeviceMode::AndroidAdb(adb));
+    }
+
+    let mut fastboot = open_recorded(device.clone(), trace).await?;
+    detect_fastboot_mode(&mut fastboot).await
+}
+
+/// A variable the device may not know, in which case it refuses the `getvar`
+async fn optional_var<Ops: FastBootOps>(
+    fastboot: &mut Fastboot<Ops>,
+    var: &str,
+) -> Result<Option<String>, FastBootError> {
+    match fastboot.get_var(var).await {
+        Ok(value) => Ok(Some(value)),
+        Err(FastBootError::FastbootFailed(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Tells the fastboot implementations a device goes through while booting apart. Only the device
+/// refusing a variable rules a mode out; any other error, e.g. a replay diverging, is returned.
+async fn detect_fastboot_mode<Ops: FastBootOps>(
+    fastboot: &mut Fastboot<Ops>,
+) -> anyhow::Result<DeviceMode> {
+    if optional_var(fastboot, "version-bootloader")
+        .await?
+        .is_some_and(|v| v.contains("U-Boot"))
     {
         return Ok(DeviceMode::UBoot);
     }
 
-    if fastboot
-        .get_var("partition-type:op2")
-        .await
-        .is_ok_and(|v| v == "raw")
+    if optional_var(fastboot, "partition-type:op2")
+        .await?
+        .is_some_and(|v| v == "raw")
     {
         return Ok(DeviceMode::VendorFastboot);
     }
@@ -65,38 +167,37 @@ fn js_error(err: JsValue) -> AppError {
     AppError::JsError(err.try_into().unwrap())
 }
 
-/// Searches for a paired device that reports given serial.
+/// Searches for a paired device with the given identity (see [identity::device_id]).
 /// If nothing is immediately found, wait until something connects that matches.
-async fn device_by_serial(serial: &str) -> anyhow::Result<UsbDevice> {
+async fn device_by_id(id: &str) -> anyhow::Result<UsbDevice> {
     let window = web_sys::window().unwrap();
     let usb = window.navigator().usb();
 
+    // Listen before looking at what's attached, so nothing connecting in between is missed
+    let (tx, rx) = futures::channel::mpsc::unbounded();
+    let listener = EventListener::new(&usb, "connect", move |event| {
+        let event = event.unchecked_ref::<web_sys::UsbConnectionEvent>();
+        let _ = tx.unbounded_send(event.device());
+    });
+
     let devices: Array = JsFuture::from(usb.get_devices())
         .await
         .map_err(js_error)?
         .unchecked_into();
-    for dev in devices {
-        let dev: UsbDevice = dev.unchecked_into();
-        if dev.serial_number().is_some_and(|v| v == serial) {
+    let attached = devices.into_iter().map(UsbDevice::unchecked_from_js);
+
+    // Devices are identified concurrently, so one that's slow to answer doesn't hold up the rest
+    let mut identified = futures::stream::iter(attached)
+        .chain(rx)
+        .map(|dev| async move { (identity::device_id(&dev).await, dev) })
+        .buffer_unordered(usize::MAX);
+    while let Some((dev_id, dev)) = identified.next().await {
+        if dev_id == id {
+            drop(listener);
             return Ok(dev);
         }
     }
-
-    let (tx, rx) = futures::channel::oneshot::channel();
-
-    let serial = serial.to_owned();
-    let mut tx = Some(tx);
-    let listener = EventListener::new(&usb, "connect", move |event| {
-        let event = event.unchecked_ref::<web_sys::UsbConnectionEvent>();
-        let dev = event.device();
-        if dev.serial_number().is_some_and(|v| v == serial) && tx.is_some() {
-            tx.take().unwrap().send(dev).unwrap();
-        }
-    });
-
-    let dev = rx.await?;
-    drop(listener);
-    Ok(dev)
+    Err(anyhow!("stopped listening for {id}"))
 }
 
 async fn wait_disconnect(device: &UsbDevice) -> anyhow::Result<()> {
@@ -134,39 +235,91 @@ async fn wait_disconnect(device: &UsbDevice) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn boot_uboot(device: UsbDevice) -> anyhow::Result<()> {
-    let window = web_sys::window().unwrap();
-    let path = U_BOOT.resolve();
+/// Opens a fastboot connection to the paired device with the given serial
+async fn open_fastboot(serial: &str) -> anyhow::Result<Fastboot<FastbootWebUsb>> {
+    let device = device_by_id(serial).await?;
+    Ok(Fastboot::new(FastbootWebUsb::new(device).await?))
+}
+
+/// Waits for a device that was just told to reboot to drop off the bus, then reopens fastboot on
+/// it once it has enumerated again.
+async fn reconnect(
+    serial: &str,
+    device: &UsbDevice,
+) -> anyhow::Result<(UsbDevice, Fastboot<FastbootWebUsb>)> {
+    wait_disconnect(device).await?;
+    let device = device_by_id(serial).await?;
+    let fastboot = Fastboot::new(FastbootWebUsb::new(device.clone()).await?);
+    Ok((device, fastboot))
+}
 
-    let mut fastboot = Fastboot::new(FastbootWebUsb::new(device).await?);
+/// The U-Boot image, fetched once however many devices are booted
+async fn uboot_payload(payloads: &PayloadCache) -> anyhow::Result<Rc<[u8]>> {
+    let path = U_BOOT.resolve().to_string_lossy().into_owned();
+    payloads.get(&path, || blob::fetch(path.clone())).await
+}
 
-    let resp = JsFuture::from(window.fetch_with_str(path.to_str().unwrap())).await;
-    let resp = resp.map_err(js_error)?.unchecked_into::<Response>();
-    let size = resp
-        .headers()
-        .get("content-length")
-        .map_err(js_error)?
-        .ok_or(anyhow!("content-length missing"))?;
-    let size = u32::from_str(&size)?;
-    let read = wasm_streams::ReadableStream::from_raw(resp.body().ok_or(anyhow!("no body"))?);
+async fn boot_uboot(
+    device: UsbDevice,
+    trace: &BootTrace,
+    payloads: &PayloadCache,
+) -> anyhow::Result<()> {
+    let payload = uboot_payload(payloads).await?;
 
-    let info = fastboot.download(size).await?;
+    let mut fastboot = open_recorded(device, trace).await?;
+    load_uboot(&mut fastboot, &payload).await
+}
+
+/// Downloads U-Boot into vendor fastboot and boots it
+async fn load_uboot<Ops: FastBootOps>(
+    fastboot: &mut Fastboot<Ops>,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    let info = fastboot.download(payload.len() as u64).await?;
     tracing::debug!("Start download success: {:?}", info);
-    let info = fastboot.do_download(read.into_async_read()).await?;
+    let info = fastboot.do_download(payload).await?;
     tracing::debug!("Download success: {:?}", info);
 
     Ok(fastboot.boot().await?)
 }
 
+/// Connects to adbd on the device, which may first need USB debugging to be allowed
+async fn adb_connect(
+    device: UsbDevice,
+    mut progress: impl FnMut(&str),
+) -> anyhow::Result<Adb<AdbWebUsb>> {
+    let key = AdbKey::load_or_generate()?;
+    let ops = AdbWebUsb::new(device).await?;
+    Ok(Adb::connect(ops, &key, || {
+        progress("Waiting for USB debugging to be allowed on the device")
+    })
+    .await?)
+}
+
 /// Handles booting a device all the way to kernel, passing through vendor fastboot and U-Boot
-/// as needed.
-async fn boot(serial: String) -> anyhow::Result<()> {
+/// as needed. Every fastboot transfer along the way is recorded into `trace`, and each step is
+/// announced through `progress`.
+async fn boot(
+    serial: String,
+    trace: BootTrace,
+    payloads: PayloadCache,
+    mut progress: impl FnMut(&str),
+) -> anyhow::Result<()> {
     loop {
-        let device = device_by_serial(&serial).await?;
-
-        match detect_device_mode(&device).await? {
+        progress("Waiting for device");
+        let device = device_by_id(&serial).await?;
+
+        progress("Detecting device mode");
+        match detect_device_mode(&device, &trace, &mut progress).await? {
+            DeviceMode::AndroidAdb(mut adb) => {
+                progress("Rebooting to bootloader");
+                adb.reboot("bootloader").await?;
+                drop(adb);
+                wait_disconnect(&device).await?;
+            }
             DeviceMode::VendorFastboot => {
-                boot_uboot(device.clone()).await?;
+                progress("Loading U-Boot");
+                boot_uboot(device.clone(), &trace, &payloads).await?;
                 wait_disconnect(&device).await?;
             }
             DeviceMode::UBoot => {
@@ -178,33 +331,31 @@ async fn boot(serial: String) -> anyhow::Result<()> {
             }
         }
     }
-
-    Ok(())
 }
 
 #[component]
 fn App() -> Element {
     let mut available_devices = use_signal(|| HashMap::new());
-    let mut active_device = use_signal(|| None);
-    let mut boot_task = use_signal(|| None);
+    let mut active_devices = use_signal(Vec::<String>::new);
+    let mut tools_device = use_signal(|| None::<String>);
+    let mut live_device = use_signal(|| None::<String>);
+    let mut boot_tasks = use_signal(Vec::new);
+    let mut boot_states = use_signal(HashMap::<String, BootState>::new);
+    let mut boot_traces = use_signal(HashMap::<String, BootTrace>::new);
+    let payloads = use_hook(PayloadCache::new);
 
     // Setup WebUSB - add handlers for device connect/disconnection events and populate
     // available devices state.
     use_resource(move || async move {
         tracing::info!("doing thing");
-        let mut add_device = move |device: UsbDevice| {
-            let serial = device.serial_number();
-            if serial.is_none() {
-                tracing::warn!(
-                    "Ignoring {}:{} as it has no serial number",
-                    device.vendor_id(),
-                    device.product_id()
-                );
-            }
-            let serial = serial.unwrap();
-            if find_fastboot_interface(&device).is_some() {
-                available_devices.write().insert(serial.clone(), device);
+        let add_device = move |device: UsbDevice| {
+            if find_fastboot_interface(&device).is_none() && find_adb_interface(&device).is_none() {
+                return;
             }
+            wasm_bindgen_futures::spawn_local(async move {
+                let id = identity::device_id(&device).await;
+                available_devices.write().insert(id, device);
+            });
         };
 
         let window = web_sys::window().unwrap();
@@ -219,6 +370,7 @@ fn App() -> Element {
         let on_disconnect =
             Closure::<dyn FnMut(_)>::new(move |event: web_sys::UsbConnectionEvent| {
                 let dev = event.device();
+                identity::forget(&dev);
                 available_devices.write().retain(|_, v| !v.loose_eq(&dev));
             });
         usb.add_event_listener_with_callback("disconnect", on_disconnect.as_ref().unchecked_ref())
@@ -234,22 +386,70 @@ fn App() -> Element {
         }
     });
 
+    let boot_payloads = payloads.clone();
+    let start_boot = move |serials: Vec<String>| {
+        let mut states = HashMap::new();
+        let mut traces = HashMap::new();
+        let mut tasks = vec![];
+        for serial in &serials {
+            let trace = BootTrace::new();
+            states.insert(serial.clone(), BootState::Running("Starting".to_string()));
+            traces.insert(serial.clone(), trace.clone());
+
+            let payloads = boot_payloads.clone();
+            to_owned![serial];
+            tasks.push(spawn(async move {
+                let progress = |step: &str| {
+                    let state = BootState::Running(step.to_string());
+                    boot_states.write().insert(serial.clone(), state);
+                };
+                let state = match boot(serial.clone(), trace, payloads, progress).await {
+                    Ok(()) => BootState::Booted,
+                    Err(err) => {
+                        tracing::error!("Booting {} failed: {:#}", serial, err);
+                        BootState::Failed(format!("{err:#}"))
+                    }
+                };
+                boot_states.write().insert(serial, state);
+            }));
+        }
+        boot_states.set(states);
+        boot_traces.set(traces);
+        boot_tasks.set(tasks);
+        active_devices.set(serials);
+    };
+
     rsx! {
-        if let Some(serial) = active_device.read().as_ref() {
-            Device { serial: serial }
+        if !active_devices.read().is_empty() {
+            Fleet {
+                serials: active_devices(),
+                states: boot_states(),
+                traces: boot_traces(),
+                on_close: move |_| {
+                    for task in boot_tasks.write().drain(..) {
+                        task.cancel();
+                    }
+                    active_devices.write().clear();
+                },
+            }
+        } else if let Some(serial) = tools_device.read().as_ref() {
+            DeviceTools {
+                serial: serial,
+                on_close: move |_| *tools_device.write() = None,
+            }
+        } else if let Some(serial) = live_device.read().as_ref() {
+            live::LiveSession {
+                serial: serial,
+                on_close: move |_| *live_device.write() = None,
+            }
         } else {
             SelectDevice {
                 available_devices: available_devices(),
-                on_select: move |serial: String| {
-                    // dev_svc.send(DeviceAction::BootDevice(serial)),
-                    *active_device.write() = Some(serial.clone());
-                    *boot_task.write() = Some(spawn(async move {
-                        if let Err(err) = boot(serial).await {
-                            tracing::error!("Sad {}", err);
-                        }
-                    }));
-                }
+                on_select: start_boot,
+                on_tools: move |serial: String| *tools_device.write() = Some(serial),
+                on_live: move |serial: String| *live_device.write() = Some(serial),
             },
+            replay::ReplaySession { payloads: payloads.clone() }
         }
     }
 }
@@ -257,9 +457,26 @@ fn App() -> Element {
 #[component]
 fn SelectDevice(
     available_devices: HashMap<String, UsbDevice>,
-    on_select: EventHandler<String>,
+    on_select: EventHandler<Vec<String>>,
+    on_tools: EventHandler<String>,
+    on_live: EventHandler<String>,
 ) -> Element {
     let mut pair_error = use_signal(|| "".to_string());
+    let mut selected = use_signal(Vec::<String>::new);
+    let boot_selected = {
+        to_owned![available_devices];
+        move |_| {
+            // Devices that went away since being ticked can't be booted
+            let serials = selected
+                .read()
+                .iter()
+                .filter(|serial| available_devices.contains_key(*serial))
+                .cloned()
+                .collect();
+            selected.write().clear();
+            on_select.call(serials);
+        }
+    };
 
     let start_pairing = move |_| async move {
         let window = web_sys::window().unwrap();
@@ -272,7 +489,18 @@ fn SelectDevice(
         filter.set_vendor_id(0x18d1);
         filter.set_product_id(0xd00d);
 
-        let filters = Array::of1(&filter);
+        // Anything running adbd, to be rebooted into the bootloader
+        let adb_filter = UsbDeviceFilter::new();
+        adb_filter.set_class_code(0xff);
+        adb_filter.set_subclass_code(0x42);
+        adb_filter.set_protocol_code(0x01);
+
+        // Serial consoles, e.g. of a booted live OS
+        let acm_filter = UsbDeviceFilter::new();
+        acm_filter.set_class_code(console::acm::CLASS_COMM);
+        acm_filter.set_subclass_code(console::acm::SUBCLASS_ACM);
+
+        let filters = Array::of3(&filter, &adb_filter, &acm_filter);
         if let Err(err) =
             JsFuture::from(usb.request_device(&UsbDeviceRequestOptions::new(&filters))).await
         {
@@ -289,16 +517,55 @@ fn SelectDevice(
                 to_owned![serial];
                 rsx! {
                     li {
+                        input {
+                            r#type: "checkbox",
+                            checked: selected.read().contains(&serial),
+                            onchange: {
+                                to_owned![serial];
+                                move |evt: Event<FormData>| {
+                                    selected.write().retain(|s| *s != serial);
+                                    if evt.checked() {
+                                        selected.write().push(serial.clone());
+                                    }
+                                }
+                            },
+                        }
                         "{dev.product_name().unwrap_or_default()} ({serial})"
                         " "
                         button {
-                            onclick: move |_| on_select.call(serial.clone()),
+                            onclick: {
+                                to_owned![serial];
+                                move |_| on_select.call(vec![serial.clone()])
+                            },
                             "Boot"
                         }
+                        " "
+                        if find_adb_interface(dev).is_some() {
+                            button {
+                                onclick: {
+                                    to_owned![serial];
+                                    move |_| on_live.call(serial.clone())
+                                },
+                                "Live"
+                            }
+                        } else {
+                            button {
+                                onclick: {
+                                    to_owned![serial];
+                                    move |_| on_tools.call(serial.clone())
+                                },
+                                "Tools"
+                            }
+                        }
                     }
                 }
             })}
         },
+        button {
+            disabled: selected.read().is_empty(),
+            onclick: boot_selected,
+            "Boot selected"
+        }
         button {
             onclick: start_pairing,
             "Pair Device"
@@ -308,8 +575,65 @@ fn SelectDevice(
 }
 
 #[component]
-fn Device(serial: String) -> Element {
+fn Device(serial: String, state: BootState, trace: BootTrace) -> Element {
+    let mut save_error = use_signal(String::new);
+
+    let save_session = {
+        to_owned![trace];
+        move |_| {
+            let data = trace.session.borrow().to_bytes();
+            if let Err(err) = blob::save_bytes(&data, "bootbud-session.fbrec") {
+                save_error.set(err.to_string());
+            }
+        }
+    };
+    let save_capture = move |_| {
+        let capture = trace.capture.borrow();
+        if let Err(err) = blob::save_bytes(capture.as_bytes(), "bootbud-usb.pcapng") {
+            save_error.set(err.to_string());
+        }
+    };
+
     rsx! {
-        "Doing boot things to {serial}"
+        match state {
+            BootState::Running(step) => rsx! { "{serial}: {step}" },
+            BootState::Booted => rsx! { "{serial}: booted" },
+            BootState::Failed(err) => rsx! { "{serial}: failed: {err}" },
+        }
+        " "
+        button {
+            onclick: save_session,
+            "Save session trace"
+        }
+        button {
+            onclick: save_capture,
+            "Save USB capture"
+        }
+        {save_error}
+        console::Console { serial: serial.clone() }
+    }
+}
+
+/// Maintenance actions for a device sitting in fastboot, as opposed to booting it.
+#[component]
+fn DeviceTools(serial: String, on_close: EventHandler<()>) -> Element {
+    let conn = use_hook({
+        to_owned![serial];
+        move || FastbootConnection::new(serial)
+    });
+
+    rsx! {
+        h2 { "{serial}" }
+        button {
+            onclick: move |_| on_close.call(()),
+            "Back"
+        }
+        backup::Backup { conn: conn.clone() }
+        slots::Slots { conn: conn.clone() }
+        flash::FlashImage { conn: conn.clone() }
+        dynamic::DynamicPartitions { conn: conn.clone() }
+        avb::Verity { conn: conn.clone() }
+        unlock::Unlock { conn: conn.clone() }
+        factory::FactoryFlash { conn: conn.clone() }
     }
 }
@@ -0,0 +1,97 @@
+//! Replay of a saved session trace (e.g. one attached to a bug report) through today's boot code,
+//! showing where the boot now diverges from what the device did back then.
+
+use crate::blob;
+use crate::fastboot::record::{Replay, Session};
+use crate::fastboot::Fastboot;
+use crate::fleet::PayloadCache;
+use crate::{detect_fastboot_mode, load_uboot, uboot_payload, DeviceMode};
+use anyhow::anyhow;
+use dioxus::logger::tracing;
+use dioxus::prelude::*;
+
+/// Runs the fastboot steps of [crate::boot] against the recording, returning the number of
+/// transfers replayed
+async fn replay_boot(session: Session, payloads: &PayloadCache) -> anyhow::Result<usize> {
+    let transfers = session.transfers.len();
+    let mut fastboot = Fastboot::new(Replay::new(session));
+    // U-Boot (or anything else) ends the boot
+    while let DeviceMode::VendorFastboot = detect_fastboot_mode(&mut fastboot).await? {
+        let payload = uboot_payload(payloads).await?;
+        load_uboot(&mut fastboot, &payload).await?;
+    }
+    if !fastboot.ops().finished() {
+        return Err(anyhow!("The boot finished before the recording did"));
+    }
+    Ok(transfers)
+}
+
+async fn load_and_replay(payloads: &PayloadCache) -> anyhow::Result<usize> {
+    let file = blob::selected_file("session-trace").ok_or(anyhow!("Select a trace first"))?;
+    let data = blob::read_prefix(&file, file.size() as u64).await?;
+    replay_boot(Session::parse(&data)?, payloads).await
+}
+
+/// Replays a `.fbrec` session trace saved from a boot
+#[component]
+pub fn ReplaySession(payloads: PayloadCache) -> Element {
+    let mut status = use_signal(String::new);
+
+    let start = move |_| {
+        to_owned![payloads];
+        async move {
+            status.set("Replaying...".to_string());
+            match load_and_replay(&payloads).await {
+                Ok(transfers) => status.set(format!("Replayed all {transfers} transfers")),
+                Err(err) => {
+                    tracing::error!("Replay failed: {:#}", err);
+                    status.set(format!("Replay diverged: {err:#}"));
+                }
+            }
+        }
+    };
+
+    rsx! {
+        h3 { "Replay session trace" }
+        input {
+            id: "session-trace",
+            r#type: "file",
+            accept: ".fbrec",
+        }
+        button { onclick: start, "Replay" }
+        {status}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fastboot::record::{Direction, Transfer};
+    use crate::fastboot::TransferStatus;
+    use futures::executor::block_on;
+
+    fn transfer(direction: Direction, data: &[u8]) -> Transfer {
+        Transfer {
+            direction,
+            status: TransferStatus::Ok,
+            timestamp: 0,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn divergence_names_transfer() {
+        let mut session = Session::new();
+        session.transfers = vec![
+            transfer(Direction::Out, b"getvar:version-bootloader"),
+            transfer(Direction::In, b"OKAYvendor-1.0"),
+            transfer(Direction::Out, b"getvar:partition-type:boot"),
+            transfer(Direction::In, b"OKAYraw"),
+        ];
+        let err = block_on(replay_boot(session, &PayloadCache::new())).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Transfer 2 sent different data than recorded"),
+            "{err:#}"
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use crate::connection::FastbootConnection;
+use crate::fastboot::webusb::FastbootWebUsb;
+use crate::fastboot::{Fastboot, Slot, SlotInfo};
+use dioxus::logger::tracing;
+use dioxus::prelude::*;
+
+async fn load_slots(
+    fastboot: &mut Fastboot<FastbootWebUsb>,
+) -> anyhow::Result<Option<(Slot, Vec<SlotInfo>)>> {
+    let slots = fastboot.slots().await?;
+    if slots.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some((fastboot.current_slot().await?, slots)))
+}
+
+fn flag(value: Option<bool>) -> &'static str {
+    match value {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "?",
+    }
+}
+
+/// Shows the A/B slot state of a device, and allows switching the active slot
+#[component]
+pub fn Slots(conn: FastbootConnection) -> Element {
+    let mut error = use_signal(String::new);
+    let mut slots = use_resource({
+        to_owned![conn];
+        move || {
+            to_owned![conn];
+            async move {
+                conn.run(async |fastboot| load_slots(fastboot).await)
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+        }
+    });
+
+    let set_active = move |slot: Slot| {
+        to_owned![conn];
+        async move {
+            error.set(String::new());
+            let result = conn
+                .run(async |fastboot| Ok(fastboot.set_active(slot).await?))
+                .await;
+            if let Err(err) = result {
+                tracing::error!("Setting active slot failed: {}", err);
+                error.set(err.to_string());
+            }
+            slots.restart();
+        }
+    };
+
+    rsx! {
+        h3 { "Slots" }
+        match &*slots.read_unchecked() {
+            None => rsx! { p { "Reading slots..." } },
+            Some(Err(err)) => rsx! { p { "Failed to read slots: {err}" } },
+            Some(Ok(None)) => rsx! { p { "Device has no A/B slots" } },
+            Some(Ok(Some((current, slots)))) => rsx! {
+                table {
+                    tr {
+                        th { "Slot" }
+                        th { "Successful" }
+                        th { "Unbootable" }
+                        th { "Retries" }
+                        th {}
+                    }
+                    for info in slots.iter().cloned() {
+                        tr {
+                            td {
+                                "{info.slot}"
+                                if info.slot == *current { " (active)" }
+                            }
+                            td { {flag(info.successful)} }
+                            td { {flag(info.unbootable)} }
+                            td {
+                                {info.retry_count.map_or("?".to_string(), |c| c.to_string())}
+                            }
+                            td {
+                                if info.slot != *current {
+                                    button {
+                                        onclick: {
+                                            let set_active = set_active.clone();
+                                            move |_| set_active(info.slot)
+                                        },
+                                        "Make active"
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+        }
+        {error}
+    }
+}
@@ -0,0 +1,167 @@
+use crate::connection::FastbootConnection;
+use crate::fastboot::webusb::FastbootWebUsb;
+use crate::fastboot::Fastboot;
+use crate::{device_by_id, wait_disconnect};
+use dioxus::logger::tracing;
+use dioxus::prelude::*;
+use futures::future::{select, Either};
+use gloo::timers::future::TimeoutFuture;
+
+/// How long to wait for the device to drop off the bus after an unlock, before assuming it
+/// stays in the bootloader
+const REBOOT_TIMEOUT_MS: u32 = 15_000;
+
+/// Lock related state the bootloader reports; `None` for whatever it doesn't
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LockState {
+    unlocked: Option<bool>,
+    secure: Option<bool>,
+    unlock_ability: Option<bool>,
+}
+
+async fn load_state(fastboot: &mut Fastboot<FastbootWebUsb>) -> anyhow::Result<LockState> {
+    Ok(LockState {
+        unlocked: fastboot.is_unlocked().await.ok(),
+        secure: fastboot.is_secure().await.ok(),
+        unlock_ability: fastboot.unlock_ability().await.ok(),
+    })
+}
+
+async fn unlock(
+    conn: &FastbootConnection,
+    critical: bool,
+    mut status: Signal<Vec<String>>,
+) -> anyhow::Result<()> {
+    // The device may reboot, so it's kept away from the other panes until it's back
+    let _exclusive = conn.exclusive().await;
+    let serial = conn.serial();
+    let device = device_by_id(serial).await?;
+    let mut fastboot = Fastboot::new(FastbootWebUsb::new(device.clone()).await?);
+    status
+        .write()
+        .push("Confirm the unlock on the device, using its volume and power keys".to_string());
+    fastboot
+        .unlock(critical, |info| status.write().push(info.to_string()))
+        .await?;
+    drop(fastboot);
+
+    status
+        .write()
+        .push("Unlocked, waiting for the device to settle".to_string());
+    let disconnect = std::pin::pin!(wait_disconnect(&device));
+    match select(disconnect, TimeoutFuture::new(REBOOT_TIMEOUT_MS)).await {
+        Either::Left((result, _)) => {
+            result?;
+            status
+                .write()
+                .push("Device rebooted, waiting for it to come back".to_string());
+            device_by_id(serial).await?;
+        }
+        Either::Right(_) => (),
+    }
+    Ok(())
+}
+
+fn flag(value: Option<bool>) -> &'static str {
+    match value {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "unknown",
+    }
+}
+
+/// Guides through unlocking the bootloader
+#[component]
+pub fn Unlock(conn: FastbootConnection) -> Element {
+    let mut status = use_signal(Vec::<String>::new);
+    let mut running = use_signal(|| false);
+    let mut confirmed = use_signal(|| false);
+    let mut critical = use_signal(|| false);
+    let mut state = use_resource({
+        to_owned![conn];
+        move || {
+            to_owned![conn];
+            async move {
+                conn.run(async |fastboot| load_state(fastboot).await)
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+        }
+    });
+
+    let start = move |_| {
+        to_owned![conn];
+        async move {
+            status.write().clear();
+            running.set(true);
+            match unlock(&conn, critical(), status).await {
+                Ok(()) => status.write().push("Done".to_string()),
+                Err(err) => {
+                    tracing::error!("Unlocking failed: {}", err);
+                    status.write().push(format!("Unlocking failed: {err}"));
+                }
+            }
+            confirmed.set(false);
+            running.set(false);
+            state.restart();
+        }
+    };
+
+    let blocked = match &*state.read() {
+        Some(Ok(state)) => {
+            state.unlock_ability == Some(false) || (state.unlocked == Some(true) && !critical())
+        }
+        _ => true,
+    };
+
+    rsx! {
+        h3 { "Bootloader unlock" }
+        match &*state.read_unchecked() {
+            None => rsx! { p { "Reading lock state..." } },
+            Some(Err(err)) => rsx! { p { "Failed to read lock state: {err}" } },
+            Some(Ok(state)) => rsx! {
+                p {
+                    "Unlocked: " {flag(state.unlocked)}
+                    ", secure: " {flag(state.secure)}
+                    ", unlock allowed: " {flag(state.unlock_ability)}
+                }
+                if state.unlock_ability == Some(false) {
+                    p {
+                        "Unlocking is not allowed. Enable \"OEM unlocking\" in the developer "
+                        "options of Android first."
+                    }
+                }
+            },
+        }
+        p {
+            "Unlocking erases all user data on the device. Back up anything you need to keep "
+            "before continuing."
+        }
+        label {
+            input {
+                r#type: "checkbox",
+                checked: critical(),
+                onchange: move |evt| critical.set(evt.checked()),
+            }
+            "Also unlock bootloader-critical partitions (unlock_critical)"
+        }
+        label {
+            input {
+                r#type: "checkbox",
+                checked: confirmed(),
+                onchange: move |evt| confirmed.set(evt.checked()),
+            }
+            "I understand all data on the device will be wiped"
+        }
+        button {
+            disabled: running() || !confirmed() || blocked,
+            onclick: start,
+            "Unlock"
+        }
+        ul {
+            for line in status.read().iter() {
+                li { "{line}" }
+            }
+        }
+    }
+}